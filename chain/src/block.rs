@@ -1,6 +1,7 @@
 //! Block and blockchain related functionality.
 pub mod cache;
 pub mod store;
+pub mod validator;
 pub use nakamoto_common::block::tree::*;
 
 pub use bitcoin::blockdata::block::{Block, BlockHeader};
@@ -7,25 +7,30 @@
 #[cfg(test)]
 pub mod test;
 
-use std::cmp::Ordering;
-use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::cmp::Reverse;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet, VecDeque};
+use std::io;
+use std::sync::Arc;
 
 use bitcoin::blockdata::block::BlockHeader;
+use bitcoin::consensus::encode::{self, Decodable, Encodable, VarInt};
 use bitcoin::consensus::params::Params;
 use bitcoin::hash_types::BlockHash;
-use bitcoin::network::constants::Network;
 
 use nonempty::NonEmpty;
 
-use nakamoto_common::block::tree::{self, BlockTree, Branch, Error, ImportResult};
+use nakamoto_common::block::tree::{self, BlockTree, Branch, Error, Event, ImportResult};
 use nakamoto_common::block::{
     self,
     iter::Iter,
     store::Store,
-    time::{self, Clock},
-    Bits, BlockTime, Height, Work,
+    time::{self, Clock, LocalTime},
+    BlockTime, Height, Work,
 };
 
+use super::validator;
+
 /// A block that is being stored by the block cache.
 #[derive(Debug, Clone, Copy)]
 struct CachedBlock {
@@ -57,6 +62,220 @@ struct Candidate {
     fork_hash: BlockHash,
 }
 
+/// How to choose between chains with equal work.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TieBreak {
+    /// Keep the chain that was seen first. This is what Bitcoin Core does, and avoids
+    /// re-orgs between chains with equal work.
+    FirstSeen,
+    /// Pick the chain whose tip has the lowest hash. Within a single import, the choice
+    /// doesn't depend on the order in which headers are received, which makes it useful
+    /// for testing. The current tip is never re-orged away from on equal work though.
+    ///
+    /// Nb. Hashes are compared as byte arrays rather than as integers.
+    LowestHash,
+}
+
+/// How two blocks relate to each other. See [`BlockCache::tip_relationship`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TipRelation {
+    /// Both blocks are the same.
+    Equal,
+    /// The first block is an ancestor of the second.
+    Ancestor,
+    /// The first block is a descendant of the second.
+    Descendant,
+    /// The blocks are on diverging branches.
+    Fork {
+        /// Height of the last block both branches have in common.
+        height: Height,
+        /// Hash of the last block both branches have in common.
+        hash: BlockHash,
+    },
+}
+
+/// Depth under which blocks are buried before they are checkpointed, when no maximum
+/// re-org depth is configured. See [`ChainConfig::checkpoint_interval`].
+pub const DEFAULT_CHECKPOINT_DEPTH: Height = 144;
+
+/// Number of headers loaded from the store between progress reports.
+/// See [`BlockCache::load`].
+pub const LOAD_PROGRESS_INTERVAL: Height = 1000;
+
+/// A checkpoint generated by the block cache as it syncs, for a block buried deep enough
+/// in the active chain not to be re-orged. Unlike hardcoded checkpoints, these are only
+/// as trustworthy as the chain they were taken from, and are meant for our own use, eg.
+/// to validate the chain against on restart. See [`BlockCache::export_checkpoints`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SelfCheckpoint {
+    /// Height of the block.
+    pub height: Height,
+    /// Hash of the block.
+    pub hash: BlockHash,
+    /// Cumulative proof-of-work of the chain, up to and including the block.
+    pub work: Work,
+}
+
+/// Limits on the headers stored by the block cache, outside of the active chain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChainConfig {
+    /// Maximum number of orphan headers, ie. headers that don't connect to the active
+    /// chain. Once exceeded, the oldest orphans are evicted first.
+    pub max_orphans: usize,
+    /// Maximum number of headers stored on side-chains, ie. forks off the active chain.
+    /// Once exceeded, headers from the branches with the least work are evicted first.
+    pub max_side_chain_headers: usize,
+    /// Maximum number of side-chains, ie. of distinct tips outside of the active chain.
+    /// Once exceeded, the side-chains with the least work are evicted first.
+    pub max_side_chains: usize,
+    /// Maximum depth of a re-org. Forks off the active chain deeper than this are rejected.
+    /// There is no limit by default, since a limit could prevent a client from recovering
+    /// after syncing a bogus chain, eg. from a single dishonest peer.
+    pub max_reorg_depth: Option<Height>,
+    /// Maximum number of headers disconnected by re-orgs to retain.
+    /// See [`BlockCache::disconnected_history`].
+    pub max_history: usize,
+    /// How to choose between chains with equal work, on every network.
+    pub tie_break: TieBreak,
+    /// A block, given by height and hash, trusted to be part of the valid chain. Headers
+    /// extending the active chain up to this block are only checked for linkage, against
+    /// checkpoints, and for their own proof-of-work: difficulty retargeting and timestamp
+    /// checks are skipped.
+    /// Headers above it are fully validated. Once it is part of the active chain, forks
+    /// below it are rejected, as with checkpoints.
+    ///
+    /// Until the block is reached, the chain is only as good as the peers it is synced
+    /// from, so this should only be set to a hash obtained from a trusted source.
+    pub assume_valid: Option<(Height, BlockHash)>,
+    /// Generate a checkpoint for every block of the active chain whose height is a multiple
+    /// of this interval, once it is buried under [`ChainConfig::max_reorg_depth`] blocks,
+    /// or [`DEFAULT_CHECKPOINT_DEPTH`] if no maximum is set. See [`SelfCheckpoint`].
+    /// Checkpoints are kept in the store, and the chain is validated against them when
+    /// it is loaded. No checkpoints are generated by default.
+    pub checkpoint_interval: Option<Height>,
+    /// Maximum difference, in seconds and in either direction, between the timestamps of
+    /// consecutive blocks of the active chain. Blocks exceeding it are still accepted,
+    /// since they are valid, but are reported with [`Event::UnusualTimestamp`].
+    /// Not checked by default.
+    pub max_timestamp_drift: Option<BlockTime>,
+}
+
+impl Default for ChainConfig {
+    fn default() -> Self {
+        Self {
+            max_orphans: 1024,
+            max_side_chain_headers: 4096,
+            max_side_chains: 256,
+            max_reorg_depth: None,
+            max_history: 0,
+            tie_break: TieBreak::FirstSeen,
+            assume_valid: None,
+            checkpoint_interval: None,
+            max_timestamp_drift: None,
+        }
+    }
+}
+
+/// A read-only view of the active chain of a [`BlockCache`], as it was when the snapshot
+/// was taken. See [`BlockCache::snapshot`].
+#[derive(Debug, Clone)]
+pub struct CacheSnapshot {
+    chain: Arc<NonEmpty<CachedBlock>>,
+    headers: Arc<HashMap<BlockHash, Height>>,
+    root: Height,
+    genesis: CachedBlock,
+}
+
+impl CacheSnapshot {
+    /// Get a block of the active chain by hash.
+    pub fn get_block(&self, hash: &BlockHash) -> Option<(Height, &BlockHeader)> {
+        self.headers
+            .get(hash)
+            .and_then(|height| self.block(*height))
+            .map(|blk| (blk.height, &blk.header))
+    }
+
+    /// Get a block of the active chain by height.
+    pub fn get_block_by_height(&self, height: Height) -> Option<&BlockHeader> {
+        self.block(height).map(|b| &b.header)
+    }
+
+    /// Get the hash of a block of the active chain by height.
+    pub fn get_block_hash(&self, height: Height) -> Option<BlockHash> {
+        self.block(height).map(|b| b.hash)
+    }
+
+    /// Get the best block hash and header.
+    pub fn tip(&self) -> (BlockHash, BlockHeader) {
+        (self.chain.last().hash, self.chain.last().header)
+    }
+
+    /// Get the height of the active chain.
+    pub fn height(&self) -> Height {
+        self.root + self.chain.tail.len() as Height
+    }
+
+    /// Get a block of the active chain by height. See [`BlockCache::prune_to_checkpoint`].
+    fn block(&self, height: Height) -> Option<&CachedBlock> {
+        match height.checked_sub(self.root) {
+            Some(ix) => self.chain.get(ix as usize),
+            None if height == self.genesis.height => Some(&self.genesis),
+            None => None,
+        }
+    }
+}
+
+/// The headers of an active chain above a base block, eg. for incremental backups, or to
+/// sync the chains of two nodes that already share a base. See [`BlockCache::export_diff`]
+/// and [`BlockCache::import_diff`].
+///
+/// Diffs are self-describing: they carry the height and hash of their base, so that they
+/// can't be applied onto the wrong chain. They are encoded with the consensus encoding, as
+/// the base height and hash, followed by the headers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChainDiff {
+    /// Height of the base block.
+    pub base_height: Height,
+    /// Hash of the base block.
+    pub base_hash: BlockHash,
+    /// Headers above the base block, lowest first.
+    pub headers: Vec<BlockHeader>,
+}
+
+impl Encodable for ChainDiff {
+    fn consensus_encode<W: io::Write>(&self, mut e: W) -> Result<usize, encode::Error> {
+        let mut len = 0;
+
+        len += self.base_height.consensus_encode(&mut e)?;
+        len += self.base_hash.consensus_encode(&mut e)?;
+        len += VarInt(self.headers.len() as u64).consensus_encode(&mut e)?;
+
+        for header in &self.headers {
+            len += header.consensus_encode(&mut e)?;
+        }
+        Ok(len)
+    }
+}
+
+impl Decodable for ChainDiff {
+    fn consensus_decode<D: io::Read>(mut d: D) -> Result<Self, encode::Error> {
+        let base_height = Height::consensus_decode(&mut d)?;
+        let base_hash = BlockHash::consensus_decode(&mut d)?;
+        let VarInt(count) = VarInt::consensus_decode(&mut d)?;
+        // Don't trust the count for allocating, the headers may not follow.
+        let mut headers = Vec::new();
+
+        for _ in 0..count {
+            headers.push(BlockHeader::consensus_decode(&mut d)?);
+        }
+        Ok(ChainDiff {
+            base_height,
+            base_hash,
+            headers,
+        })
+    }
+}
+
 /// An implementation of [`BlockTree`] using a generic storage backend.
 /// Most of the functionality is accessible via the trait.
 ///
@@ -64,10 +283,39 @@ struct Candidate {
 ///
 #[derive(Debug, Clone)]
 pub struct BlockCache<S: Store> {
-    chain: NonEmpty<CachedBlock>,
-    headers: HashMap<BlockHash, Height>,
+    /// The active chain. Shared with snapshots, and copied on write if there are any.
+    /// Starts at the genesis, unless the cache was pruned.
+    chain: Arc<NonEmpty<CachedBlock>>,
+    /// Height of the first block of `chain`.
+    root: Height,
+    /// Cumulative proof-of-work of the active chain, up to and including the first block
+    /// of `chain`.
+    root_work: Work,
+    /// The genesis block. It is only part of `chain` if the cache wasn't pruned.
+    genesis: CachedBlock,
+    /// The headers preceding the genesis block, oldest first, if the cache is rooted at a
+    /// checkpoint. They are only used to validate the headers following it.
+    ancestors: Vec<BlockHeader>,
+    /// Heights of the blocks on the active chain. Shared with snapshots, like `chain`.
+    headers: Arc<HashMap<BlockHash, Height>>,
     orphans: HashMap<BlockHash, BlockHeader>,
+    /// Hashes of the headers stored in `orphans`, by the hash of their parent.
+    orphan_children: HashMap<BlockHash, Vec<BlockHash>>,
+    /// Headers stored in `orphans`, in the order they were inserted. May contain headers
+    /// that have since been removed from `orphans`.
+    arrivals: VecDeque<BlockHash>,
+    /// Number of headers stored in `orphans` without any stored children. This bounds the
+    /// number of side-chains, without having to walk them.
+    orphan_tips: usize,
     checkpoints: BTreeMap<Height, BlockHash>,
+    /// Checkpoints generated from the active chain, by height.
+    self_checkpoints: BTreeMap<Height, SelfCheckpoint>,
+    /// Headers that were disconnected from the active chain, oldest first.
+    history: Vec<(Height, BlockHeader)>,
+    /// Limits on stored headers.
+    config: ChainConfig,
+    /// Events not yet consumed. See [`BlockTree::events`].
+    events: Vec<Event>,
     params: Params,
     store: S,
 }
@@ -79,37 +327,169 @@ impl<S: Store<Header = BlockHeader>> BlockCache<S> {
         params: Params,
         checkpoints: &[(Height, BlockHash)],
     ) -> Result<Self, Error> {
-        let genesis = store.genesis();
+        Self::load(store, params, checkpoints, |_, _| {})
+    }
+
+    /// Create a new `BlockCache` from a `Store`, like [`BlockCache::from`], reporting
+    /// progress as headers are loaded from the store. The callback is called with the
+    /// number of headers loaded and the total, every [`LOAD_PROGRESS_INTERVAL`] headers,
+    /// and once all headers are loaded. The genesis isn't counted.
+    ///
+    /// The loaded chain is validated against the checkpoints stored along with it, see
+    /// [`ChainConfig::checkpoint_interval`]. If a stored checkpoint isn't part of the
+    /// chain, [`Error::InvalidBlockHash`] is returned.
+    pub fn load(
+        store: S,
+        params: Params,
+        checkpoints: &[(Height, BlockHash)],
+        progress: impl FnMut(Height, Height),
+    ) -> Result<Self, Error> {
+        let header = store.genesis();
+        let genesis = CachedBlock {
+            height: 0,
+            hash: header.block_hash(),
+            header,
+        };
+        Self::load_rooted(
+            store,
+            genesis,
+            Vec::new(),
+            header.work(),
+            params,
+            checkpoints,
+            progress,
+        )
+    }
+
+    /// Create a new `BlockCache` rooted at a trusted checkpoint instead of the genesis,
+    /// given the checkpoint's height, header, the headers preceding it, oldest first, and the
+    /// cumulative proof-of-work of the chain up to and including it. The store holds the
+    /// chain starting at the checkpoint: its genesis must be the checkpoint header.
+    ///
+    /// The checkpoint takes the place of the genesis: it is never re-orged, headers below
+    /// it are never requested, and it ends the locators. Heights are those of the full
+    /// chain, so the checkpoint keeps its own height.
+    ///
+    /// # Errors
+    ///
+    /// Since the headers below the checkpoint aren't available to adjust the difficulty
+    /// target, the checkpoint must be the first block of a difficulty adjustment interval.
+    /// Returns [`Error::InvalidBlockHeight`] if it isn't, and [`Error::InvalidBlockHash`] if
+    /// the store doesn't start with the checkpoint header.
+    ///
+    /// The median time past of the headers following the checkpoint is computed over the
+    /// headers preceding it, so the last [`MEDIAN_TIME_SPAN`] of them are required. Returns
+    /// [`Error::BlockMissing`] if there are fewer, and [`Error::DisjointChains`] if they
+    /// don't lead up to the checkpoint.
+    ///
+    /// [`MEDIAN_TIME_SPAN`]: nakamoto_common::block::time::MEDIAN_TIME_SPAN
+    ///
+    pub fn from_checkpoint(
+        store: S,
+        height: Height,
+        header: BlockHeader,
+        ancestors: &[BlockHeader],
+        chainwork: Work,
+        params: Params,
+    ) -> Result<Self, Error> {
+        let hash = header.block_hash();
+        let target = header.target();
+
+        if store.genesis().block_hash() != hash {
+            return Err(Error::InvalidBlockHash(hash, height));
+        }
+        if !validator::is_adjustment_height(height, &params) {
+            return Err(Error::InvalidBlockHeight(height));
+        }
+        if target > params.pow_limit {
+            return Err(Error::InvalidBlockTarget(target, params.pow_limit));
+        }
+        let required = height.min(time::MEDIAN_TIME_SPAN) as usize;
+        if ancestors.len() < required {
+            let missing = ancestors.first().unwrap_or(&header).prev_blockhash;
+
+            return Err(Error::BlockMissing(missing));
+        }
+        let ancestors = ancestors[ancestors.len() - required..].to_vec();
+        let children = ancestors.iter().skip(1).chain(std::iter::once(&header));
+
+        for (parent, child) in ancestors.iter().zip(children) {
+            if child.prev_blockhash != parent.block_hash() {
+                return Err(Error::DisjointChains(child.block_hash()));
+            }
+        }
+        let root = CachedBlock {
+            height,
+            hash,
+            header,
+        };
+        Self::load_rooted(store, root, ancestors, chainwork, params, &[], |_, _| {})
+    }
+
+    /// Create a new `BlockCache` from a `Store` whose genesis is the given root block.
+    fn load_rooted(
+        store: S,
+        genesis: CachedBlock,
+        ancestors: Vec<BlockHeader>,
+        root_work: Work,
+        params: Params,
+        checkpoints: &[(Height, BlockHash)],
+        mut progress: impl FnMut(Height, Height),
+    ) -> Result<Self, Error> {
         let length = store.len()?;
         let orphans = HashMap::new();
         let checkpoints = checkpoints.iter().cloned().collect();
+        let root = genesis.height;
 
-        let chain = NonEmpty::from((
-            CachedBlock {
-                height: 0,
-                hash: genesis.block_hash(),
-                header: genesis,
-            },
-            Vec::with_capacity(length - 1),
-        ));
+        let chain = NonEmpty::from((genesis, Vec::with_capacity(length - 1)));
         let mut headers = HashMap::with_capacity(length);
         // Insert genesis in the headers map, but skip it during iteration.
-        headers.insert(chain.head.hash, 0);
+        headers.insert(chain.head.hash, root);
 
         let mut cache = Self {
-            chain,
-            headers,
+            chain: Arc::new(chain),
+            root,
+            root_work,
+            genesis,
+            ancestors,
+            headers: Arc::new(headers),
             orphans,
+            orphan_children: HashMap::new(),
+            arrivals: VecDeque::new(),
+            orphan_tips: 0,
             params,
             checkpoints,
+            self_checkpoints: BTreeMap::new(),
+            history: Vec::new(),
+            config: ChainConfig::default(),
+            events: Vec::new(),
             store,
         };
 
+        let total = length as Height - 1;
+
         for result in cache.store.iter().skip(1) {
             let (height, header) = result?;
             let hash = header.block_hash();
 
-            cache.extend_chain(height, hash, header);
+            cache.extend_chain(root + height, hash, header)?;
+
+            if height % LOAD_PROGRESS_INTERVAL == 0 && height != total {
+                progress(height, total);
+            }
+        }
+        progress(cache.height() - root, total);
+
+        // Stored heights are relative to the store's genesis.
+        for (height, hash, work) in cache.store.checkpoints()? {
+            let height = root + height;
+
+            if cache.get_block_hash(height) != Some(hash) {
+                return Err(Error::InvalidBlockHash(hash, height));
+            }
+            cache
+                .self_checkpoints
+                .insert(height, SelfCheckpoint { height, hash, work });
         }
 
         assert_eq!(length, cache.chain.len());
@@ -118,6 +498,305 @@ impl<S: Store<Header = BlockHeader>> BlockCache<S> {
         Ok(cache)
     }
 
+    /// Use the given limits on stored headers, instead of the defaults. If configured,
+    /// checkpoints are generated for the part of the loaded chain above the last stored
+    /// checkpoint, and stored.
+    pub fn with_config(mut self, config: ChainConfig) -> Result<Self, Error> {
+        self.config = config;
+
+        let depth = config.max_reorg_depth.unwrap_or(DEFAULT_CHECKPOINT_DEPTH);
+        let start = self
+            .self_checkpoints
+            .keys()
+            .next_back()
+            .map_or(self.root + 1, |height| height + depth + 1);
+
+        for height in start..=self.height() {
+            self.record_checkpoint(height)?;
+        }
+        Ok(self)
+    }
+
+    /// Retain up to `limit` headers disconnected from the active chain by re-orgs.
+    /// History isn't retained by default.
+    pub fn with_history(mut self, limit: usize) -> Self {
+        self.config.max_history = limit;
+        self
+    }
+
+    /// Headers that were disconnected from the active chain, along with the heights they
+    /// occupied, oldest first. Once the buffer is full, the oldest entries are dropped.
+    pub fn disconnected_history(&self) -> &[(Height, BlockHeader)] {
+        &self.history
+    }
+
+    /// Get the checkpoints generated from the active chain so far, lowest first.
+    /// See [`ChainConfig::checkpoint_interval`].
+    pub fn export_checkpoints(&self) -> Vec<SelfCheckpoint> {
+        self.self_checkpoints.values().cloned().collect()
+    }
+
+    /// Export the headers of the active chain above the given base height, along with the
+    /// base itself. Returns `None` if the base is above the tip.
+    pub fn export_diff(&self, base: Height) -> Option<ChainDiff> {
+        let base_hash = self.get_block_hash(base)?;
+
+        Some(ChainDiff {
+            base_height: base,
+            base_hash,
+            headers: self
+                .range(base + 1..self.height() + 1)
+                .map(|blk| blk.header)
+                .collect(),
+        })
+    }
+
+    /// Import a diff exported from another chain. The diff's base must be part of the
+    /// active chain, at the same height, and its headers must build on it, otherwise
+    /// [`Error::DiffBaseMismatch`] is returned. The headers are then imported and validated
+    /// as usual, with [`BlockTree::import_blocks`].
+    pub fn import_diff<C: Clock>(
+        &mut self,
+        diff: ChainDiff,
+        clock: &C,
+    ) -> Result<ImportResult, Error> {
+        let ChainDiff {
+            base_height,
+            base_hash,
+            headers,
+        } = diff;
+
+        if self.get_block_hash(base_height) != Some(base_hash)
+            || matches!(headers.first(), Some(h) if h.prev_blockhash != base_hash)
+        {
+            return Err(Error::DiffBaseMismatch(base_hash, base_height));
+        }
+        self.import_blocks(headers.into_iter(), clock)
+    }
+
+    /// Drop the blocks of the active chain below the highest checkpoint from memory, to
+    /// reduce memory usage. The genesis, the checkpoint and the blocks above it are kept,
+    /// along with the blocks below the checkpoint needed to validate its successors, ie.
+    /// the difficulty adjustment interval leading up to it. Returns the number of blocks
+    /// dropped.
+    ///
+    /// Pruned blocks remain in the store, and can be read from it with
+    /// [`BlockCache::read_block_by_height`]. Other lookups, eg. [`BlockTree::get_block`] and
+    /// [`BlockTree::get_block_by_height`], only search memory and don't find them. Locators
+    /// pointing to them are unknown, and their headers are no longer served to peers.
+    /// Forks below the checkpoint are already rejected, and so are unaffected.
+    ///
+    /// Since pruned blocks are unknown, a peer sending them again has them stored as
+    /// orphans, up to [`ChainConfig::max_orphans`].
+    pub fn prune_to_checkpoint(&mut self) -> usize {
+        let checkpoint = match self.checkpoints.range(..=self.height()).next_back() {
+            Some((height, _)) => *height,
+            None => return 0,
+        };
+        let window = validator::HeaderValidator::window_size(&self.params) as Height;
+        let root = checkpoint.saturating_sub(window - 1);
+
+        if root <= self.root {
+            return 0;
+        }
+        let chain = Arc::make_mut(&mut self.chain);
+        let mut pruned = chain
+            .tail
+            .drain(..(root - self.root) as usize)
+            .collect::<Vec<_>>();
+        // The last block drained becomes the first block of the chain.
+        let head = pruned.pop().expect("at least one block is drained");
+
+        pruned.push(std::mem::replace(&mut chain.head, head));
+        chain.tail.shrink_to_fit();
+        pruned.retain(|block| block.height != self.genesis.height);
+
+        let headers = Arc::make_mut(&mut self.headers);
+        for block in &pruned {
+            headers.remove(&block.hash);
+
+            if block.height > self.root {
+                self.root_work = self.root_work + block.work();
+            }
+        }
+        headers.shrink_to_fit();
+
+        self.root_work = self.root_work + head.work();
+        self.root = root;
+
+        pruned.len()
+    }
+
+    /// Get a block of the active chain by height, reading it from the store if it was
+    /// pruned from memory. See [`BlockCache::prune_to_checkpoint`].
+    pub fn read_block_by_height(&self, height: Height) -> Result<Option<BlockHeader>, Error> {
+        if let Some(header) = self.get_block_by_height(height) {
+            return Ok(Some(*header));
+        }
+        match height.checked_sub(self.genesis.height) {
+            Some(height) if height <= self.height() - self.genesis.height => {
+                Ok(Some(self.store.get(height)?))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Take a snapshot of the active chain. Snapshots are cheap to take and can be shared
+    /// with other threads. They aren't affected by later changes to the cache: instead,
+    /// the cache copies the active chain on its next change while there are snapshots.
+    pub fn snapshot(&self) -> CacheSnapshot {
+        CacheSnapshot {
+            chain: self.chain.clone(),
+            headers: self.headers.clone(),
+            root: self.root,
+            genesis: self.genesis,
+        }
+    }
+
+    /// Check whether a block is an ancestor of another. A block isn't its own ancestor.
+    /// Unknown blocks, and orphans that don't connect to the active chain, have no ancestors.
+    pub fn is_ancestor(&self, ancestor: &BlockHash, descendant: &BlockHash) -> bool {
+        let ancestor_height = self.headers.get(ancestor);
+
+        // Fast path: both blocks are on the active chain.
+        if let (Some(a), Some(d)) = (ancestor_height, self.headers.get(descendant)) {
+            return a < d;
+        }
+        // Otherwise, walk the descendant's branch down to the active chain.
+        let mut cursor = *descendant;
+
+        while let Some(header) = self.orphans.get(&cursor) {
+            cursor = header.prev_blockhash;
+
+            if cursor == *ancestor {
+                return true;
+            }
+        }
+        matches!(
+            (ancestor_height, self.headers.get(&cursor)),
+            (Some(a), Some(fork)) if a <= fork
+        )
+    }
+
+    /// Get the relationship between two blocks, eg. the active tip and the tip of a
+    /// side-chain. Both blocks have to be on the active chain or on a side-chain.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::BlockMissing`] with the first unknown ancestor of a block that
+    /// doesn't connect to the active chain.
+    ///
+    pub fn tip_relationship(&self, a: &BlockHash, b: &BlockHash) -> Result<TipRelation, Error> {
+        let (a_fork, a_branch) = self.branch_of(a)?;
+        let (b_fork, b_branch) = self.branch_of(b)?;
+        // Get the hash of a block's ancestor at the given height.
+        let ancestor = |fork: Height, branch: &[BlockHash], height: Height| {
+            if height <= fork {
+                self.block(height)
+                    .expect("the fork block is on the active chain")
+                    .hash
+            } else {
+                branch[(height - fork - 1) as usize]
+            }
+        };
+        let a_height = a_fork + a_branch.len() as Height;
+        let b_height = b_fork + b_branch.len() as Height;
+        let mut height = a_height.min(b_height);
+
+        if ancestor(a_fork, &a_branch, height) == ancestor(b_fork, &b_branch, height) {
+            return Ok(match a_height.cmp(&b_height) {
+                std::cmp::Ordering::Equal => TipRelation::Equal,
+                std::cmp::Ordering::Less => TipRelation::Ancestor,
+                std::cmp::Ordering::Greater => TipRelation::Descendant,
+            });
+        }
+
+        // Both blocks descend from the first block of the chain, so this terminates.
+        loop {
+            height -= 1;
+
+            let hash = ancestor(a_fork, &a_branch, height);
+            if hash == ancestor(b_fork, &b_branch, height) {
+                return Ok(TipRelation::Fork { height, hash });
+            }
+        }
+    }
+
+    /// Import headers that form a single chain, given in any order. The headers are first
+    /// linked into a chain starting from a known block, and then validated and imported
+    /// in order.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::BlockMissing`] with the missing parent of the first header that
+    /// doesn't link to the rest of the chain, nor to a known block, and
+    /// [`Error::DisjointChains`] with the first header of a second chain, if the headers
+    /// form more than one. Headers without a parent, other than our genesis, are rejected
+    /// with [`Error::InvalidBlockHash`]. No headers are imported in any of these cases.
+    ///
+    pub fn bulk_validate_and_import<C: Clock>(
+        &mut self,
+        headers: Vec<BlockHeader>,
+        clock: &C,
+    ) -> Result<ImportResult, Error> {
+        let mut order = Vec::with_capacity(headers.len());
+        let mut by_hash = HashMap::with_capacity(headers.len());
+        let mut children: HashMap<BlockHash, Vec<BlockHash>> = HashMap::new();
+
+        for header in headers {
+            let hash = header.block_hash();
+
+            // The genesis has no parent, and is always known: there's nothing to import.
+            if self.is_genesis(&hash) {
+                continue;
+            }
+            if by_hash.insert(hash, header).is_none() {
+                children
+                    .entry(header.prev_blockhash)
+                    .or_default()
+                    .push(hash);
+                order.push(hash);
+            }
+        }
+
+        // Find the first header of the chain, whose parent isn't part of the input.
+        let mut root = None;
+        for hash in &order {
+            let prev = by_hash[hash].prev_blockhash;
+
+            if by_hash.contains_key(&prev) {
+                continue;
+            }
+            if prev == BlockHash::default() {
+                return Err(Error::InvalidBlockHash(*hash, 0));
+            }
+            if !self.is_known(&prev) {
+                return Err(Error::BlockMissing(prev));
+            }
+            if root.is_some() {
+                return Err(Error::DisjointChains(*hash));
+            }
+            root = Some(*hash);
+        }
+
+        // Since every header has a single parent, following children from the root
+        // visits every header, unless the chain forks.
+        let mut chain = Vec::with_capacity(order.len());
+        let mut cursor = root;
+        while let Some(hash) = cursor {
+            chain.push(by_hash[&hash]);
+
+            cursor = match children.get(&hash).map(|c| c.as_slice()) {
+                Some([next]) => Some(*next),
+                Some([_, other, ..]) => return Err(Error::DisjointChains(*other)),
+                _ => None,
+            };
+        }
+        debug_assert_eq!(chain.len(), order.len());
+
+        self.import_blocks(chain.into_iter(), clock)
+    }
+
     /// Iterate over a range of blocks.
     ///
     /// # Errors
@@ -133,54 +812,127 @@ impl<S: Store<Header = BlockHeader>> BlockCache<S> {
             "BlockCache::range: range start must not be greater than range end"
         );
 
+        let start = range.start.max(self.root);
+
         self.chain
             .iter()
-            .skip(range.start as usize)
-            .take((range.end - range.start) as usize)
+            .skip((start - self.root) as usize)
+            .take(range.end.saturating_sub(start) as usize)
+    }
+
+    /// Get the headers of the active chain between two heights, inclusive.
+    ///
+    /// The range is clamped to the bounds of the active chain, so `to` may exceed the
+    /// height of the tip. If `from` is greater than `to`, no headers are returned.
+    /// Headers are borrowed from the cache; callers that need owned data should clone them.
+    ///
+    pub fn headers_between(
+        &self,
+        from: Height,
+        to: Height,
+    ) -> impl DoubleEndedIterator<Item = &BlockHeader> {
+        let from = from.max(self.root) - self.root;
+        let to = to.min(self.height()) - self.root;
+        let (head, tail) = if from > to {
+            (None, &self.chain.tail[..0])
+        } else if from == 0 {
+            (Some(&self.chain.head), &self.chain.tail[..to as usize])
+        } else {
+            (None, &self.chain.tail[from as usize - 1..to as usize])
+        };
+
+        head.into_iter().chain(tail.iter()).map(|blk| &blk.header)
     }
 
     /// Get the median time past for the blocks leading up to the given height.
     ///
     /// # Errors
     ///
-    /// Panics if height is `0`.
+    /// Panics if height is `0`, or if the blocks leading up to it were pruned.
     ///
     pub fn median_time_past(&self, height: Height) -> BlockTime {
-        assert!(height != 0, "height must be > 0");
+        validator::median_time_past(self, height)
+    }
 
-        let mut times = [0 as BlockTime; time::MEDIAN_TIME_SPAN as usize];
+    /// Get the first block of the active chain whose median time past is at or after the
+    /// given time, eg. to start scanning from a wallet's creation time. Unlike block
+    /// timestamps, the median time past never decreases along the chain, so the blocks
+    /// before the one returned can all be skipped. Returns `None` if the median time past
+    /// of the tip is before the given time, or if the block may be below the first block
+    /// available, ie. if it was pruned or precedes the checkpoint the cache is rooted at.
+    pub fn first_header_after_time(&self, time: LocalTime) -> Option<(Height, &BlockHeader)> {
+        // The median time past of a block, including the block itself.
+        let mtp = |height: Height| LocalTime::from_block_time(self.median_time_past(height + 1));
+        // The first block whose median time past can be computed. Unless the cache was
+        // pruned, the headers needed for it are available down to the root.
+        let first = if self.root == self.genesis.height {
+            self.root
+        } else {
+            self.root + time::MEDIAN_TIME_SPAN - 1
+        };
+        let (mut low, mut high) = (first, self.height() + 1);
 
-        let start = height.saturating_sub(time::MEDIAN_TIME_SPAN);
-        let end = height;
+        while low < high {
+            let mid = low + (high - low) / 2;
 
-        for (i, blk) in self.range(start..end).enumerate() {
-            times[i] = blk.time;
+            if mtp(mid) < time {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
         }
-
-        // Gracefully handle the case where `height` < `MEDIUM_TIME_SPAN`.
-        let available = &mut times[0..(end - start) as usize];
-
-        available.sort_unstable();
-        available[available.len() / 2]
+        // Any block before the first one available could be the one we're looking for.
+        if low == first && first != 0 {
+            return None;
+        }
+        self.get_block_by_height(low).map(|header| (low, header))
     }
 
     /// Import a block into the tree. Performs header validation. This function may trigger
     /// a chain re-org.
+    #[cfg(test)]
     fn import_block(
         &mut self,
         header: BlockHeader,
         clock: &impl Clock,
+    ) -> Result<ImportResult, Error> {
+        let established = self.chain.last().hash;
+
+        self.import_block_since(header, &established, clock)
+    }
+
+    /// Import a block into the tree, given the tip that was established before the
+    /// current import started. The established tip is only ever re-orged away from in
+    /// favor of a chain with strictly more work; ties between other chains are broken
+    /// according to the configured [`TieBreak`].
+    fn import_block_since(
+        &mut self,
+        header: BlockHeader,
+        established: &BlockHash,
+        clock: &impl Clock,
     ) -> Result<ImportResult, Error> {
         let hash = header.block_hash();
         let tip = self.chain.last();
         let best = tip.hash;
+        // Orphans that may be connected by this header.
+        let waiting = self.orphan_descendants(&hash);
+
+        // The genesis block isn't validated, it is part of the chain from the start.
+        if self.is_genesis(&hash) {
+            return Err(Error::DuplicateBlock(hash));
+        }
+        // Any other header without a parent claims to be a genesis block, and is rejected,
+        // so that the root of the chain can't be replaced.
+        if header.prev_blockhash == BlockHash::default() {
+            return Err(Error::InvalidBlockHash(hash, 0));
+        }
 
         // Block extends the active chain.
         if header.prev_blockhash == best {
             let height = tip.height + 1;
 
             self.validate(&tip, &header, clock)?;
-            self.extend_chain(height, hash, header);
+            self.extend_chain(height, hash, header)?;
             self.store.put(std::iter::once(header))?;
         } else if self.headers.contains_key(&hash) || self.orphans.contains_key(&hash) {
             // FIXME: This shouldn't be an error.
@@ -188,42 +940,43 @@ impl<S: Store<Header = BlockHeader>> BlockCache<S> {
         } else {
             if let Some(height) = self.headers.get(&header.prev_blockhash) {
                 // Don't accept any forks from the main chain, prior to the last checkpoint.
-                if *height < self.last_checkpoint() {
+                if *height < self.last_checkpoint() || self.is_too_deep(*height) {
                     return Err(Error::InvalidBlockHeight(*height + 1));
                 }
             }
 
-            // Validate that the block's PoW (1) is valid against its difficulty target, and (2)
-            // is greater than the minimum allowed for this network.
-            //
-            // We do this because it's cheap to verify and prevents flooding attacks.
-            let target = header.target();
-            match header.validate_pow(&target) {
-                Ok(_) => {
-                    let limit = self.params.pow_limit;
-                    if target > limit {
-                        return Err(Error::InvalidBlockTarget(target, limit));
-                    }
-                }
-                Err(bitcoin::util::Error::BlockBadProofOfWork) => {
-                    return Err(Error::InvalidBlockPoW);
-                }
-                Err(bitcoin::util::Error::BlockBadTarget) => {
-                    // The only way to get a 'bad target' error is to pass a different target
-                    // than the one specified in the header.
-                    unreachable!();
-                }
-                Err(_) => {
-                    // We've handled all possible errors above.
-                    unreachable!();
-                }
+            // Validate the block's own proof-of-work. We do this because it's cheap to verify
+            // and prevents flooding attacks.
+            Self::validate_pow(&header, &self.params)?;
+
+            self.insert_orphan(hash, header);
+
+            if let Some(missing_parent) = self.missing_ancestor(&hash) {
+                self.events.push(Event::OrphanReceived {
+                    hash,
+                    missing_parent,
+                });
             }
-            self.orphans.insert(hash, header);
         }
 
         // Activate the chain with the most work.
 
-        let candidates = self.chain_candidates(clock);
+        let tie_break = self.config.tie_break;
+        let mut candidates = self.chain_candidates(clock);
+
+        // Consider candidates in the order their tips were received, so that when
+        // several candidates have equal work, the first one seen wins.
+        if tie_break == TieBreak::FirstSeen {
+            let order = self
+                .arrivals
+                .iter()
+                .enumerate()
+                .rev()
+                .map(|(i, h)| (*h, i))
+                .collect::<HashMap<_, _>>();
+
+            candidates.sort_by_key(|c| order.get(&c.tip).copied().unwrap_or(usize::MAX));
+        }
 
         // TODO: What are we trying to do here? We're saying that if there are no
         // forks, and this header has no parent, we return an error. But:
@@ -240,6 +993,8 @@ impl<S: Store<Header = BlockHeader>> BlockCache<S> {
             && !self.orphans.contains_key(&header.prev_blockhash)
         {
             // FIXME: This shouldn't be an error.
+            self.evict();
+
             return Err(Error::BlockMissing(header.prev_blockhash));
         }
 
@@ -248,31 +1003,44 @@ impl<S: Store<Header = BlockHeader>> BlockCache<S> {
 
         // TODO: Don't switch multiple times. Switch to the best branch in one go.
         for branch in candidates.iter() {
+            if self.is_too_deep(branch.fork_height) {
+                continue;
+            }
             let candidate_work = Branch(&branch.headers).work();
             let main_work = Branch(self.chain_suffix(branch.fork_height)).work();
 
             // TODO: Validate branch before switching to it.
             if candidate_work > main_work {
                 stale = self.switch_to_fork(branch)?;
-            } else if self.params.network != Network::Bitcoin {
-                if candidate_work == main_work {
-                    // Nb. We intend here to compare the hashes as integers, and pick the lowest
-                    // hash as the winner. However, the `PartialEq` on `BlockHash` is implemented on
-                    // the underlying `[u8]` array, and does something different (lexographical
-                    // comparison). Since this code isn't run on Mainnet, it's okay, as it serves
-                    // its purpose of being determinstic when choosing the active chain.
-                    if branch.tip < self.chain.last().hash {
-                        stale = self.switch_to_fork(branch)?;
-                    }
+            } else if candidate_work == main_work
+                && tie_break == TieBreak::LowestHash
+                && self.chain.last().hash != *established
+            {
+                // Nb. We intend here to compare the hashes as integers, and pick the lowest
+                // hash as the winner. However, the `PartialEq` on `BlockHash` is implemented on
+                // the underlying `[u8]` array, and does something different (lexographical
+                // comparison). It serves its purpose of being determinstic when choosing the
+                // active chain, which is all we need.
+                if branch.tip < self.chain.last().hash {
+                    stale = self.switch_to_fork(branch)?;
                 }
             }
         }
 
-        let (hash, _) = self.tip();
-        if hash != best {
+        self.evict();
+
+        let (tip, _) = self.tip();
+        if waiting > 0 && self.missing_ancestor(&hash).is_none() {
+            self.events.push(Event::OrphansResolved {
+                count: waiting,
+                new_tip: Some(tip).filter(|t| *t != best),
+            });
+        }
+
+        if tip != best {
             // TODO: Test the reverted blocks.
             Ok(ImportResult::TipChanged(
-                hash,
+                tip,
                 self.height(),
                 stale.into_iter().map(|h| h.block_hash()).collect(),
             ))
@@ -281,7 +1049,7 @@ impl<S: Store<Header = BlockHeader>> BlockCache<S> {
         }
     }
 
-    /// Find all the potential forks off the main chain.
+    /// Find all the potential forks off the main chain, ordered by tip hash.
     fn chain_candidates(&self, clock: &impl Clock) -> Vec<Candidate> {
         let mut branches = Vec::new();
 
@@ -292,6 +1060,8 @@ impl<S: Store<Header = BlockHeader>> BlockCache<S> {
                 }
             }
         }
+        // Don't let the order of the candidates depend on hash map iteration order.
+        branches.sort_unstable_by_key(|b| b.tip);
         branches
     }
 
@@ -361,94 +1131,108 @@ impl<S: Store<Header = BlockHeader>> BlockCache<S> {
     ) -> Result<(), Error> {
         assert_eq!(tip.hash, header.prev_blockhash);
 
-        let compact_target = if self.params.allow_min_difficulty_blocks
-            && (tip.height + 1) % self.params.difficulty_adjustment_interval() != 0
-        {
-            if header.time > tip.time + self.params.pow_target_spacing as BlockTime * 2 {
-                block::pow_limit_bits(&self.params.network)
-            } else {
-                self.next_min_difficulty_target(&self.params)
+        if let Some((anchor, anchor_hash)) = self.config.assume_valid {
+            let height = tip.height + 1;
+
+            if height <= anchor {
+                let expected = if height == anchor {
+                    Some(&anchor_hash)
+                } else {
+                    self.checkpoints.get(&height)
+                };
+                if let Some(expected) = expected {
+                    let hash = header.block_hash();
+
+                    if &hash != expected {
+                        return Err(Error::InvalidBlockHash(hash, height));
+                    }
+                }
+                // Only the contextual checks are skipped: the difficulty target isn't checked
+                // against the retarget, nor the timestamp against its neighbours. The header's
+                // own proof-of-work is still checked, since it's what the chain work is based on.
+                return Self::validate_pow(header, &self.params);
             }
-        } else {
-            self.next_difficulty_target(tip.height, tip.time, tip.target(), &self.params)
-        };
+        }
+        validator::validate(
+            self,
+            tip.height,
+            &tip.header,
+            header,
+            &self.params,
+            &self.checkpoints,
+            clock,
+        )
+    }
 
-        let target = BlockHeader::u256_from_compact_target(compact_target);
+    /// Validate that the block's PoW (1) is valid against its difficulty target, and (2)
+    /// is greater than the minimum allowed for this network. This doesn't check that the
+    /// target is the expected one.
+    fn validate_pow(header: &BlockHeader, params: &Params) -> Result<(), Error> {
+        let target = header.target();
 
         match header.validate_pow(&target) {
-            Err(bitcoin::util::Error::BlockBadProofOfWork) => {
-                return Err(Error::InvalidBlockPoW);
+            Ok(_) => {
+                if target > params.pow_limit {
+                    return Err(Error::InvalidBlockTarget(target, params.pow_limit));
+                }
+                Ok(())
             }
+            Err(bitcoin::util::Error::BlockBadProofOfWork) => Err(Error::InvalidBlockPoW),
             Err(bitcoin::util::Error::BlockBadTarget) => {
-                return Err(Error::InvalidBlockTarget(header.target(), target));
+                // The only way to get a 'bad target' error is to pass a different target
+                // than the one specified in the header.
+                unreachable!();
             }
-            Err(_) => unreachable!(),
-            Ok(_) => {}
-        }
-
-        // Validate against block checkpoints.
-        let height = tip.height + 1;
-
-        if let Some(checkpoint) = self.checkpoints.get(&height) {
-            let hash = header.block_hash();
-
-            if &hash != checkpoint {
-                return Err(Error::InvalidBlockHash(hash, height));
+            Err(_) => {
+                // We've handled all possible errors above.
+                unreachable!();
             }
         }
-
-        // A timestamp is accepted as valid if it is greater than the median timestamp of
-        // the previous MEDIAN_TIME_SPAN blocks, and less than the network-adjusted
-        // time + MAX_FUTURE_BLOCK_TIME.
-        if header.time <= self.median_time_past(height) {
-            return Err(Error::InvalidBlockTime(header.time, Ordering::Less));
-        }
-        if header.time > clock.block_time() + time::MAX_FUTURE_BLOCK_TIME {
-            return Err(Error::InvalidBlockTime(header.time, Ordering::Greater));
-        }
-
-        Ok(())
     }
 
-    /// Get the height of the last checkpoint block.
+    /// Get the height of the last checkpoint block. The assume-valid block counts as a
+    /// checkpoint once it is part of the active chain.
     fn last_checkpoint(&self) -> Height {
         let height = self.height();
-
-        self.checkpoints
+        let checkpoint = self
+            .checkpoints
             .iter()
             .rev()
             .map(|(h, _)| *h)
             .find(|h| *h <= height)
-            .unwrap_or(0)
-    }
-
-    /// Get the next minimum-difficulty target. Only valid in testnet and regtest networks.
-    fn next_min_difficulty_target(&self, params: &Params) -> Bits {
-        assert!(params.allow_min_difficulty_blocks);
-
-        let pow_limit_bits = block::pow_limit_bits(&params.network);
+            .unwrap_or(0);
 
-        for (height, header) in self.iter().rev() {
-            if header.bits != pow_limit_bits
-                || height % self.params.difficulty_adjustment_interval() == 0
-            {
-                return header.bits;
-            }
+        match self.config.assume_valid {
+            Some((anchor, _)) if anchor <= height => checkpoint.max(anchor),
+            _ => checkpoint,
         }
-        pow_limit_bits
     }
 
     /// Rollback active chain to the given height. Returns the list of rolled-back headers.
     fn rollback(&mut self, height: Height) -> Result<Vec<BlockHeader>, Error> {
         let mut stale = Vec::new();
 
-        for block in self.chain.tail.drain(height as usize..) {
+        let blocks = Arc::make_mut(&mut self.chain)
+            .tail
+            .drain((height - self.root) as usize..)
+            .collect::<Vec<_>>();
+
+        for block in blocks {
             stale.push(block.header);
 
-            self.headers.remove(&block.hash);
-            self.orphans.insert(block.hash, block.header);
+            Arc::make_mut(&mut self.headers).remove(&block.hash);
+            self.insert_orphan(block.hash, block.header);
+
+            if self.config.max_history > 0 {
+                self.history.push((block.height, block.header));
+            }
+        }
+        if self.history.len() > self.config.max_history {
+            let excess = self.history.len() - self.config.max_history;
+            self.history.drain(..excess);
         }
-        self.store.rollback(height)?;
+        self.self_checkpoints.split_off(&(height + 1));
+        self.store.rollback(height - self.genesis.height)?;
 
         Ok(stale)
     }
@@ -457,34 +1241,359 @@ impl<S: Store<Header = BlockHeader>> BlockCache<S> {
     fn switch_to_fork(&mut self, branch: &Candidate) -> Result<Vec<BlockHeader>, Error> {
         let stale = self.rollback(branch.fork_height)?;
 
+        // Store the headers first, so that the checkpoints generated as the chain is
+        // extended are always part of the stored chain.
+        self.store.put(branch.headers.iter().cloned())?;
+
         for (i, header) in branch.headers.iter().enumerate() {
             self.extend_chain(
                 branch.fork_height + i as Height + 1,
                 header.block_hash(),
                 *header,
-            );
+            )?;
         }
-        self.store.put(branch.headers.iter().cloned())?;
 
         Ok(stale)
     }
 
     /// Extend the active chain with a block.
-    fn extend_chain(&mut self, height: Height, hash: BlockHash, header: BlockHeader) {
+    fn extend_chain(
+        &mut self,
+        height: Height,
+        hash: BlockHash,
+        header: BlockHeader,
+    ) -> Result<(), Error> {
         assert_eq!(header.prev_blockhash, self.chain.last().hash);
 
-        self.headers.insert(hash, height);
-        self.orphans.remove(&hash);
-        self.chain.push(CachedBlock {
+        if let Some(max) = self.config.max_timestamp_drift {
+            let drift = header.time as i64 - self.chain.last().time as i64;
+
+            if drift.abs() > max as i64 {
+                self.events.push(Event::UnusualTimestamp { height, drift });
+            }
+        }
+
+        Arc::make_mut(&mut self.headers).insert(hash, height);
+        self.remove_orphan(&hash);
+        Arc::make_mut(&mut self.chain).push(CachedBlock {
             height,
             hash,
             header,
         });
+        self.record_checkpoint(height)
+    }
+
+    /// Generate a checkpoint for the block buried by the given tip, if it falls on the
+    /// configured checkpoint interval, and store it.
+    fn record_checkpoint(&mut self, tip: Height) -> Result<(), Error> {
+        let interval = match self.config.checkpoint_interval {
+            Some(interval) if interval > 0 => interval,
+            _ => return Ok(()),
+        };
+        let depth = self
+            .config
+            .max_reorg_depth
+            .unwrap_or(DEFAULT_CHECKPOINT_DEPTH);
+        let height = match tip.checked_sub(depth) {
+            Some(height) if height > 0 && height % interval == 0 => height,
+            _ => return Ok(()),
+        };
+        // Accumulate work from the previous checkpoint, if any, to avoid summing over the
+        // whole chain every time.
+        let (start, mut work) = self
+            .self_checkpoints
+            .range(self.root..height)
+            .next_back()
+            .map(|(h, c)| (*h, c.work))
+            .unwrap_or((self.root, self.root_work));
+
+        if height <= start {
+            return Ok(());
+        }
+        let blocks = &self.chain.tail[(start - self.root) as usize..(height - self.root) as usize];
+
+        for block in blocks {
+            work = work + block.work();
+        }
+        if let Some(block) = blocks.last() {
+            self.store
+                .put_checkpoint((height - self.genesis.height, block.hash, work))?;
+            self.self_checkpoints.insert(
+                height,
+                SelfCheckpoint {
+                    height,
+                    hash: block.hash,
+                    work,
+                },
+            );
+        }
+        Ok(())
+    }
+
+    /// Store a header that isn't part of the active chain.
+    fn insert_orphan(&mut self, hash: BlockHash, header: BlockHeader) {
+        if self.orphans.insert(hash, header).is_none() {
+            let siblings = self
+                .orphan_children
+                .entry(header.prev_blockhash)
+                .or_default();
+
+            // The parent is no longer a tip once it has a child.
+            if siblings.is_empty() && self.orphans.contains_key(&header.prev_blockhash) {
+                self.orphan_tips -= 1;
+            }
+            siblings.push(hash);
+
+            if !self.orphan_children.contains_key(&hash) {
+                self.orphan_tips += 1;
+            }
+        }
+        self.arrivals.push_back(hash);
+    }
+
+    /// Remove a header that isn't part of the active chain, if it is stored.
+    fn remove_orphan(&mut self, hash: &BlockHash) -> Option<BlockHeader> {
+        let header = self.orphans.remove(hash)?;
+
+        if !self.orphan_children.contains_key(hash) {
+            self.orphan_tips -= 1;
+        }
+        if let Entry::Occupied(mut e) = self.orphan_children.entry(header.prev_blockhash) {
+            e.get_mut().retain(|h| h != hash);
+
+            // The parent becomes a tip once its last child is removed.
+            if e.get().is_empty() {
+                e.remove();
+
+                if self.orphans.contains_key(&header.prev_blockhash) {
+                    self.orphan_tips += 1;
+                }
+            }
+        }
+        Some(header)
+    }
+
+    /// Check whether a fork at the given height exceeds the maximum re-org depth.
+    fn is_too_deep(&self, fork_height: Height) -> bool {
+        matches!(self.config.max_reorg_depth, Some(depth) if fork_height + depth < self.height())
+    }
+
+    /// Get the height at which a block's branch forks off the active chain, along with the
+    /// hashes of the branch, in height order. Blocks on the active chain have an empty branch.
+    fn branch_of(&self, hash: &BlockHash) -> Result<(Height, Vec<BlockHash>), Error> {
+        let mut branch = Vec::new();
+        let mut cursor = *hash;
+
+        while let Some(header) = self.orphans.get(&cursor) {
+            branch.push(cursor);
+            cursor = header.prev_blockhash;
+        }
+        let fork = *self
+            .headers
+            .get(&cursor)
+            .ok_or(Error::BlockMissing(cursor))?;
+        branch.reverse();
+
+        Ok((fork, branch))
+    }
+
+    /// Get the first unknown ancestor of a header, if it doesn't connect to the active chain.
+    fn missing_ancestor(&self, hash: &BlockHash) -> Option<BlockHash> {
+        let mut cursor = *hash;
+
+        while let Some(header) = self.orphans.get(&cursor) {
+            cursor = header.prev_blockhash;
+        }
+        Some(cursor).filter(|h| !self.headers.contains_key(h))
+    }
+
+    /// Count the stored orphans descending from the given header.
+    fn orphan_descendants(&self, hash: &BlockHash) -> usize {
+        let mut count = 0;
+        let mut queue = vec![*hash];
+
+        while let Some(parent) = queue.pop() {
+            if let Some(hashes) = self.orphan_children.get(&parent) {
+                count += hashes.len();
+                queue.extend(hashes);
+            }
+        }
+        count
+    }
+
+    /// Evict headers stored outside of the active chain, in excess of the configured limits.
+    fn evict(&mut self) {
+        let orphans = &self.orphans;
+
+        if self.arrivals.len() > orphans.len() * 2 {
+            self.arrivals.retain(|h| orphans.contains_key(h));
+        }
+        // Each limit is checked against an upper bound of the count it limits, so that the
+        // stored headers are only walked when one of the limits may be exceeded: there
+        // are at most as many orphans and side-chain headers as stored headers, and at
+        // most as many side-chains as stored tips.
+        if orphans.len() <= self.config.max_orphans
+            && orphans.len() <= self.config.max_side_chain_headers
+            && self.orphan_tips <= self.config.max_side_chains
+        {
+            return;
+        }
+        let children = &self.orphan_children;
+
+        // Side-chain headers, ie. headers connecting to the active chain, along with the
+        // work of their branch from the fork point, found by walking down from the active
+        // chain. Every other header is an orphan.
+        let mut side: HashMap<BlockHash, Work> = HashMap::new();
+        let mut queue = children
+            .iter()
+            .filter(|(parent, _)| self.headers.contains_key(*parent))
+            .flat_map(|(_, hashes)| hashes.iter().map(|h| (*h, Work::default())))
+            .collect::<Vec<_>>();
+
+        while let Some((hash, work)) = queue.pop() {
+            let work = work + orphans[&hash].work();
+
+            if let Some(hashes) = children.get(&hash) {
+                queue.extend(hashes.iter().map(|h| (*h, work)));
+            }
+            side.insert(hash, work);
+        }
+
+        // Orphans are evicted oldest first.
+        if orphans.len() - side.len() > self.config.max_orphans {
+            let excess = orphans.len() - side.len() - self.config.max_orphans;
+            let evicted = self
+                .arrivals
+                .iter()
+                .filter(|h| orphans.contains_key(*h) && !side.contains_key(*h))
+                .take(excess)
+                .copied()
+                .collect::<Vec<_>>();
+            let set = evicted.iter().collect::<HashSet<_>>();
+
+            self.arrivals.retain(|h| !set.contains(h));
+
+            for hash in evicted {
+                self.remove_orphan(&hash);
+                self.events.push(Event::OrphanEvicted(hash));
+            }
+        }
+        // Side-chains are only walked if one of their limits may be exceeded, since there
+        // are at most as many side-chains as side-chain headers.
+        if side.len() <= self.config.max_side_chain_headers
+            && side.len().min(self.orphan_tips) <= self.config.max_side_chains
+        {
+            return;
+        }
+        // Number of stored children of each side-chain header. Side-chain tips have none.
+        let mut children = side
+            .keys()
+            .map(|h| (*h, self.orphan_children.get(h).map_or(0, |c| c.len())))
+            .collect::<HashMap<_, _>>();
+
+        // Side-chain headers are evicted from the tips of the branches with the least work,
+        // so that the remaining headers still connect to the active chain. Between branches
+        // with equal work, the lowest tip hash goes first.
+        if side.len() > self.config.max_side_chain_headers {
+            let mut tips = children
+                .iter()
+                .filter(|(_, n)| **n == 0)
+                .map(|(h, _)| Reverse((side[h], *h)))
+                .collect::<BinaryHeap<_>>();
+            let mut count = side.len();
+
+            while count > self.config.max_side_chain_headers {
+                let Reverse((_, hash)) = match tips.pop() {
+                    Some(tip) => tip,
+                    None => break,
+                };
+                let header = self.remove_orphan(&hash).expect("tips are stored");
+
+                self.events.push(Event::SideChainHeaderEvicted(hash));
+                children.remove(&hash);
+                side.remove(&hash);
+                count -= 1;
+
+                // Once its last child is evicted, the parent header becomes a tip.
+                let parent = header.prev_blockhash;
+                if let Some(n) = children.get_mut(&parent) {
+                    *n -= 1;
+
+                    if *n == 0 {
+                        tips.push(Reverse((side[&parent], parent)));
+                    }
+                }
+            }
+        }
+
+        // Whole side-chains are evicted once there are too many of them, starting with the
+        // ones with the least work. Between side-chains with equal work, the oldest is
+        // evicted, so that a side-chain that is still being received isn't evicted before
+        // it is complete. Headers shared with other side-chains are kept, so evicting a
+        // side-chain never turns another header into a tip.
+        let mut tips = children
+            .iter()
+            .filter(|(_, n)| **n == 0)
+            .map(|(h, _)| *h)
+            .collect::<Vec<_>>();
+
+        if tips.len() > self.config.max_side_chains {
+            let mut arrivals = HashMap::with_capacity(self.arrivals.len());
+            for (i, hash) in self.arrivals.iter().enumerate() {
+                arrivals.entry(*hash).or_insert(i);
+            }
+            tips.sort_unstable_by_key(|h| (side[h], arrivals.get(h).copied(), *h));
+
+            let excess = tips.len() - self.config.max_side_chains;
+
+            for tip in tips.into_iter().take(excess) {
+                let mut cursor = tip;
+
+                while children.get(&cursor) == Some(&0) {
+                    children.remove(&cursor);
+
+                    match self.remove_orphan(&cursor) {
+                        Some(header) => {
+                            cursor = header.prev_blockhash;
+
+                            if let Some(n) = children.get_mut(&cursor) {
+                                *n -= 1;
+                            }
+                        }
+                        None => break,
+                    }
+                }
+                self.events.push(Event::SideChainEvicted(tip));
+            }
+        }
     }
 
     /// Get the blocks starting from the given height.
     fn chain_suffix(&self, height: Height) -> &[CachedBlock] {
-        &self.chain.tail[height as usize..]
+        &self.chain.tail[(height - self.root) as usize..]
+    }
+
+    /// Get a block of the active chain by height. Only the genesis is found below the
+    /// first block of the chain. See [`BlockCache::prune_to_checkpoint`].
+    fn block(&self, height: Height) -> Option<&CachedBlock> {
+        match height.checked_sub(self.root) {
+            Some(ix) => self.chain.get(ix as usize),
+            None if height == self.genesis.height => Some(&self.genesis),
+            None => None,
+        }
+    }
+}
+
+impl<S: Store<Header = BlockHeader>> validator::Context for BlockCache<S> {
+    fn header(&self, height: Height) -> Option<&BlockHeader> {
+        if height < self.genesis.height {
+            let start = self.genesis.height - self.ancestors.len() as Height;
+
+            return height
+                .checked_sub(start)
+                .and_then(|i| self.ancestors.get(i as usize));
+        }
+        self.get_block_by_height(height)
     }
 }
 
@@ -496,9 +1605,10 @@ impl<S: Store<Header = BlockHeader>> BlockTree for BlockCache<S> {
         context: &C,
     ) -> Result<ImportResult, Error> {
         let mut result = None;
+        let established = self.chain.last().hash;
 
         for (i, header) in chain.enumerate() {
-            match self.import_block(header, context) {
+            match self.import_block_since(header, &established, context) {
                 Ok(r) => result = Some(r),
                 Err(Error::DuplicateBlock(hash)) => log::trace!("Duplicate block {}", hash),
                 Err(Error::BlockMissing(hash)) => log::trace!("Missing block {}", hash),
@@ -521,7 +1631,7 @@ impl<S: Store<Header = BlockHeader>> BlockTree for BlockCache<S> {
             let height = tip.height + 1;
 
             self.validate(&tip, &header, clock)?;
-            self.extend_chain(height, hash, header);
+            self.extend_chain(height, hash, header)?;
             self.store.put(std::iter::once(header))?;
 
             Ok(ImportResult::TipChanged(hash, height, vec![]))
@@ -534,13 +1644,23 @@ impl<S: Store<Header = BlockHeader>> BlockTree for BlockCache<S> {
     fn get_block(&self, hash: &BlockHash) -> Option<(Height, &BlockHeader)> {
         self.headers
             .get(hash)
-            .and_then(|height| self.chain.get(*height as usize))
+            .and_then(|height| self.block(*height))
             .map(|blk| (blk.height, &blk.header))
     }
 
+    /// Consume the events emitted by the cache, oldest first.
+    fn events(&mut self) -> Vec<Event> {
+        std::mem::take(&mut self.events)
+    }
+
     /// Get a block by height.
     fn get_block_by_height(&self, height: Height) -> Option<&BlockHeader> {
-        self.chain.get(height as usize).map(|b| &b.header)
+        self.block(height).map(|b| &b.header)
+    }
+
+    /// Get a block hash by height.
+    fn get_block_hash(&self, height: Height) -> Option<BlockHash> {
+        self.block(height).map(|b| b.hash)
     }
 
     /// Get the best block hash and header.
@@ -550,17 +1670,28 @@ impl<S: Store<Header = BlockHeader>> BlockTree for BlockCache<S> {
 
     /// Get the genesis block header.
     fn genesis(&self) -> &BlockHeader {
-        &self.chain.first().header
+        &self.genesis.header
     }
 
-    /// Iterate over the longest chain, starting from genesis.
+    /// Check whether a block hash is the hash of the genesis block.
+    fn is_genesis(&self, hash: &BlockHash) -> bool {
+        &self.genesis.hash == hash
+    }
+
+    /// Iterate over the longest chain, starting from genesis, or from the first block
+    /// kept in memory if the cache was pruned.
     fn iter<'a>(&'a self) -> Box<dyn DoubleEndedIterator<Item = (Height, BlockHeader)> + 'a> {
-        Box::new(Iter::new(&self.chain).map(|(i, h)| (i, h.header)))
+        Box::new(Iter::new(&self.chain).map(|(_, b)| (b.height, b.header)))
     }
 
     /// Return the height of the longest chain.
     fn height(&self) -> Height {
-        self.chain.tail.len() as Height
+        self.root + self.chain.tail.len() as Height
+    }
+
+    /// Get the cumulative proof-of-work of the longest chain, including pruned blocks.
+    fn chain_work(&self) -> Work {
+        self.root_work + Branch(&self.chain.tail).work()
     }
 
     /// Check whether this block hash is known.
@@ -602,9 +1733,12 @@ impl<S: Store<Header = BlockHeader>> BlockTree for BlockCache<S> {
             let (height, _) = self.get_block(hash).unwrap();
             height
         } else {
-            0 as Height
+            self.root
         };
-
+        // Blocks below the first block of the chain were pruned, and can't be served.
+        if start < self.root {
+            return vec![];
+        }
         let start = start + 1;
         let stop = self
             .get_block(&stop_hash)
@@ -620,6 +1754,10 @@ impl<S: Store<Header = BlockHeader>> BlockTree for BlockCache<S> {
     }
 
     /// Get the locator hashes for the active chain, starting at the given height.
+    /// The locator needn't start at the tip: starting lower down the chain, eg. around a
+    /// suspected fork point, helps find a common ancestor with a peer.
+    ///
+    /// Locators don't go past the last checkpoint.
     ///
     /// *Panics* if the given starting height is out of bounds.
     ///
@@ -636,10 +1774,51 @@ impl<S: Store<Header = BlockHeader>> BlockTree for BlockCache<S> {
                 // older than our last checkpoint.
                 break;
             }
-            if let Some(blk) = self.chain.get(height as usize) {
+            if let Some(blk) = self.block(height) {
                 hashes.push(blk.hash);
             }
         }
+        // Always include the root of the chain, as with the genesis.
+        if hashes.last() != Some(&self.genesis.hash) && self.genesis.height >= last_checkpoint {
+            hashes.push(self.genesis.hash);
+        }
         hashes
     }
+
+    /// Get the height of the assume-valid block, once it is part of the active chain.
+    /// Until then, the headers leading up to it may still be reverted.
+    fn assumed_valid_height(&self) -> Height {
+        match self.config.assume_valid {
+            Some((anchor, _)) if anchor <= self.height() => anchor,
+            _ => 0,
+        }
+    }
+
+    /// Fully validate a range of headers of the active chain, ignoring the assume-valid block.
+    fn revalidate<C: Clock>(
+        &self,
+        range: std::ops::Range<Height>,
+        clock: &C,
+    ) -> Result<(), (Height, Error)> {
+        let end = range.end.min(self.height() + 1);
+
+        for height in range.start.max(self.root + 1)..end {
+            let (tip, block) = match (self.block(height - 1), self.block(height)) {
+                (Some(tip), Some(block)) => (tip, block),
+                _ => break,
+            };
+
+            validator::validate(
+                self,
+                tip.height,
+                &tip.header,
+                &block.header,
+                &self.params,
+                &self.checkpoints,
+                clock,
+            )
+            .map_err(|err| (height, err))?;
+        }
+        Ok(())
+    }
 }
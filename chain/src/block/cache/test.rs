@@ -1,8 +1,8 @@
-use super::BlockCache;
+use super::{BlockCache, ChainConfig, ChainDiff, Event, TieBreak, TipRelation};
 
 use nakamoto_common::block::time::{AdjustedTime, Clock, LocalTime};
 use nakamoto_common::block::tree::{BlockTree, Error, ImportResult};
-use nakamoto_common::block::{BlockTime, Height, Target};
+use nakamoto_common::block::{BlockTime, Height, Target, Work};
 
 use nakamoto_test::block;
 use nakamoto_test::block::cache::model;
@@ -512,6 +512,29 @@ fn test_bitcoin_difficulty() {
     }
 }
 
+#[test]
+fn test_difficulty() {
+    let network = bitcoin::Network::Bitcoin;
+    let genesis = constants::genesis_block(network).header;
+    let mut cache = HeightCache::new(genesis);
+
+    assert_eq!(cache.difficulty(), 1.);
+
+    // The first difficulty adjustment on the main chain, at height 32256.
+    cache.import(
+        32256,
+        BlockHeader {
+            version: 1,
+            time: 1262152739,
+            bits: 0x1d00d86a,
+            merkle_root: Default::default(),
+            prev_blockhash: Default::default(),
+            nonce: 0,
+        },
+    );
+    assert!((cache.difficulty() - 1.182899534312841).abs() < 1e-9);
+}
+
 // Test that we're correctly loading headers from the header store.
 #[test]
 fn test_from_store() {
@@ -556,6 +579,110 @@ fn test_median_time_past() {
     assert_eq!(cache.median_time_past(13), headers[7].time);
 }
 
+#[test]
+fn test_first_header_after_time() {
+    let network = bitcoin::Network::Bitcoin;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::File::open(&*nakamoto_test::headers::PATH, genesis).unwrap();
+
+    let cache = BlockCache::from(store, params, &[]).unwrap();
+    let mtp = |height: Height| cache.median_time_past(height + 1);
+    let height = (1..cache.height())
+        .find(|h| *h > 100 && mtp(*h) > mtp(h - 1) + 1)
+        .unwrap();
+
+    // A time in between the median time past of two blocks.
+    let time = LocalTime::from_block_time(mtp(height - 1) + 1);
+    let (h, header) = cache.first_header_after_time(time).unwrap();
+    assert_eq!(h, height);
+    assert_eq!(header, cache.get_block_by_height(height).unwrap());
+
+    // The bound is inclusive.
+    let time = LocalTime::from_block_time(mtp(height));
+    assert_eq!(cache.first_header_after_time(time).unwrap().0, height);
+
+    // Times before genesis return genesis.
+    let (h, header) = cache
+        .first_header_after_time(LocalTime::from_secs(0))
+        .unwrap();
+    assert_eq!(h, 0);
+    assert_eq!(header, &genesis);
+
+    // Times after the tip return nothing.
+    let time = LocalTime::from_block_time(mtp(cache.height()) + 1);
+    assert!(cache.first_header_after_time(time).is_none());
+}
+
+#[test]
+fn test_headers_between() {
+    let network = bitcoin::Network::Bitcoin;
+    let params = Params::new(network);
+    let chain = nakamoto_test::BITCOIN_HEADERS.clone();
+    let cache = BlockCache::from(store::Memory::new(chain.clone()), params, &[]).unwrap();
+    let height = cache.height();
+
+    // Mid-chain range.
+    assert_eq!(
+        cache.headers_between(3, 7).cloned().collect::<Vec<_>>(),
+        chain.iter().skip(3).take(5).cloned().collect::<Vec<_>>()
+    );
+    // Range including genesis.
+    assert_eq!(
+        cache.headers_between(0, 2).cloned().collect::<Vec<_>>(),
+        chain.iter().take(3).cloned().collect::<Vec<_>>()
+    );
+    // Single header.
+    assert_eq!(
+        cache.headers_between(5, 5).collect::<Vec<_>>(),
+        vec![chain.get(5).unwrap()]
+    );
+    // The end of the range is clamped to the tip.
+    assert_eq!(
+        cache
+            .headers_between(height - 1, height + 100)
+            .cloned()
+            .collect::<Vec<_>>(),
+        chain.tail[chain.tail.len() - 2..].to_vec()
+    );
+    // Empty ranges.
+    assert_eq!(cache.headers_between(7, 3).count(), 0);
+    assert_eq!(cache.headers_between(height + 1, height + 9).count(), 0);
+}
+
+#[test]
+fn test_locator_hashes() {
+    let network = bitcoin::Network::Bitcoin;
+    let params = Params::new(network);
+    let chain = nakamoto_test::BITCOIN_HEADERS.clone();
+    let cache = BlockCache::from(store::Memory::new(chain.clone()), params, &[]).unwrap();
+    let hash = |height: usize| chain.get(height).unwrap().block_hash();
+
+    assert!(cache.height() > 1000);
+
+    // Dense for the first heights, then exponentially sparser, down to genesis.
+    assert_eq!(
+        cache.locator_hashes(1000),
+        [1000, 999, 998, 997, 996, 995, 994, 993, 992, 990, 986, 978, 962, 930, 866, 738, 482, 0]
+            .iter()
+            .map(|h| hash(*h))
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(
+        cache.locator_hashes(cache.height()).first(),
+        Some(&cache.tip().0)
+    );
+
+    // Locators stop at the last checkpoint.
+    let cache = BlockCache::from(
+        store::Memory::new(chain.clone()),
+        Params::new(network),
+        &[(900, hash(900))],
+    )
+    .unwrap();
+    assert_eq!(cache.locator_hashes(1000).last(), Some(&hash(930)));
+}
+
 #[test]
 fn prop_cache_import_ordered() {
     fn prop(input: arbitrary::OrderedHeaders) -> bool {
@@ -849,6 +976,321 @@ fn test_cache_import_back_and_forth() {
     assert_eq!(cache.tip().0, b5.hash);
 }
 
+#[test]
+fn test_cache_disconnected_history() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let mut cache = BlockCache::from(store, params, &[])
+        .unwrap()
+        .with_history(3);
+
+    let g = &mut rand::thread_rng();
+
+    let a0 = Tree::new(genesis);
+
+    // a0 <- a1 <- a2 *
+    let a1 = a0.next(g);
+    let a2 = a1.next(g);
+
+    cache.import_blocks(a0.branch([&a1, &a2]), &ctx).unwrap();
+    assert!(cache.disconnected_history().is_empty());
+
+    // a0 <- a1 <- a2
+    //           \
+    //            <- b2 <- b3 *
+    let b2 = a1.next(g);
+    let b3 = b2.next(g);
+
+    cache.import_blocks(a0.branch([&b2, &b3]), &ctx).unwrap();
+    assert_eq!(cache.tip().0, b3.hash);
+    assert_eq!(cache.disconnected_history(), &[(2, a2.block())]);
+
+    // a0 <- a1 <- a2
+    //     \      \
+    //      \      <- b2 <- b3
+    //       \
+    //        <- c1 <- c2 <- c3 <- c4 *
+    let c1 = a0.next(g);
+    let c2 = c1.next(g);
+    let c3 = c2.next(g);
+    let c4 = c3.next(g);
+
+    cache.import_blocks(a0.branch([&c1, &c4]), &ctx).unwrap();
+    assert_eq!(cache.tip().0, c4.hash);
+
+    // The oldest entry is dropped to make room.
+    assert_eq!(
+        cache.disconnected_history(),
+        &[(1, a1.block()), (2, b2.block()), (3, b3.block())]
+    );
+}
+
+#[test]
+fn test_cache_max_orphans() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let mut cache = BlockCache::from(store, params, &[])
+        .unwrap()
+        .with_config(ChainConfig {
+            max_orphans: 10,
+            ..ChainConfig::default()
+        })
+        .unwrap();
+
+    let g = &mut rand::thread_rng();
+    let mut tree = Tree::new(genesis).next(g);
+    let mut orphans = Vec::new();
+
+    // Headers descending from `a1`, which is never imported.
+    for _ in 0..11 {
+        tree = tree.next(g);
+        orphans.push(tree.block());
+    }
+    for orphan in orphans.iter().take(10) {
+        cache.import_block(*orphan, &ctx).ok();
+    }
+    assert!(orphans[..10]
+        .iter()
+        .all(|h| cache.is_known(&h.block_hash())));
+    assert!(!cache
+        .events()
+        .into_iter()
+        .any(|e| matches!(e, Event::OrphanEvicted(_))));
+
+    // The 11th orphan evicts the oldest one.
+    cache.import_block(orphans[10], &ctx).ok();
+
+    assert!(!cache.is_known(&orphans[0].block_hash()));
+    assert!(orphans[1..].iter().all(|h| cache.is_known(&h.block_hash())));
+    assert_eq!(
+        cache
+            .events()
+            .into_iter()
+            .filter(|e| matches!(e, Event::OrphanEvicted(_)))
+            .collect::<Vec<_>>(),
+        vec![Event::OrphanEvicted(orphans[0].block_hash())]
+    );
+    assert_eq!(cache.height(), 0);
+}
+
+#[test]
+fn test_cache_max_side_chain_headers() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let mut cache = BlockCache::from(store, params, &[])
+        .unwrap()
+        .with_config(ChainConfig {
+            max_side_chain_headers: 2,
+            ..ChainConfig::default()
+        })
+        .unwrap();
+
+    let g = &mut rand::thread_rng();
+
+    // a0 <- a1 <- a2 <- a3 <- a4 *
+    //    <- b1 <- b2
+    //    <- c1
+    let a0 = Tree::new(genesis);
+    let a1 = a0.next(g);
+    let a4 = a1.next(g).next(g).next(g);
+    cache.import_blocks(a0.branch([&a1, &a4]), &ctx).unwrap();
+
+    let b1 = a0.next(g);
+    let b2 = b1.next(g);
+    let c1 = a0.next(g);
+
+    cache.import_blocks(a0.branch([&b1, &b2]), &ctx).unwrap();
+    assert_eq!(cache.events().len(), 0);
+
+    // The branch with the least work loses a header.
+    cache.import_block(c1.block(), &ctx).unwrap();
+    assert_eq!(cache.events(), vec![Event::SideChainHeaderEvicted(c1.hash)]);
+    assert!(cache.is_known(&b1.hash));
+    assert!(cache.is_known(&b2.hash));
+    assert_eq!(cache.tip().0, a4.hash);
+}
+
+#[test]
+fn test_cache_max_side_chains() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let mut cache = BlockCache::from(store, params, &[])
+        .unwrap()
+        .with_config(ChainConfig {
+            max_side_chains: 5,
+            ..ChainConfig::default()
+        })
+        .unwrap();
+
+    let g = &mut rand::thread_rng();
+
+    // a0 <- a1 <- a2 <- a3 <- a4 <- a5 *
+    //    <- b1
+    //    <- c1 <- c2   (x4)
+    let a0 = Tree::new(genesis);
+    let a1 = a0.next(g);
+    let a5 = a1.next(g).next(g).next(g).next(g);
+    cache.import_blocks(a0.branch([&a1, &a5]), &ctx).unwrap();
+
+    let b1 = a0.next(g);
+    cache.import_block(b1.block(), &ctx).unwrap();
+
+    let mut side = Vec::new();
+    for _ in 0..4 {
+        let c1 = a0.next(g);
+        let c2 = c1.next(g);
+
+        cache.import_blocks(a0.branch([&c1, &c2]), &ctx).unwrap();
+        side.push((c1, c2));
+    }
+    assert_eq!(cache.events().len(), 0);
+
+    // A sixth side-chain evicts the one with the least work.
+    let d1 = a0.next(g);
+    let d3 = d1.next(g).next(g);
+    cache.import_blocks(a0.branch([&d1, &d3]), &ctx).unwrap();
+
+    assert_eq!(cache.events(), vec![Event::SideChainEvicted(b1.hash)]);
+    assert!(!cache.is_known(&b1.hash));
+    assert!(side
+        .iter()
+        .all(|(c1, c2)| cache.is_known(&c1.hash) && cache.is_known(&c2.hash)));
+    assert!(cache.is_known(&d3.hash));
+
+    // The active chain is unaffected.
+    assert_eq!(cache.tip().0, a5.hash);
+    assert_eq!(cache.height(), 5);
+    assert!(a5
+        .branch([&a1, &a5])
+        .all(|h| cache.contains(&h.block_hash())));
+}
+
+#[test]
+fn test_cache_orphan_tips() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let mut cache = BlockCache::from(store, params, &[])
+        .unwrap()
+        .with_config(ChainConfig {
+            max_side_chains: 1,
+            ..ChainConfig::default()
+        })
+        .unwrap();
+
+    let tips = |cache: &BlockCache<_>| {
+        cache
+            .orphans
+            .keys()
+            .filter(|h| !cache.orphan_children.contains_key(*h))
+            .count()
+    };
+    let g = &mut rand::thread_rng();
+
+    // a0 <- a1 <- a2 <- a3 *
+    //    <- b1 <- b2 <- b3 <- b4
+    //          <- c2
+    let a0 = Tree::new(genesis);
+    let a1 = a0.next(g);
+    let a3 = a1.next(g).next(g);
+    cache.import_blocks(a0.branch([&a1, &a3]), &ctx).unwrap();
+    assert_eq!(cache.orphan_tips, 0);
+
+    let b1 = a0.next(g);
+    let b3 = b1.next(g).next(g);
+    cache.import_blocks(a0.branch([&b1, &b3]), &ctx).unwrap();
+    assert_eq!(cache.orphan_tips, 1);
+    assert_eq!(cache.orphan_tips, tips(&cache));
+
+    // Disconnected headers count as tips, but don't count towards the side-chain limit.
+    let d2 = a0.next(g).next(g);
+    cache.import_block(d2.block(), &ctx).ok();
+    assert_eq!(cache.orphan_tips, 2);
+    assert_eq!(cache.orphan_tips, tips(&cache));
+    assert!(cache.is_known(&d2.hash));
+    assert!(cache.is_known(&b3.hash));
+
+    // The re-org turns the active chain into a side-chain.
+    let b4 = b3.next(g);
+    cache.import_block(b4.block(), &ctx).unwrap();
+    assert_eq!(cache.tip().0, b4.hash);
+    assert!(cache.is_known(&a3.hash));
+    assert_eq!(cache.orphan_tips, 2);
+    assert_eq!(cache.orphan_tips, tips(&cache));
+
+    // A second side-chain evicts the one with the least work.
+    let c2 = b1.next(g);
+    cache.import_block(c2.block(), &ctx).unwrap();
+    assert_eq!(
+        cache.events().last(),
+        Some(&Event::SideChainEvicted(c2.hash))
+    );
+    assert!(!cache.is_known(&c2.hash));
+    assert!(cache.is_known(&a3.hash));
+    assert_eq!(cache.orphan_tips, 2);
+    assert_eq!(cache.orphan_tips, tips(&cache));
+}
+
+#[test]
+fn test_cache_orphan_events() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let mut cache = BlockCache::from(store, params, &[]).unwrap();
+
+    let g = &mut rand::thread_rng();
+
+    // a0 <- a1 <- a2 <- a3
+    let a0 = Tree::new(genesis);
+    let a1 = a0.next(g);
+    let a2 = a1.next(g);
+    let a3 = a2.next(g);
+
+    // The orphans are missing `a1`.
+    cache.import_block(a3.block(), &ctx).ok();
+    cache.import_block(a2.block(), &ctx).ok();
+    assert_eq!(
+        cache.events(),
+        vec![
+            Event::OrphanReceived {
+                hash: a3.hash,
+                missing_parent: a2.hash
+            },
+            Event::OrphanReceived {
+                hash: a2.hash,
+                missing_parent: a1.hash
+            },
+        ]
+    );
+
+    // The missing parent arrives, and both orphans are connected.
+    cache.import_block(a1.block(), &ctx).unwrap();
+    assert_eq!(
+        cache.events(),
+        vec![Event::OrphansResolved {
+            count: 2,
+            new_tip: Some(a3.hash)
+        }]
+    );
+    assert_eq!(cache.tip().0, a3.hash);
+}
+
 #[test]
 fn test_cache_import_equal_difficulty_blocks() {
     let mut headers = vec![
@@ -892,7 +1334,15 @@ fn test_cache_import_equal_difficulty_blocks() {
     let store = store::Memory::new(NonEmpty::new(genesis));
     let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
 
-    let mut real = BlockCache::from(store.clone(), params.clone(), &[]).unwrap();
+    // The model picks the lowest hash between chains with equal work.
+    let config = ChainConfig {
+        tie_break: TieBreak::LowestHash,
+        ..ChainConfig::default()
+    };
+    let mut real = BlockCache::from(store.clone(), params.clone(), &[])
+        .unwrap()
+        .with_config(config)
+        .unwrap();
     let mut model = model::Cache::new(genesis);
 
     model.import_blocks(headers.iter().cloned(), &ctx).unwrap();
@@ -911,7 +1361,10 @@ fn test_cache_import_equal_difficulty_blocks() {
 
     headers.swap(1, 2);
 
-    let mut real = BlockCache::from(store, params, &[]).unwrap();
+    let mut real = BlockCache::from(store, params, &[])
+        .unwrap()
+        .with_config(config)
+        .unwrap();
     let mut model = model::Cache::new(genesis);
 
     model.import_blocks(headers.iter().cloned(), &ctx).unwrap();
@@ -922,69 +1375,396 @@ fn test_cache_import_equal_difficulty_blocks() {
 }
 
 #[test]
-fn test_cache_import_longer_chain_with_less_difficulty() {
-    // TODO
-}
-
-#[test]
-fn test_cache_import_with_checkpoints() {
+fn test_cache_tie_break() {
     let network = bitcoin::Network::Regtest;
     let genesis = constants::genesis_block(network).header;
     let params = Params::new(network);
-    let store = store::Memory::new(NonEmpty::new(genesis));
     let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
-    let g = &mut rand::thread_rng();
 
-    let tree = Tree::new(genesis);
+    let g = &mut rand::thread_rng();
+    let root = Tree::new(genesis);
+    // Two chains with equal work, forking off genesis.
+    let a = root.next(g).next(g);
+    let b = root.next(g).next(g);
+    let lowest = a.hash.min(b.hash);
+
+    for (first, second) in &[(&a, &b), (&b, &a)] {
+        for tie_break in &[TieBreak::FirstSeen, TieBreak::LowestHash] {
+            let store = store::Memory::new(NonEmpty::new(genesis));
+            let mut cache = BlockCache::from(store, params.clone(), &[])
+                .unwrap()
+                .with_config(ChainConfig {
+                    tie_break: *tie_break,
+                    ..ChainConfig::default()
+                })
+                .unwrap();
+
+            cache
+                .import_blocks(first.branch([&root, first]), &ctx)
+                .unwrap();
+            assert_eq!(cache.tip().0, first.hash);
+
+            cache
+                .import_blocks(second.branch([&root, second]), &ctx)
+                .unwrap();
+
+            // An established tip is never displaced by a chain with equal work.
+            assert_eq!(cache.tip().0, first.hash, "the first chain is kept");
+            assert_eq!(cache.height(), 2);
+        }
 
-    // a0 <- a1 <- a2 <- a3 *
-    let a1 = tree.next(g);
-    let a2 = a1.next(g);
-    let a3 = a2.next(g);
+        // When neither chain is the established tip, the tie-break decides.
+        for tie_break in &[TieBreak::FirstSeen, TieBreak::LowestHash] {
+            let store = store::Memory::new(NonEmpty::new(genesis));
+            let mut cache = BlockCache::from(store, params.clone(), &[])
+                .unwrap()
+                .with_config(ChainConfig {
+                    tie_break: *tie_break,
+                    ..ChainConfig::default()
+                })
+                .unwrap();
+            let headers = first
+                .branch([&root, first])
+                .chain(second.branch([&root, second]))
+                .collect::<Vec<_>>();
+
+            cache.import_blocks(headers.into_iter(), &ctx).unwrap();
+
+            match tie_break {
+                TieBreak::FirstSeen => {
+                    assert_eq!(cache.tip().0, first.hash, "the first chain is kept")
+                }
+                TieBreak::LowestHash => {
+                    assert_eq!(cache.tip().0, lowest, "the lowest hash wins")
+                }
+            }
+        }
 
-    let mut cache = BlockCache::from(store.clone(), params.clone(), &[]).unwrap();
-    cache.import_blocks(tree.branch([&a1, &a3]), &ctx).unwrap();
+        // The first-seen chain is kept by default, whatever the network.
+        let store = store::Memory::new(NonEmpty::new(genesis));
+        let mut cache = BlockCache::from(store, params.clone(), &[]).unwrap();
+        let headers = first
+            .branch([&root, first])
+            .chain(second.branch([&root, second]))
+            .collect::<Vec<_>>();
+
+        cache.import_blocks(headers.into_iter(), &ctx).unwrap();
+        assert_eq!(cache.tip().0, first.hash, "the first chain is kept");
+    }
+}
 
-    let mut cache =
-        BlockCache::from(store.clone(), params.clone(), &[(32, Default::default())]).unwrap();
-    cache
-        .import_blocks(tree.branch([&a1, &a3]), &ctx)
-        .expect("A checkpoint in the future cannot cause any error");
+#[test]
+fn test_cache_reorg_requires_more_work() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
 
-    let mut cache =
-        BlockCache::from(store.clone(), params.clone(), &[(1, Default::default())]).unwrap();
-    assert!(
-        matches! {
-            cache.import_block(a1.block(), &ctx),
-            Err(Error::InvalidBlockHash(hash, 1)) if hash == a1.hash
-        },
-        "An incorrect checkpoint at height 1 causes an error"
+    let g = &mut rand::thread_rng();
+    let root = Tree::new(genesis);
+    let a = root.next(g).next(g);
+    let b = root.next(g).next(g);
+    let c = b.next(g);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let mut cache = BlockCache::from(store, params, &[])
+        .unwrap()
+        .with_config(ChainConfig {
+            tie_break: TieBreak::LowestHash,
+            ..ChainConfig::default()
+        })
+        .unwrap();
+
+    cache.import_blocks(a.branch([&root, &a]), &ctx).unwrap();
+    assert_eq!(cache.tip().0, a.hash);
+
+    // A competing chain with equal work doesn't displace the tip, whatever its hash.
+    assert_eq!(
+        cache.import_blocks(b.branch([&root, &b]), &ctx).unwrap(),
+        ImportResult::TipUnchanged
     );
+    assert_eq!(cache.tip().0, a.hash);
 
-    let mut cache = BlockCache::from(store, params, &[(1, a1.hash), (2, a2.hash)]).unwrap();
-    cache
-        .import_blocks(tree.branch([&a1, &a2]), &ctx)
-        .expect("Correct checkpoints cause no error");
+    // Once it has strictly more work, it does.
+    assert!(matches!(
+        cache.import_blocks(c.branch([&root, &c]), &ctx).unwrap(),
+        ImportResult::TipChanged(tip, 3, _) if tip == c.hash
+    ));
 }
 
 #[test]
-fn test_cache_import_invalid_fork() {
+fn test_cache_tip_relationship() {
     let network = bitcoin::Network::Regtest;
     let genesis = constants::genesis_block(network).header;
     let params = Params::new(network);
     let store = store::Memory::new(NonEmpty::new(genesis));
     let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
     let mut cache = BlockCache::from(store, params, &[]).unwrap();
-    let g = &mut rand::thread_rng();
 
-    let a0 = Tree::new(genesis);
+    let g = &mut rand::thread_rng();
+    let root = Tree::new(genesis);
+    // Active chain.
+    let a1 = root.next(g);
+    let a4 = a1.next(g).next(g).next(g);
+    // Side-chain forking off `a1`.
+    let b2 = a1.next(g);
+    let b3 = b2.next(g);
+    // Side-chain forking off `b2`.
+    let c3 = b2.next(g);
 
-    // a0 <- a1 <- a2 <- a3 *
-    let a1 = a0.next(g);
-    let a2 = a1.next(g);
-    let a3 = a2.next(g);
+    cache.import_blocks(a4.branch([&root, &a4]), &ctx).unwrap();
+    cache.import_blocks(b3.branch([&a1, &b3]), &ctx).unwrap();
+    cache.import_blocks(c3.branch([&b2, &c3]), &ctx).unwrap();
+    assert_eq!(cache.tip().0, a4.hash);
 
-    cache.import_blocks(a0.branch([&a1, &a3]), &ctx).unwrap();
+    assert_eq!(
+        cache.tip_relationship(&a1.hash, &a4.hash).unwrap(),
+        TipRelation::Ancestor
+    );
+    assert_eq!(
+        cache.tip_relationship(&a4.hash, &a1.hash).unwrap(),
+        TipRelation::Descendant
+    );
+    assert_eq!(
+        cache.tip_relationship(&a4.hash, &a4.hash).unwrap(),
+        TipRelation::Equal
+    );
+    assert_eq!(
+        cache.tip_relationship(&b2.hash, &c3.hash).unwrap(),
+        TipRelation::Ancestor
+    );
+    assert_eq!(
+        cache.tip_relationship(&a4.hash, &b3.hash).unwrap(),
+        TipRelation::Fork {
+            height: 1,
+            hash: a1.hash
+        }
+    );
+    assert_eq!(
+        cache.tip_relationship(&c3.hash, &b3.hash).unwrap(),
+        TipRelation::Fork {
+            height: 2,
+            hash: b2.hash
+        }
+    );
+
+    let unknown = a4.next(g);
+    assert!(matches!(
+        cache.tip_relationship(&a4.hash, &unknown.hash),
+        Err(Error::BlockMissing(hash)) if hash == unknown.hash
+    ));
+}
+
+#[test]
+fn test_cache_is_ancestor() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let mut cache = BlockCache::from(store, params, &[]).unwrap();
+
+    let g = &mut rand::thread_rng();
+
+    // a0 <- a1 <- a2 <- a3 <- a4 *
+    //          <- b2 <- b3
+    let a0 = Tree::new(genesis);
+    let a1 = a0.next(g);
+    let a2 = a1.next(g);
+    let a3 = a2.next(g);
+    let a4 = a3.next(g);
+    let b2 = a1.next(g);
+    let b3 = b2.next(g);
+
+    cache.import_blocks(a0.branch([&a1, &a4]), &ctx).unwrap();
+    cache.import_blocks(a1.branch([&b2, &b3]), &ctx).unwrap();
+    assert_eq!(cache.tip().0, a4.hash);
+
+    // Both blocks on the active chain.
+    assert!(cache.is_ancestor(&a0.hash, &a4.hash));
+    assert!(cache.is_ancestor(&a1.hash, &a2.hash));
+    assert!(!cache.is_ancestor(&a3.hash, &a1.hash));
+    assert!(!cache.is_ancestor(&a2.hash, &a2.hash));
+
+    // Descendant on a side-chain.
+    assert!(cache.is_ancestor(&a0.hash, &b3.hash));
+    assert!(cache.is_ancestor(&a1.hash, &b3.hash));
+    assert!(cache.is_ancestor(&b2.hash, &b3.hash));
+    assert!(!cache.is_ancestor(&a2.hash, &b3.hash));
+    assert!(!cache.is_ancestor(&b3.hash, &b2.hash));
+    assert!(!cache.is_ancestor(&b2.hash, &a3.hash));
+
+    // Unknown blocks.
+    let unknown = a4.next(g);
+    assert!(!cache.is_ancestor(&a0.hash, &unknown.hash));
+    assert!(!cache.is_ancestor(&unknown.hash, &a3.hash));
+}
+
+#[test]
+fn test_cache_bulk_validate_and_import() {
+    use rand::seq::SliceRandom;
+
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let g = &mut rand::thread_rng();
+
+    // a0 <- a1 <- ... <- a8
+    //          <- b2
+    let a0 = Tree::new(genesis);
+    let mut chain = vec![a0.next(g)];
+    for _ in 1..8 {
+        let next = chain.last().unwrap().next(g);
+        chain.push(next);
+    }
+    let tip = chain.last().unwrap().clone();
+    let b2 = chain[0].next(g);
+
+    // A shuffled but complete chain is imported in order.
+    {
+        let mut cache = BlockCache::from(store.clone(), params.clone(), &[]).unwrap();
+        let mut headers = chain.iter().map(|t| t.block()).collect::<Vec<_>>();
+        headers.shuffle(g);
+
+        let result = cache.bulk_validate_and_import(headers, &ctx).unwrap();
+        assert!(matches!(result, ImportResult::TipChanged(hash, 8, _) if hash == tip.hash));
+        assert_eq!(cache.tip().0, tip.hash);
+    }
+
+    // A chain with a gap fails on the missing header.
+    {
+        let mut cache = BlockCache::from(store.clone(), params.clone(), &[]).unwrap();
+        let missing = chain[4].hash;
+        let mut headers = chain
+            .iter()
+            .filter(|t| t.hash != missing)
+            .map(|t| t.block())
+            .collect::<Vec<_>>();
+        headers.shuffle(g);
+
+        assert!(matches!(
+            cache.bulk_validate_and_import(headers, &ctx),
+            Err(Error::BlockMissing(hash)) if hash == missing
+        ));
+        assert_eq!(cache.height(), 0, "No headers are imported");
+    }
+
+    // A chain that forks is rejected.
+    {
+        let mut cache = BlockCache::from(store, params, &[]).unwrap();
+        let headers = chain
+            .iter()
+            .chain(iter::once(&b2))
+            .map(|t| t.block())
+            .collect::<Vec<_>>();
+
+        assert!(matches!(
+            cache.bulk_validate_and_import(headers, &ctx),
+            Err(Error::DisjointChains(hash)) if hash == b2.hash
+        ));
+        assert_eq!(cache.height(), 0, "No headers are imported");
+    }
+}
+
+#[test]
+fn test_cache_snapshot() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let mut cache = BlockCache::from(store, params, &[]).unwrap();
+    let g = &mut rand::thread_rng();
+
+    let a0 = Tree::new(genesis);
+    let a1 = a0.next(g);
+    let a2 = a1.next(g);
+
+    cache.import_block(a1.block(), &ctx).unwrap();
+
+    let snapshot = cache.snapshot();
+    cache.import_block(a2.block(), &ctx).unwrap();
+
+    assert_eq!(cache.tip().0, a2.hash);
+    assert_eq!(cache.height(), 2);
+
+    // The snapshot still shows the chain as it was, and can be read from another thread.
+    std::thread::spawn(move || {
+        assert_eq!(snapshot.tip().0, a1.hash);
+        assert_eq!(snapshot.height(), 1);
+        assert_eq!(snapshot.get_block(&a1.hash), Some((1, &a1.block())));
+        assert_eq!(snapshot.get_block(&a2.hash), None);
+        assert_eq!(snapshot.get_block_by_height(2), None);
+    })
+    .join()
+    .unwrap();
+}
+
+#[test]
+fn test_cache_import_longer_chain_with_less_difficulty() {
+    // TODO
+}
+
+#[test]
+fn test_cache_import_with_checkpoints() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let g = &mut rand::thread_rng();
+
+    let tree = Tree::new(genesis);
+
+    // a0 <- a1 <- a2 <- a3 *
+    let a1 = tree.next(g);
+    let a2 = a1.next(g);
+    let a3 = a2.next(g);
+
+    let mut cache = BlockCache::from(store.clone(), params.clone(), &[]).unwrap();
+    cache.import_blocks(tree.branch([&a1, &a3]), &ctx).unwrap();
+
+    let mut cache =
+        BlockCache::from(store.clone(), params.clone(), &[(32, Default::default())]).unwrap();
+    cache
+        .import_blocks(tree.branch([&a1, &a3]), &ctx)
+        .expect("A checkpoint in the future cannot cause any error");
+
+    let mut cache =
+        BlockCache::from(store.clone(), params.clone(), &[(1, Default::default())]).unwrap();
+    assert!(
+        matches! {
+            cache.import_block(a1.block(), &ctx),
+            Err(Error::InvalidBlockHash(hash, 1)) if hash == a1.hash
+        },
+        "An incorrect checkpoint at height 1 causes an error"
+    );
+
+    let mut cache = BlockCache::from(store, params, &[(1, a1.hash), (2, a2.hash)]).unwrap();
+    cache
+        .import_blocks(tree.branch([&a1, &a2]), &ctx)
+        .expect("Correct checkpoints cause no error");
+}
+
+#[test]
+fn test_cache_import_invalid_fork() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let mut cache = BlockCache::from(store, params, &[]).unwrap();
+    let g = &mut rand::thread_rng();
+
+    let a0 = Tree::new(genesis);
+
+    // a0 <- a1 <- a2 <- a3 *
+    let a1 = a0.next(g);
+    let a2 = a1.next(g);
+    let a3 = a2.next(g);
+
+    cache.import_blocks(a0.branch([&a1, &a3]), &ctx).unwrap();
     assert_eq!(cache.tip().0, a3.hash);
 
     //            <- c2 <- c3 <- c4 <- (c5)
@@ -1394,3 +2174,1139 @@ fn test_cache_locate_headers() {
         "If the stop height is equal to the start height, we don't expect anything"
     );
 }
+
+#[test]
+fn test_header_validator() {
+    use crate::block::validator::HeaderValidator;
+
+    let clock = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let chain = &nakamoto_test::BITCOIN_HEADERS;
+    let mut validator = HeaderValidator::new(
+        *chain.first(),
+        Params::new(bitcoin::Network::Bitcoin),
+        &[(11, chain.get(11).unwrap().block_hash())],
+    );
+
+    for (i, header) in chain.tail.iter().enumerate() {
+        assert_eq!(validator.push(*header, &clock).unwrap(), i as Height + 1);
+    }
+    assert_eq!(validator.tip(), (chain.tail.len() as Height, chain.last()));
+
+    // A header that doesn't build on the tip is rejected.
+    let header = *chain.get(7).unwrap();
+    assert!(matches!(
+        validator.validate(&header, &clock),
+        Err(Error::BlockMissing(hash)) if hash == header.prev_blockhash
+    ));
+}
+
+#[test]
+fn test_header_validator_invalid_retarget() {
+    use crate::block::validator::HeaderValidator;
+
+    let clock = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    // Start with a target that's low enough for the retarget computation not to overflow.
+    let mut genesis = BlockHeader {
+        bits: 0x2000ffff,
+        ..constants::genesis_block(bitcoin::Network::Regtest).header
+    };
+    block::solve(&mut genesis);
+
+    let params = Params {
+        pow_target_spacing: 60,
+        pow_target_timespan: 60 * 10,
+        allow_min_difficulty_blocks: false,
+        no_pow_retargeting: false,
+        ..Params::new(bitcoin::Network::Regtest)
+    };
+    let interval = params.difficulty_adjustment_interval();
+    let mut validator = HeaderValidator::new(genesis, params, &[]);
+
+    let next = |prev: &BlockHeader, bits| {
+        let mut header = BlockHeader {
+            version: 1,
+            prev_blockhash: prev.block_hash(),
+            merkle_root: TxMerkleNode::default(),
+            // Blocks are found four times faster than the target spacing.
+            time: prev.time + 15,
+            bits,
+            nonce: 0,
+        };
+        block::solve(&mut header);
+        header
+    };
+
+    let mut tip = genesis;
+    for _ in 1..interval {
+        tip = next(&tip, genesis.bits);
+        validator.push(tip, &clock).unwrap();
+    }
+    assert_eq!(validator.tip().0, interval - 1);
+
+    // At the retarget height, the difficulty should go up by the maximum factor of four.
+    let invalid = next(&tip, genesis.bits);
+    assert!(matches!(
+        validator.validate(&invalid, &clock),
+        Err(Error::InvalidBlockTarget(_, _))
+    ));
+    assert!(validator.push(invalid, &clock).is_err());
+    assert_eq!(validator.tip(), (interval - 1, &tip));
+
+    let bits = BlockHeader::compact_target_from_u256(&(genesis.target() >> 2));
+    let valid = next(&tip, bits);
+    assert_eq!(validator.push(valid, &clock).unwrap(), interval);
+}
+
+#[test]
+fn test_cache_assume_valid() {
+    let network = bitcoin::Network::Regtest;
+    let clock = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let genesis = constants::genesis_block(network).header;
+    let next = |prev: &BlockHeader, time: BlockTime| {
+        let mut header = BlockHeader {
+            prev_blockhash: prev.block_hash(),
+            time,
+            ..*prev
+        };
+        block::solve(&mut header);
+        header
+    };
+
+    // Headers whose timestamps aren't above the median time past, and so are invalid.
+    let mut headers = Vec::<BlockHeader>::new();
+    for _ in 0..12 {
+        let prev = headers.last().unwrap_or(&genesis);
+        headers.push(next(prev, genesis.time));
+    }
+    let anchor = (10, headers[9].block_hash());
+
+    let mut cache = BlockCache::from(
+        store::Memory::new(NonEmpty::new(genesis)),
+        Params::new(network),
+        &[],
+    )
+    .unwrap()
+    .with_config(ChainConfig {
+        assume_valid: Some(anchor),
+        ..ChainConfig::default()
+    })
+    .unwrap();
+
+    // Headers up to the assume-valid block skip the timestamp checks.
+    for header in &headers[..10] {
+        cache.import_block(*header, &clock).unwrap();
+    }
+    assert_eq!(cache.tip(), (anchor.1, headers[9]));
+
+    // Headers above it are fully validated.
+    assert!(matches!(
+        cache.import_block(headers[10], &clock),
+        Err(Error::InvalidBlockTime(_, _))
+    ));
+    assert_eq!(cache.height(), 10);
+
+    let valid = next(&headers[9], genesis.time + 1);
+    cache.import_block(valid, &clock).unwrap();
+    assert_eq!(cache.height(), 11);
+
+    // A chain that doesn't lead to the assume-valid block is rejected.
+    let mut cache = BlockCache::from(
+        store::Memory::new(NonEmpty::new(genesis)),
+        Params::new(network),
+        &[],
+    )
+    .unwrap()
+    .with_config(ChainConfig {
+        assume_valid: Some((10, headers[10].block_hash())),
+        ..ChainConfig::default()
+    })
+    .unwrap();
+
+    for header in &headers[..9] {
+        cache.import_block(*header, &clock).unwrap();
+    }
+    assert!(matches!(
+        cache.import_block(headers[9], &clock),
+        Err(Error::InvalidBlockHash(hash, 10)) if hash == anchor.1
+    ));
+    assert_eq!(cache.height(), 9);
+
+    // Headers below the assume-valid block with forged difficulty bits are rejected, since
+    // their proof-of-work is still checked.
+    let mut cache = BlockCache::from(
+        store::Memory::new(NonEmpty::new(genesis)),
+        Params::new(network),
+        &[],
+    )
+    .unwrap()
+    .with_config(ChainConfig {
+        assume_valid: Some(anchor),
+        ..ChainConfig::default()
+    })
+    .unwrap();
+
+    for header in &headers[..5] {
+        cache.import_block(*header, &clock).unwrap();
+    }
+    let work = cache.chain_work();
+    let tip = headers[4];
+
+    let forged = BlockHeader {
+        prev_blockhash: tip.block_hash(),
+        bits: 0x1d00ffff,
+        ..tip
+    };
+    assert!(matches!(
+        cache.import_block(forged, &clock),
+        Err(Error::InvalidBlockPoW)
+    ));
+
+    let mut forged = BlockHeader {
+        prev_blockhash: tip.block_hash(),
+        bits: 0x2100ffff,
+        ..tip
+    };
+    block::solve(&mut forged);
+    assert!(matches!(
+        cache.import_block(forged, &clock),
+        Err(Error::InvalidBlockTarget(_, _))
+    ));
+    assert_eq!(cache.height(), 5);
+    assert_eq!(cache.chain_work(), work);
+}
+
+#[test]
+fn test_cache_revalidate() {
+    let network = bitcoin::Network::Regtest;
+    let clock = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let genesis = constants::genesis_block(network).header;
+    let next = |prev: &BlockHeader, time: BlockTime| {
+        let mut header = BlockHeader {
+            prev_blockhash: prev.block_hash(),
+            time,
+            ..*prev
+        };
+        block::solve(&mut header);
+        header
+    };
+
+    let mut headers = Vec::<BlockHeader>::new();
+    for i in 1..=12 {
+        let prev = headers.last().unwrap_or(&genesis);
+        headers.push(next(prev, genesis.time + i * 600));
+    }
+    let anchor = (10, headers[9].block_hash());
+    let config = ChainConfig {
+        assume_valid: Some(anchor),
+        ..ChainConfig::default()
+    };
+
+    // A valid chain passes full validation.
+    let mut cache = BlockCache::from(
+        store::Memory::new(NonEmpty::new(genesis)),
+        Params::new(network),
+        &[],
+    )
+    .unwrap()
+    .with_config(config)
+    .unwrap();
+
+    for header in &headers[..9] {
+        cache.import_block(*header, &clock).unwrap();
+    }
+    assert_eq!(
+        cache.assumed_valid_height(),
+        0,
+        "The anchor isn't reached yet"
+    );
+
+    for header in &headers[9..] {
+        cache.import_block(*header, &clock).unwrap();
+    }
+    assert_eq!(cache.assumed_valid_height(), 10);
+    assert!(cache.revalidate(0..11, &clock).is_ok());
+
+    // A header with an invalid timestamp in the assumed-valid region is caught.
+    let mut invalid = headers[..10].to_vec();
+    invalid[4] = next(&invalid[3], genesis.time);
+    for i in 5..invalid.len() {
+        invalid[i] = next(&invalid[i - 1], invalid[i].time);
+    }
+    let mut cache = BlockCache::from(
+        store::Memory::new(NonEmpty::new(genesis)),
+        Params::new(network),
+        &[],
+    )
+    .unwrap()
+    .with_config(ChainConfig {
+        assume_valid: Some((10, invalid[9].block_hash())),
+        ..config
+    })
+    .unwrap();
+
+    for header in &invalid {
+        cache.import_block(*header, &clock).unwrap();
+    }
+    assert!(cache.revalidate(0..3, &clock).is_ok());
+    assert!(matches!(
+        cache.revalidate(0..11, &clock),
+        Err((5, Error::InvalidBlockTime(_, _)))
+    ));
+}
+
+#[test]
+fn test_next_difficulty_estimate() {
+    let genesis = constants::genesis_block(bitcoin::Network::Regtest).header;
+    // A difficulty adjustment interval of 10 blocks.
+    let params = Params {
+        pow_target_spacing: 60,
+        pow_target_timespan: 60 * 10,
+        no_pow_retargeting: false,
+        ..Params::new(bitcoin::Network::Regtest)
+    };
+    // Build a chain whose blocks are mined at the given interval, up to the given height.
+    let chain = |spacing: BlockTime, height: Height| {
+        let mut headers = NonEmpty::new(genesis);
+
+        for _ in 0..height {
+            let prev = *headers.last();
+            headers.push(BlockHeader {
+                prev_blockhash: prev.block_hash(),
+                time: prev.time + spacing,
+                ..prev
+            });
+        }
+        BlockCache::from(store::Memory::new(headers), params.clone(), &[]).unwrap()
+    };
+
+    // Four blocks into the second interval, mined twice as fast as the target. Projected
+    // over the nine block intervals measured by a retarget, that's 270 seconds instead of
+    // the target 600.
+    let (height, factor) = chain(30, 14).next_difficulty_estimate(&params).unwrap();
+    assert_eq!(height, 20);
+    assert!((factor - 600. / 270.).abs() < 1e-9, "{}", factor);
+
+    // Blocks mined too slowly: the change is limited to a quarter of the difficulty.
+    let (height, factor) = chain(600, 5).next_difficulty_estimate(&params).unwrap();
+    assert_eq!(height, 10);
+    assert!((factor - 0.25).abs() < 1e-9, "{}", factor);
+
+    // Nothing to project from at the start of an interval.
+    assert_eq!(chain(30, 10).next_difficulty_estimate(&params), None);
+
+    // No difficulty adjustments on regtest.
+    let regtest = Params::new(bitcoin::Network::Regtest);
+    assert_eq!(chain(30, 14).next_difficulty_estimate(&regtest), None);
+}
+
+#[test]
+fn test_is_caught_up() {
+    use nakamoto_common::block::time::MAX_TIP_AGE;
+
+    let network = bitcoin::Network::Regtest;
+    let params = Params::new(network);
+    let tip = BlockHeader {
+        // Just over the maximum tip age, according to our local clock.
+        time: LOCAL_TIME.block_time() - MAX_TIP_AGE - 30 * 60,
+        ..constants::genesis_block(network).header
+    };
+    let cache = BlockCache::from(store::Memory::new(NonEmpty::new(tip)), params, &[]).unwrap();
+    let mut clock = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+
+    assert!(!cache.is_caught_up(&clock));
+
+    // Our peers consistently report that our clock is an hour ahead.
+    for i in 0..4 {
+        clock.record_offset(([127, 0, 0, i], 8333).into(), -60 * 60);
+    }
+    assert_eq!(clock.local_time(), LOCAL_TIME);
+    assert!(cache.is_caught_up(&clock));
+}
+
+#[test]
+fn test_genesis_validation() {
+    use crate::block::validator::HeaderValidator;
+
+    let clock = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    // A custom network, with its own genesis.
+    let mut custom = BlockHeader {
+        time: 1600000000,
+        bits: 0x207fffff,
+        ..constants::genesis_block(bitcoin::Network::Regtest).header
+    };
+    block::solve(&mut custom);
+
+    let networks = vec![
+        (
+            constants::genesis_block(bitcoin::Network::Bitcoin).header,
+            Params::new(bitcoin::Network::Bitcoin),
+        ),
+        (
+            constants::genesis_block(bitcoin::Network::Testnet).header,
+            Params::new(bitcoin::Network::Testnet),
+        ),
+        (
+            custom,
+            Params {
+                pow_target_spacing: 60,
+                ..Params::new(bitcoin::Network::Regtest)
+            },
+        ),
+    ];
+
+    for (genesis, params) in networks {
+        let hash = genesis.block_hash();
+        let checkpoints = &[(0, hash)];
+        let mut cache = BlockCache::from(
+            store::Memory::new(NonEmpty::new(genesis)),
+            params.clone(),
+            checkpoints,
+        )
+        .unwrap();
+
+        assert!(cache.is_genesis(&hash));
+        assert!(!cache.is_genesis(&genesis.prev_blockhash));
+
+        // Importing the genesis doesn't trip any validation.
+        assert!(matches!(
+            cache.import_block(genesis, &clock),
+            Err(Error::DuplicateBlock(h)) if h == hash
+        ));
+        assert_eq!(
+            cache
+                .bulk_validate_and_import(vec![genesis], &clock)
+                .unwrap(),
+            ImportResult::TipUnchanged
+        );
+        assert_eq!(cache.tip(), (hash, genesis));
+
+        let mut validator = HeaderValidator::new(genesis, params.clone(), checkpoints);
+        assert!(validator.is_genesis(&hash));
+        assert!(validator.validate(&genesis, &clock).is_ok());
+        assert_eq!(validator.push(genesis, &clock).unwrap(), 0);
+        assert_eq!(validator.tip(), (0, &genesis));
+
+        let validator = HeaderValidator::from_tree(&cache, params, checkpoints);
+        assert!(validator.is_genesis(&hash));
+        assert!(validator.validate(&genesis, &clock).is_ok());
+    }
+
+    // The model cache only considers branches rooted at its own genesis.
+    let model = model::Cache::new(custom);
+    assert!(model.is_genesis(&custom.block_hash()));
+    assert!(!model.is_genesis(
+        &constants::genesis_block(bitcoin::Network::Regtest)
+            .header
+            .block_hash()
+    ));
+}
+
+#[test]
+fn test_self_checkpoints() {
+    let network = bitcoin::Network::Regtest;
+    let params = Params::new(network);
+    let clock = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let genesis = constants::genesis_block(network).header;
+    let config = ChainConfig {
+        checkpoint_interval: Some(10),
+        max_reorg_depth: Some(20),
+        ..ChainConfig::default()
+    };
+    let mut cache = BlockCache::from(
+        store::Memory::new(NonEmpty::new(genesis)),
+        params.clone(),
+        &[],
+    )
+    .unwrap()
+    .with_config(config)
+    .unwrap();
+
+    let mut headers = Vec::new();
+    let mut tip = genesis;
+    for _ in 0..75 {
+        let mut header = BlockHeader {
+            prev_blockhash: tip.block_hash(),
+            time: tip.time + 600,
+            ..tip
+        };
+        block::solve(&mut header);
+        headers.push(header);
+        tip = header;
+    }
+
+    // Nothing is buried deep enough yet.
+    cache
+        .import_blocks(headers[..29].iter().cloned(), &clock)
+        .unwrap();
+    assert!(cache.export_checkpoints().is_empty());
+
+    cache
+        .import_blocks(headers[29..].iter().cloned(), &clock)
+        .unwrap();
+    assert_eq!(cache.height(), 75);
+
+    // Blocks buried under the re-org depth, at every interval.
+    let checkpoints = cache.export_checkpoints();
+    assert_eq!(
+        checkpoints.iter().map(|c| c.height).collect::<Vec<_>>(),
+        vec![10, 20, 30, 40, 50]
+    );
+    for checkpoint in &checkpoints {
+        let (height, _) = cache.get_block(&checkpoint.hash).unwrap();
+        let work = cache
+            .iter()
+            .take(height as usize + 1)
+            .fold(Work::default(), |work, (_, h)| work + h.work());
+
+        assert_eq!(height, checkpoint.height);
+        assert_eq!(work, checkpoint.work);
+    }
+
+    // On restart, the checkpoints are loaded from the store, and the chain is validated
+    // against them.
+    let restarted = BlockCache::from(cache.store.clone(), params.clone(), &[]).unwrap();
+    assert_eq!(restarted.export_checkpoints(), checkpoints);
+
+    // Only the blocks above the last stored checkpoint are checkpointed again.
+    let mut store = cache.store.clone();
+    store.rollback(45).unwrap();
+    assert_eq!(store.checkpoints().unwrap().len(), 4);
+    store.put(headers[45..].iter().cloned()).unwrap();
+
+    let restarted = BlockCache::from(store, params.clone(), &[])
+        .unwrap()
+        .with_config(config)
+        .unwrap();
+    assert_eq!(restarted.export_checkpoints(), checkpoints);
+    assert_eq!(
+        restarted.store.checkpoints().unwrap().len(),
+        checkpoints.len()
+    );
+
+    // They are generated from the stored chain if there are none.
+    let restarted = BlockCache::from(
+        store::Memory::new(NonEmpty::from((genesis, headers.clone()))),
+        params.clone(),
+        &[],
+    )
+    .unwrap()
+    .with_config(config)
+    .unwrap();
+    assert_eq!(restarted.export_checkpoints(), checkpoints);
+
+    // A stored chain that doesn't match its checkpoints is rejected.
+    let mut store = cache.store.clone();
+    store.rollback(30).unwrap();
+    store
+        .put_checkpoint((40, checkpoints[0].hash, checkpoints[0].work))
+        .unwrap();
+    store.put(headers[30..].iter().cloned()).unwrap();
+
+    assert!(matches!(
+        BlockCache::from(store, params.clone(), &[]),
+        Err(Error::InvalidBlockHash(hash, 40)) if hash == checkpoints[0].hash
+    ));
+
+    // They can be used as checkpoints for the chain they were taken from.
+    let anchors = checkpoints
+        .iter()
+        .map(|c| (c.height, c.hash))
+        .collect::<Vec<_>>();
+    let mut validated =
+        BlockCache::from(store::Memory::new(NonEmpty::new(genesis)), params, &anchors).unwrap();
+    validated
+        .import_blocks(headers.iter().cloned(), &clock)
+        .unwrap();
+    assert_eq!(validated.tip(), cache.tip());
+
+    // No checkpoints are generated by default.
+    assert!(validated.export_checkpoints().is_empty());
+}
+
+#[test]
+fn test_forged_genesis_rejected() {
+    let network = bitcoin::Network::Regtest;
+    let params = Params::new(network);
+    let clock = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let genesis = constants::genesis_block(network).header;
+    let mut cache =
+        BlockCache::from(store::Memory::new(NonEmpty::new(genesis)), params, &[]).unwrap();
+
+    // A header at the genesis position, ie. without a parent, but with different contents.
+    let mut forged = BlockHeader {
+        time: genesis.time + 1,
+        ..genesis
+    };
+    block::solve(&mut forged);
+    assert_eq!(forged.prev_blockhash, BlockHash::default());
+
+    let mut child = BlockHeader {
+        prev_blockhash: forged.block_hash(),
+        time: forged.time + 600,
+        ..forged
+    };
+    block::solve(&mut child);
+
+    assert!(matches!(
+        cache.import_blocks(vec![forged, child].into_iter(), &clock),
+        Err(Error::BlockImportAborted(err, 0, 0))
+            if matches!(*err, Error::InvalidBlockHash(h, 0) if h == forged.block_hash())
+    ));
+    assert!(matches!(
+        cache.bulk_validate_and_import(vec![child, forged], &clock),
+        Err(Error::InvalidBlockHash(h, 0)) if h == forged.block_hash()
+    ));
+
+    // The real genesis is intact, and the forged headers weren't stored anywhere.
+    assert_eq!(cache.genesis(), &genesis);
+    assert_eq!(cache.tip(), (genesis.block_hash(), genesis));
+    assert_eq!(cache.store.get(0).unwrap(), genesis);
+    assert!(!cache.is_known(&forged.block_hash()));
+    assert!(!cache.is_known(&child.block_hash()));
+}
+
+#[test]
+fn test_cache_prune_to_checkpoint() {
+    let network = bitcoin::Network::Regtest;
+    let clock = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let genesis = constants::genesis_block(network).header;
+    let mut headers = vec![genesis];
+
+    for _ in 0..2200 {
+        let prev = *headers.last().unwrap();
+        let mut header = BlockHeader {
+            prev_blockhash: prev.block_hash(),
+            time: prev.time + 600,
+            ..prev
+        };
+        block::solve(&mut header);
+        headers.push(header);
+    }
+    let hash = |height: usize| headers[height].block_hash();
+    let checkpoints = &[
+        (20, hash(20)),
+        (2100, hash(2100)),
+        (3000, BlockHash::default()),
+    ];
+    let mut cache = BlockCache::from(
+        store::Memory::new(NonEmpty::from_vec(headers[..=2150].to_vec()).unwrap()),
+        Params::new(network),
+        checkpoints,
+    )
+    .unwrap();
+    let work = cache.chain_work();
+
+    // The highest checkpoint on the active chain is at height 2100. The difficulty
+    // adjustment interval leading up to it is kept, ie. the blocks from height 85.
+    assert_eq!(cache.prune_to_checkpoint(), 84);
+    assert_eq!(cache.prune_to_checkpoint(), 0);
+    assert_eq!(cache.height(), 2150);
+    assert_eq!(cache.chain_work(), work);
+
+    // Below, blocks are no longer in memory, but can be read from the store.
+    assert_eq!(cache.get_block(&hash(30)), None);
+    assert_eq!(cache.get_block_by_height(30), None);
+    assert!(!cache.contains(&hash(84)));
+    assert_eq!(cache.read_block_by_height(30).unwrap(), Some(headers[30]));
+    assert_eq!(
+        cache.read_block_by_height(1000).unwrap(),
+        Some(headers[1000])
+    );
+    assert_eq!(cache.read_block_by_height(2151).unwrap(), None);
+    assert_eq!(cache.snapshot().get_block_by_height(30), None);
+    assert_eq!(cache.snapshot().height(), 2150);
+
+    // The genesis, the kept blocks and the checkpoint are found.
+    assert_eq!(cache.get_block(&genesis.block_hash()), Some((0, &genesis)));
+    assert_eq!(cache.get_block(&hash(85)), Some((85, &headers[85])));
+    assert_eq!(cache.get_block(&hash(2100)), Some((2100, &headers[2100])));
+    assert_eq!(cache.iter().next(), Some((85, headers[85])));
+
+    // Pruned headers aren't served.
+    assert!(cache
+        .locate_headers(&[genesis.block_hash()], BlockHash::default(), 10)
+        .is_empty());
+    assert_eq!(
+        cache.locate_headers(&[hash(2100)], BlockHash::default(), 1),
+        vec![headers[2101]]
+    );
+
+    // Blocks are only found by time once the headers needed for their median time past
+    // are in memory.
+    let time = |height: usize| LocalTime::from_block_time(headers[height].time);
+    assert_eq!(cache.first_header_after_time(time(30)), None);
+    assert_eq!(cache.first_header_after_time(time(90)), None);
+    assert_eq!(
+        cache.first_header_after_time(time(91)),
+        Some((96, &headers[96]))
+    );
+
+    // The chain can still be extended.
+    cache
+        .import_blocks(headers[2151..].iter().cloned(), &clock)
+        .unwrap();
+    assert_eq!(cache.height(), 2200);
+    assert_eq!(cache.get_block(&hash(2200)), Some((2200, &headers[2200])));
+}
+
+#[test]
+fn test_cache_from_checkpoint() {
+    let network = bitcoin::Network::Regtest;
+    let params = Params::new(network);
+    let clock = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let genesis = constants::genesis_block(network).header;
+    let mut headers = vec![genesis];
+
+    for _ in 0..2100 {
+        let prev = *headers.last().unwrap();
+        let mut header = BlockHeader {
+            prev_blockhash: prev.block_hash(),
+            time: prev.time + 600,
+            ..prev
+        };
+        block::solve(&mut header);
+        headers.push(header);
+    }
+    let hash = |height: usize| headers[height].block_hash();
+    let work = |range: std::ops::RangeInclusive<usize>| {
+        headers[range]
+            .iter()
+            .fold(Work::default(), |work, h| work + h.work())
+    };
+
+    // The checkpoint must start a difficulty adjustment interval.
+    assert!(matches!(
+        BlockCache::from_checkpoint(
+            store::Memory::new(NonEmpty::new(headers[2000])),
+            2000,
+            headers[2000],
+            &headers[1989..2000],
+            work(0..=2000),
+            params.clone(),
+        ),
+        Err(Error::InvalidBlockHeight(2000))
+    ));
+    // The store must start with the checkpoint.
+    assert!(matches!(
+        BlockCache::from_checkpoint(
+            store::Memory::new(NonEmpty::new(genesis)),
+            2016,
+            headers[2016],
+            &headers[2005..2016],
+            work(0..=2016),
+            params.clone(),
+        ),
+        Err(Error::InvalidBlockHash(h, 2016)) if h == hash(2016)
+    ));
+    // The headers needed to compute the median time past above it must be given.
+    assert!(matches!(
+        BlockCache::from_checkpoint(
+            store::Memory::new(NonEmpty::new(headers[2016])),
+            2016,
+            headers[2016],
+            &headers[2006..2016],
+            work(0..=2016),
+            params.clone(),
+        ),
+        Err(Error::BlockMissing(h)) if h == hash(2005)
+    ));
+    assert!(matches!(
+        BlockCache::from_checkpoint(
+            store::Memory::new(NonEmpty::new(headers[2016])),
+            2016,
+            headers[2016],
+            &headers[2004..2015],
+            work(0..=2016),
+            params.clone(),
+        ),
+        Err(Error::DisjointChains(h)) if h == hash(2016)
+    ));
+
+    // Headers right above the checkpoint are validated against the median time past of
+    // the headers preceding it.
+    let mut cache = BlockCache::from_checkpoint(
+        store::Memory::new(NonEmpty::new(headers[2016])),
+        2016,
+        headers[2016],
+        &headers[2005..2016],
+        work(0..=2016),
+        params.clone(),
+    )
+    .unwrap();
+    let next = |time: BlockTime| {
+        let mut header = BlockHeader {
+            prev_blockhash: hash(2016),
+            time,
+            ..headers[2016]
+        };
+        block::solve(&mut header);
+        header
+    };
+    assert_eq!(cache.median_time_past(2017), headers[2011].time);
+    assert!(matches!(
+        cache.import_block(next(headers[2011].time), &clock),
+        Err(Error::InvalidBlockTime(_, _))
+    ));
+    cache
+        .import_block(next(headers[2012].time), &clock)
+        .unwrap();
+    assert_eq!(cache.height(), 2017);
+
+    let mut cache = BlockCache::from_checkpoint(
+        store::Memory::new(NonEmpty::new(headers[2016])),
+        2016,
+        headers[2016],
+        &headers[2005..2016],
+        work(0..=2016),
+        params.clone(),
+    )
+    .unwrap()
+    .with_config(ChainConfig {
+        checkpoint_interval: Some(10),
+        max_reorg_depth: Some(20),
+        ..ChainConfig::default()
+    })
+    .unwrap();
+
+    // The checkpoint is the root of the chain, at its own height.
+    assert_eq!(cache.height(), 2016);
+    assert_eq!(cache.tip(), (hash(2016), headers[2016]));
+    assert_eq!(cache.genesis(), &headers[2016]);
+    assert_eq!(cache.get_block_by_height(2015), None);
+    assert_eq!(cache.get_block_by_height(0), None);
+    assert_eq!(cache.locator_hashes(2016), vec![hash(2016)]);
+
+    // It syncs forward from the checkpoint.
+    assert_eq!(
+        cache
+            .import_blocks(headers[2017..].iter().cloned(), &clock)
+            .unwrap(),
+        ImportResult::TipChanged(hash(2100), 2100, vec![])
+    );
+    assert_eq!(cache.height(), 2100);
+    assert_eq!(cache.get_block(&hash(2050)), Some((2050, &headers[2050])));
+    assert_eq!(cache.get_block_by_height(2017), Some(&headers[2017]));
+    assert_eq!(cache.iter().next(), Some((2016, headers[2016])));
+    assert_eq!(cache.chain_work(), work(0..=2100));
+    assert_eq!(cache.locator_hashes(2100).last(), Some(&hash(2016)));
+    assert_eq!(
+        cache.locate_headers(&[hash(2016)], BlockHash::default(), 2),
+        vec![headers[2017], headers[2018]]
+    );
+    assert_eq!(
+        cache.read_block_by_height(2050).unwrap(),
+        Some(headers[2050])
+    );
+    assert_eq!(cache.read_block_by_height(2000).unwrap(), None);
+
+    // Blocks are only found by time above the checkpoint.
+    let time = |height: usize| LocalTime::from_block_time(headers[height].time);
+    assert_eq!(cache.first_header_after_time(time(2000)), None);
+    assert_eq!(cache.first_header_after_time(time(2011)), None);
+    assert_eq!(
+        cache.first_header_after_time(time(2012)),
+        Some((2017, &headers[2017]))
+    );
+
+    // The checkpoint can't be imported again, nor replaced.
+    assert!(matches!(
+        cache.import_block(headers[2016], &clock),
+        Err(Error::DuplicateBlock(_))
+    ));
+
+    // Checkpoints are generated above the root, with the work of the full chain.
+    let checkpoints = cache.export_checkpoints();
+    assert_eq!(
+        checkpoints.iter().map(|c| c.height).collect::<Vec<_>>(),
+        vec![2020, 2030, 2040, 2050, 2060, 2070, 2080]
+    );
+    assert_eq!(checkpoints[0].hash, hash(2020));
+    assert_eq!(checkpoints[0].work, work(0..=2020));
+
+    // On restart, the stored chain is loaded above the checkpoint, and validated against
+    // the stored checkpoints.
+    let restarted = BlockCache::from_checkpoint(
+        cache.store.clone(),
+        2016,
+        headers[2016],
+        &headers[2005..2016],
+        work(0..=2016),
+        params,
+    )
+    .unwrap();
+    assert_eq!(restarted.tip(), cache.tip());
+    assert_eq!(restarted.height(), 2100);
+    assert_eq!(restarted.export_checkpoints(), checkpoints);
+}
+
+#[test]
+fn test_cache_load_progress() {
+    use super::LOAD_PROGRESS_INTERVAL;
+
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let total = LOAD_PROGRESS_INTERVAL * 5 + LOAD_PROGRESS_INTERVAL / 2;
+    let mut headers = NonEmpty::new(genesis);
+
+    for _ in 0..total {
+        let prev = *headers.last();
+        headers.push(BlockHeader {
+            prev_blockhash: prev.block_hash(),
+            time: prev.time + 600,
+            ..prev
+        });
+    }
+    let mut reports = Vec::new();
+    let cache = BlockCache::load(
+        store::Memory::new(headers),
+        Params::new(network),
+        &[],
+        |loaded, total| reports.push((loaded, total)),
+    )
+    .unwrap();
+
+    assert_eq!(cache.height(), total);
+    assert_eq!(reports.len(), 6);
+    assert!(reports.windows(2).all(|w| w[0].0 < w[1].0));
+    assert!(reports.iter().all(|(_, t)| *t == total));
+    assert_eq!(reports.first(), Some(&(LOAD_PROGRESS_INTERVAL, total)));
+    assert_eq!(reports.last(), Some(&(total, total)));
+
+    // An empty store reports completion right away.
+    let mut reports = Vec::new();
+    BlockCache::load(
+        store::Memory::new(NonEmpty::new(genesis)),
+        Params::new(network),
+        &[],
+        |loaded, total| reports.push((loaded, total)),
+    )
+    .unwrap();
+    assert_eq!(reports, vec![(0, 0)]);
+}
+
+#[test]
+fn test_cache_get_block_hash() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let mut headers = vec![genesis];
+
+    for _ in 0..10 {
+        let prev = *headers.last().unwrap();
+        let mut header = BlockHeader {
+            prev_blockhash: prev.block_hash(),
+            time: prev.time + 600,
+            ..prev
+        };
+        block::solve(&mut header);
+        headers.push(header);
+    }
+    let cache = BlockCache::from(
+        store::Memory::new(NonEmpty::from_vec(headers.clone()).unwrap()),
+        Params::new(network),
+        &[],
+    )
+    .unwrap();
+
+    for height in [0, 1, 5, 10].iter() {
+        let expected = headers[*height as usize].block_hash();
+
+        assert_eq!(cache.get_block_hash(*height), Some(expected));
+        assert_eq!(BlockTree::get_block_hash(&cache, *height), Some(expected));
+    }
+    assert_eq!(cache.get_block_hash(11), None);
+}
+
+#[test]
+fn test_cache_shared_readers() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let cache = Arc::new(RwLock::new(BlockCache::from(store, params, &[]).unwrap()));
+    let done = Arc::new(AtomicBool::new(false));
+    let g = &mut rand::thread_rng();
+
+    let mut tree = Tree::new(genesis);
+    let mut headers = Vec::new();
+    for _ in 0..64 {
+        tree = tree.next(g);
+        headers.push(tree.block());
+    }
+
+    let readers = (0..4)
+        .map(|_| {
+            let cache = cache.clone();
+            let done = done.clone();
+
+            std::thread::spawn(move || {
+                let mut last = 0;
+
+                while !done.load(Ordering::SeqCst) {
+                    let cache = cache.read().unwrap();
+                    let (tip, _) = cache.tip();
+                    let height = cache.height();
+
+                    // Readers always see a consistent chain, that only grows.
+                    assert!(height >= last);
+                    assert_eq!(cache.get_block_hash(height), Some(tip));
+                    assert_eq!(cache.get_block(&tip).map(|(h, _)| h), Some(height));
+
+                    last = height;
+                }
+                last
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let writer = {
+        let cache = cache.clone();
+        let done = done.clone();
+
+        std::thread::spawn(move || {
+            let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+
+            for header in headers {
+                cache
+                    .write()
+                    .unwrap()
+                    .import_blocks(iter::once(header), &ctx)
+                    .unwrap();
+            }
+            done.store(true, Ordering::SeqCst);
+        })
+    };
+
+    writer.join().unwrap();
+
+    for reader in readers {
+        assert!(reader.join().unwrap() <= 64);
+    }
+    assert_eq!(cache.read().unwrap().height(), 64);
+}
+
+#[test]
+fn test_cache_unusual_timestamp() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let mut cache = BlockCache::from(store, params, &[])
+        .unwrap()
+        .with_config(ChainConfig {
+            max_timestamp_drift: Some(60 * 30),
+            ..ChainConfig::default()
+        })
+        .unwrap();
+    let header = |prev: &BlockHeader, time: BlockTime| {
+        let mut header = BlockHeader {
+            version: 1,
+            prev_blockhash: prev.block_hash(),
+            merkle_root: TxMerkleNode::default(),
+            time,
+            bits: prev.bits,
+            nonce: 0,
+        };
+        block::solve(&mut header);
+        header
+    };
+
+    // Blocks ten minutes apart are unremarkable.
+    let mut tip = genesis;
+    for _ in 0..12 {
+        tip = header(&tip, tip.time + 600);
+        cache.import_block(tip, &ctx).unwrap();
+    }
+    assert_eq!(cache.events().len(), 0);
+
+    // A block forty minutes earlier than its parent is accepted, since it's still after
+    // the median time past, but is reported.
+    let early = header(&tip, tip.time - 2400);
+    assert!(matches!(
+        cache.import_block(early, &ctx),
+        Ok(ImportResult::TipChanged(_, 13, _))
+    ));
+    assert_eq!(
+        cache.events(),
+        vec![Event::UnusualTimestamp {
+            height: 13,
+            drift: -2400
+        }]
+    );
+
+    let next = header(&early, early.time + 600);
+    cache.import_block(next, &ctx).unwrap();
+    assert_eq!(cache.events().len(), 0);
+}
+
+#[test]
+fn test_cache_diff() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let cache = |headers: &[BlockHeader]| {
+        let store = store::Memory::new(NonEmpty::new(genesis));
+        let mut cache = BlockCache::from(store, params.clone(), &[]).unwrap();
+
+        cache.import_blocks(headers.iter().cloned(), &ctx).unwrap();
+        cache
+    };
+    let chain = |prev: BlockHeader, len: usize, nonce: u32| {
+        let mut prev = prev;
+        (0..len)
+            .map(|_| {
+                let mut header = BlockHeader {
+                    version: 1,
+                    prev_blockhash: prev.block_hash(),
+                    merkle_root: TxMerkleNode::default(),
+                    time: prev.time + 600,
+                    bits: prev.bits,
+                    nonce,
+                };
+                block::solve(&mut header);
+                prev = header;
+                header
+            })
+            .collect::<Vec<_>>()
+    };
+    let headers = chain(genesis, 16, 0);
+    let source = cache(&headers);
+
+    let diff = source.export_diff(10).unwrap();
+    assert_eq!(diff.base_height, 10);
+    assert_eq!(diff.base_hash, headers[9].block_hash());
+    assert_eq!(diff.headers, headers[10..].to_vec());
+    assert!(source.export_diff(17).is_none());
+
+    // The diff survives a round-trip through its encoding.
+    let encoded = bitcoin::consensus::serialize(&diff);
+    let decoded: ChainDiff = bitcoin::consensus::deserialize(&encoded).unwrap();
+    assert_eq!(decoded, diff);
+
+    // It applies onto a chain that ends at its base.
+    let mut target = cache(&headers[..10]);
+    assert!(matches!(
+        target.import_diff(decoded, &ctx),
+        Ok(ImportResult::TipChanged(_, 16, _))
+    ));
+    assert_eq!(target.tip(), source.tip());
+
+    // But not onto a chain with another block at the base height.
+    let mut fork = cache(&chain(genesis, 10, 1));
+    assert!(matches!(
+        fork.import_diff(diff.clone(), &ctx),
+        Err(Error::DiffBaseMismatch(hash, 10)) if hash == diff.base_hash
+    ));
+    assert_eq!(fork.height(), 10);
+
+    // Nor onto a chain that is too short.
+    let mut short = cache(&headers[..8]);
+    assert!(matches!(
+        short.import_diff(diff, &ctx),
+        Err(Error::DiffBaseMismatch(_, 10))
+    ));
+    assert_eq!(short.height(), 8);
+}
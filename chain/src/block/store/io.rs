@@ -4,12 +4,15 @@ use std::io::{self, Read, Seek, Write};
 use std::iter;
 use std::marker::PhantomData;
 use std::mem;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use bitcoin::consensus::encode::{Decodable, Encodable};
 
-use nakamoto_common::block::store::{Error, Store};
-use nakamoto_common::block::Height;
+use nakamoto_common::block::store::{Checkpoint, Error, Store};
+use nakamoto_common::block::{BlockHash, Height, Work};
+
+/// Size of an encoded checkpoint: its height, block hash and work.
+const CHECKPOINT_SIZE: usize = 8 + 32 + 32;
 
 /// Append a block to the end of the stream.
 fn put<H: Sized + Encodable, S: Seek + Write, I: Iterator<Item = H>>(
@@ -68,33 +71,68 @@ impl<H: Decodable> Iterator for Iter<H> {
     }
 }
 
-/// A `Store` backed by a single file.
+/// Get the path of the checkpoints file kept alongside the headers file at the given path.
+fn checkpoints_path(path: &Path) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(".checkpoints");
+    path.into()
+}
+
+/// A `Store` backed by a single file for headers. Checkpoints are kept in a second file,
+/// created alongside it once the first checkpoint is stored.
 #[derive(Debug)]
 pub struct File<H> {
     file: fs::File,
+    checkpoints: PathBuf,
     genesis: H,
 }
 
 impl<H> File<H> {
     /// Open a new file store from the given path and genesis header.
     pub fn open<P: AsRef<Path>>(path: P, genesis: H) -> io::Result<Self> {
+        let path = path.as_ref();
+
         fs::OpenOptions::new()
             .create(true)
             .read(true)
             .append(true)
             .open(path)
-            .map(|file| Self { file, genesis })
+            .map(|file| Self {
+                file,
+                checkpoints: checkpoints_path(path),
+                genesis,
+            })
     }
 
     /// Create a new file store at the given path, with the provided genesis header.
+    /// Checkpoints left over from a previous store at that path are discarded.
     pub fn create<P: AsRef<Path>>(path: P, genesis: H) -> Result<Self, Error> {
+        let path = path.as_ref();
         let file = fs::OpenOptions::new()
             .create_new(true)
             .read(true)
             .append(true)
             .open(path)?;
+        let checkpoints = checkpoints_path(path);
+
+        match fs::remove_file(&checkpoints) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+            _ => {}
+        }
+
+        Ok(Self {
+            file,
+            checkpoints,
+            genesis,
+        })
+    }
 
-        Ok(Self { file, genesis })
+    /// Read the checkpoints file. Returns nothing if it doesn't exist yet.
+    fn read_checkpoints(&self) -> Result<Vec<u8>, Error> {
+        match fs::read(&self.checkpoints) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+            result => result.map_err(Error::from),
+        }
     }
 }
 
@@ -123,11 +161,56 @@ impl<H: 'static + Copy + Encodable + Decodable> Store for File<H> {
         }
     }
 
-    /// Rollback the chain to the given height. Behavior is undefined if the given
-    /// height is not contained in the store.
+    /// Append a checkpoint to the end of the checkpoints file.
+    fn put_checkpoint(&mut self, (height, hash, work): Checkpoint) -> Result<(), Error> {
+        let mut buf = Vec::with_capacity(CHECKPOINT_SIZE);
+
+        height.consensus_encode(&mut buf)?;
+        hash.consensus_encode(&mut buf)?;
+        work.consensus_encode(&mut buf)?;
+
+        fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.checkpoints)?
+            .write_all(&buf)
+            .map_err(Error::from)
+    }
+
+    /// Read all checkpoints from the checkpoints file.
+    fn checkpoints(&self) -> Result<Vec<Checkpoint>, Error> {
+        let buf = self.read_checkpoints()?;
+
+        if buf.len() % CHECKPOINT_SIZE != 0 {
+            return Err(Error::Corruption);
+        }
+        buf.chunks(CHECKPOINT_SIZE)
+            .map(|mut chunk| {
+                let height = Height::consensus_decode(&mut chunk)?;
+                let hash = BlockHash::consensus_decode(&mut chunk)?;
+                let work = Work::consensus_decode(&mut chunk)?;
+
+                Ok((height, hash, work))
+            })
+            .collect()
+    }
+
+    /// Rollback the chain to the given height, along with the checkpoints. Behavior is
+    /// undefined if the given height is not contained in the store.
     fn rollback(&mut self, height: Height) -> Result<(), Error> {
         let size = mem::size_of::<H>();
-
+        let checkpoints = self.checkpoints()?;
+        let kept = checkpoints
+            .iter()
+            .take_while(|(h, _, _)| *h <= height)
+            .count();
+
+        if kept < checkpoints.len() {
+            fs::OpenOptions::new()
+                .write(true)
+                .open(&self.checkpoints)?
+                .set_len((kept * CHECKPOINT_SIZE) as u64)?;
+        }
         self.file
             .set_len((height) * size as u64)
             .map_err(Error::from)
@@ -172,6 +255,7 @@ impl<H: 'static + Copy + Encodable + Decodable> Store for File<H> {
 
     /// Check the file store integrity.
     fn check(&self) -> Result<(), Error> {
+        self.checkpoints()?;
         self.len().map(|_| ())
     }
 
@@ -188,6 +272,15 @@ impl<H: 'static + Copy + Encodable + Decodable> Store for File<H> {
             self.file.set_len(len - extraneous as u64)?;
         }
 
+        let len = self.read_checkpoints()?.len();
+        let extraneous = len % CHECKPOINT_SIZE;
+        if extraneous != 0 {
+            fs::OpenOptions::new()
+                .write(true)
+                .open(&self.checkpoints)?
+                .set_len((len - extraneous) as u64)?;
+        }
+
         Ok(())
     }
 }
@@ -196,7 +289,9 @@ impl<H: 'static + Copy + Encodable + Decodable> Store for File<H> {
 mod test {
     use std::{io, iter};
 
-    use super::{Error, File, Height, Store};
+    use bitcoin_hashes::Hash;
+
+    use super::{BlockHash, Error, File, Height, Store, Work};
     use crate::block::BlockHeader;
 
     const HEADER_SIZE: usize = 80;
@@ -340,6 +435,51 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_checkpoints() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("headers.db");
+        let genesis = BlockHeader {
+            version: 1,
+            prev_blockhash: Default::default(),
+            merkle_root: Default::default(),
+            bits: 0x2ffffff,
+            time: 39123818,
+            nonce: 0,
+        };
+        let mut store = File::open(&path, genesis).unwrap();
+
+        assert!(store.checkpoints().unwrap().is_empty());
+
+        let checkpoints = (1..=3)
+            .map(|i| {
+                (
+                    i * 10,
+                    BlockHash::hash(&[i as u8]),
+                    Work::from_u64(i).unwrap(),
+                )
+            })
+            .collect::<Vec<_>>();
+        for checkpoint in &checkpoints {
+            store.put_checkpoint(*checkpoint).unwrap();
+        }
+        assert_eq!(store.checkpoints().unwrap(), checkpoints);
+
+        // Checkpoints above the rollback height are dropped.
+        store.rollback(25).unwrap();
+        assert_eq!(store.checkpoints().unwrap(), checkpoints[..2]);
+
+        // They persist across restarts, but not across re-creations.
+        let store = File::open(&path, genesis).unwrap();
+        assert_eq!(store.checkpoints().unwrap(), checkpoints[..2]);
+
+        drop(store);
+        std::fs::remove_file(&path).unwrap();
+
+        let store = File::create(&path, genesis).unwrap();
+        assert!(store.checkpoints().unwrap().is_empty());
+    }
+
     #[test]
     fn test_corrupt_file() {
         let mut store = store("headers.db");
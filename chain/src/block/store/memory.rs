@@ -1,31 +1,31 @@
 //! Ephemeral storage backend for blocks.
 use nonempty::NonEmpty;
 
-use nakamoto_common::block::store::{Error, Genesis, Store};
+use nakamoto_common::block::store::{Checkpoint, Error, Genesis, Store};
 use nakamoto_common::block::Height;
 use nakamoto_common::network::Network;
 
-/// In-memory block store.
+/// In-memory block store, along with its checkpoints.
 #[derive(Debug, Clone)]
-pub struct Memory<H>(NonEmpty<H>);
+pub struct Memory<H>(NonEmpty<H>, Vec<Checkpoint>);
 
 impl<H> Memory<H> {
     /// Create a new in-memory block store.
     pub fn new(chain: NonEmpty<H>) -> Self {
-        Self(chain)
+        Self(chain, Vec::new())
     }
 }
 
 impl<H: Default> Default for Memory<H> {
     fn default() -> Self {
-        Self(NonEmpty::new(H::default()))
+        Self(NonEmpty::new(H::default()), Vec::new())
     }
 }
 
 impl<H: Genesis> Memory<H> {
     /// Create a memory store with only the genesis.
     pub fn genesis(network: Network) -> Self {
-        Self(NonEmpty::new(H::genesis(network)))
+        Self(NonEmpty::new(H::genesis(network)), Vec::new())
     }
 }
 
@@ -54,12 +54,25 @@ impl<H: 'static + Copy + Clone + Genesis> Store for Memory<H> {
         }
     }
 
+    /// Store a checkpoint of the chain.
+    fn put_checkpoint(&mut self, checkpoint: Checkpoint) -> Result<(), Error> {
+        self.1.push(checkpoint);
+        Ok(())
+    }
+
+    /// Get the stored checkpoints, lowest first.
+    fn checkpoints(&self) -> Result<Vec<Checkpoint>, Error> {
+        Ok(self.1.clone())
+    }
+
     /// Rollback the chain to the given height.
     fn rollback(&mut self, height: Height) -> Result<(), Error> {
         match height {
             0 => self.0.tail.clear(),
             h => self.0.tail.truncate(h as usize),
         }
+        self.1.retain(|(h, _, _)| *h <= height);
+
         Ok(())
     }
 
@@ -0,0 +1,274 @@
+//! Block header validation, independent of header storage.
+//!
+//! Headers are validated against a [`Context`], which gives access to the headers
+//! preceding them. The [`BlockCache`] is one such context. The [`HeaderValidator`] is
+//! another: it only keeps the headers needed to validate the next one, and can be used
+//! to validate a stream of headers, eg. read from a file, without storing them.
+//!
+//! [`BlockCache`]: super::cache::BlockCache
+//!
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, VecDeque};
+
+use bitcoin::blockdata::block::BlockHeader;
+use bitcoin::consensus::params::Params;
+use bitcoin::hash_types::BlockHash;
+
+use nakamoto_common::block::tree::{BlockTree, Error};
+use nakamoto_common::block::{
+    self,
+    time::{self, Clock},
+    Bits, BlockTime, Height,
+};
+
+/// Access to the headers of the chain being validated.
+pub trait Context {
+    /// Get the header at the given height, if available.
+    fn header(&self, height: Height) -> Option<&BlockHeader>;
+}
+
+/// Validate a block header as the successor of the given tip. This performs full header
+/// validation, ie. proof-of-work, difficulty target, checkpoint and timestamp checks.
+pub fn validate<C: Context>(
+    ctx: &C,
+    tip_height: Height,
+    tip: &BlockHeader,
+    header: &BlockHeader,
+    params: &Params,
+    checkpoints: &BTreeMap<Height, BlockHash>,
+    clock: &impl Clock,
+) -> Result<(), Error> {
+    let compact_target =
+        if params.allow_min_difficulty_blocks && !is_adjustment_height(tip_height + 1, params) {
+            if header.time > tip.time + params.pow_target_spacing as BlockTime * 2 {
+                block::pow_limit_bits(&params.network)
+            } else {
+                next_min_difficulty_target(ctx, tip_height, params)
+            }
+        } else {
+            next_difficulty_target(ctx, tip_height, tip, params)
+        };
+
+    let target = BlockHeader::u256_from_compact_target(compact_target);
+
+    match header.validate_pow(&target) {
+        Err(bitcoin::util::Error::BlockBadProofOfWork) => {
+            return Err(Error::InvalidBlockPoW);
+        }
+        Err(bitcoin::util::Error::BlockBadTarget) => {
+            return Err(Error::InvalidBlockTarget(header.target(), target));
+        }
+        Err(_) => unreachable!(),
+        Ok(_) => {}
+    }
+
+    // Validate against block checkpoints.
+    let height = tip_height + 1;
+
+    if let Some(checkpoint) = checkpoints.get(&height) {
+        let hash = header.block_hash();
+
+        if &hash != checkpoint {
+            return Err(Error::InvalidBlockHash(hash, height));
+        }
+    }
+
+    // A timestamp is accepted as valid if it is greater than the median timestamp of
+    // the previous MEDIAN_TIME_SPAN blocks, and less than the network-adjusted
+    // time + MAX_FUTURE_BLOCK_TIME.
+    if header.time <= median_time_past(ctx, height) {
+        return Err(Error::InvalidBlockTime(header.time, Ordering::Less));
+    }
+    if header.time > clock.block_time() + time::MAX_FUTURE_BLOCK_TIME {
+        return Err(Error::InvalidBlockTime(header.time, Ordering::Greater));
+    }
+
+    Ok(())
+}
+
+/// Get the median time past for the blocks leading up to the given height.
+///
+/// # Errors
+///
+/// Panics if height is `0`.
+///
+pub fn median_time_past<C: Context>(ctx: &C, height: Height) -> BlockTime {
+    assert!(height != 0, "height must be > 0");
+
+    let start = height.saturating_sub(time::MEDIAN_TIME_SPAN);
+    let mut times = (start..height)
+        .filter_map(|h| ctx.header(h))
+        .map(|h| h.time)
+        .collect::<Vec<_>>();
+
+    times.sort_unstable();
+    times[times.len() / 2]
+}
+
+/// Check whether the difficulty target is adjusted at the given height, ie. whether the
+/// block at that height is the first of a difficulty adjustment interval.
+pub(crate) fn is_adjustment_height(height: Height, params: &Params) -> bool {
+    height % params.difficulty_adjustment_interval() == 0
+}
+
+/// Get the difficulty target of the block following the given tip.
+fn next_difficulty_target<C: Context>(
+    ctx: &C,
+    tip_height: Height,
+    tip: &BlockHeader,
+    params: &Params,
+) -> Bits {
+    // Only adjust on set intervals. Otherwise return current target.
+    // Since the height is 0-indexed, we add `1` to check it against the interval.
+    if !is_adjustment_height(tip_height + 1, params) {
+        return BlockHeader::compact_target_from_u256(&tip.target());
+    }
+    let last_adjustment_height =
+        tip_height.saturating_sub(params.difficulty_adjustment_interval() - 1);
+    let last_adjustment_block = ctx
+        .header(last_adjustment_height)
+        .expect("the first block of the adjustment interval must be available");
+
+    block::retarget(last_adjustment_block, tip.time, tip.target(), params)
+}
+
+/// Get the next minimum-difficulty target. Only valid in testnet and regtest networks.
+fn next_min_difficulty_target<C: Context>(ctx: &C, tip_height: Height, params: &Params) -> Bits {
+    assert!(params.allow_min_difficulty_blocks);
+
+    let pow_limit_bits = block::pow_limit_bits(&params.network);
+
+    // The walk stops at the first block of the difficulty adjustment interval at the
+    // latest, so it doesn't need any header below a chain rooted at a checkpoint.
+    for height in (0..=tip_height).rev() {
+        if let Some(header) = ctx.header(height) {
+            if header.bits != pow_limit_bits || is_adjustment_height(height, params) {
+                return header.bits;
+            }
+        } else {
+            break;
+        }
+    }
+    pow_limit_bits
+}
+
+/// Validates a chain of headers one at a time, keeping only the most recent headers.
+#[derive(Debug, Clone)]
+pub struct HeaderValidator {
+    /// The most recent headers of the chain, oldest first. The last header is the tip.
+    window: VecDeque<BlockHeader>,
+    /// Height of the tip.
+    height: Height,
+    /// Hash of the genesis block.
+    genesis: BlockHash,
+    params: Params,
+    checkpoints: BTreeMap<Height, BlockHash>,
+}
+
+impl HeaderValidator {
+    /// Create a validator for the chain starting at the given genesis header.
+    pub fn new(genesis: BlockHeader, params: Params, checkpoints: &[(Height, BlockHash)]) -> Self {
+        Self {
+            window: VecDeque::from(vec![genesis]),
+            height: 0,
+            genesis: genesis.block_hash(),
+            params,
+            checkpoints: checkpoints.iter().cloned().collect(),
+        }
+    }
+
+    /// Create a validator for the headers following the tip of the given block tree.
+    /// This can be used to validate headers before importing them.
+    pub fn from_tree<T: BlockTree>(
+        tree: &T,
+        params: Params,
+        checkpoints: &[(Height, BlockHash)],
+    ) -> Self {
+        let height = tree.height();
+        let start = height.saturating_sub(Self::window_size(&params) as Height - 1);
+        let window = (start..=height)
+            .filter_map(|h| tree.get_block_by_height(h))
+            .cloned()
+            .collect();
+
+        Self {
+            window,
+            height,
+            genesis: tree.genesis().block_hash(),
+            params,
+            checkpoints: checkpoints.iter().cloned().collect(),
+        }
+    }
+
+    /// Get the tip of the validated chain, along with its height.
+    pub fn tip(&self) -> (Height, &BlockHeader) {
+        let tip = self
+            .window
+            .back()
+            .expect("HeaderValidator::tip: there is always at least one header");
+
+        (self.height, tip)
+    }
+
+    /// Check whether a block hash is the hash of the genesis block.
+    pub fn is_genesis(&self, hash: &BlockHash) -> bool {
+        &self.genesis == hash
+    }
+
+    /// Validate a header as the successor of the current tip, without extending the chain.
+    /// The genesis header is always valid, since it starts the chain.
+    pub fn validate(&self, header: &BlockHeader, clock: &impl Clock) -> Result<(), Error> {
+        if self.is_genesis(&header.block_hash()) {
+            return Ok(());
+        }
+        let (height, tip) = self.tip();
+
+        if header.prev_blockhash != tip.block_hash() {
+            return Err(Error::BlockMissing(header.prev_blockhash));
+        }
+        self::validate(
+            self,
+            height,
+            tip,
+            header,
+            &self.params,
+            &self.checkpoints,
+            clock,
+        )
+    }
+
+    /// Validate a header and extend the chain with it. Returns the header's height.
+    ///
+    /// Since the chain starts with the genesis header, pushing it again leaves the chain
+    /// unchanged. This allows streams of headers starting from genesis to be validated.
+    pub fn push(&mut self, header: BlockHeader, clock: &impl Clock) -> Result<Height, Error> {
+        if self.is_genesis(&header.block_hash()) {
+            return Ok(0);
+        }
+        self.validate(&header, clock)?;
+
+        self.window.push_back(header);
+        self.height += 1;
+
+        if self.window.len() > Self::window_size(&self.params) {
+            self.window.pop_front();
+        }
+        Ok(self.height)
+    }
+
+    /// Number of headers needed to validate the next header.
+    pub(crate) fn window_size(params: &Params) -> usize {
+        (params.difficulty_adjustment_interval() as usize).max(time::MEDIAN_TIME_SPAN as usize)
+    }
+}
+
+impl Context for HeaderValidator {
+    fn header(&self, height: Height) -> Option<&BlockHeader> {
+        let offset = self.height.checked_sub(height)? as usize;
+
+        self.window
+            .len()
+            .checked_sub(offset + 1)
+            .and_then(|ix| self.window.get(ix))
+    }
+}
@@ -1,6 +1,7 @@
 #![allow(dead_code)]
 //! Compact block filter cache.
 
+use std::collections::BTreeMap;
 use std::io;
 use std::ops::Range;
 
@@ -9,7 +10,7 @@ use nonempty::NonEmpty;
 use bitcoin::consensus::{encode, Decodable, Encodable};
 
 pub use nakamoto_common::block::filter::{
-    self, BlockFilter, Error, FilterHash, FilterHeader, Filters,
+    self, BlockFilter, BlockHash, Error, FilterHash, FilterHeader, Filters,
 };
 pub use nakamoto_common::block::store::Store;
 
@@ -19,8 +20,10 @@ use nakamoto_common::network::Network;
 
 use crate::filter::store;
 
+/// A filter header, as it is stored, along with the hash of its filter and of its block.
 #[derive(Debug, Clone, Copy, Default)]
 pub struct StoredHeader {
+    block: BlockHash,
     hash: FilterHash,
     header: FilterHeader,
 }
@@ -29,6 +32,7 @@ impl Encodable for StoredHeader {
     fn consensus_encode<W: io::Write>(&self, mut e: W) -> Result<usize, encode::Error> {
         let mut len = 0;
 
+        len += self.block.consensus_encode(&mut e)?;
         len += self.hash.consensus_encode(&mut e)?;
         len += self.header.consensus_encode(&mut e)?;
 
@@ -38,25 +42,38 @@ impl Encodable for StoredHeader {
 
 impl Decodable for StoredHeader {
     fn consensus_decode<D: io::Read>(mut d: D) -> Result<Self, encode::Error> {
+        let block = BlockHash::consensus_decode(&mut d)?;
         let hash = FilterHash::consensus_decode(&mut d)?;
         let header = FilterHeader::consensus_decode(&mut d)?;
 
-        Ok(StoredHeader { hash, header })
+        Ok(StoredHeader {
+            block,
+            hash,
+            header,
+        })
     }
 }
 
 impl Genesis for StoredHeader {
     fn genesis(network: Network) -> Self {
         Self {
+            block: network.genesis_hash(),
             hash: filter::genesis_hash(network),
             header: FilterHeader::genesis(network),
         }
     }
 }
 
+/// Maximum number of filters kept in memory, to be served to peers. Once exceeded, the
+/// filters of the lowest blocks are dropped first.
+pub const MAX_CACHED_FILTERS: usize = 4096;
+
 pub struct FilterCache<S> {
     headers: NonEmpty<StoredHeader>,
     header_store: S,
+    /// Filters received from peers, by block height. Unlike filter headers, these
+    /// aren't persisted.
+    filters: BTreeMap<Height, BlockFilter>,
 }
 
 impl<S: Store<Header = StoredHeader>> FilterCache<S> {
@@ -71,6 +88,7 @@ impl<S: Store<Header = StoredHeader>> FilterCache<S> {
         Ok(Self {
             header_store,
             headers,
+            filters: BTreeMap::new(),
         })
     }
 }
@@ -114,20 +132,45 @@ impl<S: Store<Header = StoredHeader>> Filters for FilterCache<S> {
             .collect()
     }
 
+    fn get_block_hash(&self, height: Height) -> Option<BlockHash> {
+        self.headers.get(height as usize).map(|s| s.block)
+    }
+
     fn import_headers(
         &mut self,
-        headers: Vec<(FilterHash, FilterHeader)>,
+        headers: Vec<(BlockHash, FilterHash, FilterHeader)>,
     ) -> Result<Height, Error> {
         let iter = headers
             .into_iter()
-            .map(|(hash, header)| StoredHeader { hash, header });
+            .map(|(block, hash, header)| StoredHeader {
+                block,
+                hash,
+                header,
+            });
 
         self.headers.tail.extend(iter.clone());
         self.header_store.put(iter).map_err(Error::from)
     }
 
+    fn get_filter(&self, height: Height) -> Option<BlockFilter> {
+        self.filters.get(&height).cloned()
+    }
+
+    fn import_filter(&mut self, height: Height, filter: BlockFilter) -> Result<(), Error> {
+        if height > self.height() {
+            return Err(Error::NotFound(height));
+        }
+        self.filters.insert(height, filter);
+
+        while self.filters.len() > MAX_CACHED_FILTERS {
+            let lowest = *self.filters.keys().next().expect("the map is not empty");
+            self.filters.remove(&lowest);
+        }
+        Ok(())
+    }
+
     fn tip(&self) -> (&FilterHash, &FilterHeader) {
-        let StoredHeader { hash, header } = self.headers.last();
+        let StoredHeader { hash, header, .. } = self.headers.last();
         (hash, header)
     }
 
@@ -141,7 +184,99 @@ impl<S: Store<Header = StoredHeader>> Filters for FilterCache<S> {
 
         self.header_store.rollback(height)?;
         self.headers.tail.truncate(height as usize);
+        self.filters.split_off(&(height + 1));
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use bitcoin_hashes::Hash;
+
+    use crate::block::store::{io, memory};
+
+    /// Generate a valid filter header chain of the given length, on top of `prev`.
+    fn headers(prev: FilterHeader, count: usize) -> Vec<(BlockHash, FilterHash, FilterHeader)> {
+        let mut prev = prev;
+
+        (0..count)
+            .map(|i| {
+                let block = BlockHash::hash(&i.to_be_bytes());
+                let hash = FilterHash::hash(&i.to_le_bytes());
+                let header = FilterHeader::new(hash, &prev);
+
+                prev = header;
+
+                (block, hash, header)
+            })
+            .collect()
+    }
+
+    /// Get the filter hashes and headers of a chain.
+    fn filter_headers(
+        chain: &[(BlockHash, FilterHash, FilterHeader)],
+    ) -> Vec<(FilterHash, FilterHeader)> {
+        chain
+            .iter()
+            .map(|(_, hash, header)| (*hash, *header))
+            .collect()
+    }
+
+    #[test]
+    fn test_save_load_roundtrip() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("filters.db");
+        let network = Network::Regtest;
+        let genesis = StoredHeader::genesis(network);
+        let chain = headers(genesis.header, 32);
+
+        {
+            let store = io::File::create(&path, genesis).unwrap();
+            let mut cache = FilterCache::from(store).unwrap();
+
+            assert_eq!(cache.import_headers(chain.clone()).unwrap(), 32);
+            cache.header_store.sync().unwrap();
+        }
+
+        let store = io::File::open(&path, genesis).unwrap();
+        let cache = FilterCache::from(store).unwrap();
+
+        cache.verify(network).unwrap();
+
+        assert_eq!(cache.height(), 32);
+        assert_eq!(cache.get_headers(1..33), filter_headers(&chain));
+        assert_eq!(cache.get_block_hash(32), Some(chain[31].0));
+        assert_eq!(cache.get_block_hash(0), Some(network.genesis_hash()));
+        assert_eq!(cache.tip(), (&chain[31].1, &chain[31].2));
+    }
+
+    #[test]
+    fn test_rollback() {
+        let network = Network::Regtest;
+        let store = memory::Memory::genesis(network);
+        let mut cache = FilterCache::from(store).unwrap();
+        let chain = headers(FilterHeader::genesis(network), 16);
+
+        cache.import_headers(chain.clone()).unwrap();
+        for height in 8..=16 {
+            let filter = BlockFilter::new(&[1, height as u8]);
+            cache.import_filter(height, filter).unwrap();
+        }
+        assert!(cache.import_filter(17, BlockFilter::new(&[])).is_err());
+
+        cache.rollback(6).unwrap();
+
+        assert_eq!(cache.height(), 10);
+        assert_eq!(cache.header_store.height().unwrap(), 10);
+        assert_eq!(cache.tip(), (&chain[9].1, &chain[9].2));
+        assert_eq!(cache.get_header(11), None);
+        assert_eq!(cache.get_block_hash(11), None);
+        assert_eq!(cache.get_filter(10), Some(BlockFilter::new(&[1, 10])));
+        assert_eq!(cache.get_filter(11), None);
+
+        cache.verify(network).unwrap();
+    }
+}
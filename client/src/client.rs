@@ -33,7 +33,8 @@ use nakamoto_p2p::protocol::Command;
 use nakamoto_p2p::protocol::Link;
 use nakamoto_p2p::protocol::{connmgr, peermgr, spvmgr, syncmgr};
 
-pub use nakamoto_p2p::event::Event;
+pub use nakamoto_p2p::event::{Event, Overflow};
+pub use nakamoto_p2p::protocol::addrmgr::AddressType;
 pub use nakamoto_p2p::reactor::Reactor;
 
 use crate::error::Error;
@@ -49,10 +50,14 @@ pub struct Config {
     pub network: Network,
     /// Peers to connect to.
     pub connect: Vec<net::SocketAddr>,
+    /// Peers that are always connected to, and reconnected to when they disconnect.
+    pub persistent_peers: Vec<net::SocketAddr>,
     /// Target number of outbound peers to connect to.
     pub target_outbound_peers: usize,
     /// Maximum number of inbound peers supported.
     pub max_inbound_peers: usize,
+    /// Maximum number of connections, inbound and outbound combined.
+    pub max_connections: usize,
     /// Timeout duration for client commands.
     pub timeout: time::Duration,
     /// Client home path, where runtime data is stored, eg. block headers and filters.
@@ -61,6 +66,16 @@ pub struct Config {
     pub name: &'static str,
     /// Services offered by this node.
     pub services: ServiceFlags,
+    /// Maximum number of events waiting to be consumed, or `None` for no limit.
+    pub event_buffer_size: Option<usize>,
+    /// What to do with new events once the limit is reached.
+    pub event_overflow: Overflow,
+    /// Networks to connect to, in order of preference. If empty, all reachable networks
+    /// are used, without preference.
+    pub networks: Vec<AddressType>,
+    /// SOCKS5 proxy through which onion addresses are reachable, eg. a Tor daemon. Onion
+    /// addresses are only connected to if this is set.
+    pub onion_proxy: Option<net::SocketAddr>,
 }
 
 impl Config {
@@ -83,12 +98,15 @@ impl Config {
 impl From<Config> for p2p::protocol::Config {
     fn from(cfg: Config) -> Self {
         Self {
-            network: cfg.network,
-            target: cfg.name,
-            connect: cfg.connect,
+            services: cfg.services,
+            persistent_peers: cfg.persistent_peers,
             target_outbound_peers: cfg.target_outbound_peers,
             max_inbound_peers: cfg.max_inbound_peers,
-            ..Self::default()
+            max_connections: cfg.max_connections,
+            event_overflow: cfg.event_overflow,
+            networks: cfg.networks,
+            onion_proxy: cfg.onion_proxy,
+            ..Self::from(cfg.name, cfg.network, cfg.connect)
         }
     }
 }
@@ -99,11 +117,17 @@ impl Default for Config {
             listen: vec![([0, 0, 0, 0], 0).into()],
             network: Network::default(),
             connect: Vec::new(),
+            persistent_peers: Vec::new(),
             timeout: time::Duration::from_secs(60),
             home: PathBuf::from(env::var("HOME").unwrap_or_default()),
             target_outbound_peers: p2p::protocol::connmgr::TARGET_OUTBOUND_PEERS,
             max_inbound_peers: p2p::protocol::connmgr::MAX_INBOUND_PEERS,
+            max_connections: p2p::protocol::connmgr::MAX_CONNECTIONS,
             services: ServiceFlags::NONE,
+            event_buffer_size: None,
+            event_overflow: Overflow::default(),
+            networks: Vec::new(),
+            onion_proxy: None,
             name: "self",
         }
     }
@@ -185,7 +209,10 @@ impl<R: Reactor> Client<R> {
     /// Create a new client.
     pub fn new(config: Config) -> Result<Self, Error> {
         let (handle, commands) = chan::unbounded::<Command>();
-        let (subscriber, events) = chan::unbounded::<Event>();
+        let (subscriber, events) = match config.event_buffer_size {
+            Some(size) => chan::bounded::<Event>(size),
+            None => chan::unbounded::<Event>(),
+        };
         let reactor = R::new(subscriber, commands)?;
         let blocks = Arc::new(Mutex::new(BlockSubscribers::new()));
         let filters = Arc::new(Mutex::new(FilterSubscribers::new()));
@@ -248,13 +275,17 @@ impl<R: Reactor> Client<R> {
         let local_time = SystemTime::now().into();
         let checkpoints = self.config.network.checkpoints().collect::<Vec<_>>();
         let clock = AdjustedTime::<net::SocketAddr>::new(local_time);
-        let cache = BlockCache::from(store, params, &checkpoints)?;
+        let cache = BlockCache::load(store, params, &checkpoints, |loaded, total| {
+            log::debug!("Loaded {}/{} block headers", loaded, total);
+        })?;
         let rng = fastrand::Rng::new();
 
         log::info!("Initializing block filters..");
 
         let cfheaders_genesis = filter::cache::StoredHeader::genesis(self.config.network);
-        let cfheaders_path = dir.join("filters.db");
+        // Filter headers are stored along with their block hash since `filters-v2.db`.
+        // Stores in the previous format are left alone, and filter headers are re-synced.
+        let cfheaders_path = dir.join("filters-v2.db");
         let cfheaders_store = match store::File::create(&cfheaders_path, cfheaders_genesis) {
             Err(store::Error::Io(e)) if e.kind() == io::ErrorKind::AlreadyExists => {
                 log::info!("Found existing store {:?}", cfheaders_path);
@@ -316,16 +347,7 @@ impl<R: Reactor> Client<R> {
             log::info!("{} seeds added to address book", peers.len());
         }
 
-        let cfg = p2p::protocol::Config {
-            network: self.config.network,
-            params: self.config.network.params(),
-            target: self.config.name,
-            connect: self.config.connect,
-            target_outbound_peers: self.config.target_outbound_peers,
-            max_inbound_peers: self.config.max_inbound_peers,
-            services: self.config.services,
-            ..p2p::protocol::Config::default()
-        };
+        let cfg: p2p::protocol::Config = self.config.into();
         let builder = p2p::protocol::Builder {
             cache,
             clock,
@@ -353,14 +375,7 @@ impl<R: Reactor> Client<R> {
         filters: F,
         peers: P,
     ) -> Result<(), Error> {
-        let cfg = p2p::protocol::Config {
-            services: self.config.services,
-            ..p2p::protocol::Config::from(
-                self.config.name,
-                self.config.network,
-                self.config.connect,
-            )
-        };
+        let cfg: p2p::protocol::Config = self.config.clone().into();
 
         log::info!("Initializing client ({:?})..", cfg.network);
         log::info!("Genesis block hash is {}", cfg.network.genesis_hash());
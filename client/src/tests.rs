@@ -97,3 +97,56 @@ fn test_full_sync() {
         thread.join().unwrap();
     }
 }
+
+#[test]
+fn test_persistent_peers() {
+    use std::io::Read;
+    use std::time;
+
+    use nakamoto_common::network::Network;
+
+    // Accept a connection on the given listener, failing after a while.
+    fn accept(listener: &net::TcpListener) -> net::TcpStream {
+        let deadline = time::Instant::now() + time::Duration::from_secs(10);
+
+        loop {
+            match listener.accept() {
+                Ok((stream, _)) => return stream,
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    assert!(time::Instant::now() < deadline, "peer was not dialed");
+                    thread::sleep(time::Duration::from_millis(10));
+                }
+                Err(e) => panic!("{}", e),
+            }
+        }
+    }
+
+    let tmp = tempfile::tempdir().unwrap();
+    let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.set_nonblocking(true).unwrap();
+
+    let cfg = Config {
+        network: Network::Regtest,
+        listen: vec![([127, 0, 0, 1], 0).into()],
+        persistent_peers: vec![listener.local_addr().unwrap()],
+        home: tmp.path().to_path_buf(),
+        ..Config::default()
+    };
+    let node = Client::<Reactor>::new(cfg).unwrap();
+    let handle = node.handle();
+    let t = thread::spawn(move || node.run().unwrap());
+
+    // The persistent peer is dialed on startup, and we get the client's `version` message.
+    let mut stream = accept(&listener);
+    let mut buf = [0; 24];
+    stream.set_nonblocking(false).unwrap();
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf[4..11], b"version");
+
+    // Dropping the connection makes the client reconnect right away.
+    drop(stream);
+    accept(&listener);
+
+    handle.shutdown().unwrap();
+    t.join().unwrap();
+}
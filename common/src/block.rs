@@ -55,6 +55,101 @@ pub fn locators_indexes(mut from: Height) -> Vec<Height> {
     indexes
 }
 
+/// Get the difficulty of a block with the given compact target, as a multiple of the
+/// minimum difficulty (`0x1d00ffff`).
+///
+/// ```
+/// use nakamoto_common::block;
+///
+/// assert_eq!(block::difficulty(0x1d00ffff), 1.);
+/// assert!((block::difficulty(0x1b0404cb) - 16307.420938523983).abs() < 1e-6);
+/// ```
+pub fn difficulty(bits: Bits) -> f64 {
+    let mut shift = (bits >> 24) & 0xff;
+    let mut diff = 0x0000ffff as f64 / (bits & 0x00ffffff) as f64;
+
+    while shift < 29 {
+        diff *= 256.;
+        shift += 1;
+    }
+    while shift > 29 {
+        diff /= 256.;
+        shift -= 1;
+    }
+    diff
+}
+
+/// Get the base-2 logarithm of an amount of work, ie. of the expected number of hashes
+/// needed to produce it. This is the usual way of expressing total chain work compactly.
+pub fn work_log2(work: Work) -> f64 {
+    let hashes = work
+        .0
+        .iter()
+        .rev()
+        .fold(0., |acc, word| acc * 2f64.powi(64) + *word as f64);
+
+    if hashes > 0. {
+        hashes.log2()
+    } else {
+        0.
+    }
+}
+
+/// Format an amount of work as an approximate number of hashes, along with its base-2
+/// logarithm.
+///
+/// ```
+/// use nakamoto_common::block::{self, Work};
+///
+/// assert_eq!(block::format_work(Work::from_u64(1 << 32).unwrap()), "4.29e9 hashes (2^32.00)");
+/// ```
+pub fn format_work(work: Work) -> String {
+    let log2 = work_log2(work);
+
+    format!("{:.2e} hashes (2^{:.2})", 2f64.powf(log2), log2)
+}
+
+/// Compute the difficulty target following a difficulty adjustment interval, given the
+/// first block of the interval, and the time and target of the last block.
+pub fn retarget(
+    last_adjustment_block: &BlockHeader,
+    last_time: BlockTime,
+    last_target: Target,
+    params: &bitcoin::consensus::params::Params,
+) -> Bits {
+    let last_adjustment_time = last_adjustment_block.time;
+
+    if params.no_pow_retargeting {
+        return last_adjustment_block.bits;
+    }
+
+    let adjusted_timespan = retarget_timespan(last_time - last_adjustment_time, params);
+    let mut target = last_target;
+
+    target = target.mul_u32(adjusted_timespan);
+    target = target / Target::from_u64(params.pow_target_timespan).unwrap();
+
+    // Ensure a difficulty floor.
+    if target > params.pow_limit {
+        target = params.pow_limit;
+    }
+
+    BlockHeader::compact_target_from_u256(&target)
+}
+
+/// Limit the time it took to mine a difficulty adjustment interval to the bounds
+/// allowed by a retarget, ie. between a quarter and four times the target timespan.
+pub fn retarget_timespan(
+    actual_timespan: BlockTime,
+    params: &bitcoin::consensus::params::Params,
+) -> BlockTime {
+    let target_timespan = params.pow_target_timespan as BlockTime;
+
+    actual_timespan
+        .max(target_timespan / 4)
+        .min(target_timespan * 4)
+}
+
 /// Get the proof-of-work limit for the network, in bits.
 pub fn pow_limit_bits(network: &bitcoin::Network) -> Bits {
     match network {
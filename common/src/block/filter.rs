@@ -9,7 +9,7 @@ use thiserror::Error;
 use bitcoin::consensus::encode;
 use bitcoin::consensus::{Decodable, Encodable};
 
-pub use bitcoin::hash_types::FilterHash;
+pub use bitcoin::hash_types::{BlockHash, FilterHash};
 pub use bitcoin::util::bip158::BlockFilter;
 
 use super::Height;
@@ -147,9 +147,18 @@ pub trait Filters {
     fn get_headers(&self, range: Range<Height>) -> Vec<(FilterHash, FilterHeader)>;
     /// Get the filter header at the given height. Includes the hash of the filter itself.
     fn get_header(&self, height: Height) -> Option<(FilterHash, FilterHeader)>;
-    /// Import filter headers.
-    fn import_headers(&mut self, headers: Vec<(FilterHash, FilterHeader)>)
-        -> Result<Height, Error>;
+    /// Get the hash of the block the filter header at the given height was imported for.
+    fn get_block_hash(&self, height: Height) -> Option<BlockHash>;
+    /// Import filter headers, along with the hashes of the blocks they are for.
+    fn import_headers(
+        &mut self,
+        headers: Vec<(BlockHash, FilterHash, FilterHeader)>,
+    ) -> Result<Height, Error>;
+    /// Get the filter of the block at the given height, if it is stored.
+    fn get_filter(&self, height: Height) -> Option<BlockFilter>;
+    /// Store the filter of the block at the given height. The filter should have been
+    /// checked against its filter header.
+    fn import_filter(&mut self, height: Height, filter: BlockFilter) -> Result<(), Error>;
     /// Get the tip of the filter header chain.
     fn tip(&self) -> (&FilterHash, &FilterHeader);
     /// Get the height of the filter header chain.
@@ -163,6 +172,7 @@ pub trait Filters {
             self.get_header(height - 1).map(|(_, h)| h)
         }
     }
-    /// Rollback chain by the given number of headers.
+    /// Rollback chain by the given number of headers. Filters above the new tip are
+    /// discarded as well.
     fn rollback(&mut self, n: usize) -> Result<(), Error>;
 }
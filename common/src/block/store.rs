@@ -1,6 +1,6 @@
 //! Block header storage.
 #![allow(clippy::len_without_is_empty)]
-use crate::block::Height;
+use crate::block::{BlockHash, Height, Work};
 
 use bitcoin::blockdata::block::BlockHeader;
 use bitcoin::consensus::encode;
@@ -35,6 +35,10 @@ impl Genesis for BlockHeader {
     }
 }
 
+/// A checkpoint of the stored chain: the height and hash of a block, and the cumulative
+/// proof-of-work of the chain up to and including it.
+pub type Checkpoint = (Height, BlockHash, Work);
+
 /// Represents objects that can store block headers.
 pub trait Store {
     /// The type of header used in the store.
@@ -46,7 +50,11 @@ pub trait Store {
     fn put<I: Iterator<Item = Self::Header>>(&mut self, headers: I) -> Result<Height, Error>;
     /// Get the block at the given height.
     fn get(&self, height: Height) -> Result<Self::Header, Error>;
-    /// Rollback the chain to the given height.
+    /// Store a checkpoint of the chain. Checkpoints are stored in increasing height order.
+    fn put_checkpoint(&mut self, checkpoint: Checkpoint) -> Result<(), Error>;
+    /// Get the stored checkpoints, lowest first.
+    fn checkpoints(&self) -> Result<Vec<Checkpoint>, Error>;
+    /// Rollback the chain to the given height. Checkpoints above it are dropped.
     fn rollback(&mut self, height: Height) -> Result<(), Error>;
     /// Synchronize the changes to disk.
     fn sync(&mut self) -> Result<(), Error>;
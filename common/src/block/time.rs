@@ -12,6 +12,9 @@ pub const MAX_TIME_ADJUSTMENT: TimeOffset = 70 * 60;
 /// it is considered invalid (2 hours).
 pub const MAX_FUTURE_BLOCK_TIME: BlockTime = 60 * 60 * 2;
 
+/// Maximum age of the tip of a chain considered caught up with the network (24 hours).
+pub const MAX_TIP_AGE: BlockTime = 60 * 60 * 24;
+
 /// Number of previous blocks to look at when determining the median
 /// block time.
 pub const MEDIAN_TIME_SPAN: Height = 11;
@@ -233,6 +236,8 @@ pub struct AdjustedTime<K> {
     samples: Vec<TimeOffset>,
     /// Current time offset, based on our samples.
     offset: TimeOffset,
+    /// Median offset of our samples, if it was too large to adjust our time by.
+    skew: Option<TimeOffset>,
     /// Last known local time.
     local_time: LocalTime,
 }
@@ -268,6 +273,7 @@ impl<K: Hash + Eq> AdjustedTime<K> {
             sources,
             samples,
             offset,
+            skew: None,
             local_time,
         }
     }
@@ -315,10 +321,11 @@ impl<K: Hash + Eq> AdjustedTime<K> {
             // Don't let other nodes change our time by more than a certain amount.
             if median_offset.abs() <= MAX_TIME_ADJUSTMENT {
                 self.offset = median_offset;
+                self.skew = None;
             } else {
-                // TODO: Check whether other nodes have times similar to ours, otherwise
-                // log a warning about our clock possibly being wrong.
+                // Our clock is likely wrong, but by too much for us to rely on our peers.
                 self.offset = 0;
+                self.skew = Some(median_offset);
             }
             #[cfg(feature = "log")]
             log::debug!("Time offset adjusted to {} seconds", self.offset);
@@ -330,6 +337,13 @@ impl<K: Hash + Eq> AdjustedTime<K> {
         self.offset
     }
 
+    /// Get the median network time offset, if it is beyond [`MAX_TIME_ADJUSTMENT`]. This
+    /// means that our local clock is likely wrong, and that the network-adjusted time
+    /// can't be relied on.
+    pub fn skew(&self) -> Option<TimeOffset> {
+        self.skew
+    }
+
     /// Get the network-adjusted time given a local time.
     pub fn from(&self, time: BlockTime) -> BlockTime {
         let adjustment = self.offset;
@@ -382,6 +396,7 @@ mod tests {
             }
         }
         assert_eq!(adjusted_time.offset(), 47); // samples = [0, 42, 47, 4201, 4201]
+        assert_eq!(adjusted_time.skew(), None);
 
         adjusted_time.record_offset(([127, 0, 0, 5], 8333).into(), MAX_TIME_ADJUSTMENT + 1);
         assert_eq!(
@@ -396,6 +411,7 @@ mod tests {
             0,
             "A too large time adjustment reverts back to 0",
         ); // samples = [0, 42, 47, 4201, 4201, 4201, 4201]
+        assert_eq!(adjusted_time.skew(), Some(MAX_TIME_ADJUSTMENT + 1));
     }
 
     #[test]
@@ -41,6 +41,15 @@ pub enum Error {
     #[error("block missing: {0}")]
     BlockMissing(BlockHash),
 
+    /// The headers don't form a single chain. The given block starts another chain.
+    #[error("headers form more than one chain, another one starts at {0}")]
+    DisjointChains(BlockHash),
+
+    /// A chain diff doesn't apply to the active chain: its base block, given with its
+    /// height, isn't part of it.
+    #[error("chain diff base {0} at height {1} is not part of the active chain")]
+    DiffBaseMismatch(BlockHash, Height),
+
     /// A block import was aborted. FIXME: Move this error out of here.
     #[error("block import aborted at height {2}: {0} ({1} block(s) imported)")]
     BlockImportAborted(Box<Self>, usize, Height),
@@ -77,6 +86,40 @@ pub enum ImportResult {
     TipUnchanged, // TODO: We could add a parameter eg. BlockMissing or DuplicateBlock.
 }
 
+/// An event emitted by a block tree, about headers stored outside of the active chain, or
+/// unusual blocks joining it. See [`BlockTree::events`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// An orphan header was evicted to make room for newer ones.
+    OrphanEvicted(BlockHash),
+    /// A side-chain header was evicted to make room for branches with more work.
+    SideChainHeaderEvicted(BlockHash),
+    /// A side-chain was evicted to make room for side-chains with more work, along with
+    /// the headers it didn't share with other side-chains. The hash is that of its tip.
+    SideChainEvicted(BlockHash),
+    /// A header that doesn't connect to any known header was stored.
+    OrphanReceived {
+        /// Hash of the orphan.
+        hash: BlockHash,
+        /// The missing ancestor of the orphan, which should be requested from peers.
+        missing_parent: BlockHash,
+    },
+    /// A block joined the active chain with a timestamp unusually far from its parent's.
+    UnusualTimestamp {
+        /// Height of the block.
+        height: Height,
+        /// Difference between the block's timestamp and its parent's, in seconds.
+        drift: i64,
+    },
+    /// A header was received that connected previously stored orphans.
+    OrphansResolved {
+        /// Number of orphans connected.
+        count: usize,
+        /// The new tip of the active chain, if it changed.
+        new_tip: Option<BlockHash>,
+    },
+}
+
 /// A chain of block headers that may or may not lead back to genesis.
 #[derive(Debug, Clone)]
 pub struct Branch<'a, H: Header>(pub &'a [H]);
@@ -93,6 +136,11 @@ impl<'a, H: Header> Branch<'a, H> {
 }
 
 /// A representation of all known blocks that keeps track of the longest chain.
+///
+/// Only [`BlockTree::import_blocks`] and [`BlockTree::extend_tip`] modify the tree, along
+/// with [`BlockTree::events`], which consumes the events they emitted: every other method
+/// takes `&self`. A tree shared between protocol instances, eg. behind an
+/// `Arc<RwLock<_>>`, is thus only locked for writing while headers are imported.
 pub trait BlockTree {
     /// Import a chain of block headers into the block tree.
     fn import_blocks<I: Iterator<Item = BlockHeader>, C: Clock>(
@@ -107,10 +155,21 @@ pub trait BlockTree {
         header: BlockHeader,
         context: &C,
     ) -> Result<ImportResult, Error>;
+    /// Consume the events emitted by the tree since the last call, oldest first. Events
+    /// are emitted as headers are imported, including by imports that fail, and are kept
+    /// until consumed.
+    fn events(&mut self) -> Vec<Event> {
+        Vec::new()
+    }
     /// Get a block by hash.
     fn get_block(&self, hash: &BlockHash) -> Option<(Height, &BlockHeader)>;
     /// Get a block by height.
     fn get_block_by_height(&self, height: Height) -> Option<&BlockHeader>;
+    /// Get the hash of a block of the longest chain by height. Implementations that keep
+    /// block hashes around should override this, to avoid hashing the header.
+    fn get_block_hash(&self, height: Height) -> Option<BlockHash> {
+        self.get_block_by_height(height).map(|h| h.block_hash())
+    }
     /// Iterate over the longest chain, starting from genesis.
     fn chain<'a>(&'a self) -> Box<dyn Iterator<Item = BlockHeader> + 'a> {
         Box::new(self.iter().map(|(_, h)| h))
@@ -148,6 +207,24 @@ pub trait BlockTree {
         self.get_block_by_height(0)
             .expect("the genesis block is always present")
     }
+    /// Check whether a block hash is the hash of the genesis block. The genesis block is
+    /// exempt from validation: it has no parent, and is part of the chain from the start.
+    /// Validation code should consult this function rather than special-casing genesis.
+    fn is_genesis(&self, hash: &BlockHash) -> bool {
+        &self.genesis().block_hash() == hash
+    }
+    /// Return the total proof-of-work of the active chain.
+    fn chain_work(&self) -> Work {
+        self.iter()
+            .fold(Work::default(), |work, (_, header)| work + header.work())
+    }
+    /// Return the difficulty of the best block, relative to the minimum difficulty.
+    /// This is the value reported by Bitcoin Core's `getdifficulty`.
+    fn difficulty(&self) -> f64 {
+        let (_, tip) = self.best_block();
+
+        crate::block::difficulty(tip.bits)
+    }
     /// Check whether a block hash is known.
     fn is_known(&self, hash: &BlockHash) -> bool;
     /// Check whether a block hash is part of the active chain.
@@ -161,6 +238,63 @@ pub trait BlockTree {
     ) -> Vec<BlockHeader>;
     /// Get the locator hashes starting from the given height and going backwards.
     fn locator_hashes(&self, from: Height) -> Vec<BlockHash>;
+    /// Get the height up to which the active chain was imported without full validation,
+    /// eg. because it leads up to an assume-valid block. Returns `0` if all headers of the
+    /// active chain were fully validated.
+    fn assumed_valid_height(&self) -> Height {
+        0
+    }
+    /// Fully validate the headers of the active chain in the given range of heights, even
+    /// those that were imported without full validation. On failure, returns the height of
+    /// the first invalid header, along with the error.
+    fn revalidate<C: Clock>(
+        &self,
+        _range: std::ops::Range<Height>,
+        _clock: &C,
+    ) -> Result<(), (Height, Error)> {
+        Ok(())
+    }
+    /// Check whether the chain is caught up with the network, ie. whether its tip is less
+    /// than [`MAX_TIP_AGE`] old. The tip's age is measured against the clock's
+    /// network-adjusted time, so that a skewed local clock doesn't affect the result.
+    ///
+    /// [`MAX_TIP_AGE`]: crate::block::time::MAX_TIP_AGE
+    fn is_caught_up(&self, clock: &impl Clock) -> bool {
+        let (_, tip) = self.tip();
+
+        tip.time + crate::block::time::MAX_TIP_AGE >= clock.block_time()
+    }
+    /// Estimate the next difficulty adjustment, by projecting the rate at which the blocks
+    /// of the current difficulty adjustment interval were mined so far. Returns the height
+    /// of the first block of the next interval, along with the factor by which the
+    /// difficulty is expected to change, eg. `1.1` for an increase of 10%.
+    ///
+    /// Returns `None` on networks without difficulty adjustments, or when the tip is the
+    /// first block of an interval, since there is nothing to project from.
+    fn next_difficulty_estimate(&self, params: &Params) -> Option<(Height, f64)> {
+        if params.no_pow_retargeting {
+            return None;
+        }
+        let interval = params.difficulty_adjustment_interval();
+        let (_, tip) = self.tip();
+        let height = self.height();
+        let start = height - height % interval;
+        let elapsed = height - start;
+
+        if elapsed == 0 {
+            return None;
+        }
+        let first = self.get_block_by_height(start)?;
+        // A retarget measures the time between the first and last block of an interval.
+        let projected =
+            tip.time.saturating_sub(first.time) as f64 * (interval - 1) as f64 / elapsed as f64;
+        let timespan = crate::block::retarget_timespan(projected as BlockTime, params);
+
+        Some((
+            start + interval,
+            params.pow_target_timespan as f64 / timespan as f64,
+        ))
+    }
     /// Get the next difficulty given a block height, time and bits.
     fn next_difficulty_target(
         &self,
@@ -180,31 +314,7 @@ pub trait BlockTree {
         let last_adjustment_block = self
             .get_block_by_height(last_adjustment_height)
             .unwrap_or_else(|| self.genesis());
-        let last_adjustment_time = last_adjustment_block.time;
-
-        if params.no_pow_retargeting {
-            return last_adjustment_block.bits;
-        }
-
-        let actual_timespan = last_time - last_adjustment_time;
-        let mut adjusted_timespan = actual_timespan;
-
-        if actual_timespan < params.pow_target_timespan as BlockTime / 4 {
-            adjusted_timespan = params.pow_target_timespan as BlockTime / 4;
-        } else if actual_timespan > params.pow_target_timespan as BlockTime * 4 {
-            adjusted_timespan = params.pow_target_timespan as BlockTime * 4;
-        }
-
-        let mut target = last_target;
-
-        target = target.mul_u32(adjusted_timespan);
-        target = target / Target::from_u64(params.pow_target_timespan).unwrap();
-
-        // Ensure a difficulty floor.
-        if target > params.pow_limit {
-            target = params.pow_limit;
-        }
 
-        BlockHeader::compact_target_from_u256(&target)
+        crate::block::retarget(last_adjustment_block, last_time, last_target, params)
     }
 }
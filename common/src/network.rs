@@ -1,4 +1,5 @@
 //! Bitcoin peer network. Eg. *Mainnet*.
+use std::net;
 
 use bitcoin::blockdata::block::{Block, BlockHeader};
 use bitcoin::consensus::params::Params;
@@ -97,6 +98,34 @@ impl Network {
             Network::Regtest => &[], // No seeds
         }
     }
+
+    /// Fixed seed nodes. Used to bootstrap the client's address book when DNS seeds
+    /// are disabled or unreachable. These are long-running nodes, but since any of
+    /// them may go offline, the list should be refreshed from time to time.
+    pub fn fixed_seeds(&self) -> Vec<net::SocketAddr> {
+        let ips: &[[u8; 4]] = match self {
+            Network::Mainnet => &[
+                [5, 9, 11, 235],
+                [23, 175, 0, 212],
+                [35, 239, 104, 154],
+                [45, 79, 40, 205],
+                [65, 21, 187, 127],
+                [88, 99, 65, 175],
+                [95, 216, 66, 35],
+                [144, 76, 19, 28],
+            ],
+            Network::Testnet => &[
+                [5, 9, 138, 70],
+                [45, 77, 145, 58],
+                [88, 99, 96, 94],
+                [178, 128, 221, 177],
+            ],
+            Network::Regtest => &[], // No seeds
+        };
+        ips.iter()
+            .map(|ip| net::SocketAddr::from((*ip, self.port())))
+            .collect()
+    }
 }
 
 impl Network {
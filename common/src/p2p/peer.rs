@@ -111,6 +111,8 @@ pub enum Source {
     Peer(net::SocketAddr),
     /// An address that came from a DNS seed.
     Dns,
+    /// An address that came from the fixed seeds.
+    Fixed,
 }
 
 impl std::fmt::Display for Source {
@@ -118,6 +120,7 @@ impl std::fmt::Display for Source {
         match self {
             Self::Peer(addr) => write!(f, "{}", addr),
             Self::Dns => write!(f, "DNS"),
+            Self::Fixed => write!(f, "fixed seeds"),
         }
     }
 }
@@ -177,6 +180,7 @@ impl KnownAddress {
             "source".to_owned(),
             match self.source {
                 Source::Dns => Value::String("dns".to_owned()),
+                Source::Fixed => Value::String("fixed".to_owned()),
                 Source::Peer(addr) => Value::String(addr.to_string()),
             },
         );
@@ -215,6 +219,8 @@ impl KnownAddress {
             Some(Value::String(s)) => {
                 if s == "dns" {
                     Source::Dns
+                } else if s == "fixed" {
+                    Source::Fixed
                 } else {
                     match s.parse() {
                         Ok(addr) => Source::Peer(addr),
@@ -0,0 +1,42 @@
+//! Feed arbitrary inputs to the protocol state machine.
+//!
+//! Run with `cargo fuzz run protocol_step`. Seeds are found under `corpus/protocol_step`.
+#![no_main]
+use std::collections::HashMap;
+
+use crossbeam_channel as chan;
+use libfuzzer_sys::fuzz_target;
+
+use nakamoto_chain::block::cache::BlockCache;
+use nakamoto_chain::block::store;
+use nakamoto_chain::filter::cache::FilterCache;
+
+use nakamoto_common::block::time::{AdjustedTime, LocalTime};
+use nakamoto_common::network::Network;
+
+use nakamoto_p2p::protocol::{self, fuzz, Builder};
+
+fuzz_target!(|steps: Vec<fuzz::Step>| {
+    let network = Network::Mainnet;
+    let genesis = network.genesis();
+    let cache = BlockCache::from(store::Memory::genesis(network), network.params(), &[]).unwrap();
+    let filters = FilterCache::from(store::Memory::genesis(network)).unwrap();
+    let time = LocalTime::from_secs(genesis.time as u64);
+    let (tx, rx) = chan::unbounded();
+
+    let mut protocol = Builder {
+        cache,
+        filters,
+        peers: HashMap::new(),
+        clock: AdjustedTime::new(time),
+        rng: fastrand::Rng::with_seed(0),
+        cfg: protocol::Config {
+            network,
+            ..protocol::Config::default()
+        },
+    }
+    .build(tx);
+
+    protocol.initialize(time).ok();
+    fuzz::step(&mut protocol, &rx, time, steps);
+});
@@ -28,6 +28,7 @@
 #[cfg(unix)]
 pub mod reactor;
 pub mod socket;
+pub mod socks;
 pub mod time;
 
 pub use reactor::Reactor;
@@ -11,8 +11,12 @@ use nakamoto_common::p2p::peer;
 
 use nakamoto_p2p;
 use nakamoto_p2p::error::Error;
-use nakamoto_p2p::event::Event;
+use nakamoto_p2p::event::{Event, Overflow, Publisher};
+use nakamoto_p2p::protocol::multiplex::{self, Multiplexer};
 use nakamoto_p2p::protocol::{self, Command, DisconnectReason, Input, Link, Out};
+use nakamoto_p2p::reactor::{Machine, PeerKey};
+
+use nakamoto_common::network::Network;
 
 use log::*;
 
@@ -28,6 +32,7 @@ use std::time::SystemTime;
 
 use crate::fallible;
 use crate::socket::Socket;
+use crate::socks;
 use crate::time::TimeoutManager;
 
 /// Maximum time to wait when reading from a socket.
@@ -45,36 +50,46 @@ enum Control {
 }
 
 #[derive(Debug, PartialEq, Eq, Clone)]
-enum Source {
-    Peer(net::SocketAddr),
+enum Source<K> {
+    Peer(K),
     Listener,
     Waker,
 }
 
 /// A single-threaded non-blocking reactor.
-pub struct Reactor<R: Write + Read> {
-    peers: HashMap<net::SocketAddr, Socket<R, RawNetworkMessage>>,
-    connecting: HashSet<net::SocketAddr>,
-    inputs: VecDeque<Input>,
-    subscriber: chan::Sender<Event>,
-    commands: chan::Receiver<Command>,
-    sources: popol::Sources<Source>,
+///
+/// Peer connections are keyed by `K`, which is the peer address when running a single
+/// protocol instance. Commands received by the reactor are of type `C`.
+pub struct Reactor<R: Write + Read, K = net::SocketAddr, C = Command> {
+    peers: HashMap<K, Socket<R, RawNetworkMessage>>,
+    connecting: HashSet<K>,
+    /// Peers connected through the onion proxy, whose handshake with the proxy isn't
+    /// complete, along with the part of the proxy's reply received so far.
+    handshakes: HashMap<K, Vec<u8>>,
+    /// Proxy through which onion addresses are connected to.
+    onion_proxy: Option<net::SocketAddr>,
+    inputs: VecDeque<Input<K, C>>,
+    subscriber: Publisher,
+    commands: chan::Receiver<C>,
+    sources: popol::Sources<Source<K>>,
     waker: Arc<popol::Waker>,
     timeouts: TimeoutManager<()>,
 }
 
 /// The `R` parameter represents the underlying stream type, eg. `net::TcpStream`.
-impl<R: Write + Read + AsRawFd> Reactor<R> {
+impl<R: Write + Read + AsRawFd, K: PeerKey, C> Reactor<R, K, C> {
     /// Register a peer with the reactor.
-    fn register_peer(&mut self, addr: net::SocketAddr, stream: R, link: Link) {
+    fn register_peer(&mut self, addr: K, stream: R, link: Link) {
         self.sources
             .register(Source::Peer(addr), &stream, popol::interest::ALL);
-        self.peers.insert(addr, Socket::from(stream, addr, link));
+        self.peers
+            .insert(addr, Socket::from(stream, addr.addr(), link));
     }
 
     /// Unregister a peer from the reactor.
-    fn unregister_peer(&mut self, addr: net::SocketAddr, reason: DisconnectReason) {
+    fn unregister_peer(&mut self, addr: K, reason: DisconnectReason) {
         self.connecting.remove(&addr);
+        self.handshakes.remove(&addr);
         self.inputs.push_back(Input::Disconnected(addr, reason));
         self.sources.unregister(&Source::Peer(addr));
         self.peers.remove(&addr);
@@ -88,18 +103,85 @@ impl nakamoto_p2p::reactor::Reactor for Reactor<net::TcpStream> {
     fn new(
         subscriber: chan::Sender<Event>,
         commands: chan::Receiver<Command>,
+    ) -> Result<Self, io::Error> {
+        Self::with(subscriber, commands)
+    }
+
+    /// Run the given protocol with the reactor.
+    fn run<T: BlockTree, F: Filters, P: peer::Store, C: Fn(Event)>(
+        &mut self,
+        builder: protocol::Builder<T, F, P>,
+        listen_addrs: &[net::SocketAddr],
+        callback: C,
+    ) -> Result<(), Error> {
+        self.subscriber.set_overflow(builder.cfg.event_overflow);
+        self.onion_proxy = builder.cfg.onion_proxy;
+
+        let (tx, rx) = chan::unbounded();
+        let protocol = builder.build(tx);
+
+        self.drive(protocol, &rx, listen_addrs, callback)
+    }
+
+    /// Wake the waker.
+    fn wake(waker: &Arc<popol::Waker>) -> io::Result<()> {
+        waker.wake()
+    }
+
+    /// Return a new waker.
+    ///
+    /// Used to wake up the main event loop.
+    fn waker(&self) -> Arc<popol::Waker> {
+        self.waker.clone()
+    }
+}
+
+impl Reactor<net::TcpStream, multiplex::Peer, (Network, Command)> {
+    /// Run the given protocol instances side by side, each on its own network, behind a
+    /// single set of listening addresses. Commands are given along with the network of the
+    /// instance they are meant for. See [`Multiplexer`].
+    ///
+    /// The overflow policy of the event channel is that of the first instance. Onion
+    /// addresses are connected to through the first onion proxy configured.
+    pub fn run_multiplexed<T: BlockTree, F: Filters, P: peer::Store, C: Fn(Event)>(
+        &mut self,
+        builders: Vec<protocol::Builder<T, F, P>>,
+        listen_addrs: &[net::SocketAddr],
+        callback: C,
+    ) -> Result<(), Error> {
+        if let Some(builder) = builders.first() {
+            self.subscriber.set_overflow(builder.cfg.event_overflow);
+        }
+        self.onion_proxy = builders.iter().find_map(|b| b.cfg.onion_proxy);
+
+        let (tx, rx) = chan::unbounded();
+        let mux = Multiplexer::new(builders, tx);
+
+        self.drive(mux, &rx, listen_addrs, callback)
+    }
+}
+
+impl<K: PeerKey, C> Reactor<net::TcpStream, K, C> {
+    /// Construct a new reactor, given a channel to send events on, and a channel to
+    /// receive commands.
+    pub fn with(
+        subscriber: chan::Sender<Event>,
+        commands: chan::Receiver<C>,
     ) -> Result<Self, io::Error> {
         let peers = HashMap::new();
-        let inputs: VecDeque<Input> = VecDeque::new();
+        let inputs = VecDeque::new();
 
         let mut sources = popol::Sources::new();
         let waker = Arc::new(popol::Waker::new(&mut sources, Source::Waker)?);
         let timeouts = TimeoutManager::new();
         let connecting = HashSet::new();
+        let subscriber = Publisher::new(subscriber, Overflow::default());
 
         Ok(Self {
             peers,
             connecting,
+            handshakes: HashMap::new(),
+            onion_proxy: None,
             sources,
             inputs,
             subscriber,
@@ -109,12 +191,14 @@ impl nakamoto_p2p::reactor::Reactor for Reactor<net::TcpStream> {
         })
     }
 
-    /// Run the given protocol with the reactor.
-    fn run<T: BlockTree, F: Filters, P: peer::Store, C: Fn(Event)>(
+    /// Drive the given state machine with the reactor, until it shuts down. The machine's
+    /// outputs are expected on the given channel.
+    pub fn drive<M: Machine<Peer = K, Command = C>, Cb: Fn(Event)>(
         &mut self,
-        builder: protocol::Builder<T, F, P>,
+        mut machine: M,
+        rx: &chan::Receiver<Out<K>>,
         listen_addrs: &[net::SocketAddr],
-        callback: C,
+        callback: Cb,
     ) -> Result<(), Error> {
         let listener = if listen_addrs.is_empty() {
             None
@@ -124,7 +208,7 @@ impl nakamoto_p2p::reactor::Reactor for Reactor<net::TcpStream> {
 
             self.sources
                 .register(Source::Listener, &listener, popol::interest::READ);
-            self.subscriber.send(Event::Listening(local_addr))?;
+            self.subscriber.publish(Event::Listening(local_addr));
 
             info!("Listening on {}", local_addr);
 
@@ -133,21 +217,19 @@ impl nakamoto_p2p::reactor::Reactor for Reactor<net::TcpStream> {
 
         info!("Initializing protocol..");
 
-        let (tx, rx) = chan::unbounded();
-        let mut protocol = builder.build(tx);
         let local_time = SystemTime::now().into();
 
-        protocol.initialize(local_time);
+        machine.initialize(local_time)?;
 
-        if let Control::Shutdown = self.process(&rx, local_time, &callback)? {
+        if let Control::Shutdown = self.process(rx, local_time, &callback)? {
             return Ok(());
         }
 
         // Drain input events in case some were added during the processing of outputs.
         while let Some(event) = self.inputs.pop_front() {
-            protocol.step(event, local_time);
+            machine.step(event, local_time);
 
-            if let Control::Shutdown = self.process(&rx, local_time, &callback)? {
+            if let Control::Shutdown = self.process(rx, local_time, &callback)? {
                 return Ok(());
             }
         }
@@ -210,6 +292,16 @@ impl nakamoto_p2p::reactor::Reactor for Reactor<net::TcpStream> {
 
                                     let local_addr = conn.local_addr()?;
                                     let link = Link::Inbound;
+                                    let addr = K::inbound(addr);
+
+                                    // Sockets are keyed by peer: registering this one would
+                                    // replace the existing connection. Keep the latter.
+                                    if self.peers.contains_key(&addr) {
+                                        debug!("{}: Dropping duplicate connection", addr);
+
+                                        conn.shutdown(net::Shutdown::Both).ok();
+                                        continue;
+                                    }
 
                                     self.inputs.push_back(Input::Connected {
                                         addr,
@@ -240,36 +332,27 @@ impl nakamoto_p2p::reactor::Reactor for Reactor<net::TcpStream> {
             }
 
             while let Some(event) = self.inputs.pop_front() {
-                protocol.step(event, local_time);
+                machine.step(event, local_time);
 
-                if let Control::Shutdown = self.process(&rx, local_time, &callback)? {
+                if let Control::Shutdown = self.process(rx, local_time, &callback)? {
                     return Ok(());
                 }
             }
         }
     }
-
-    /// Wake the waker.
-    fn wake(waker: &Arc<popol::Waker>) -> io::Result<()> {
-        waker.wake()
-    }
-
-    /// Return a new waker.
-    ///
-    /// Used to wake up the main event loop.
-    fn waker(&self) -> Arc<popol::Waker> {
-        self.waker.clone()
-    }
 }
 
-impl Reactor<net::TcpStream> {
+impl<K: PeerKey, C> Reactor<net::TcpStream, K, C> {
     /// Process protocol state machine outputs.
-    fn process<C: Fn(Event)>(
+    fn process<Cb: Fn(Event)>(
         &mut self,
-        outputs: &chan::Receiver<Out>,
+        outputs: &chan::Receiver<Out<K>>,
         local_time: LocalTime,
-        callback: C,
+        callback: Cb,
     ) -> Result<Control, Error> {
+        // Send events buffered while the consumer wasn't keeping up.
+        self.subscriber.flush();
+
         // Note that there may be messages destined for a peer that has since been
         // disconnected.
         for out in outputs.try_iter() {
@@ -290,7 +373,7 @@ impl Reactor<net::TcpStream> {
 
                         peer.queue(msg);
 
-                        if let Err(err) = peer.drain(&mut self.inputs, src) {
+                        if let Err(err) = peer.drain(addr, &mut self.inputs, src) {
                             error!("{}: Write error: {}", addr, err.to_string());
 
                             peer.disconnect().ok();
@@ -301,17 +384,27 @@ impl Reactor<net::TcpStream> {
                         }
                     }
                 }
-                // TODO: Use connection timeout, or handle timeouts in connection manager.
+                // Sockets are keyed by peer: dialing a peer we already have a socket for
+                // would replace the existing connection. The protocol's connection manager
+                // times out the attempt instead.
+                Out::Connect(addr, _timeout) if self.peers.contains_key(&addr) => {
+                    debug!("{}: Ignoring connection attempt: already connected", addr);
+                }
+                // Connection timeouts are handled by the protocol's connection manager.
                 Out::Connect(addr, _timeout) => {
                     trace!("Connecting to {}...", &addr);
 
-                    match self::dial(&addr) {
-                        Ok(stream) => {
+                    match self.dial(&addr.addr()) {
+                        Ok((stream, proxied)) => {
                             trace!("{:#?}", stream);
 
                             self.register_peer(addr, stream, Link::Outbound);
                             self.connecting.insert(addr);
                             self.inputs.push_back(Input::Connecting { addr });
+
+                            if proxied {
+                                self.handshakes.insert(addr, Vec::new());
+                            }
                         }
                         Err(err) => {
                             self.inputs.push_back(Input::Timeout);
@@ -340,7 +433,7 @@ impl Reactor<net::TcpStream> {
                     trace!("Event: {:?}", event);
 
                     callback(event.clone());
-                    self.subscriber.try_send(event).unwrap(); // FIXME
+                    self.subscriber.publish(event);
                 }
                 Out::Shutdown => {
                     info!("Shutdown received");
@@ -352,7 +445,62 @@ impl Reactor<net::TcpStream> {
         Ok(Control::Continue)
     }
 
-    fn handle_readable(&mut self, addr: &net::SocketAddr) {
+    /// Connect to a peer given a remote address. Onion addresses are connected to through
+    /// the onion proxy: returns whether this is the case.
+    fn dial(&self, addr: &net::SocketAddr) -> Result<(net::TcpStream, bool), Error> {
+        match (socks::onion_name(&addr.ip()), self.onion_proxy) {
+            (Some(_), Some(proxy)) => self::dial(&proxy).map(|stream| (stream, true)),
+            (Some(_), None) => Err(Error::Io(io::Error::other(
+                "no proxy configured for onion addresses",
+            ))),
+            (None, _) => self::dial(addr).map(|stream| (stream, false)),
+        }
+    }
+
+    /// Read the onion proxy's reply to our handshake. Once the proxy is connected to the
+    /// peer, the peer is connected.
+    fn handle_handshake(&mut self, addr: &K) {
+        let socket = self.peers.get_mut(addr).unwrap();
+        let reply = self.handshakes.get_mut(addr).unwrap();
+        let mut buf = [0; 64];
+
+        let result = match socket.read_raw(&mut buf) {
+            Ok(0) => Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => {
+                reply.extend(&buf[..n]);
+                socks::reply(reply)
+            }
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(err) => Err(err),
+        }
+        .and_then(|done| match done {
+            Some(_) => socket.local_address().map(Some),
+            None => Ok(None),
+        });
+
+        match result {
+            Ok(Some(local_addr)) => {
+                self.handshakes.remove(addr);
+                self.inputs.push_back(Input::Connected {
+                    addr: *addr,
+                    local_addr,
+                    link: socket.link,
+                });
+            }
+            Ok(None) => {}
+            Err(err) => {
+                error!("{}: Proxy error: {}", addr, err.to_string());
+
+                socket.disconnect().ok();
+                self.unregister_peer(*addr, DisconnectReason::ConnectionError(err.to_string()));
+            }
+        }
+    }
+
+    fn handle_readable(&mut self, addr: &K) {
+        if self.handshakes.contains_key(addr) {
+            return self.handle_handshake(addr);
+        }
         let socket = self.peers.get_mut(&addr).unwrap();
 
         trace!("{}: Socket is readable", addr);
@@ -370,6 +518,9 @@ impl Reactor<net::TcpStream> {
                 Err(encode::Error::Io(err)) if err.kind() == io::ErrorKind::WouldBlock => {
                     break;
                 }
+                Err(encode::Error::UnrecognizedNetworkCommand(cmd)) => {
+                    self.inputs.push_back(Input::Unrecognized(*addr, cmd));
+                }
                 Err(err) => {
                     match err {
                         encode::Error::Io(ref err)
@@ -389,23 +540,38 @@ impl Reactor<net::TcpStream> {
         }
     }
 
-    fn handle_writable(&mut self, addr: &net::SocketAddr, source: &Source) -> io::Result<()> {
+    fn handle_writable(&mut self, addr: &K, source: &Source<K>) -> io::Result<()> {
         trace!("{}: Socket is writable", addr);
 
         let src = self.sources.get_mut(source).unwrap();
         let socket = self.peers.get_mut(&addr).unwrap();
 
         if self.connecting.remove(addr) {
-            let local_addr = socket.local_address()?;
+            if self.handshakes.contains_key(addr) {
+                // Ask the proxy to connect to the peer. The peer is connected once the proxy
+                // replies.
+                let request = socks::request(&addr.addr());
 
-            self.inputs.push_back(Input::Connected {
-                addr: socket.address,
-                local_addr,
-                link: socket.link,
-            });
+                if let Err(err) = request.and_then(|r| socket.write_raw(&r)) {
+                    error!("{}: Proxy error: {}", addr, err.to_string());
+
+                    socket.disconnect().ok();
+                    self.unregister_peer(*addr, DisconnectReason::ConnectionError(err.to_string()));
+
+                    return Ok(());
+                }
+            } else {
+                let local_addr = socket.local_address()?;
+
+                self.inputs.push_back(Input::Connected {
+                    addr: *addr,
+                    local_addr,
+                    link: socket.link,
+                });
+            }
         }
 
-        if let Err(err) = socket.drain(&mut self.inputs, src) {
+        if let Err(err) = socket.drain(*addr, &mut self.inputs, src) {
             error!("{}: Write error: {}", addr, err.to_string());
 
             socket.disconnect().ok();
@@ -448,3 +614,135 @@ fn listen<A: net::ToSocketAddrs>(addr: A) -> Result<net::TcpListener, Error> {
 
     Ok(sock)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A machine that connects to a peer, possibly more than once, and records its inputs.
+    /// It shuts down once connected.
+    struct Dialer {
+        addr: net::SocketAddr,
+        attempts: usize,
+        outputs: chan::Sender<Out>,
+        inputs: chan::Sender<Input>,
+    }
+
+    impl Machine for Dialer {
+        type Peer = net::SocketAddr;
+        type Command = Command;
+
+        fn initialize(&mut self, _time: LocalTime) -> Result<(), Error> {
+            for _ in 0..self.attempts {
+                self.outputs
+                    .send(Out::Connect(self.addr, LocalDuration::from_secs(6)))
+                    .unwrap();
+            }
+            Ok(())
+        }
+
+        fn step(&mut self, input: Input, _time: LocalTime) {
+            if let Input::Connected { .. } = input {
+                self.outputs.send(Out::Shutdown).unwrap();
+            }
+            self.inputs.send(input).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_duplicate_connection() {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let (events, _events) = chan::unbounded();
+        let (_commands, commands) = chan::unbounded();
+        let (outputs, rx) = chan::unbounded();
+        let (inputs, recorded) = chan::unbounded();
+
+        let mut reactor = Reactor::<net::TcpStream>::with(events, commands).unwrap();
+        let dialer = Dialer {
+            addr,
+            attempts: 2,
+            outputs,
+            inputs,
+        };
+        reactor.drive(dialer, &rx, &[], |_| {}).unwrap();
+
+        let inputs = recorded.try_iter().collect::<Vec<_>>();
+
+        assert!(
+            matches!(
+                inputs.as_slice(),
+                [Input::Connecting { addr: a }, Input::Connected { addr: b, .. }]
+                if *a == addr && *b == addr
+            ),
+            "The peer is connected once, and stays connected: {:?}",
+            inputs
+        );
+        assert_eq!(reactor.peers.len(), 1, "The connection is kept");
+
+        listener.set_nonblocking(true).unwrap();
+        listener.accept().expect("The peer was dialed");
+        assert!(listener.accept().is_err(), "The peer was dialed once");
+    }
+
+    #[test]
+    fn test_onion_proxy() {
+        use std::thread;
+
+        let proxy = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy_addr = proxy.local_addr().unwrap();
+        // The OnionCat address of `expyuzz4wqqyqhjn.onion`.
+        let addr = net::SocketAddr::new(
+            net::IpAddr::from([
+                0xfd, 0x87, 0xd8, 0x7e, 0xeb, 0x43, 0x25, 0xdf, 0x8a, 0x67, 0x3c, 0xb4, 0x21, 0x88,
+                0x1d, 0x2d,
+            ]),
+            8333,
+        );
+
+        let proxy = thread::spawn(move || {
+            let (mut conn, _) = proxy.accept().unwrap();
+            let mut request = vec![0; 3 + 5 + 22 + 2];
+
+            conn.read_exact(&mut request).unwrap();
+            // Method selection, and connect reply with an IPv4 bound address.
+            conn.write_all(&[5, 0, 5, 0, 0, 1, 127, 0, 0, 1, 0x20, 0x8d])
+                .unwrap();
+
+            (conn, request)
+        });
+
+        let (events, _events) = chan::unbounded();
+        let (_commands, commands) = chan::unbounded();
+        let (outputs, rx) = chan::unbounded();
+        let (inputs, recorded) = chan::unbounded();
+
+        let mut reactor = Reactor::<net::TcpStream>::with(events, commands).unwrap();
+        reactor.onion_proxy = Some(proxy_addr);
+
+        let dialer = Dialer {
+            addr,
+            attempts: 1,
+            outputs,
+            inputs,
+        };
+        reactor.drive(dialer, &rx, &[], |_| {}).unwrap();
+
+        let (_conn, request) = proxy.join().unwrap();
+        let inputs = recorded.try_iter().collect::<Vec<_>>();
+
+        assert_eq!(&request[..8], &[5, 1, 0, 5, 1, 0, 3, 22]);
+        assert_eq!(&request[8..30], b"expyuzz4wqqyqhjn.onion");
+        assert_eq!(&request[30..], &8333u16.to_be_bytes());
+        assert!(
+            matches!(
+                inputs.as_slice(),
+                [Input::Connecting { addr: a }, Input::Connected { addr: b, .. }]
+                if *a == addr && *b == addr
+            ),
+            "The peer is connected once the proxy is: {:?}",
+            inputs
+        );
+    }
+}
@@ -6,7 +6,6 @@ use std::net;
 
 use bitcoin::consensus::encode::Decodable;
 use bitcoin::consensus::encode::{self, Encodable};
-use bitcoin::network::stream_reader::StreamReader;
 
 use log::*;
 
@@ -16,6 +15,77 @@ use crate::fallible;
 
 /// Maximum peer-to-peer message size.
 const MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+/// Size of a message header, ie. message magic, command, payload size and checksum.
+const MESSAGE_HEADER_SIZE: usize = 24;
+
+/// Reads messages from a stream.
+///
+/// Unlike `bitcoin`'s `StreamReader`, messages with an unrecognized command are
+/// discarded after the error is returned, so that the messages following them can
+/// still be read.
+struct MessageReader<R> {
+    /// Stream to read from.
+    stream: R,
+    /// I/O buffer.
+    data: Vec<u8>,
+    /// Bytes read from the stream, not yet decoded.
+    unparsed: Vec<u8>,
+}
+
+impl<R> Debug for MessageReader<R> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "MessageReader with buffer_size={} and {} unparsed byte(s)",
+            self.data.len(),
+            self.unparsed.len()
+        )
+    }
+}
+
+impl<R: Read> MessageReader<R> {
+    fn new(stream: R, buffer_size: usize) -> Self {
+        Self {
+            stream,
+            data: vec![0; buffer_size],
+            unparsed: Vec::new(),
+        }
+    }
+
+    fn read_next<D: Decodable>(&mut self) -> Result<D, encode::Error> {
+        loop {
+            match encode::deserialize_partial::<D>(&self.unparsed) {
+                // The message is incomplete, read more data.
+                Err(encode::Error::Io(ref err)) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                    let count = self.stream.read(&mut self.data)?;
+
+                    if count > 0 {
+                        self.unparsed.extend(&self.data[..count]);
+                    } else {
+                        return Err(encode::Error::Io(io::ErrorKind::UnexpectedEof.into()));
+                    }
+                }
+                // The command is checked after the payload is read, so the message is
+                // complete, and can be discarded.
+                Err(encode::Error::UnrecognizedNetworkCommand(cmd)) => {
+                    let mut size = [0; 4];
+                    size.copy_from_slice(&self.unparsed[16..20]);
+
+                    let len = MESSAGE_HEADER_SIZE + u32::from_le_bytes(size) as usize;
+                    self.unparsed.drain(..len.min(self.unparsed.len()));
+
+                    return Err(encode::Error::UnrecognizedNetworkCommand(cmd));
+                }
+                Err(err) => return Err(err),
+                Ok((message, index)) => {
+                    self.unparsed.drain(..index);
+
+                    return Ok(message);
+                }
+            }
+        }
+    }
+}
 
 /// Peer-to-peer socket abstraction.
 #[derive(Debug)]
@@ -23,7 +93,7 @@ pub struct Socket<R: Read + Write, M> {
     pub address: net::SocketAddr,
     pub link: Link,
 
-    raw: StreamReader<R>,
+    raw: MessageReader<R>,
     queue: VecDeque<M>,
 }
 
@@ -35,6 +105,17 @@ impl<M> Socket<net::TcpStream, M> {
     pub fn local_address(&self) -> io::Result<net::SocketAddr> {
         self.raw.stream.local_addr()
     }
+
+    /// Write bytes to the underlying stream, bypassing message encoding. Used for
+    /// handshakes that precede the peer-to-peer protocol.
+    pub fn write_raw(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.raw.stream.write_all(buf)
+    }
+
+    /// Read bytes from the underlying stream, bypassing message decoding.
+    pub fn read_raw(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.raw.stream.read(buf)
+    }
 }
 
 impl<M: Encodable + Decodable + Debug> Socket<net::TcpStream, M> {
@@ -46,7 +127,7 @@ impl<M: Encodable + Decodable + Debug> Socket<net::TcpStream, M> {
 impl<R: Read + Write, M: Encodable + Decodable + Debug> Socket<R, M> {
     /// Create a new socket from a `io::Read` and an address pair.
     pub fn from(r: R, address: net::SocketAddr, link: Link) -> Self {
-        let raw = StreamReader::new(r, Some(MAX_MESSAGE_SIZE));
+        let raw = MessageReader::new(r, MAX_MESSAGE_SIZE);
         let queue = VecDeque::new();
 
         Self {
@@ -93,15 +174,17 @@ impl<R: Read + Write, M: Encodable + Decodable + Debug> Socket<R, M> {
         }
     }
 
-    pub fn drain(
+    /// Write the queued messages to the socket, reporting them as sent by the given peer.
+    pub fn drain<K: Copy, C>(
         &mut self,
-        inputs: &mut VecDeque<Input>,
+        peer: K,
+        inputs: &mut VecDeque<Input<K, C>>,
         source: &mut popol::Source,
     ) -> Result<(), encode::Error> {
         while let Some(msg) = self.queue.pop_front() {
             match self.write(&msg) {
                 Ok(n) => {
-                    inputs.push_back(Input::Sent(self.address, n));
+                    inputs.push_back(Input::Sent(peer, n));
                 }
                 Err(encode::Error::Io(err)) if err.kind() == io::ErrorKind::WouldBlock => {
                     source.set(popol::interest::WRITE);
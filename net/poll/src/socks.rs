@@ -0,0 +1,119 @@
+//! SOCKS5 client handshake, used to connect to onion addresses through a Tor proxy.
+//!
+//! Onion addresses are known by their OnionCat encoding, an IPv6 address in the
+//! `fd87:d87e:eb43::/48` range. They aren't routable: instead, the proxy is asked to
+//! connect to the onion service by name. See RFC 1928.
+use std::io;
+use std::net;
+
+/// SOCKS protocol version.
+const VERSION: u8 = 5;
+/// "No authentication" method.
+const NO_AUTH: u8 = 0;
+/// "Connect" command.
+const CONNECT: u8 = 1;
+/// Domain name address type.
+const DOMAIN: u8 = 3;
+/// IPv4 address type.
+const IPV4: u8 = 1;
+/// IPv6 address type.
+const IPV6: u8 = 4;
+/// OnionCat prefix of onion addresses, ie. `fd87:d87e:eb43::/48`.
+const ONIONCAT_PREFIX: [u8; 6] = [0xfd, 0x87, 0xd8, 0x7e, 0xeb, 0x43];
+/// Base32 alphabet, as used in onion names.
+const BASE32: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+/// Get the onion name of an OnionCat address, eg. `expyuzz4wqqyqhjn.onion`. Returns
+/// `None` if the address isn't an onion address.
+pub fn onion_name(ip: &net::IpAddr) -> Option<String> {
+    let octets = match ip {
+        net::IpAddr::V6(ip) if ip.octets()[..6] == ONIONCAT_PREFIX => ip.octets(),
+        _ => return None,
+    };
+    // The remaining 80 bits are the service identifier, encoded 5 bits at a time.
+    let id = octets[6..]
+        .iter()
+        .fold(0u128, |acc, byte| acc << 8 | *byte as u128);
+    let mut name = (0..16)
+        .rev()
+        .map(|i| BASE32[(id >> (i * 5)) as usize & 0x1f] as char)
+        .collect::<String>();
+
+    name.push_str(".onion");
+
+    Some(name)
+}
+
+/// Encode the handshake asking the proxy to connect to the given onion address. This
+/// is the method selection, followed by the connect request, which the proxy processes
+/// once it has selected the method.
+pub fn request(addr: &net::SocketAddr) -> io::Result<Vec<u8>> {
+    let name = onion_name(&addr.ip())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not an onion address"))?;
+    let mut buf = vec![VERSION, 1, NO_AUTH];
+
+    buf.extend([VERSION, CONNECT, 0, DOMAIN, name.len() as u8]);
+    buf.extend(name.as_bytes());
+    buf.extend(addr.port().to_be_bytes());
+
+    Ok(buf)
+}
+
+/// Check the proxy's reply to a [`request`]. Returns the length of the reply once it is
+/// complete, or `None` if more bytes are needed.
+pub fn reply(buf: &[u8]) -> io::Result<Option<usize>> {
+    let error = |msg: String| Err(io::Error::other(msg));
+
+    // Method selection reply.
+    match buf {
+        [] | [_] => return Ok(None),
+        [VERSION, NO_AUTH, ..] => {}
+        [_, method, ..] => return error(format!("proxy selected method {}", method)),
+    }
+    // Connect reply, followed by the address the proxy bound to.
+    let bound = match buf[2..] {
+        [version, ..] if version != VERSION => {
+            return error("invalid connect reply".to_owned());
+        }
+        [_, code, ..] if code != 0 => {
+            return error(format!("proxy failed to connect (code {})", code));
+        }
+        [_, _, _, IPV4, ..] => 4,
+        [_, _, _, IPV6, ..] => 16,
+        [_, _, _, DOMAIN, len, ..] => 1 + len as usize,
+        [_, _, _, DOMAIN] => return Ok(None),
+        [_, _, _, atyp, ..] => return error(format!("unknown address type {}", atyp)),
+        _ => return Ok(None),
+    };
+    let len = 2 + 4 + bound + 2;
+
+    Ok(Some(len).filter(|len| buf.len() >= *len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_onion_name() {
+        let ip = net::IpAddr::from([
+            0xfd, 0x87, 0xd8, 0x7e, 0xeb, 0x43, 0x25, 0xdf, 0x8a, 0x67, 0x3c, 0xb4, 0x21, 0x88,
+            0x1d, 0x2d,
+        ]);
+        assert_eq!(onion_name(&ip).unwrap(), "expyuzz4wqqyqhjn.onion");
+        assert_eq!(onion_name(&net::IpAddr::from([127, 0, 0, 1])), None);
+    }
+
+    #[test]
+    fn test_reply() {
+        assert_eq!(reply(&[5]).unwrap(), None);
+        assert_eq!(reply(&[5, 0, 5, 0, 0]).unwrap(), None);
+        assert_eq!(reply(&[5, 0, 5, 0, 0, 1, 0, 0, 0, 0, 0]).unwrap(), None);
+        assert_eq!(
+            reply(&[5, 0, 5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).unwrap(),
+            Some(12)
+        );
+        assert!(reply(&[5, 0xff]).is_err(), "No acceptable method");
+        assert!(reply(&[5, 0, 5, 4, 0, 1]).is_err(), "Host unreachable");
+    }
+}
@@ -7,6 +7,8 @@ use std::io;
 
 use crossbeam_channel as crossbeam;
 
+use nakamoto_common::block::filter;
+
 use thiserror::Error;
 
 /// An error occuring in peer-to-peer networking code.
@@ -20,6 +22,10 @@ pub enum Error {
     #[error("encode/decode error: {0}")]
     Encode(#[from] encode::Error),
 
+    /// A filter header store error.
+    #[error("filter store error: {0}")]
+    Filters(#[from] filter::Error),
+
     /// A channel send or receive error.
     #[error("channel error: {0}")]
     Channel(Box<dyn std::error::Error + Send + Sync>),
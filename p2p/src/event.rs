@@ -1,10 +1,17 @@
 //! Events generated by the peer-to-peer system.
+use std::collections::VecDeque;
 use std::net;
 
+use crossbeam_channel as chan;
+
 use bitcoin::network::message::NetworkMessage;
 
-use crate::protocol::PeerId;
+use nakamoto_common::block::time::{LocalDuration, TimeOffset};
+use nakamoto_common::block::tree::ImportResult;
+use nakamoto_common::block::{BlockHash, Height};
+
 use crate::protocol::{addrmgr, connmgr, peermgr, spvmgr, syncmgr};
+use crate::protocol::{PeerId, ProtocolState};
 
 /// A peer-to-peer event.
 #[derive(Debug, Clone)]
@@ -23,4 +30,181 @@ pub enum Event {
     PeerManager(peermgr::Event),
     /// An SPV manager event.
     SpvManager(spvmgr::Event),
+    /// The headers below the assume-valid block were fully validated in the background,
+    /// up to the given height.
+    BackgroundValidationComplete(Height),
+    /// A header below the assume-valid block failed background validation. The chain
+    /// we synced can't be trusted.
+    ValidationFailure(Height, BlockHash, String),
+    /// Our clock is out of sync with the network by more than we can adjust for. Header
+    /// validation depends on the time, so this should be fixed by the operator.
+    ClockOutOfSync {
+        /// Median offset of the network time from our clock, in seconds.
+        offset: TimeOffset,
+    },
+    /// A peer completed a handshake over an inbound connection, showing that we are
+    /// reachable. Emitted once, on the first such handshake.
+    InboundReachable,
+    /// No peer completed a handshake over an inbound connection within the given time
+    /// since startup, even though we accept them. We may not be reachable, eg. because of
+    /// a firewall, or missing port forwarding. Emitted at most once.
+    NoInboundConnections(LocalDuration),
+    /// The overall state of the protocol changed. See [`Protocol::state`].
+    ///
+    /// [`Protocol::state`]: crate::protocol::Protocol::state
+    StateChanged(ProtocolState),
+    /// The given number of events were dropped, because the consumer wasn't keeping up.
+    /// See [`Overflow::DropOldest`].
+    EventsDropped(usize),
+}
+
+impl Event {
+    /// Check whether the event is critical, ie. whether missing it could leave a consumer
+    /// with an inconsistent view of the chain. Critical events are never dropped.
+    pub fn is_critical(&self) -> bool {
+        matches!(
+            self,
+            Self::ValidationFailure(..) | Self::ClockOutOfSync { .. }
+        ) || matches!(
+            self,
+            Self::SyncManager(syncmgr::Event::HeadersImported(ImportResult::TipChanged(
+                _,
+                _,
+                reverted
+            ))) if !reverted.is_empty()
+        )
+    }
+}
+
+/// What to do with new events when the consumer isn't keeping up, ie. when the event
+/// channel is full. Only applies to bounded channels.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum Overflow {
+    /// Buffer events until the buffer is as large as the channel, then drop the oldest
+    /// non-critical events. The consumer is notified with [`Event::EventsDropped`].
+    #[default]
+    DropOldest,
+    /// Wait for the consumer to make room in the channel. This blocks the protocol.
+    Block,
+}
+
+/// Publishes events to a consumer, according to an [`Overflow`] policy.
+#[derive(Debug)]
+pub struct Publisher {
+    subscriber: chan::Sender<Event>,
+    overflow: Overflow,
+    /// Events that didn't fit in the channel.
+    pending: VecDeque<Event>,
+    /// Number of events dropped since the consumer was last notified.
+    dropped: usize,
+}
+
+impl Publisher {
+    /// Create a new publisher, sending events on the given channel.
+    pub fn new(subscriber: chan::Sender<Event>, overflow: Overflow) -> Self {
+        Self {
+            subscriber,
+            overflow,
+            pending: VecDeque::new(),
+            dropped: 0,
+        }
+    }
+
+    /// Set the overflow policy.
+    pub fn set_overflow(&mut self, overflow: Overflow) {
+        self.overflow = overflow;
+    }
+
+    /// Publish an event. Events are dropped if the consumer has disconnected.
+    pub fn publish(&mut self, event: Event) {
+        match self.overflow {
+            Overflow::Block => {
+                self.flush();
+                self.subscriber.send(event).ok();
+            }
+            Overflow::DropOldest => {
+                self.pending.push_back(event);
+                self.flush();
+
+                let capacity = self.subscriber.capacity().unwrap_or(usize::MAX);
+
+                while self.pending.len() > capacity {
+                    match self.pending.iter().position(|e| !e.is_critical()) {
+                        Some(ix) => {
+                            self.pending.remove(ix);
+                            self.dropped += 1;
+                        }
+                        // Critical events are kept regardless of the buffer size.
+                        None => break,
+                    }
+                }
+            }
+        }
+    }
+
+    /// Send buffered events, as long as there is room in the channel.
+    pub fn flush(&mut self) {
+        if self.dropped > 0 {
+            match self.subscriber.try_send(Event::EventsDropped(self.dropped)) {
+                Ok(()) | Err(chan::TrySendError::Disconnected(_)) => self.dropped = 0,
+                Err(chan::TrySendError::Full(_)) => return,
+            }
+        }
+        while let Some(event) = self.pending.pop_front() {
+            match self.subscriber.try_send(event) {
+                Ok(()) | Err(chan::TrySendError::Disconnected(_)) => {}
+                Err(chan::TrySendError::Full(event)) => {
+                    self.pending.push_front(event);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_publisher_drop_oldest() {
+        let (tx, rx) = chan::bounded(2);
+        let mut publisher = Publisher::new(tx, Overflow::DropOldest);
+        let peer: PeerId = ([127, 0, 0, 1], 8333).into();
+        let reorg = Event::SyncManager(syncmgr::Event::HeadersImported(ImportResult::TipChanged(
+            Default::default(),
+            1,
+            vec![Default::default()],
+        )));
+
+        // The consumer isn't reading: two events fill the channel, the next two the buffer.
+        for nonce in 0..3 {
+            publisher.publish(Event::Received(peer, NetworkMessage::Ping(nonce)));
+        }
+        publisher.publish(reorg.clone());
+        // The buffer is full, the oldest non-critical events are dropped.
+        publisher.publish(Event::Received(peer, NetworkMessage::Ping(4)));
+        publisher.publish(reorg);
+
+        let received = |rx: &chan::Receiver<Event>| {
+            rx.try_iter()
+                .map(|e| match e {
+                    Event::Received(_, NetworkMessage::Ping(nonce)) => format!("ping {}", nonce),
+                    Event::EventsDropped(count) => format!("dropped {}", count),
+                    e if e.is_critical() => String::from("reorg"),
+                    e => panic!("unexpected event {:?}", e),
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(received(&rx), vec!["ping 0", "ping 1"]);
+
+        publisher.flush();
+        assert_eq!(received(&rx), vec!["dropped 2", "reorg"]);
+
+        publisher.flush();
+        assert_eq!(received(&rx), vec!["reorg"]);
+
+        publisher.flush();
+        assert!(received(&rx).is_empty());
+    }
 }
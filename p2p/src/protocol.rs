@@ -4,27 +4,43 @@ use crossbeam_channel as chan;
 use log::*;
 
 pub mod addrmgr;
+pub mod blockmgr;
 pub mod channel;
 pub mod connmgr;
+pub mod mempool;
+pub mod multiplex;
 pub mod peermgr;
 pub mod pingmgr;
+pub mod reachability;
 pub mod spvmgr;
+pub mod state;
 pub mod syncmgr;
+pub mod throughput;
+pub mod validation;
 
+#[cfg(any(test, fuzzing))]
+pub mod fuzz;
 #[cfg(test)]
 mod tests;
 
 use addrmgr::AddressManager;
-use channel::Channel;
+use blockmgr::BlockManager;
+use channel::{Channel, SetTimeout};
 use connmgr::ConnectionManager;
+use mempool::MemPool;
 use peermgr::PeerManager;
 use pingmgr::PingManager;
+use reachability::Reachability;
 use spvmgr::SpvManager;
 use syncmgr::SyncManager;
+use throughput::Throughput;
+use validation::BackgroundValidation;
 
-use crate::event::Event;
+use crate::error::Error;
+use crate::event::{self, Event};
+use crate::reactor::Machine;
 
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
 use std::fmt::{self, Debug};
 use std::net;
 use std::ops::Range;
@@ -35,11 +51,11 @@ use bitcoin::network::constants::ServiceFlags;
 use bitcoin::network::message::{NetworkMessage, RawNetworkMessage};
 use bitcoin::network::message_blockdata::{GetHeadersMessage, Inventory};
 
-use nakamoto_common::block::filter::Filters;
-use nakamoto_common::block::time::{AdjustedTime, LocalDuration, LocalTime};
+use nakamoto_common::block::filter::{self, Filters};
+use nakamoto_common::block::time::{AdjustedTime, LocalDuration, LocalTime, TimeOffset};
 use nakamoto_common::block::tree::{self, BlockTree, ImportResult};
 use nakamoto_common::block::Transaction;
-use nakamoto_common::block::{BlockHash, Height};
+use nakamoto_common::block::{self, BlockHash, Height, Work};
 use nakamoto_common::network::{self, Network};
 use nakamoto_common::p2p::peer;
 
@@ -48,6 +64,30 @@ use nakamoto_common::p2p::peer;
 pub const PROTOCOL_VERSION: u32 = 70012;
 /// User agent included in `version` messages.
 pub const USER_AGENT: &str = "/nakamoto:0.1.0/";
+/// Commands of messages we can't decode, but which compliant peers may send us.
+/// Messages with these commands are ignored.
+pub const IGNORED_COMMANDS: &[&str] = &[
+    // Compact blocks (BIP 152).
+    "sendcmpct",
+    "cmpctblock",
+    "getblocktxn",
+    "blocktxn",
+    // Bloom filters (BIP 37).
+    "filterload",
+    "filteradd",
+    "filterclear",
+    "merkleblock",
+    // Address relay version 2 (BIP 155).
+    "sendaddrv2",
+    "addrv2",
+    // WTXID-based transaction relay (BIP 339).
+    "wtxidrelay",
+    // Large message size negotiation, used by some forks.
+    "protoconf",
+    "extmsg",
+];
+/// Maximum number of headers returned by [`Command::GetHeaderPage`].
+pub const MAX_HEADER_PAGE: usize = 2000;
 
 /// Block locators. Consists of starting hashes and a stop hash.
 type Locators = (Vec<BlockHash>, BlockHash);
@@ -82,11 +122,93 @@ impl Link {
     }
 }
 
+/// Optional protocol features, which can be enabled or disabled at runtime.
+///
+/// Disabled features are neither advertised to peers during the handshake, nor are
+/// their messages sent or handled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Features(u64);
+
+impl Features {
+    /// No optional features.
+    pub const NONE: Features = Features(0);
+    /// BIP 157/158 compact block filters.
+    pub const COMPACT_FILTERS: Features = Features(1 << 0);
+    /// Serving the compact filters we have to peers, and advertising `NODE_COMPACT_FILTERS`.
+    /// Only has an effect if [`Features::COMPACT_FILTERS`] are enabled.
+    pub const SERVE_FILTERS: Features = Features(1 << 1);
+    /// All supported features.
+    pub const ALL: Features = Features(Self::COMPACT_FILTERS.0 | Self::SERVE_FILTERS.0);
+
+    /// Check whether all the given features are enabled.
+    pub fn has(self, features: Features) -> bool {
+        self.0 & features.0 == features.0
+    }
+
+    /// Enable the given features.
+    pub fn add(&mut self, features: Features) -> Features {
+        self.0 |= features.0;
+        *self
+    }
+
+    /// Disable the given features.
+    pub fn remove(&mut self, features: Features) -> Features {
+        self.0 &= !features.0;
+        *self
+    }
+
+    /// Get the service flags advertised for the given features.
+    pub fn services(self) -> ServiceFlags {
+        let mut services = ServiceFlags::NONE;
+
+        if self.has(Features::COMPACT_FILTERS) {
+            services.add(ServiceFlags::COMPACT_FILTERS);
+        }
+        services
+    }
+}
+
+impl Default for Features {
+    /// Compact filters are enabled by default, but not served.
+    fn default() -> Self {
+        Self::COMPACT_FILTERS
+    }
+}
+
+impl std::ops::BitOr for Features {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
 /// A command or request that can be sent to the protocol.
 #[derive(Debug, Clone)]
 pub enum Command {
     /// Get the tip of the active chain.
     GetTip(chan::Sender<(Height, BlockHeader)>),
+    /// Get a page of at most `count` headers of the active chain, starting at the given
+    /// height, along with the height of the chain. The count is capped to
+    /// [`MAX_HEADER_PAGE`]. The page is empty if the start height is beyond the tip.
+    GetHeaderPage(Height, usize, chan::Sender<(Vec<BlockHeader>, Height)>),
+    /// Get the age of the tip, ie. the time elapsed since its timestamp, according to the
+    /// network-adjusted time. A tip that stays old while we have peers suggests we're stuck.
+    GetTipAge(chan::Sender<LocalDuration>),
+    /// Get the total work of the active chain, along with a human-readable approximation.
+    GetChainWork(chan::Sender<(Work, String)>),
+    /// Get statistics on recent reorgs of the active chain.
+    GetReorgStats(chan::Sender<syncmgr::ReorgStats>),
+    /// Get a summary of the tracked unconfirmed transactions.
+    GetMemPoolInfo(chan::Sender<mempool::MemPoolInfo>),
+    /// Get the recent header and block sync throughput.
+    GetSyncSpeed(chan::Sender<throughput::SyncSpeed>),
+    /// Get the number of messages received from a peer, by command. Returns `None` if
+    /// the peer isn't connected.
+    GetMessageCounts(PeerId, chan::Sender<Option<BTreeMap<String, usize>>>),
+    /// Acknowledge that our clock is out of sync with the network, and resume importing
+    /// headers. See [`Config::halt_on_clock_skew`].
+    AcknowledgeClockSkew,
     /// Get a block from the active chain.
     GetBlock(BlockHash),
     /// Get block filters.
@@ -106,49 +228,110 @@ pub enum Command {
     ),
     /// Submit a transaction to the network.
     SubmitTransaction(Transaction),
+    /// Only sync headers from the given peer, or from any peer if `None`.
+    /// Meant for debugging.
+    PinSyncPeer(Option<PeerId>),
+    /// Fetch the headers of the active chain from height `from` to `to` (inclusive) again
+    /// from the given peer, and import them. Headers that differ from ours replace them
+    /// if they carry more work, as with any fork. Meant for repairing a suspicious range
+    /// without a full resync.
+    RefetchHeaders {
+        /// Peer to fetch the headers from.
+        peer: PeerId,
+        /// Height of the first header to fetch.
+        from: Height,
+        /// Height of the last header to fetch.
+        to: Height,
+    },
     /// Shutdown the protocol.
     Shutdown,
+    /// Inject a message into the protocol, as if it was received from the given peer.
+    /// Only available for testing.
+    #[cfg(any(test, feature = "test-utils"))]
+    InjectMessage(PeerId, NetworkMessage),
 }
 
-/// A protocol input event, parametrized over the network message type.
+/// A protocol input event, parametrized over the type identifying peers, and the type of
+/// commands. A single protocol instance identifies peers by address, but a driver of
+/// several instances may need to tell apart the peers of different instances sharing an
+/// address, see [`multiplex`].
 /// These are input events generated outside of the protocol.
 #[derive(Debug, Clone)]
-pub enum Input {
+pub enum Input<P = PeerId, C = Command> {
     /// Connection attempt underway.
     Connecting {
         /// Remote peer address.
-        addr: net::SocketAddr,
+        addr: P,
     },
     /// New connection with a peer.
     Connected {
         /// Remote peer id.
-        addr: PeerId,
+        addr: P,
         /// Local peer id.
-        local_addr: PeerId,
+        local_addr: net::SocketAddr,
         /// Link direction.
         link: Link,
     },
     /// Disconnected from peer.
-    Disconnected(PeerId, DisconnectReason),
+    Disconnected(P, DisconnectReason),
     /// Received a message from a remote peer.
-    Received(PeerId, RawNetworkMessage),
+    Received(P, RawNetworkMessage),
+    /// Received a message with an unrecognized command from a remote peer.
+    /// The message itself is discarded.
+    Unrecognized(P, String),
     /// Sent a message to a remote peer, of the given size.
-    Sent(PeerId, usize),
+    Sent(P, usize),
     /// An external command has been received.
-    Command(Command),
+    Command(C),
     /// A timeout has been reached.
     Timeout,
 }
 
-/// Output of a state transition (step) of the `Protocol` state machine.
+/// What to do with messages whose command is unrecognized, and not ignored.
+/// See [`Config::ignored_commands`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum UnknownMessagePolicy {
+    /// Ignore the message.
+    Ignore,
+    /// Disconnect the peer that sent the message.
+    Disconnect,
+}
+
+/// Coarse state of the protocol as a whole, derived from the state of its managers.
+/// See [`Protocol::state`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ProtocolState {
+    /// The protocol wasn't initialized yet.
+    Initializing,
+    /// We have no peers, and no addresses to connect to, eg. because the address book is
+    /// being populated from DNS seeds.
+    Bootstrapping,
+    /// We have addresses, or are connecting to peers, but no outbound peer has completed
+    /// a handshake yet.
+    Connecting,
+    /// Our peers have a longer chain than ours, and we're downloading their headers.
+    SyncingHeaders,
+    /// Our headers are in sync with our peers, and requested blocks are being downloaded.
+    SyncingBlocks,
+    /// Our headers are in sync with our peers, and there are no blocks to download.
+    Synced,
+    /// Header import is halted, because our clock is out of sync with the network.
+    /// See [`Config::halt_on_clock_skew`].
+    Paused,
+    /// The protocol was told to shut down.
+    ShuttingDown,
+}
+
+/// Output of a state transition (step) of the `Protocol` state machine, parametrized over
+/// the type identifying peers, like [`Input`].
 #[derive(Debug)]
-pub enum Out {
+pub enum Out<P = PeerId> {
     /// Send a message to a peer.
-    Message(PeerId, RawNetworkMessage),
+    Message(P, RawNetworkMessage),
     /// Connect to a peer.
-    Connect(PeerId, Timeout),
+    Connect(P, Timeout),
     /// Disconnect from a peer.
-    Disconnect(PeerId, DisconnectReason),
+    Disconnect(P, DisconnectReason),
     /// Set a timeout.
     SetTimeout(Timeout),
     /// An event has occured.
@@ -157,7 +340,7 @@ pub enum Out {
     Shutdown,
 }
 
-impl From<Event> for Out {
+impl<P> From<Event> for Out<P> {
     fn from(event: Event) -> Self {
         Out::Event(event)
     }
@@ -174,14 +357,24 @@ pub enum DisconnectReason {
     PeerServices(ServiceFlags),
     /// Peer chain is too far behind.
     PeerHeight(Height),
+    /// Peer chain has too little work compared to ours.
+    PeerChainWork,
     /// Peer magic is invalid.
     PeerMagic(u32),
     /// Peer timed out.
     PeerTimeout,
+    /// Peer connected but didn't complete the handshake in time.
+    PeerHandshakeTimeout,
+    /// Connection attempt to the peer timed out.
+    ConnectionTimeout,
     /// Connection to self was detected.
     SelfConnection,
+    /// Peer is already connected, via this or another address.
+    DuplicateConnection,
     /// Inbound connection limit reached.
     ConnectionLimit,
+    /// Inbound connections are disabled.
+    InboundDisabled,
     /// Error with the underlying connection.
     ConnectionError(String),
     /// Peer was forced to disconnect by external command.
@@ -193,7 +386,12 @@ impl DisconnectReason {
     /// after some time.
     pub fn is_transient(&self) -> bool {
         match self {
-            Self::ConnectionLimit | Self::PeerTimeout | Self::PeerHeight(_) => true,
+            Self::ConnectionLimit
+            | Self::ConnectionTimeout
+            | Self::DuplicateConnection
+            | Self::PeerTimeout
+            | Self::PeerHeight(_)
+            | Self::PeerChainWork => true,
             _ => false,
         }
     }
@@ -206,10 +404,15 @@ impl fmt::Display for DisconnectReason {
             Self::PeerProtocolVersion(_) => write!(f, "peer protocol version mismatch"),
             Self::PeerServices(_) => write!(f, "peer doesn't have the required services"),
             Self::PeerHeight(_) => write!(f, "peer is too far behind"),
+            Self::PeerChainWork => write!(f, "peer chain has too little work"),
             Self::PeerMagic(magic) => write!(f, "received message with invalid magic: {}", magic),
             Self::PeerTimeout => write!(f, "peer timed out"),
+            Self::PeerHandshakeTimeout => write!(f, "peer handshake timed out"),
+            Self::ConnectionTimeout => write!(f, "connection attempt timed out"),
             Self::SelfConnection => write!(f, "detected self-connection"),
+            Self::DuplicateConnection => write!(f, "peer is already connected"),
             Self::ConnectionLimit => write!(f, "inbound connection limit reached"),
+            Self::InboundDisabled => write!(f, "inbound connections are disabled"),
             Self::ConnectionError(err) => write!(f, "connection error: {}", err),
             Self::Command => write!(f, "received external command"),
         }
@@ -260,6 +463,18 @@ pub struct Protocol<T, F, P> {
     params: Params,
     /// Peer whitelist.
     whitelist: Whitelist,
+    /// Enabled protocol features.
+    features: Features,
+    /// Commands of unrecognized messages to ignore.
+    ignored_commands: HashSet<String>,
+    /// What to do with other unrecognized messages.
+    unknown_messages: UnknownMessagePolicy,
+    /// Whether to stop importing headers while our clock is out of sync.
+    halt_on_clock_skew: bool,
+    /// Offset of the network time from our clock, if it's too large to adjust for.
+    clock_skew: Option<TimeOffset>,
+    /// Whether the operator acknowledged that our clock is out of sync.
+    clock_skew_acknowledged: bool,
     /// Peer address manager.
     addrmgr: AddressManager<P, Upstream>,
     /// Blockchain synchronization manager.
@@ -272,6 +487,22 @@ pub struct Protocol<T, F, P> {
     spvmgr: SpvManager<F, Upstream>,
     /// Peer manager.
     peermgr: PeerManager<Upstream>,
+    /// Block download scheduler.
+    blockmgr: BlockManager,
+    /// Unconfirmed transactions.
+    mempool: MemPool,
+    /// Sync throughput.
+    throughput: Throughput,
+    /// Background validation of the assumed-valid region of the chain.
+    validation: BackgroundValidation,
+    /// Whether we're reachable by peers, as inferred from inbound connections.
+    reachability: Reachability,
+    /// Overall protocol state, as last reported.
+    state: ProtocolState,
+    /// Whether the protocol was initialized.
+    initialized: bool,
+    /// Whether the protocol was told to shut down.
+    shutting_down: bool,
     /// Network-adjusted clock.
     clock: AdjustedTime<PeerId>,
     /// Informational name of this protocol instance. Used for logging purposes only.
@@ -295,7 +526,9 @@ pub struct Builder<T, F, P> {
     pub peers: P,
     /// Clock.
     pub clock: AdjustedTime<PeerId>,
-    /// RNG.
+    /// RNG. All randomness of the protocol, including hash map iteration order, derives
+    /// from it: instances built with identically seeded RNGs produce the same outputs
+    /// given the same inputs.
     pub rng: fastrand::Rng,
     /// Configuration.
     pub cfg: Config,
@@ -323,6 +556,9 @@ pub struct Config {
     pub network: network::Network,
     /// Peers to connect to.
     pub connect: Vec<net::SocketAddr>,
+    /// Peers that are always connected to. They are dialed on startup, reconnected to as
+    /// soon as they disconnect, and never disconnected for being unresponsive to pings.
+    pub persistent_peers: Vec<net::SocketAddr>,
     /// Services offered by our peer.
     pub services: ServiceFlags,
     /// Required peer services.
@@ -335,10 +571,49 @@ pub struct Config {
     pub protocol_version: u32,
     /// Our user agent.
     pub user_agent: &'static str,
+    /// Optional protocol features to enable.
+    pub features: Features,
     /// Target outbound peer connections.
     pub target_outbound_peers: usize,
     /// Maximum inbound peer connections.
     pub max_inbound_peers: usize,
+    /// Whether to accept inbound connections. If not, inbound connections are dropped as
+    /// soon as they are established, and we don't advertise an address we can be reached at.
+    pub accept_inbound: bool,
+    /// Maximum number of connections, inbound and outbound combined. Takes precedence
+    /// over the outbound target and the inbound limit.
+    pub max_connections: usize,
+    /// Time to wait for an outbound connection to be established.
+    pub connect_timeout: LocalDuration,
+    /// Time to wait for a connected peer to complete the handshake.
+    pub handshake_timeout: LocalDuration,
+    /// Maximum number of outbound peers in the process of connecting or handshaking.
+    pub max_concurrent_handshakes: usize,
+    /// Networks to connect to, in order of preference. If empty, all reachable networks
+    /// are used, without preference.
+    pub networks: Vec<addrmgr::AddressType>,
+    /// SOCKS5 proxy through which onion addresses are reachable, eg. a Tor daemon. Onion
+    /// addresses are only connected to if this is set.
+    pub onion_proxy: Option<net::SocketAddr>,
+    /// Peers to bootstrap the address book with, if it's empty on startup, eg. because
+    /// DNS seeds are disabled or unreachable. See [`network::Network::fixed_seeds`].
+    pub fixed_seeds: Vec<net::SocketAddr>,
+    /// Commands of unrecognized messages to ignore. These are usually commands of
+    /// standard messages that we don't support. See [`IGNORED_COMMANDS`].
+    pub ignored_commands: HashSet<String>,
+    /// What to do with unrecognized messages whose command isn't ignored.
+    pub unknown_messages: UnknownMessagePolicy,
+    /// Whether to stop importing headers from peers once our clock is found to be out of
+    /// sync with the network, until [`Command::AcknowledgeClockSkew`] is received. Header
+    /// validation depends on the time, so a wrong clock can cause valid headers to be
+    /// rejected.
+    pub halt_on_clock_skew: bool,
+    /// Whether to coalesce the changes made to the active chain within a protocol step,
+    /// eg. during a rapid resync, into a single [`syncmgr::Event::HeadersImported`] event,
+    /// rather than emitting one for every intermediate tip.
+    pub coalesce_reorgs: bool,
+    /// What the reactor does with new events when the event channel is full.
+    pub event_overflow: event::Overflow,
     /// Log target.
     pub target: &'static str,
 }
@@ -349,13 +624,28 @@ impl Default for Config {
             network: network::Network::Mainnet,
             params: Params::new(network::Network::Mainnet.into()),
             connect: Vec::new(),
+            persistent_peers: Vec::new(),
             services: ServiceFlags::NONE,
             required_services: ServiceFlags::NETWORK,
             whitelist: Whitelist::default(),
             protocol_version: PROTOCOL_VERSION,
             target_outbound_peers: connmgr::TARGET_OUTBOUND_PEERS,
             max_inbound_peers: connmgr::MAX_INBOUND_PEERS,
+            accept_inbound: true,
+            max_connections: connmgr::MAX_CONNECTIONS,
+            connect_timeout: connmgr::CONNECTION_TIMEOUT,
+            handshake_timeout: peermgr::HANDSHAKE_TIMEOUT,
+            max_concurrent_handshakes: connmgr::MAX_CONCURRENT_HANDSHAKES,
+            networks: Vec::new(),
+            onion_proxy: None,
+            fixed_seeds: network::Network::Mainnet.fixed_seeds(),
+            ignored_commands: IGNORED_COMMANDS.iter().map(|c| (*c).to_owned()).collect(),
+            unknown_messages: UnknownMessagePolicy::Disconnect,
+            halt_on_clock_skew: false,
+            coalesce_reorgs: false,
+            event_overflow: event::Overflow::default(),
             user_agent: USER_AGENT,
+            features: Features::default(),
             target: "self",
         }
     }
@@ -375,6 +665,7 @@ impl Config {
             connect,
             target,
             params,
+            fixed_seeds: network.fixed_seeds(),
             ..Self::default()
         }
     }
@@ -409,6 +700,19 @@ impl Whitelist {
     }
 }
 
+impl<T: BlockTree, F: Filters, P: peer::Store> Machine for Protocol<T, F, P> {
+    type Peer = PeerId;
+    type Command = Command;
+
+    fn initialize(&mut self, time: LocalTime) -> Result<(), Error> {
+        Protocol::initialize(self, time).map_err(Error::from)
+    }
+
+    fn step(&mut self, input: Input, time: LocalTime) {
+        Protocol::step(self, input, time)
+    }
+}
+
 impl<T: BlockTree, F: Filters, P: peer::Store> Protocol<T, F, P> {
     /// Construct a new protocol instance.
     pub fn new(
@@ -423,17 +727,54 @@ impl<T: BlockTree, F: Filters, P: peer::Store> Protocol<T, F, P> {
         let Config {
             network,
             connect,
+            persistent_peers,
             services,
             whitelist,
             protocol_version,
             target_outbound_peers,
             max_inbound_peers,
+            accept_inbound,
+            max_connections,
+            connect_timeout,
+            handshake_timeout,
+            max_concurrent_handshakes,
+            networks,
+            onion_proxy,
+            fixed_seeds,
+            ignored_commands,
+            unknown_messages,
+            halt_on_clock_skew,
+            coalesce_reorgs,
+            // Only used by the reactor.
+            event_overflow: _,
             user_agent,
             required_services,
+            features,
             target,
             params,
         } = config;
 
+        // Don't advertise services for features that are disabled.
+        let services = {
+            let mut disabled = Features::ALL;
+            disabled.remove(features);
+
+            let mut services =
+                ServiceFlags::from(services.as_u64() & !disabled.services().as_u64());
+
+            // Advertise compact filters if we serve them.
+            if features.has(Features::COMPACT_FILTERS | Features::SERVE_FILTERS) {
+                services.add(ServiceFlags::COMPACT_FILTERS);
+            }
+            services
+        };
+        // Only prefer peers offering services for the features we've enabled.
+        let preferred_services = if features.has(Features::COMPACT_FILTERS) {
+            syncmgr::REQUIRED_SERVICES | spvmgr::REQUIRED_SERVICES
+        } else {
+            syncmgr::REQUIRED_SERVICES
+        };
+
         let upstream = Upstream::new(network, protocol_version, target, upstream);
 
         let syncmgr = SyncManager::new(
@@ -441,6 +782,7 @@ impl<T: BlockTree, F: Filters, P: peer::Store> Protocol<T, F, P> {
                 max_message_headers: syncmgr::MAX_MESSAGE_HEADERS,
                 request_timeout: syncmgr::REQUEST_TIMEOUT,
                 params: params.clone(),
+                coalesce_reorgs,
             },
             rng.clone(),
             upstream.clone(),
@@ -450,13 +792,19 @@ impl<T: BlockTree, F: Filters, P: peer::Store> Protocol<T, F, P> {
             connmgr::Config {
                 target_outbound_peers,
                 max_inbound_peers,
+                accept_inbound,
+                max_connections,
                 retry: connect,
+                persistent: persistent_peers.clone(),
                 required_services,
-                // Include services required by all sub-protocols.
-                preferred_services: syncmgr::REQUIRED_SERVICES | spvmgr::REQUIRED_SERVICES,
+                // Include services required by all enabled sub-protocols.
+                preferred_services,
+                connect_timeout,
+                max_concurrent_handshakes,
             },
+            rng.clone(),
         );
-        let pingmgr = PingManager::new(rng.clone(), upstream.clone());
+        let pingmgr = PingManager::new(persistent_peers, rng.clone(), upstream.clone());
         let spvmgr = SpvManager::new(
             spvmgr::Config::default(),
             rng.clone(),
@@ -470,12 +818,19 @@ impl<T: BlockTree, F: Filters, P: peer::Store> Protocol<T, F, P> {
                 required_services,
                 services,
                 user_agent,
+                handshake_timeout,
+                accept_inbound,
             },
             rng.clone(),
             upstream.clone(),
         );
         let addrmgr = AddressManager::new(
-            addrmgr::Config { required_services },
+            addrmgr::Config {
+                required_services,
+                networks,
+                onion_proxy,
+                fixed_seeds,
+            },
             rng.clone(),
             peers,
             upstream.clone(),
@@ -486,6 +841,12 @@ impl<T: BlockTree, F: Filters, P: peer::Store> Protocol<T, F, P> {
             network,
             protocol_version,
             whitelist,
+            features,
+            ignored_commands,
+            unknown_messages,
+            halt_on_clock_skew,
+            clock_skew: None,
+            clock_skew_acknowledged: false,
             target,
             params,
             clock,
@@ -495,6 +856,14 @@ impl<T: BlockTree, F: Filters, P: peer::Store> Protocol<T, F, P> {
             pingmgr,
             spvmgr,
             peermgr,
+            blockmgr: BlockManager::new(rng.clone()),
+            mempool: MemPool::new(),
+            throughput: Throughput::new(),
+            validation: BackgroundValidation::new(),
+            reachability: Reachability::new(),
+            state: ProtocolState::Initializing,
+            initialized: false,
+            shutting_down: false,
             last_tick: LocalTime::default(),
             rng,
             upstream,
@@ -502,22 +871,93 @@ impl<T: BlockTree, F: Filters, P: peer::Store> Protocol<T, F, P> {
     }
 
     /// Initialize the protocol. Called once before any event is sent to the state machine.
-    pub fn initialize(&mut self, time: LocalTime) {
+    /// Fails if the filter header chain couldn't be checked against the block chain.
+    pub fn initialize(&mut self, time: LocalTime) -> Result<(), filter::Error> {
         self.clock.set_local_time(time);
         self.syncmgr.initialize(time, &self.tree);
+
+        // Peers we're told to connect to take the place of the address book.
+        if self.connmgr.config.retry.is_empty() {
+            self.addrmgr.bootstrap();
+        }
         self.connmgr
             .initialize::<P, AddressManager<P, Channel>>(time, &mut self.addrmgr);
-        self.spvmgr.initialize(time, &self.tree);
+
+        if self.features.has(Features::COMPACT_FILTERS) {
+            self.spvmgr.initialize(time, &self.tree)?;
+        }
+        if self.connmgr.config.accept_inbound {
+            self.reachability.initialize(time);
+            self.upstream.set_timeout(reachability::INBOUND_WINDOW);
+        }
+        self.initialized = true;
+        self.update_state();
+
+        Ok(())
+    }
+
+    /// Get the overall state of the protocol. Changes are reported with
+    /// [`Event::StateChanged`].
+    pub fn state(&self) -> ProtocolState {
+        self.state
+    }
+
+    /// Get the connected peers known to have the given block, because they announced it.
+    pub fn peers_with_block(&self, hash: &BlockHash) -> Vec<PeerId> {
+        self.blockmgr.peers_with_block(hash)
+    }
+
+    /// Export the transient protocol state, eg. to hand it over to a new process.
+    /// See the [`state`] module for what is and isn't exported.
+    pub fn export_state(&self) -> state::State {
+        let mut peers = self.peermgr.export();
+        let mut requests = self.syncmgr.export_requests();
+
+        for peer in peers.iter_mut() {
+            peer.misbehavior = self.syncmgr.misbehavior(&peer.addr).unwrap_or_default();
+        }
+        peers.sort_by_key(|p| p.addr);
+        requests.sort_by_key(|r| r.addr);
+
+        state::State { peers, requests }
+    }
+
+    /// Restore a state exported by another protocol instance. The sockets of the
+    /// restored peers are expected to be connected already. This should be called before
+    /// [`Protocol::initialize`], so that no new connections are made in their place.
+    pub fn restore_state(&mut self, state: state::State, time: LocalTime) {
+        // Requests are restored first, so that none are sent in their place once the
+        // peers are negotiated.
+        for request in state.requests {
+            self.syncmgr.restore_request(request, time);
+        }
+        for peer in state.peers {
+            let (addr, local_addr, link) = (peer.addr, peer.local_addr, peer.link);
+            let misbehavior = peer.misbehavior;
+
+            self.addrmgr.peer_connected(&addr, time);
+            self.connmgr.peer_connected(addr, local_addr, link, time);
+
+            self.peermgr.restore(peer, time);
+            self.peer_negotiated(addr, time);
+            self.syncmgr.restore_peer(&addr, misbehavior);
+        }
     }
 
     /// Process the next input and advance the state machine by one step.
     pub fn step(&mut self, input: Input, local_time: LocalTime) {
         self.tick(local_time);
+        self.syncmgr.begin_step(&self.tree);
 
         match input {
             Input::Connecting { addr } => {
                 self.addrmgr.peer_attempted(&addr, local_time);
             }
+            Input::Connected { addr, .. } if self.connmgr.is_connected(&addr) => {
+                debug!(target: self.target, "{}: Dropping duplicate connection", addr);
+
+                self.connmgr.peer_reconnected(addr);
+            }
             Input::Connected {
                 addr,
                 local_addr,
@@ -533,6 +973,11 @@ impl<T: BlockTree, F: Filters, P: peer::Store> Protocol<T, F, P> {
                 self.peermgr
                     .peer_connected(addr, local_addr, link, height, local_time);
             }
+            Input::Disconnected(addr, DisconnectReason::DuplicateConnection)
+                if self.connmgr.duplicate_disconnected(&addr) =>
+            {
+                debug!(target: self.target, "{}: Duplicate connection dropped", addr);
+            }
             Input::Disconnected(addr, reason) => {
                 debug!(target: self.target, "{}: Disconnected: {}", addr, reason);
 
@@ -540,22 +985,63 @@ impl<T: BlockTree, F: Filters, P: peer::Store> Protocol<T, F, P> {
                 self.syncmgr.peer_disconnected(&addr);
                 self.addrmgr.peer_disconnected(&addr, reason);
                 self.connmgr
-                    .peer_disconnected::<P, AddressManager<P, Channel>>(&addr, &self.addrmgr);
+                    .peer_disconnected::<P, AddressManager<P, Channel>>(
+                        &addr,
+                        &self.addrmgr,
+                        local_time,
+                    );
                 self.pingmgr.peer_disconnected(&addr);
                 self.peermgr.peer_disconnected(&addr);
+                self.blockmgr.peer_disconnected(&addr);
+                self.request_blocks(local_time);
             }
             Input::Received(addr, msg) => {
+                self.peermgr.received_message(&addr, msg.cmd());
                 self.upstream
                     .event(Event::Received(addr, msg.payload.clone()));
                 self.receive(addr, msg);
             }
+            Input::Unrecognized(addr, cmd) => {
+                self.peermgr.received_message(&addr, &cmd);
+
+                if let Some(feature) = peermgr::Feature::from_cmd(&cmd) {
+                    self.received_feature(addr, feature);
+                } else if cmd == "cmpctblock" {
+                    debug!(target: self.target, "{}: Falling back from compact block", addr);
+
+                    self.syncmgr
+                        .received_compact_block(addr, &self.clock, &self.tree);
+                } else if self.ignored_commands.contains(&cmd) {
+                    debug!(target: self.target, "{}: Ignoring {:?} message", addr, cmd);
+                } else {
+                    match self.unknown_messages {
+                        UnknownMessagePolicy::Ignore => {
+                            debug!(
+                                target: self.target,
+                                "{}: Ignoring unknown {:?} message", addr, cmd
+                            );
+                        }
+                        UnknownMessagePolicy::Disconnect => {
+                            debug!(
+                                target: self.target,
+                                "{}: Received unknown {:?} message", addr, cmd
+                            );
+                            self.disconnect(
+                                addr,
+                                DisconnectReason::PeerMisbehaving("unknown message received"),
+                            );
+                        }
+                    }
+                }
+            }
             Input::Sent(_addr, _msg) => {}
             Input::Command(cmd) => match cmd {
                 Command::Connect(addr) => {
                     debug!(target: self.target, "Received command: Connect({})", addr);
 
                     self.whitelist.addr.insert(addr.ip());
-                    self.connmgr.connect::<P, AddressManager<P, Channel>>(&addr);
+                    self.connmgr
+                        .connect::<P, AddressManager<P, Channel>>(&addr, local_time);
                 }
                 Command::Disconnect(addr) => {
                     debug!(target: self.target, "Received command: Disconnect({})", addr);
@@ -598,25 +1084,107 @@ impl<T: BlockTree, F: Filters, P: peer::Store> Protocol<T, F, P> {
 
                     reply.send((height, header)).ok();
                 }
+                Command::GetHeaderPage(start, count, reply) => {
+                    let height = self.tree.height();
+                    let count = count.min(MAX_HEADER_PAGE) as Height;
+                    let end = start.saturating_add(count).min(height + 1);
+                    let page = if start < end {
+                        self.tree.range(start..end).collect()
+                    } else {
+                        Vec::new()
+                    };
+                    reply.send((page, height)).ok();
+                }
+                Command::GetTipAge(reply) => {
+                    let (_, tip) = self.tree.tip();
+                    let age = self.clock.get().saturating_sub(tip.time);
+
+                    reply.send(LocalDuration::from_secs(age as u64)).ok();
+                }
+                Command::GetChainWork(reply) => {
+                    let work = self.tree.chain_work();
+
+                    reply.send((work, block::format_work(work))).ok();
+                }
+                Command::GetReorgStats(reply) => {
+                    let stats = self.syncmgr.reorg_stats(local_time);
+
+                    reply.send(stats).ok();
+                }
+                Command::GetMemPoolInfo(reply) => {
+                    reply.send(self.mempool.info()).ok();
+                }
+                Command::GetMessageCounts(addr, reply) => {
+                    reply
+                        .send(self.peermgr.received_messages(&addr).cloned())
+                        .ok();
+                }
+                Command::AcknowledgeClockSkew => {
+                    self.clock_skew_acknowledged = true;
+                }
+                Command::GetSyncSpeed(reply) => {
+                    reply.send(self.throughput.speed(local_time)).ok();
+                }
                 Command::GetFilters(range) => {
                     debug!(target: self.target,
                         "Received command: GetFilters({}..{})", range.start, range.end);
 
-                    self.spvmgr.get_cfilters(range, &self.tree);
+                    if self.features.has(Features::COMPACT_FILTERS) {
+                        self.spvmgr.get_cfilters(range, &self.tree);
+                    } else {
+                        warn!(target: self.target, "Ignoring command: compact filters are disabled");
+                    }
                 }
                 Command::GetBlock(hash) => {
-                    self.query(NetworkMessage::GetData(vec![Inventory::Block(hash)]), |p| {
-                        p.services.has(ServiceFlags::NETWORK)
-                    });
+                    let height = self.tree.get_block(&hash).map(|(h, _)| h);
+                    let priority = blockmgr::Priority::of(height, self.tree.height());
+
+                    self.blockmgr.request(hash, priority);
+                    self.request_blocks(local_time);
                 }
                 Command::SubmitTransaction(tx) => {
                     debug!(target: self.target, "Received command: SubmitTransaction(..)");
 
+                    self.mempool.insert(tx.clone());
                     self.query(NetworkMessage::Tx(tx), |p| p.relay);
                 }
+                Command::PinSyncPeer(peer) => {
+                    debug!(target: self.target, "Received command: PinSyncPeer({:?})", peer);
+
+                    self.syncmgr.pin(peer, local_time, &self.tree);
+                }
+                Command::RefetchHeaders { peer, from, to } => {
+                    debug!(
+                        target: self.target,
+                        "Received command: RefetchHeaders({}, {}..={})", peer, from, to
+                    );
+
+                    if !self.syncmgr.refetch(peer, from, to, local_time, &self.tree) {
+                        warn!(
+                            target: self.target,
+                            "Ignoring command: can't refetch headers {}..={} from {}", from, to, peer
+                        );
+                    }
+                }
                 Command::Shutdown => {
+                    self.shutting_down = true;
                     self.upstream.push(Out::Shutdown);
                 }
+                #[cfg(any(test, feature = "test-utils"))]
+                Command::InjectMessage(addr, payload) => {
+                    debug!(
+                        target: self.target,
+                        "Received command: InjectMessage({}, {:?})", addr, payload.cmd()
+                    );
+
+                    let msg = RawNetworkMessage {
+                        magic: self.network.magic(),
+                        payload,
+                    };
+                    self.upstream
+                        .event(Event::Received(addr, msg.payload.clone()));
+                    self.receive(addr, msg);
+                }
             },
             Input::Timeout => {
                 trace!(target: self.target, "Received timeout");
@@ -625,11 +1193,51 @@ impl<T: BlockTree, F: Filters, P: peer::Store> Protocol<T, F, P> {
                     .received_timeout::<P, AddressManager<P, Channel>>(local_time, &self.addrmgr);
                 self.syncmgr.received_timeout(local_time, &self.tree);
                 self.pingmgr.received_timeout(local_time);
+                self.blockmgr.received_timeout(local_time);
+                self.request_blocks(local_time);
                 self.addrmgr.received_timeout(local_time);
                 self.peermgr.received_timeout(local_time);
-                self.spvmgr.received_timeout(local_time, &self.tree);
+
+                if self.features.has(Features::COMPACT_FILTERS) {
+                    self.spvmgr.received_timeout(local_time, &self.tree);
+                }
+            }
+        };
+        self.syncmgr.end_step();
+        self.update_state();
+    }
+
+    /// Derive the overall protocol state, and report it if it changed.
+    fn update_state(&mut self) {
+        let state = if self.shutting_down {
+            ProtocolState::ShuttingDown
+        } else if !self.initialized {
+            ProtocolState::Initializing
+        } else if self.halt_on_clock_skew
+            && self.clock_skew.is_some()
+            && !self.clock_skew_acknowledged
+        {
+            ProtocolState::Paused
+        } else if self.peermgr.outbound().next().is_none() {
+            if self.connmgr.is_connecting() || !self.addrmgr.is_empty() {
+                ProtocolState::Connecting
+            } else {
+                ProtocolState::Bootstrapping
             }
+        } else if matches!(self.syncmgr.best_height(), Some(h) if h > self.tree.height()) {
+            ProtocolState::SyncingHeaders
+        } else if !self.blockmgr.is_idle() {
+            ProtocolState::SyncingBlocks
+        } else {
+            ProtocolState::Synced
         };
+
+        if state != self.state {
+            debug!(target: self.target, "State changed: {:?} -> {:?}", self.state, state);
+
+            self.state = state;
+            self.upstream.event(Event::StateChanged(state));
+        }
     }
 
     /// Send a message to a random peer. Returns the peer id.
@@ -660,6 +1268,17 @@ impl<T: BlockTree, F: Filters, P: peer::Store> Protocol<T, F, P> {
         // The local time is set from outside the protocol.
         self.clock.set_local_time(local_time);
 
+        if let Some(event) = self.validation.step(&self.tree, &self.clock) {
+            self.upstream.event(event);
+        }
+        if let Some(event) = self.reachability.tick(local_time) {
+            warn!(
+                target: self.target,
+                "No inbound connections in the last {}, we may not be reachable", reachability::INBOUND_WINDOW
+            );
+            self.upstream.event(event);
+        }
+
         if local_time - self.last_tick >= LocalDuration::from_secs(30) {
             let (tip, _) = self.tree.tip();
             let height = self.tree.height();
@@ -697,6 +1316,86 @@ impl<T: BlockTree, F: Filters, P: peer::Store> Protocol<T, F, P> {
         }
     }
 
+    /// Called when a peer completed the handshake, or was restored.
+    fn peer_negotiated(&mut self, addr: PeerId, now: LocalTime) {
+        let (height, services, link, time_offset, version) =
+            match self.peermgr.peers().find(|p| p.address() == addr) {
+                Some(p) => (p.height, p.services, p.conn.link, p.time_offset, p.version),
+                None => return,
+            };
+        self.clock.record_offset(addr, time_offset);
+
+        let skew = self.clock.skew();
+        if let (None, Some(offset)) = (self.clock_skew, skew) {
+            warn!(
+                target: self.target,
+                "Local clock is out of sync with the network by {} second(s)", offset
+            );
+            self.upstream.event(Event::ClockOutOfSync { offset });
+        }
+        self.clock_skew = skew;
+
+        if let Some(event) = self.reachability.peer_negotiated(link) {
+            info!(target: self.target, "{}: Inbound connection negotiated, we are reachable", addr);
+            self.upstream.event(event);
+        }
+        self.addrmgr.peer_negotiated(&addr, services, link, now);
+        self.pingmgr.peer_negotiated(addr, version, now);
+        self.connmgr
+            .peer_negotiated::<P, AddressManager<P, Channel>>(addr, services, &self.addrmgr, now);
+
+        if self.features.has(Features::COMPACT_FILTERS) {
+            self.spvmgr
+                .peer_negotiated(addr, height, services, link, &self.clock, &self.tree);
+        }
+        self.syncmgr
+            .peer_negotiated(addr, height, services, link, &self.clock, &self.tree);
+        self.request_blocks(now);
+    }
+
+    /// Request queued blocks from our peers, highest priority first, as long as there is
+    /// capacity for more requests.
+    fn request_blocks(&mut self, now: LocalTime) {
+        let pinned = self.syncmgr.pinned();
+        let peers = self
+            .peermgr
+            .outbound()
+            .filter(|p| {
+                p.services.has(ServiceFlags::NETWORK)
+                    && (pinned.is_none() || pinned == Some(p.address()))
+            })
+            .map(|p| p.address())
+            .collect::<Vec<_>>();
+
+        if peers.is_empty() {
+            return;
+        }
+        while let Some((hash, priority)) = self.blockmgr.next_request() {
+            // Prefer the peers that announced the block, if any.
+            let announced = self
+                .blockmgr
+                .peers_with_block(&hash)
+                .into_iter()
+                .filter(|p| peers.contains(p))
+                .collect::<Vec<_>>();
+            let candidates = if announced.is_empty() {
+                &peers
+            } else {
+                &announced
+            };
+            let addr = candidates[self.rng.usize(..candidates.len())];
+
+            debug!(
+                target: self.target,
+                "{}: Requesting block {} ({:?})", addr, hash, priority
+            );
+            self.upstream
+                .message(addr, NetworkMessage::GetData(vec![Inventory::Block(hash)]))
+                .set_timeout(blockmgr::REQUEST_TIMEOUT);
+            self.blockmgr.sent(addr, now);
+        }
+    }
+
     fn receive(&mut self, addr: PeerId, msg: RawNetworkMessage) {
         let now = self.clock.local_time();
         let cmd = msg.cmd();
@@ -716,6 +1415,11 @@ impl<T: BlockTree, F: Filters, P: peer::Store> Protocol<T, F, P> {
             addr, cmd
         );
 
+        let filters = self.features.has(Features::COMPACT_FILTERS);
+        let serving = self
+            .features
+            .has(Features::COMPACT_FILTERS | Features::SERVE_FILTERS);
+
         match msg.payload {
             NetworkMessage::Version(msg) => {
                 let height = self.tree.height();
@@ -724,50 +1428,57 @@ impl<T: BlockTree, F: Filters, P: peer::Store> Protocol<T, F, P> {
                     .received_version(&addr, msg, height, now, &mut self.addrmgr);
             }
             NetworkMessage::Verack => {
-                if let Some(peer) = self.peermgr.received_verack(&addr, now) {
-                    self.clock.record_offset(peer.address(), peer.time_offset);
-                    self.addrmgr
-                        .peer_negotiated(&addr, peer.services, peer.conn.link, now);
-                    self.pingmgr.peer_negotiated(peer.address(), now);
-                    self.connmgr.peer_negotiated(peer.address(), peer.services);
-                    self.spvmgr.peer_negotiated(
-                        peer.address(),
-                        peer.height,
-                        peer.services,
-                        peer.conn.link,
-                        &self.clock,
-                        &self.tree,
-                    );
-                    self.syncmgr.peer_negotiated(
-                        peer.address(),
-                        peer.height,
-                        peer.services,
-                        peer.conn.link,
-                        &self.clock,
-                        &self.tree,
-                    );
+                if self.peermgr.received_verack(&addr, now).is_some() {
+                    self.peer_negotiated(addr, now);
                 }
             }
             NetworkMessage::Ping(nonce) => {
                 self.pingmgr.received_ping(addr, nonce);
             }
             NetworkMessage::Pong(nonce) => {
-                self.pingmgr.received_pong(addr, nonce, now);
+                if let Some(latency) = self.pingmgr.received_pong(addr, nonce, now) {
+                    self.syncmgr.peer_latency(&addr, latency);
+                }
             }
             NetworkMessage::Headers(headers) => {
-                match self
-                    .syncmgr
-                    .received_headers(&addr, headers, &self.clock, &mut self.tree)
+                if self.halt_on_clock_skew
+                    && self.clock_skew.is_some()
+                    && !self.clock_skew_acknowledged
                 {
+                    debug!(target: self.target, "{}: Ignoring headers: clock is out of sync", addr);
+                    return;
+                }
+                let height = self.tree.height();
+
+                let result =
+                    self.syncmgr
+                        .received_headers(&addr, headers, &self.clock, &mut self.tree);
+
+                if let Ok(ImportResult::TipChanged(_, tip, reverted)) = &result {
+                    let fork_height = height.saturating_sub(reverted.len() as Height);
+
+                    self.throughput
+                        .headers_imported((tip - fork_height) as usize, now);
+                }
+                match result {
                     Err(e) => log::error!("Error receiving headers: {}", e),
-                    Ok(ImportResult::TipChanged(_, _, reverted)) if !reverted.is_empty() => {
-                        // By rolling back the filter headers, we will trigger
-                        // a re-download of the missing headers, which should result
-                        // in us having the new headers.
-                        self.spvmgr.rollback(reverted.len()).unwrap();
-                        self.spvmgr.sync(&self.tree);
-                    }
-                    Ok(ImportResult::TipChanged(_, _, _)) => {
+                    Ok(ImportResult::TipChanged(_, _, reverted)) if filters => {
+                        if !reverted.is_empty() {
+                            // By rolling back the filter headers to the fork point, we will
+                            // trigger a re-download of the missing headers, which should result
+                            // in us having the new headers.
+                            let fork_height = height.saturating_sub(reverted.len() as Height);
+
+                            if let Err(e) = self.spvmgr.rollback(fork_height) {
+                                log::error!(
+                                    target: self.target,
+                                    "Error rolling back filter headers to height {}: {}",
+                                    fork_height,
+                                    e
+                                );
+                                return;
+                            }
+                        }
                         // Trigger a sync, since we're going to have to catch up on the new block
                         // header(s). This is not required, but reduces latency.
                         self.spvmgr.sync(&self.tree);
@@ -787,16 +1498,33 @@ impl<T: BlockTree, F: Filters, P: peer::Store> Protocol<T, F, P> {
                 );
             }
             NetworkMessage::Block(block) => {
+                let requested = self.blockmgr.received(&block.block_hash());
+
+                self.throughput.block_received(block.get_size(), now);
+
+                self.mempool.remove_confirmed(&block);
                 self.syncmgr.received_block(&addr, block, &self.tree);
+
+                if requested {
+                    self.request_blocks(now);
+                }
+            }
+            NetworkMessage::Tx(tx) => {
+                self.mempool.insert(tx);
             }
             NetworkMessage::Inv(inventory) => {
+                for inv in &inventory {
+                    if let Inventory::Block(hash) = inv {
+                        self.blockmgr.announced(addr, *hash);
+                    }
+                }
                 // Receive an `inv` message. This will happen if we are out of sync with a
                 // peer. And blocks are being announced. Otherwise, we expect to receive a
                 // `headers` message.
                 self.syncmgr
                     .received_inv(addr, inventory, &self.clock, &self.tree);
             }
-            NetworkMessage::CFHeaders(msg) => {
+            NetworkMessage::CFHeaders(msg) if filters => {
                 match self.spvmgr.received_cfheaders(&addr, msg, &self.tree) {
                     Err(spvmgr::Error::InvalidMessage { reason, .. }) => {
                         self.disconnect(addr, DisconnectReason::PeerMisbehaving(reason))
@@ -804,15 +1532,18 @@ impl<T: BlockTree, F: Filters, P: peer::Store> Protocol<T, F, P> {
                     _ => {}
                 }
             }
-            NetworkMessage::GetCFHeaders(msg) => {
-                match self.spvmgr.received_getcfheaders(&addr, msg, &self.tree) {
+            NetworkMessage::GetCFHeaders(msg) if serving => {
+                match self
+                    .spvmgr
+                    .received_getcfheaders(&addr, msg, now, &self.tree)
+                {
                     Err(spvmgr::Error::InvalidMessage { reason, .. }) => {
                         self.disconnect(addr, DisconnectReason::PeerMisbehaving(reason))
                     }
                     _ => {}
                 }
             }
-            NetworkMessage::CFilter(msg) => {
+            NetworkMessage::CFilter(msg) if filters => {
                 match self.spvmgr.received_cfilter(&addr, msg, &self.tree) {
                     Err(spvmgr::Error::InvalidMessage { reason, .. }) => {
                         self.disconnect(addr, DisconnectReason::PeerMisbehaving(reason))
@@ -820,8 +1551,30 @@ impl<T: BlockTree, F: Filters, P: peer::Store> Protocol<T, F, P> {
                     _ => {}
                 }
             }
-            NetworkMessage::GetCFilters(msg) => {
-                self.spvmgr.received_getcfilters(&addr, msg, &self.tree);
+            NetworkMessage::GetCFilters(msg) if serving => {
+                match self
+                    .spvmgr
+                    .received_getcfilters(&addr, msg, now, &self.tree)
+                {
+                    Err(spvmgr::Error::InvalidMessage { reason, .. }) => {
+                        self.disconnect(addr, DisconnectReason::PeerMisbehaving(reason))
+                    }
+                    _ => {}
+                }
+            }
+            NetworkMessage::GetCFCheckpt(msg) if serving => {
+                match self
+                    .spvmgr
+                    .received_getcfcheckpt(&addr, msg, now, &self.tree)
+                {
+                    Err(spvmgr::Error::InvalidMessage { reason, .. }) => {
+                        self.disconnect(addr, DisconnectReason::PeerMisbehaving(reason))
+                    }
+                    _ => {}
+                }
+            }
+            NetworkMessage::SendHeaders => {
+                self.received_feature(addr, peermgr::Feature::SendHeaders);
             }
             NetworkMessage::Addr(addrs) => {
                 self.addrmgr.received_addr(addr, addrs);
@@ -835,6 +1588,15 @@ impl<T: BlockTree, F: Filters, P: peer::Store> Protocol<T, F, P> {
         }
     }
 
+    fn received_feature(&mut self, addr: PeerId, feature: peermgr::Feature) {
+        if !self.peermgr.received_feature(&addr, feature) {
+            debug!(
+                target: self.target,
+                "{}: Ignoring {:?} at this stage of the handshake", addr, feature
+            );
+        }
+    }
+
     fn disconnect(&mut self, addr: PeerId, reason: DisconnectReason) {
         debug!(target: self.target, "{}: Disconnecting peer: {}", addr, reason);
 
@@ -25,6 +25,8 @@ pub const IDLE_TIMEOUT: LocalDuration = LocalDuration::from_mins(30);
 const MAX_GETADDR_ADDRESSES: usize = 8;
 /// Maximum number of addresses we store for a given address range.
 const MAX_RANGE_SIZE: usize = 256;
+/// IPv6 prefix under which onion addresses are encoded.
+const ONIONCAT_PREFIX: [u16; 3] = [0xfd87, 0xd87e, 0xeb43];
 
 /// Address manager event emission.
 pub trait Events {
@@ -56,6 +58,8 @@ pub enum Event {
     },
     /// A new peer address was discovered.
     AddressDiscovered(Address, Source),
+    /// The address book is empty, and there are no fixed seeds to bootstrap it with.
+    BootstrapFailed,
     /// An error was encountered.
     Error(String),
 }
@@ -73,6 +77,9 @@ impl std::fmt::Display for Event {
             Event::AddressDiscovered(addr, source) => {
                 write!(fmt, "{:?} discovered from source `{}`", addr, source)
             }
+            Event::BootstrapFailed => {
+                write!(fmt, "Address book is empty and there are no fixed seeds")
+            }
             Event::Error(msg) => {
                 write!(fmt, "error: {}", msg)
             }
@@ -80,17 +87,52 @@ impl std::fmt::Display for Event {
     }
 }
 
+/// The network an address belongs to.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum AddressType {
+    /// IPv4 address.
+    Ipv4,
+    /// IPv6 address.
+    Ipv6,
+    /// Tor onion address.
+    Onion,
+}
+
+impl AddressType {
+    /// Get the network of an IP address. Onion addresses are recognized by their OnionCat
+    /// encoding in the `fd87:d87e:eb43::/48` range, as used in `addr` messages.
+    pub fn of(ip: &net::IpAddr) -> Self {
+        match ip {
+            net::IpAddr::V4(_) => Self::Ipv4,
+            net::IpAddr::V6(ip) if ip.segments()[..3] == ONIONCAT_PREFIX => Self::Onion,
+            net::IpAddr::V6(_) => Self::Ipv6,
+        }
+    }
+}
+
 /// Address manager configuration.
 #[derive(Debug)]
 pub struct Config {
     /// Services required from peers.
     pub required_services: ServiceFlags,
+    /// Networks to pick addresses from, in order of preference. Addresses on networks
+    /// that aren't listed are never picked. If empty, addresses are picked from all
+    /// reachable networks, without preference.
+    pub networks: Vec<AddressType>,
+    /// Proxy through which onion addresses are reachable. Onion addresses are only picked
+    /// if this is set; the reactor routes their connections through it.
+    pub onion_proxy: Option<net::SocketAddr>,
+    /// Peers to bootstrap the address book with, if it's empty.
+    pub fixed_seeds: Vec<net::SocketAddr>,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
             required_services: ServiceFlags::NONE,
+            networks: Vec::new(),
+            onion_proxy: None,
+            fixed_seeds: Vec::new(),
         }
     }
 }
@@ -296,6 +338,27 @@ impl<P: Store, U: Events> AddressManager<P, U> {
         self.insert(addrs.into_iter(), source);
     }
 
+    /// Bootstrap the address book with the fixed seeds, if it's empty. Emits
+    /// [`Event::BootstrapFailed`] if there are no fixed seeds to use.
+    pub fn bootstrap(&mut self) {
+        if !self.is_empty() {
+            return;
+        }
+        if self.cfg.fixed_seeds.is_empty() {
+            self.upstream.event(Event::BootstrapFailed);
+            return;
+        }
+        for addr in self.cfg.fixed_seeds.clone() {
+            let ka = KnownAddress::new(Address::new(&addr, ServiceFlags::NONE), Source::Fixed);
+
+            if self.peers.insert(addr.ip(), ka.clone()) {
+                self.populate_address_ranges(&addr.ip());
+                self.upstream
+                    .event(Event::AddressDiscovered(ka.addr, Source::Fixed));
+            }
+        }
+    }
+
     /// Add addresses to the address manager. The input matches that of the `addr` message
     /// sent by peers on the network.
     ///
@@ -341,10 +404,7 @@ impl<P: Store, U: Events> AddressManager<P, U> {
                 continue;
             }
 
-            let net_addr = match addr.socket_addr() {
-                Ok(a) => a,
-                Err(_) => continue,
-            };
+            let net_addr = self::socket_addr(&addr);
             let ip = net_addr.ip();
 
             // Ensure no self-connections.
@@ -384,6 +444,10 @@ impl<P: Store, U: Events> AddressManager<P, U> {
     ///
     /// This works under the assumption that adversaries are *localized*.
     ///
+    /// Addresses are picked from the configured networks in order of preference: an address
+    /// is only picked from a network if none are available from the networks preferred
+    /// over it. Unreachable networks are skipped.
+    ///
     /// ```
     /// use std::collections::HashMap;
     ///
@@ -441,6 +505,24 @@ impl<P: Store, U: Events> AddressManager<P, U> {
     /// TODO: Should return an iterator.
     ///
     pub fn sample(&self, services: ServiceFlags) -> Option<(&Address, Source)> {
+        if self.cfg.networks.is_empty() {
+            return self.sample_with(services, |ip| self.is_reachable(AddressType::of(ip)));
+        }
+        self.cfg
+            .networks
+            .iter()
+            .filter(|network| self.is_reachable(**network))
+            .find_map(|network| self.sample_with(services, |ip| AddressType::of(ip) == *network))
+    }
+
+    ////////////////////////////////////////////////////////////////////////////
+
+    /// Pick an address at random from the known addresses matching the given predicate.
+    fn sample_with(
+        &self,
+        services: ServiceFlags,
+        predicate: impl Fn(&net::IpAddr) -> bool,
+    ) -> Option<(&Address, Source)> {
         if self.is_empty() {
             return None;
         }
@@ -464,16 +546,19 @@ impl<P: Store, U: Events> AddressManager<P, U> {
 
             visited.insert(ip);
 
+            if !predicate(ip) {
+                continue;
+            }
             // FIXME
             if ka.last_attempt.is_some() {
                 continue;
             }
             if !ka.addr.services.has(services) {
                 match ka.source {
-                    Source::Dns => {
+                    Source::Dns | Source::Fixed => {
                         // If we've negotiated with this peer and it hasn't signaled the
                         // required services, we know not to return it.
-                        // The reason we check this is that DNS-sourced addresses don't include
+                        // The reason we check this is that DNS-sourced and fixed addresses don't include
                         // service information, so we can only know once negotiated.
                         if ka.last_success.is_some() {
                             continue;
@@ -495,7 +580,13 @@ impl<P: Store, U: Events> AddressManager<P, U> {
         None
     }
 
-    ////////////////////////////////////////////////////////////////////////////
+    /// Check whether we can connect to addresses on the given network.
+    fn is_reachable(&self, network: AddressType) -> bool {
+        match network {
+            AddressType::Ipv4 | AddressType::Ipv6 => true,
+            AddressType::Onion => self.cfg.onion_proxy.is_some(),
+        }
+    }
 
     /// Populate address ranges with an IP. This may remove an existing IP if
     /// its range is full. Returns the range key that was used.
@@ -554,6 +645,19 @@ impl<P: Store, U: Events + SyncAddresses> AddressSource for AddressManager<P, U>
     }
 }
 
+/// Get the socket address of a peer address. Unlike [`Address::socket_addr`], this keeps
+/// onion addresses in their OnionCat encoding, rather than returning an error.
+pub fn socket_addr(addr: &Address) -> net::SocketAddr {
+    let ip = net::Ipv6Addr::from(addr.address);
+
+    match ip.to_ipv4() {
+        Some(ipv4) if ip.segments()[..3] != ONIONCAT_PREFIX => {
+            net::SocketAddr::new(ipv4.into(), addr.port)
+        }
+        _ => net::SocketAddr::new(ip.into(), addr.port),
+    }
+}
+
 /// Check whether an IP address is globally routable.
 pub fn is_routable(addr: &net::IpAddr) -> bool {
     match addr {
@@ -588,6 +692,14 @@ fn addr_key(ip: &net::IpAddr) -> u8 {
 
             (bits % u8::MAX as u16) as u8
         }
+        net::IpAddr::V6(ip) if ip.segments()[..3] == ONIONCAT_PREFIX => {
+            // Onion addresses all share the same prefix, so use the first 32 bits of the
+            // onion service identifier instead.
+            let segments: [u16; 8] = ip.segments();
+            let bits: u32 = (segments[3] as u32) << 16 | segments[4] as u32;
+
+            (bits % u8::MAX as u32) as u8
+        }
         net::IpAddr::V6(ip) => {
             // Use the first 32 bits of an IPv6 address to as a key.
             let segments: [u16; 8] = ip.segments();
@@ -679,6 +791,68 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_sample_network_preference() {
+        let services = ServiceFlags::NONE;
+        let time = BlockTime::default();
+        let onions = (1..=4)
+            .map(|i| net::IpAddr::from([0xfd87, 0xd87e, 0xeb43, i * 1024, 7, i, 0, 1]))
+            .collect::<Vec<_>>();
+        let ipv4 = (1..=4)
+            .map(|i| net::IpAddr::from([i * 20, 8, 55, 2]))
+            .collect::<Vec<_>>();
+        let addrs = onions
+            .iter()
+            .chain(ipv4.iter())
+            .map(|ip| (time, Address::new(&(*ip, 8333).into(), services)))
+            .collect::<Vec<_>>();
+
+        assert!(onions
+            .iter()
+            .all(|ip| AddressType::of(ip) == AddressType::Onion));
+        assert!(ipv4
+            .iter()
+            .all(|ip| AddressType::of(ip) == AddressType::Ipv4));
+
+        // With a proxy configured, onion addresses are picked first.
+        let cfg = Config {
+            networks: vec![AddressType::Onion, AddressType::Ipv4],
+            onion_proxy: Some(([127, 0, 0, 1], 9050).into()),
+            ..Config::default()
+        };
+        let mut addrmgr = AddressManager::new(cfg, fastrand::Rng::new(), HashMap::new(), ());
+        addrmgr.insert(addrs.iter().cloned(), Source::Dns);
+
+        let mut sampled = Vec::new();
+        while let Some((addr, _)) = addrmgr.sample(services) {
+            let ip = socket_addr(addr).ip();
+
+            addrmgr.peers.get_mut(&ip).unwrap().last_attempt = Some(LocalTime::default());
+            sampled.push(ip);
+        }
+        assert_eq!(sampled.len(), onions.len() + ipv4.len());
+        assert!(sampled[..onions.len()].iter().all(|ip| onions.contains(ip)));
+        assert!(sampled[onions.len()..].iter().all(|ip| ipv4.contains(ip)));
+
+        // Without a proxy, onion addresses are skipped entirely.
+        for networks in [vec![AddressType::Onion, AddressType::Ipv4], vec![]] {
+            let cfg = Config {
+                networks,
+                ..Config::default()
+            };
+            let mut addrmgr = AddressManager::new(cfg, fastrand::Rng::new(), HashMap::new(), ());
+            addrmgr.insert(addrs.iter().cloned(), Source::Dns);
+
+            for _ in 0..32 {
+                let (addr, _) = addrmgr.sample(services).unwrap();
+                assert!(ipv4.contains(&socket_addr(addr).ip()));
+            }
+        }
+
+        // Onion addresses aren't all placed in the same range.
+        assert!(onions.iter().any(|ip| addr_key(ip) != addr_key(&onions[0])));
+    }
+
     #[test]
     fn test_addr_key() {
         assert_eq!(
@@ -0,0 +1,212 @@
+//! Block download scheduler.
+//!
+//! Blocks are requested from peers in order of priority, with a limit on the number of
+//! requests in flight. Blocks at or near the tip of the chain are prioritized over
+//! historical blocks, eg. the ones fetched during a wallet rescan, so that following the
+//! tip isn't held back by bulk downloads. Blocks of the same priority are requested in the
+//! order they were asked for.
+//!
+//! Blocks are preferably requested from peers that announced them, since they are known
+//! to have them.
+//!
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, VecDeque};
+
+use nakamoto_common::block::time::{LocalDuration, LocalTime};
+use nakamoto_common::block::{BlockHash, Height};
+use nakamoto_common::collections::HashMap;
+
+use super::PeerId;
+
+/// Maximum number of block requests in flight.
+pub const MAX_INFLIGHT_BLOCKS: usize = 16;
+/// How long to wait for a requested block, before requesting it again.
+pub const REQUEST_TIMEOUT: LocalDuration = LocalDuration::from_secs(60);
+/// Blocks this close to the tip are fetched with [`Priority::Tip`].
+pub const MAX_TIP_DEPTH: Height = 6;
+/// Maximum number of announced blocks to remember the announcing peers of.
+pub const MAX_ANNOUNCED_BLOCKS: usize = 1024;
+
+/// The priority of a block download.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// A historical block, eg. for a rescan.
+    Historical,
+    /// A block at or near the tip of the chain.
+    Tip,
+}
+
+impl Priority {
+    /// Get the priority of the block at the given height, given the height of our tip.
+    /// Blocks of unknown height are likely to be new, and are given [`Priority::Tip`].
+    pub fn of(height: Option<Height>, tip: Height) -> Self {
+        match height {
+            Some(height) if height + MAX_TIP_DEPTH < tip => Self::Historical,
+            _ => Self::Tip,
+        }
+    }
+}
+
+/// A queued block request.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct Request {
+    hash: BlockHash,
+    priority: Priority,
+    /// Order in which the block was asked for. Lower is earlier.
+    seq: u64,
+}
+
+impl Ord for Request {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.priority, Reverse(self.seq)).cmp(&(other.priority, Reverse(other.seq)))
+    }
+}
+
+impl PartialOrd for Request {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Schedules block downloads.
+#[derive(Debug)]
+pub struct BlockManager {
+    /// Blocks waiting to be requested. May contain requests that were superseded by a
+    /// request for the same block with a higher priority.
+    queue: BinaryHeap<Request>,
+    /// Priority of each queued block.
+    queued: HashMap<BlockHash, Priority>,
+    /// Requested blocks, along with the peer they were requested from and when.
+    inflight: HashMap<BlockHash, (Request, PeerId, LocalTime)>,
+    /// Sequence number of the next request.
+    seq: u64,
+    /// Peers that announced each block, in the order they announced it.
+    announced: HashMap<BlockHash, Vec<PeerId>>,
+    /// Blocks in `announced`, oldest first.
+    announcements: VecDeque<BlockHash>,
+}
+
+impl BlockManager {
+    /// Create a new block manager.
+    pub fn new(rng: fastrand::Rng) -> Self {
+        Self {
+            queue: BinaryHeap::new(),
+            queued: HashMap::with_hasher(rng.clone().into()),
+            inflight: HashMap::with_hasher(rng.clone().into()),
+            seq: 0,
+            announced: HashMap::with_hasher(rng.into()),
+            announcements: VecDeque::new(),
+        }
+    }
+
+    /// Called when a peer announced a block, eg. via an `inv` message. Only the most
+    /// recently announced blocks are remembered, up to [`MAX_ANNOUNCED_BLOCKS`].
+    pub fn announced(&mut self, addr: PeerId, hash: BlockHash) {
+        let announcements = &mut self.announcements;
+        let peers = self.announced.entry(hash).or_insert_with(|| {
+            announcements.push_back(hash);
+            Vec::new()
+        });
+        if !peers.contains(&addr) {
+            peers.push(addr);
+        }
+        while self.announcements.len() > MAX_ANNOUNCED_BLOCKS {
+            if let Some(hash) = self.announcements.pop_front() {
+                self.announced.remove(&hash);
+            }
+        }
+    }
+
+    /// Get the connected peers known to have the given block, because they announced it.
+    pub fn peers_with_block(&self, hash: &BlockHash) -> Vec<PeerId> {
+        self.announced.get(hash).cloned().unwrap_or_default()
+    }
+
+    /// Check whether there are no blocks queued or in flight.
+    pub fn is_idle(&self) -> bool {
+        self.queued.is_empty() && self.inflight.is_empty()
+    }
+
+    /// Queue a block for download. A block that is already queued is moved up if the new
+    /// priority is higher. Returns `false` if the block is already queued or requested
+    /// with at least this priority.
+    pub fn request(&mut self, hash: BlockHash, priority: Priority) -> bool {
+        if self.inflight.contains_key(&hash) {
+            return false;
+        }
+        match self.queued.get(&hash) {
+            Some(queued) if *queued >= priority => return false,
+            _ => {}
+        }
+        self.queued.insert(hash, priority);
+        self.queue.push(Request {
+            hash,
+            priority,
+            seq: self.seq,
+        });
+        self.seq += 1;
+
+        true
+    }
+
+    /// Get the next block to request, if there is capacity for another request. The block
+    /// must then be marked as sent with [`BlockManager::sent`].
+    pub fn next_request(&mut self) -> Option<(BlockHash, Priority)> {
+        if self.inflight.len() >= MAX_INFLIGHT_BLOCKS {
+            return None;
+        }
+        while let Some(req) = self.queue.peek() {
+            if self.queued.get(&req.hash) == Some(&req.priority) {
+                return Some((req.hash, req.priority));
+            }
+            // Superseded by a higher-priority request.
+            self.queue.pop();
+        }
+        None
+    }
+
+    /// Mark the next block, as returned by [`BlockManager::next_request`], as requested from
+    /// the given peer.
+    pub fn sent(&mut self, addr: PeerId, now: LocalTime) {
+        if let Some(req) = self.queue.pop() {
+            self.queued.remove(&req.hash);
+            self.inflight.insert(req.hash, (req, addr, now));
+        }
+    }
+
+    /// Called when a block was received. Returns `true` if it was requested.
+    pub fn received(&mut self, hash: &BlockHash) -> bool {
+        self.inflight.remove(hash).is_some()
+    }
+
+    /// Called when a peer disconnected. Blocks requested from it are queued again.
+    pub fn peer_disconnected(&mut self, addr: &PeerId) {
+        self.requeue(|_, peer| peer == addr);
+
+        for peers in self.announced.values_mut() {
+            peers.retain(|peer| peer != addr);
+        }
+    }
+
+    /// Called when a timeout was received. Requests that timed out are queued again.
+    pub fn received_timeout(&mut self, now: LocalTime) {
+        self.requeue(|sent_at, _| now - *sent_at >= REQUEST_TIMEOUT);
+    }
+
+    /// Queue again the in-flight requests matching the predicate, in their original order.
+    fn requeue(&mut self, f: impl Fn(&LocalTime, &PeerId) -> bool) {
+        let expired = self
+            .inflight
+            .iter()
+            .filter(|(_, (_, addr, sent_at))| f(sent_at, addr))
+            .map(|(hash, _)| *hash)
+            .collect::<Vec<_>>();
+
+        for hash in expired {
+            if let Some((req, _, _)) = self.inflight.remove(&hash) {
+                self.queued.insert(hash, req.priority);
+                self.queue.push(req);
+            }
+        }
+    }
+}
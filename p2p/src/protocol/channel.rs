@@ -11,7 +11,7 @@ use crossbeam_channel as chan;
 use bitcoin::network::address::Address;
 use bitcoin::network::message::NetworkMessage;
 use bitcoin::network::message_blockdata::GetHeadersMessage;
-use bitcoin::network::message_filter::{CFHeaders, CFilter, GetCFHeaders, GetCFilters};
+use bitcoin::network::message_filter::{CFCheckpt, CFHeaders, CFilter, GetCFHeaders, GetCFilters};
 use bitcoin::network::message_network::VersionMessage;
 
 use nakamoto_common::block::time::LocalDuration;
@@ -232,7 +232,7 @@ impl spvmgr::SyncFilters for Channel {
     }
 
     fn send_cfheaders(&self, addr: PeerId, headers: CFHeaders) {
-        todo!()
+        self.message(addr, NetworkMessage::CFHeaders(headers));
     }
 
     fn get_cfilters(
@@ -253,7 +253,11 @@ impl spvmgr::SyncFilters for Channel {
     }
 
     fn send_cfilter(&self, addr: PeerId, cfilter: CFilter) {
-        todo!()
+        self.message(addr, NetworkMessage::CFilter(cfilter));
+    }
+
+    fn send_cfcheckpt(&self, addr: PeerId, checkpt: CFCheckpt) {
+        self.message(addr, NetworkMessage::CFCheckpt(checkpt));
     }
 }
 
@@ -1,18 +1,18 @@
 //! Peer connection manager.
 
-use std::collections::{HashMap, HashSet};
 use std::net;
 
 use bitcoin::network::constants::ServiceFlags;
 
 use nakamoto_common::block::time::{LocalDuration, LocalTime};
+use nakamoto_common::collections::{HashMap, HashSet};
 use nakamoto_common::p2p::peer::{self, AddressSource, Source};
 
+use super::addrmgr;
 use super::channel::{Disconnect, SetTimeout};
 use crate::protocol::{DisconnectReason, Link, PeerId, Timeout};
 
-/// Time to wait for a new connection.
-/// TODO: Should be in config.
+/// Time to wait for a new connection to be established.
 pub const CONNECTION_TIMEOUT: LocalDuration = LocalDuration::from_secs(3);
 /// Time to wait until idle.
 pub const IDLE_TIMEOUT: LocalDuration = LocalDuration::from_mins(1);
@@ -20,6 +20,13 @@ pub const IDLE_TIMEOUT: LocalDuration = LocalDuration::from_mins(1);
 pub const TARGET_OUTBOUND_PEERS: usize = 8;
 /// Maximum number of inbound peer connections.
 pub const MAX_INBOUND_PEERS: usize = 16;
+/// Maximum number of connections of any kind, including the ones being established.
+pub const MAX_CONNECTIONS: usize = TARGET_OUTBOUND_PEERS + MAX_INBOUND_PEERS;
+/// Maximum number of outbound peers in the process of connecting or handshaking.
+pub const MAX_CONCURRENT_HANDSHAKES: usize = 8;
+/// Maximum number of sampled addresses we're already connecting to, before we stop trying
+/// to make new connections.
+const MAX_SAMPLE_RETRIES: usize = 32;
 
 /// Ability to connect to peers.
 pub trait Connect {
@@ -44,6 +51,8 @@ pub enum Event {
     Connected(PeerId, Link),
     /// A peer has been disconnected.
     Disconnected(PeerId),
+    /// A connection attempt to a peer timed out.
+    ConnectionTimeout(PeerId),
     /// Address book exhausted when trying to connect.
     AddressBookExhausted,
 }
@@ -56,6 +65,9 @@ impl std::fmt::Display for Event {
             }
             Event::Connected(addr, link) => write!(fmt, "{}: Peer connected ({:?})", &addr, link),
             Event::Disconnected(addr) => write!(fmt, "Disconnected from {}", &addr),
+            Event::ConnectionTimeout(addr) => {
+                write!(fmt, "{}: Connection attempt timed out", &addr)
+            }
             Event::AddressBookExhausted => {
                 write!(fmt, "Address book exhausted when attempting to connect..")
             }
@@ -70,13 +82,27 @@ pub struct Config {
     pub target_outbound_peers: usize,
     /// Maximum number of inbound peer connections.
     pub max_inbound_peers: usize,
+    /// Whether inbound connections are accepted.
+    pub accept_inbound: bool,
+    /// Maximum number of connections of any kind, including outbound connections being
+    /// established. Once reached, no new peers are dialed and inbound connections are
+    /// refused, regardless of the other limits.
+    pub max_connections: usize,
     /// Peer addresses that should always be retried.
     pub retry: Vec<net::SocketAddr>,
+    /// Peers that are always connected to. They are dialed on startup, and reconnected to
+    /// as soon as they disconnect.
+    pub persistent: Vec<net::SocketAddr>,
     /// Peer services required.
     pub required_services: ServiceFlags,
     /// Peer services preferred. We try to maintain as many
     /// connections to peers with these services.
     pub preferred_services: ServiceFlags,
+    /// Time to wait for a connection to be established before giving up on the peer.
+    pub connect_timeout: LocalDuration,
+    /// Maximum number of outbound peers we are connecting to or handshaking with at
+    /// any given time. New connections are only attempted once there is a free slot.
+    pub max_concurrent_handshakes: usize,
 }
 
 /// A connected peer.
@@ -90,6 +116,8 @@ struct Peer {
     link: Link,
     /// Services offered.
     services: ServiceFlags,
+    /// Whether the handshake with the peer was completed.
+    negotiated: bool,
     /// Time connected.
     time: LocalTime,
 }
@@ -99,12 +127,14 @@ struct Peer {
 pub struct ConnectionManager<U> {
     /// Configuration.
     pub config: Config,
-    /// Set of outbound peers being connected to.
-    connecting: HashSet<PeerId>,
+    /// Set of outbound peers being connected to, and when the attempt was made.
+    connecting: HashMap<PeerId, LocalTime>,
     /// Set of all connected peers.
     connected: HashMap<PeerId, Peer>,
     /// Set of disconnected peers.
     disconnected: HashSet<PeerId>,
+    /// Set of connected peers for which a duplicate connection is being dropped.
+    duplicates: HashSet<PeerId>,
     /// Last time we were idle.
     last_idle: Option<LocalTime>,
     /// Channel to the network.
@@ -113,11 +143,12 @@ pub struct ConnectionManager<U> {
 
 impl<U: Connect + Disconnect + Events + SetTimeout> ConnectionManager<U> {
     /// Create a new connection manager.
-    pub fn new(upstream: U, config: Config) -> Self {
+    pub fn new(upstream: U, config: Config, rng: fastrand::Rng) -> Self {
         Self {
-            connecting: HashSet::new(),
-            connected: HashMap::new(),
-            disconnected: HashSet::new(),
+            connecting: HashMap::with_hasher(rng.clone().into()),
+            connected: HashMap::with_hasher(rng.clone().into()),
+            disconnected: HashSet::with_hasher(rng.clone().into()),
+            duplicates: HashSet::with_hasher(rng.into()),
             last_idle: None,
             config,
             upstream,
@@ -125,11 +156,7 @@ impl<U: Connect + Disconnect + Events + SetTimeout> ConnectionManager<U> {
     }
 
     /// Initialize the connection manager. Must be called once.
-    pub fn initialize<S: peer::Store, A: AddressSource>(
-        &mut self,
-        _time: LocalTime,
-        addrs: &mut A,
-    ) {
+    pub fn initialize<S: peer::Store, A: AddressSource>(&mut self, time: LocalTime, addrs: &mut A) {
         let retry = self
             .config
             .retry
@@ -139,19 +166,28 @@ impl<U: Connect + Disconnect + Events + SetTimeout> ConnectionManager<U> {
             .collect::<Vec<_>>();
 
         for addr in retry {
-            self.connect::<S, A>(&addr);
+            self.connect::<S, A>(&addr, time);
         }
+        self.connect_persistent::<S, A>(time);
         self.upstream.set_timeout(IDLE_TIMEOUT);
-        self.maintain_connections::<S, A>(addrs);
+        self.maintain_connections::<S, A>(addrs, time);
     }
 
     /// Connect to a peer.
-    pub fn connect<S: peer::Store, A: AddressSource>(&mut self, addr: &PeerId) -> bool {
-        if self.connected.contains_key(&addr) || self.connecting.contains(addr) {
+    pub fn connect<S: peer::Store, A: AddressSource>(
+        &mut self,
+        addr: &PeerId,
+        local_time: LocalTime,
+    ) -> bool {
+        if self.connected.contains_key(&addr) || self.connecting.contains_key(addr) {
+            return false;
+        }
+        if self.connections() >= self.config.max_connections {
             return false;
         }
-        self.connecting.insert(*addr);
-        self.upstream.connect(*addr, CONNECTION_TIMEOUT);
+        self.connecting.insert(*addr, local_time);
+        self.upstream.connect(*addr, self.config.connect_timeout);
+        self.upstream.set_timeout(self.config.connect_timeout);
 
         true
     }
@@ -165,6 +201,33 @@ impl<U: Connect + Disconnect + Events + SetTimeout> ConnectionManager<U> {
         }
     }
 
+    /// Check whether the given peer is a persistent peer.
+    pub fn is_persistent(&self, addr: &PeerId) -> bool {
+        self.config.persistent.contains(addr)
+    }
+
+    /// Check whether the given peer is connected.
+    pub fn is_connected(&self, addr: &PeerId) -> bool {
+        self.connected.contains_key(addr)
+    }
+
+    /// Call when a peer we're already connected to connected again. The new connection
+    /// is dropped, and the existing one is kept.
+    pub fn peer_reconnected(&mut self, addr: PeerId) {
+        debug_assert!(self.connected.contains_key(&addr));
+
+        self.duplicates.insert(addr);
+        self.upstream
+            .disconnect(addr, DisconnectReason::DuplicateConnection);
+    }
+
+    /// Call when a duplicate connection was dropped. Returns `false` if there was no
+    /// duplicate connection to the given peer, in which case the peer itself was
+    /// disconnected.
+    pub fn duplicate_disconnected(&mut self, addr: &PeerId) -> bool {
+        self.duplicates.remove(addr)
+    }
+
     /// Call when a peer connected.
     pub fn peer_connected(
         &mut self,
@@ -178,8 +241,15 @@ impl<U: Connect + Disconnect + Events + SetTimeout> ConnectionManager<U> {
         Events::event(&self.upstream, Event::Connected(address, link));
 
         match link {
-            Link::Inbound if self.inbound_peers().count() >= self.config.max_inbound_peers => {
-                // Don't allow inbound connections beyond the configured limit.
+            Link::Inbound if !self.config.accept_inbound => {
+                self.upstream
+                    .disconnect(address, DisconnectReason::InboundDisabled);
+            }
+            Link::Inbound
+                if self.inbound_peers().count() >= self.config.max_inbound_peers
+                    || self.connections() >= self.config.max_connections =>
+            {
+                // Don't allow inbound connections beyond the configured limits.
                 self.upstream
                     .disconnect(address, DisconnectReason::ConnectionLimit);
             }
@@ -192,6 +262,7 @@ impl<U: Connect + Disconnect + Events + SetTimeout> ConnectionManager<U> {
                         address,
                         local_address,
                         services: ServiceFlags::NONE,
+                        negotiated: false,
                         link,
                         time,
                     },
@@ -201,11 +272,23 @@ impl<U: Connect + Disconnect + Events + SetTimeout> ConnectionManager<U> {
     }
 
     /// Call when a peer negotiated.
-    pub fn peer_negotiated(&mut self, address: net::SocketAddr, services: ServiceFlags) {
+    pub fn peer_negotiated<S: peer::Store, A: AddressSource>(
+        &mut self,
+        address: net::SocketAddr,
+        services: ServiceFlags,
+        addrs: &A,
+        local_time: LocalTime,
+    ) {
         let peer = self.connected.get_mut(&address).expect(
             "ConnectionManager::peer_negotiated: negotiated peers should be connected first",
         );
         peer.services = services;
+        peer.negotiated = true;
+
+        // If this was an outbound peer, it frees up a handshake slot.
+        if peer.link.is_outbound() {
+            self.maintain_connections::<S, A>(addrs, local_time);
+        }
     }
 
     /// Call when a peer disconnected.
@@ -213,21 +296,27 @@ impl<U: Connect + Disconnect + Events + SetTimeout> ConnectionManager<U> {
         &mut self,
         addr: &net::SocketAddr,
         addrs: &A,
+        local_time: LocalTime,
     ) {
-        debug_assert!(self.connected.contains_key(&addr));
         debug_assert!(!self.disconnected.contains(&addr));
 
-        Events::event(&self.upstream, Event::Disconnected(*addr));
+        if let Some(peer) = self.connected.remove(&addr) {
+            Events::event(&self.upstream, Event::Disconnected(*addr));
 
-        self.disconnected.insert(*addr);
+            self.disconnected.insert(*addr);
 
-        if let Some(peer) = self.connected.remove(&addr) {
+            // Persistent peers are reconnected to right away. If the attempt fails, they
+            // are retried the next time we're idle.
+            if self.is_persistent(addr) {
+                self.connect::<S, A>(addr, local_time);
+            }
             // If an outbound peer disconnected, we should make sure to maintain
             // our target outbound connection count.
             if peer.link.is_outbound() {
-                self.maintain_connections::<S, A>(addrs);
+                self.maintain_connections::<S, A>(addrs, local_time);
             }
         } else {
+            // The connection was never established, or the attempt already timed out.
             self.connecting.remove(&addr);
         }
     }
@@ -238,13 +327,41 @@ impl<U: Connect + Disconnect + Events + SetTimeout> ConnectionManager<U> {
         local_time: LocalTime,
         addrs: &A,
     ) {
+        let mut timed_out = self
+            .connecting
+            .iter()
+            .filter(|(_, since)| local_time - **since >= self.config.connect_timeout)
+            .map(|(addr, _)| *addr)
+            .collect::<Vec<_>>();
+
+        // Disconnect in a stable order, so that the outputs don't depend on the hasher.
+        timed_out.sort_unstable();
+
+        if !timed_out.is_empty() {
+            for addr in timed_out {
+                self.connecting.remove(&addr);
+                self.upstream
+                    .disconnect(addr, DisconnectReason::ConnectionTimeout);
+
+                Events::event(&self.upstream, Event::ConnectionTimeout(addr));
+            }
+            // Try other peers in place of the ones we couldn't connect to.
+            self.maintain_connections::<S, A>(addrs, local_time);
+        }
+
         if local_time - self.last_idle.unwrap_or_default() >= IDLE_TIMEOUT {
-            self.maintain_connections::<S, A>(addrs);
+            self.connect_persistent::<S, A>(local_time);
+            self.maintain_connections::<S, A>(addrs, local_time);
             self.upstream.set_timeout(IDLE_TIMEOUT);
             self.last_idle = Some(local_time);
         }
     }
 
+    /// Check whether we're connecting to, or handshaking with any outbound peer.
+    pub fn is_connecting(&self) -> bool {
+        self.handshakes() > 0
+    }
+
     /// Returns outbound peer addresses.
     pub fn outbound_peers(&self) -> impl Iterator<Item = &PeerId> {
         self.connected
@@ -261,25 +378,46 @@ impl<U: Connect + Disconnect + Events + SetTimeout> ConnectionManager<U> {
             .map(|(addr, _)| addr)
     }
 
+    /// Connect to the persistent peers we aren't connected or connecting to.
+    fn connect_persistent<S: peer::Store, A: AddressSource>(&mut self, local_time: LocalTime) {
+        let persistent = self.config.persistent.clone();
+
+        for addr in &persistent {
+            self.connect::<S, A>(addr, local_time);
+        }
+    }
+
     /// Attempt to maintain a certain number of outbound peers.
-    fn maintain_connections<S: peer::Store, A: AddressSource>(&mut self, addrs: &A) {
-        while self.outbound().count() + self.connecting.len() < self.config.target_outbound_peers {
+    fn maintain_connections<S: peer::Store, A: AddressSource>(
+        &mut self,
+        addrs: &A,
+        local_time: LocalTime,
+    ) {
+        let mut retries = 0;
+
+        while self.outbound().count() + self.connecting.len() < self.config.target_outbound_peers
+            && self.handshakes() < self.config.max_concurrent_handshakes
+            && self.connections() < self.config.max_connections
+        {
             // Prefer addresses with the preferred services.
             let result = addrs
                 .sample(self.config.preferred_services)
                 .or_else(|| addrs.sample(self.config.required_services));
 
             if let Some((addr, source)) = result {
-                // TODO: Support Tor?
-                if let Ok(sockaddr) = addr.socket_addr() {
-                    // TODO: Remove this assertion once address manager no longer cares about
-                    // connections.
-                    debug_assert!(!self.connected.contains_key(&sockaddr));
-
-                    if self.connect::<S, A>(&sockaddr) {
-                        self.upstream.event(Event::Connecting(sockaddr, source));
-                        break;
-                    }
+                let sockaddr = addrmgr::socket_addr(addr);
+
+                // TODO: Remove this assertion once address manager no longer cares about
+                // connections.
+                debug_assert!(!self.connected.contains_key(&sockaddr));
+
+                if self.connect::<S, A>(&sockaddr, local_time) {
+                    self.upstream.event(Event::Connecting(sockaddr, source));
+                } else if retries < MAX_SAMPLE_RETRIES {
+                    // We're already connecting to this peer, try another one.
+                    retries += 1;
+                } else {
+                    break;
                 }
             } else {
                 // We're out of addresses. We don't need to do anything here, the address manager
@@ -294,4 +432,14 @@ impl<U: Connect + Disconnect + Events + SetTimeout> ConnectionManager<U> {
     fn outbound(&self) -> impl Iterator<Item = &Peer> + Clone {
         self.connected.values().filter(|p| p.link.is_outbound())
     }
+
+    /// Number of connections, including the ones being established.
+    fn connections(&self) -> usize {
+        self.connected.len() + self.connecting.len()
+    }
+
+    /// Number of outbound peers we are connecting to or handshaking with.
+    fn handshakes(&self) -> usize {
+        self.connecting.len() + self.outbound().filter(|p| !p.negotiated).count()
+    }
 }
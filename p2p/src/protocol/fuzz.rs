@@ -0,0 +1,233 @@
+//! Fuzzing entry point for the protocol state machine.
+//!
+//! Arbitrary bytes are decoded into a sequence of [`Step`]s, which are fed to a protocol
+//! instance one at a time. The inputs respect the guarantees given by the reactor, eg.
+//! messages are only ever received from connected peers, and disconnects requested by the
+//! protocol are carried out. Any panic therefore points to a bug in the protocol itself.
+//!
+//! Only compiled for tests, and with `--cfg fuzzing`, which `cargo fuzz` sets. See the
+//! targets under `fuzz/` along with their seed corpus.
+//!
+use arbitrary::Arbitrary;
+use crossbeam_channel as chan;
+
+use bitcoin::consensus::encode;
+use bitcoin::network::message::{NetworkMessage, RawNetworkMessage};
+use bitcoin::network::message_blockdata::{GetHeadersMessage, Inventory};
+
+use nakamoto_common::block::filter::Filters;
+use nakamoto_common::block::time::{LocalDuration, LocalTime};
+use nakamoto_common::block::tree::BlockTree;
+use nakamoto_common::block::{BlockHash, BlockHeader, Height};
+use nakamoto_common::p2p::peer;
+
+use super::{DisconnectReason, Input, Link, Out, PeerId, Protocol, PROTOCOL_VERSION};
+
+/// Number of distinct remote peers inputs can refer to.
+pub const MAX_PEERS: u8 = 8;
+
+/// Maximum number of items decoded for a single message, eg. headers or inventories.
+pub const MAX_ITEMS: usize = 16;
+
+/// A protocol input, along with the time elapsed before it is received.
+#[derive(Debug, Clone, Arbitrary)]
+pub struct Step {
+    /// Seconds elapsed since the previous step.
+    pub elapsed: u8,
+    /// Remote peer the input concerns, out of [`MAX_PEERS`].
+    pub peer: u8,
+    /// The input itself.
+    pub action: Action,
+}
+
+/// A protocol input. Inputs that don't apply to the current state, eg. messages from
+/// peers that aren't connected, are skipped.
+#[derive(Debug, Clone, Arbitrary)]
+pub enum Action {
+    /// The peer connected.
+    Connect {
+        /// Whether the connection is outbound.
+        outbound: bool,
+    },
+    /// The peer disconnected.
+    Disconnect,
+    /// A message was received from the peer.
+    Receive(Message),
+    /// A timeout fired.
+    Timeout,
+}
+
+/// A message received from a peer.
+#[derive(Debug, Clone, Arbitrary)]
+pub enum Message {
+    /// A `version` message, valid for the protocol.
+    Version {
+        /// Nonce of the message.
+        nonce: u16,
+        /// Start height of the peer.
+        height: u16,
+    },
+    /// A `verack` message.
+    Verack,
+    /// A `headers` message. Headers are consensus-encoded.
+    Headers(Vec<[u8; 80]>),
+    /// An `inv` message announcing blocks.
+    Inv(Vec<[u8; 32]>),
+    /// A `getheaders` message.
+    GetHeaders {
+        /// Locator hashes.
+        locators: Vec<[u8; 32]>,
+        /// Stop hash.
+        stop: [u8; 32],
+    },
+    /// A `ping` message.
+    Ping(u16),
+    /// A `getaddr` message.
+    GetAddr,
+    /// Anything else the wire format allows, consensus-encoded.
+    Raw(Vec<u8>),
+}
+
+impl Message {
+    /// Decode the message, for a connected peer.
+    fn decode<T: BlockTree, F: Filters, P: peer::Store>(
+        self,
+        protocol: &Protocol<T, F, P>,
+        addr: PeerId,
+        now: LocalTime,
+    ) -> Option<NetworkMessage> {
+        let hashes = |hashes: Vec<[u8; 32]>| {
+            hashes
+                .iter()
+                .take(MAX_ITEMS)
+                .map(|h| encode::deserialize::<BlockHash>(h).ok())
+                .collect::<Option<Vec<_>>>()
+        };
+        let msg = match self {
+            Self::Version { nonce, height } => {
+                let local = ([0, 0, 0, 0], 0).into();
+
+                NetworkMessage::Version(protocol.peermgr.version(
+                    local,
+                    addr,
+                    nonce as u64,
+                    height as Height,
+                    now,
+                ))
+            }
+            Self::Verack => NetworkMessage::Verack,
+            Self::Headers(headers) => NetworkMessage::Headers(
+                headers
+                    .iter()
+                    .take(MAX_ITEMS)
+                    .map(|h| encode::deserialize::<BlockHeader>(h).ok())
+                    .collect::<Option<Vec<_>>>()?,
+            ),
+            Self::Inv(blocks) => {
+                NetworkMessage::Inv(hashes(blocks)?.into_iter().map(Inventory::Block).collect())
+            }
+            Self::GetHeaders { locators, stop } => NetworkMessage::GetHeaders(GetHeadersMessage {
+                version: PROTOCOL_VERSION,
+                locator_hashes: hashes(locators)?,
+                stop_hash: encode::deserialize(&stop).ok()?,
+            }),
+            Self::Ping(nonce) => NetworkMessage::Ping(nonce as u64),
+            Self::GetAddr => NetworkMessage::GetAddr,
+            Self::Raw(bytes) => {
+                let raw: RawNetworkMessage = encode::deserialize(&bytes).ok()?;
+
+                raw.payload
+            }
+        };
+        Some(msg)
+    }
+}
+
+/// Get the input for the given step, if it applies to the current state.
+fn input<T: BlockTree, F: Filters, P: peer::Store>(
+    step: Step,
+    protocol: &Protocol<T, F, P>,
+    connected: &mut Vec<PeerId>,
+    now: LocalTime,
+) -> Option<Input> {
+    let addr: PeerId = ([88, 88, 88, step.peer % MAX_PEERS], 8333).into();
+    let is_connected = connected.contains(&addr);
+
+    match step.action {
+        Action::Connect { outbound } if !is_connected => {
+            let link = if outbound {
+                Link::Outbound
+            } else {
+                Link::Inbound
+            };
+            connected.push(addr);
+
+            Some(Input::Connected {
+                addr,
+                local_addr: ([0, 0, 0, 0], 0).into(),
+                link,
+            })
+        }
+        Action::Disconnect if is_connected => {
+            connected.retain(|a| *a != addr);
+
+            Some(Input::Disconnected(
+                addr,
+                DisconnectReason::ConnectionError(String::from("fuzz")),
+            ))
+        }
+        Action::Receive(msg) if is_connected => msg.decode(protocol, addr, now).map(|payload| {
+            Input::Received(
+                addr,
+                RawNetworkMessage {
+                    magic: protocol.network.magic(),
+                    payload,
+                },
+            )
+        }),
+        Action::Timeout => Some(Input::Timeout),
+        // The input doesn't apply to the current state, skip it.
+        _ => None,
+    }
+}
+
+/// Drive the protocol with the given steps, checking invariants along the way. The
+/// protocol is expected to be initialized, and its outputs to be sent to `outbound`.
+pub fn step<T: BlockTree, F: Filters, P: peer::Store>(
+    protocol: &mut Protocol<T, F, P>,
+    outbound: &chan::Receiver<Out>,
+    mut time: LocalTime,
+    steps: Vec<Step>,
+) {
+    let mut connected = Vec::new();
+
+    for step in steps {
+        time = time + LocalDuration::from_secs(step.elapsed as u64);
+
+        if let Some(input) = self::input(step, protocol, &mut connected, time) {
+            protocol.step(input, time);
+        }
+        // Carry out the disconnects requested by the protocol, like the reactor would.
+        let disconnects = outbound
+            .try_iter()
+            .filter_map(|o| match o {
+                Out::Disconnect(addr, reason) => Some((addr, reason)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        for (addr, reason) in disconnects {
+            if connected.contains(&addr) {
+                connected.retain(|a| *a != addr);
+                protocol.step(Input::Disconnected(addr, reason), time);
+            }
+        }
+        outbound.try_iter().for_each(drop);
+
+        let (tip, _) = protocol.tree.tip();
+        let (height, best) = protocol.tree.best_block();
+
+        assert_eq!(tip, best.block_hash());
+        assert_eq!(height, protocol.tree.height());
+    }
+}
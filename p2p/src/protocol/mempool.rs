@@ -0,0 +1,123 @@
+//! Tracking of unconfirmed transactions.
+//!
+//! Transactions we submit, or receive from peers, are tracked until they are confirmed by
+//! a block we receive, or until they are evicted to make room for newer ones.
+//!
+//! Since light clients don't have the UTXO set, the fee of a transaction is only known
+//! when all the outputs it spends belong to other tracked transactions.
+//!
+use std::collections::{HashMap, VecDeque};
+
+use bitcoin::{Block, Transaction, Txid};
+
+/// Maximum number of tracked transactions. Once exceeded, the oldest are evicted first.
+pub const MAX_TRACKED_TRANSACTIONS: usize = 4096;
+
+/// Summary of the tracked transactions, mirroring Bitcoin Core's `getmempoolinfo`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MemPoolInfo {
+    /// Number of tracked transactions.
+    pub size: usize,
+    /// Total size of the tracked transactions, in bytes.
+    pub bytes: usize,
+    /// Lowest fee rate among the tracked transactions whose fee is known, in satoshis per
+    /// virtual byte. `None` if no fees are known.
+    pub min_fee_rate: Option<f64>,
+}
+
+/// Unconfirmed transactions.
+#[derive(Debug, Default)]
+pub struct MemPool {
+    /// Tracked transactions, along with the sequence number of their insertion.
+    txs: HashMap<Txid, (u64, Transaction)>,
+    /// Transactions in the order they were inserted. May contain transactions that have
+    /// since been removed from `txs`.
+    arrivals: VecDeque<(u64, Txid)>,
+    /// Sequence number of the next insertion.
+    seq: u64,
+}
+
+impl MemPool {
+    /// Create an empty mempool.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Track a transaction. Returns `false` if it was already tracked.
+    pub fn insert(&mut self, tx: Transaction) -> bool {
+        let txid = tx.txid();
+
+        if self.txs.contains_key(&txid) {
+            return false;
+        }
+        self.txs.insert(txid, (self.seq, tx));
+        self.arrivals.push_back((self.seq, txid));
+        self.seq += 1;
+
+        while self.txs.len() > MAX_TRACKED_TRANSACTIONS {
+            if let Some((seq, txid)) = self.arrivals.pop_front() {
+                if matches!(self.txs.get(&txid), Some((s, _)) if *s == seq) {
+                    self.txs.remove(&txid);
+                }
+            }
+        }
+        // Drop the arrivals of transactions that are no longer tracked.
+        if self.arrivals.len() > MAX_TRACKED_TRANSACTIONS * 2 {
+            let txs = &self.txs;
+
+            self.arrivals
+                .retain(|(seq, txid)| matches!(txs.get(txid), Some((s, _)) if s == seq));
+        }
+        true
+    }
+
+    /// Check whether a transaction is tracked.
+    pub fn contains(&self, txid: &Txid) -> bool {
+        self.txs.contains_key(txid)
+    }
+
+    /// Stop tracking the transactions confirmed by the given block.
+    pub fn remove_confirmed(&mut self, block: &Block) {
+        for tx in &block.txdata {
+            self.txs.remove(&tx.txid());
+        }
+    }
+
+    /// Get a summary of the tracked transactions.
+    pub fn info(&self) -> MemPoolInfo {
+        let mut bytes = 0;
+        let mut min_fee_rate: Option<f64> = None;
+
+        for (_, tx) in self.txs.values() {
+            bytes += tx.get_size();
+
+            if let Some(fee) = self.fee(tx) {
+                let vsize = tx.get_weight().div_ceil(4);
+                let rate = fee as f64 / vsize as f64;
+
+                min_fee_rate = Some(min_fee_rate.map_or(rate, |min| min.min(rate)));
+            }
+        }
+
+        MemPoolInfo {
+            size: self.txs.len(),
+            bytes,
+            min_fee_rate,
+        }
+    }
+
+    /// Get the fee paid by a transaction, if all the outputs it spends are tracked.
+    fn fee(&self, tx: &Transaction) -> Option<u64> {
+        let mut spent = 0;
+
+        for input in &tx.input {
+            let (_, prev) = self.txs.get(&input.previous_output.txid)?;
+            let output = prev.output.get(input.previous_output.vout as usize)?;
+
+            spent += output.value;
+        }
+        let sent = tx.output.iter().map(|o| o.value).sum::<u64>();
+
+        spent.checked_sub(sent)
+    }
+}
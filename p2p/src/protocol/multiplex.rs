@@ -0,0 +1,323 @@
+//! Run multiple protocol instances behind a single reactor.
+//!
+//! Each instance runs its own network, eg. mainnet and testnet, with its own consensus
+//! parameters and message magic. The multiplexer offers the same interface as a single
+//! protocol: it is initialized, stepped with inputs, and sends its outputs on a single
+//! channel. It can thus be driven by a reactor, see [`Machine`].
+//!
+//! Peers of different networks may share an address, eg. if a host is listed in the address
+//! books of two instances. The multiplexer therefore identifies peers by [`Peer`], which
+//! namespaces their address by the instance owning them, so that the reactor keeps their
+//! connections apart. Instances only ever see the addresses of their own peers:
+//!
+//! * Outbound peers are owned by the instance that requested the connection.
+//! * Inbound peers are owned by the instance whose network magic matches the first
+//!   message received from the peer. Until then, the connection is held back.
+//!
+//! Commands are routed to the instance running the network they are given with, and
+//! timeouts are delivered to all instances.
+//!
+use std::collections::HashMap;
+use std::fmt;
+use std::net;
+
+use crossbeam_channel as chan;
+use log::*;
+
+use nakamoto_common::block::filter::Filters;
+use nakamoto_common::block::time::LocalTime;
+use nakamoto_common::block::tree::BlockTree;
+use nakamoto_common::network::Network;
+use nakamoto_common::p2p::peer;
+
+use super::{Builder, Command, DisconnectReason, Input, Out, PeerId, Protocol};
+use crate::error::Error;
+use crate::reactor::{Machine, PeerKey};
+
+/// A peer of the multiplexer: its address, namespaced by the instance owning it.
+///
+/// Outbound peers are namespaced by the instance that connected to them. Inbound peers are
+/// accepted before it is known which instance owns them, and aren't namespaced: since the
+/// address of an inbound peer is that of its end of the connection, it is unique to it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Peer {
+    /// Index of the instance that connected to the peer, for outbound peers.
+    pub instance: Option<usize>,
+    /// Address of the peer.
+    pub addr: PeerId,
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.instance {
+            Some(instance) => write!(f, "{}#{}", self.addr, instance),
+            None => write!(f, "{}", self.addr),
+        }
+    }
+}
+
+impl PeerKey for Peer {
+    fn addr(&self) -> net::SocketAddr {
+        self.addr
+    }
+
+    fn inbound(addr: net::SocketAddr) -> Self {
+        Self {
+            instance: None,
+            addr,
+        }
+    }
+}
+
+/// A protocol instance, along with the channel it sends its outputs on.
+#[derive(Debug)]
+struct Instance<T, F, P> {
+    protocol: Protocol<T, F, P>,
+    outbound: chan::Receiver<Out>,
+}
+
+/// Multiplexes protocol instances running different networks.
+#[derive(Debug)]
+pub struct Multiplexer<T, F, P> {
+    /// Protocol instances.
+    instances: Vec<Instance<T, F, P>>,
+    /// The peer behind each address known to an instance, by instance index and address.
+    routes: HashMap<(usize, PeerId), Peer>,
+    /// The instance owning each inbound peer.
+    inbound: HashMap<PeerId, usize>,
+    /// Inbound connections that aren't owned by any instance yet.
+    pending: HashMap<PeerId, Input>,
+    /// Channel for the outputs of all instances.
+    upstream: chan::Sender<Out<Peer>>,
+}
+
+impl<T: BlockTree, F: Filters, P: peer::Store> Multiplexer<T, F, P> {
+    /// Construct a multiplexer from the given protocol builders, one per instance.
+    ///
+    /// # Panics
+    ///
+    /// Panics if no builders are given, or if two builders are configured with networks
+    /// sharing the same message magic.
+    ///
+    pub fn new(builders: Vec<Builder<T, F, P>>, upstream: chan::Sender<Out<Peer>>) -> Self {
+        assert!(
+            !builders.is_empty(),
+            "Multiplexer::new: at least one instance is required"
+        );
+
+        let instances = builders
+            .into_iter()
+            .map(|builder| {
+                let (tx, rx) = chan::unbounded();
+
+                Instance {
+                    protocol: builder.build(tx),
+                    outbound: rx,
+                }
+            })
+            .collect::<Vec<_>>();
+
+        for (i, instance) in instances.iter().enumerate() {
+            assert!(
+                instances[..i].iter().all(
+                    |other| other.protocol.network.magic() != instance.protocol.network.magic()
+                ),
+                "Multiplexer::new: instances must run networks with different magics"
+            );
+        }
+
+        Self {
+            instances,
+            routes: HashMap::new(),
+            inbound: HashMap::new(),
+            pending: HashMap::new(),
+            upstream,
+        }
+    }
+
+    /// Get the protocol instance at the given index, in the order it was built.
+    pub fn instance(&self, index: usize) -> Option<&Protocol<T, F, P>> {
+        self.instances.get(index).map(|i| &i.protocol)
+    }
+
+    /// Get the index of the instance running the given network.
+    pub fn index(&self, network: Network) -> Option<usize> {
+        self.instances
+            .iter()
+            .position(|i| i.protocol.network.magic() == network.magic())
+    }
+
+    /// Deliver a command to the instance running the given network.
+    pub fn command(&mut self, network: Network, cmd: Command, time: LocalTime) {
+        self.step(Input::Command((network, cmd)), time);
+    }
+
+    /// Get the instance owning the given peer, if any.
+    fn owner(&self, peer: &Peer) -> Option<usize> {
+        match peer.instance {
+            Some(index) => Some(index),
+            None => self.inbound.get(&peer.addr).copied(),
+        }
+    }
+
+    /// Deliver an input to an instance.
+    fn deliver(&mut self, index: usize, input: Input, time: LocalTime) {
+        self.instances[index].protocol.step(input, time);
+        self.drain(index);
+    }
+
+    /// Forward the outputs of an instance upstream, namespacing the peers they concern.
+    fn drain(&mut self, index: usize) {
+        let outputs = self.instances[index]
+            .outbound
+            .try_iter()
+            .collect::<Vec<_>>();
+
+        for out in outputs {
+            let out = match out {
+                Out::Connect(addr, timeout) => {
+                    let peer = Peer {
+                        instance: Some(index),
+                        addr,
+                    };
+                    self.routes.insert((index, addr), peer);
+
+                    Out::Connect(peer, timeout)
+                }
+                // Outputs for peers that are no longer connected are dropped, as the reactor
+                // would.
+                Out::Message(addr, msg) => match self.routes.get(&(index, addr)) {
+                    Some(peer) => Out::Message(*peer, msg),
+                    None => continue,
+                },
+                Out::Disconnect(addr, reason) => match self.routes.get(&(index, addr)) {
+                    Some(peer) => Out::Disconnect(*peer, reason),
+                    None => continue,
+                },
+                Out::SetTimeout(timeout) => Out::SetTimeout(timeout),
+                Out::Event(event) => Out::Event(event),
+                Out::Shutdown => Out::Shutdown,
+            };
+            self.upstream.send(out).unwrap();
+        }
+    }
+}
+
+impl<T: BlockTree, F: Filters, P: peer::Store> Machine for Multiplexer<T, F, P> {
+    type Peer = Peer;
+    type Command = (Network, Command);
+
+    /// Initialize all instances.
+    fn initialize(&mut self, time: LocalTime) -> Result<(), Error> {
+        for index in 0..self.instances.len() {
+            self.instances[index].protocol.initialize(time)?;
+            self.drain(index);
+        }
+        Ok(())
+    }
+
+    /// Process the next input and advance the state machines by one step.
+    fn step(&mut self, input: Input<Peer, (Network, Command)>, time: LocalTime) {
+        match input {
+            Input::Connected {
+                addr: peer,
+                local_addr,
+                link,
+            } if peer.instance.is_none() && !self.inbound.contains_key(&peer.addr) => {
+                self.pending.insert(
+                    peer.addr,
+                    Input::Connected {
+                        addr: peer.addr,
+                        local_addr,
+                        link,
+                    },
+                );
+            }
+            Input::Received(peer, msg)
+                if peer.instance.is_none() && self.pending.contains_key(&peer.addr) =>
+            {
+                let index = self
+                    .instances
+                    .iter()
+                    .position(|i| i.protocol.network.magic() == msg.magic);
+
+                let reason = match index {
+                    // The instance is already connected to a peer at this address.
+                    Some(index) if self.routes.contains_key(&(index, peer.addr)) => {
+                        DisconnectReason::DuplicateConnection
+                    }
+                    Some(index) => {
+                        if let Some(connected) = self.pending.remove(&peer.addr) {
+                            self.inbound.insert(peer.addr, index);
+                            self.routes.insert((index, peer.addr), peer);
+                            self.deliver(index, connected, time);
+                            self.deliver(index, Input::Received(peer.addr, msg), time);
+                        }
+                        return;
+                    }
+                    None => DisconnectReason::PeerMagic(msg.magic),
+                };
+                self.upstream.send(Out::Disconnect(peer, reason)).unwrap();
+            }
+            Input::Disconnected(peer, reason) => {
+                if peer.instance.is_none() && self.pending.remove(&peer.addr).is_some() {
+                    return;
+                }
+                if let Some(index) = self.owner(&peer) {
+                    if peer.instance.is_none() {
+                        self.inbound.remove(&peer.addr);
+                    }
+                    if self.routes.get(&(index, peer.addr)) == Some(&peer) {
+                        self.routes.remove(&(index, peer.addr));
+                        self.deliver(index, Input::Disconnected(peer.addr, reason), time);
+                    }
+                }
+            }
+            Input::Connecting { addr: peer } => {
+                if let Some(index) = self.owner(&peer) {
+                    self.deliver(index, Input::Connecting { addr: peer.addr }, time);
+                }
+            }
+            Input::Connected {
+                addr: peer,
+                local_addr,
+                link,
+            } => {
+                if let Some(index) = self.owner(&peer) {
+                    let input = Input::Connected {
+                        addr: peer.addr,
+                        local_addr,
+                        link,
+                    };
+                    self.deliver(index, input, time);
+                }
+            }
+            Input::Received(peer, msg) => {
+                if let Some(index) = self.owner(&peer) {
+                    self.deliver(index, Input::Received(peer.addr, msg), time);
+                }
+            }
+            Input::Unrecognized(peer, cmd) => {
+                if let Some(index) = self.owner(&peer) {
+                    self.deliver(index, Input::Unrecognized(peer.addr, cmd), time);
+                }
+            }
+            Input::Sent(peer, size) => {
+                if let Some(index) = self.owner(&peer) {
+                    self.deliver(index, Input::Sent(peer.addr, size), time);
+                }
+            }
+            Input::Command((network, cmd)) => match self.index(network) {
+                Some(index) => self.deliver(index, Input::Command(cmd), time),
+                None => {
+                    warn!("Ignoring command: no instance is running {:?}", network);
+                }
+            },
+            Input::Timeout => {
+                for index in 0..self.instances.len() {
+                    self.deliver(index, Input::Timeout, time);
+                }
+            }
+        }
+    }
+}
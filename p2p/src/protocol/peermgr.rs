@@ -14,6 +14,19 @@
 //!   3. Send `verack` message.
 //!   4. Expect `verack` message from remote.
 //!
+//! Peers may also send feature negotiation messages as part of the handshake. Which ones
+//! are valid depends on how far along the handshake is:
+//!
+//! | Message       | Before `version` | Before `verack` | After `verack` |
+//! |---------------|------------------|-----------------|----------------|
+//! | `sendheaders` | no               | yes             | yes            |
+//! | `sendcmpct`   | no               | yes             | yes            |
+//! | `wtxidrelay`  | no               | yes             | no (BIP 339)   |
+//! | `sendaddrv2`  | no               | yes             | no (BIP 155)   |
+//!
+//! Valid messages are recorded in the peer's [`PeerFeatures`], others are ignored.
+//!
+use std::collections::BTreeMap;
 use std::net;
 
 use bitcoin::network::address::Address;
@@ -24,7 +37,7 @@ use nakamoto_common::block::time::{LocalDuration, LocalTime};
 use nakamoto_common::block::Height;
 use nakamoto_common::collections::HashMap;
 
-use crate::protocol::addrmgr;
+use crate::protocol::{addrmgr, state};
 
 use super::{
     channel::{Disconnect, SetTimeout},
@@ -38,6 +51,12 @@ pub const HANDSHAKE_TIMEOUT: LocalDuration = LocalDuration::from_secs(10);
 /// Maximum height difference for a stale peer, to maintain the connection (2 weeks).
 const MAX_STALE_HEIGHT_DIFFERENCE: Height = 2016;
 
+/// Minimum number of negotiated peers needed to compare our protocol version to theirs.
+const MIN_VERSION_SAMPLE: usize = 3;
+/// How far above ours the median protocol version of our peers may be, before we
+/// consider our client outdated.
+const MAX_VERSION_LAG: u32 = 2;
+
 /// A time offset, in seconds.
 type TimeOffset = i64;
 
@@ -56,6 +75,14 @@ pub enum Event {
         /// The peer's id.
         addr: PeerId,
     },
+    /// Our peers mostly run a protocol version that is notably higher than ours. The
+    /// client should probably be upgraded. Emitted at most once.
+    OutdatedClient {
+        /// Our protocol version.
+        our_version: u32,
+        /// The median protocol version of our peers.
+        network_median: u32,
+    },
 }
 
 impl std::fmt::Display for Event {
@@ -67,6 +94,14 @@ impl std::fmt::Display for Event {
                 addr, msg.version, msg.start_height, msg.user_agent, msg.services, msg.timestamp
             ),
             Self::PeerNegotiated { addr } => write!(fmt, "{}: Peer negotiated..", addr),
+            Self::OutdatedClient {
+                our_version,
+                network_median,
+            } => write!(
+                fmt,
+                "Protocol version {} is outdated, peers run version {}",
+                our_version, network_median
+            ),
         }
     }
 }
@@ -98,6 +133,58 @@ pub struct Config {
     pub required_services: ServiceFlags,
     /// Our user agent.
     pub user_agent: &'static str,
+    /// Time to wait for a connected peer to complete the handshake.
+    pub handshake_timeout: LocalDuration,
+    /// Whether we accept inbound connections. If not, the address we send in `version`
+    /// messages is unspecified, so that peers don't try to reach us at it.
+    pub accept_inbound: bool,
+}
+
+/// A feature negotiation message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Feature {
+    /// `sendheaders` (BIP 130): announce blocks with `headers` instead of `inv`.
+    SendHeaders,
+    /// `sendcmpct` (BIP 152): announce blocks with compact blocks.
+    SendCompact,
+    /// `wtxidrelay` (BIP 339): relay transactions by witness transaction id.
+    WtxidRelay,
+    /// `sendaddrv2` (BIP 155): relay addresses with `addrv2`.
+    SendAddrV2,
+}
+
+impl Feature {
+    /// Get the feature negotiated by the message with the given command, if any.
+    pub fn from_cmd(cmd: &str) -> Option<Self> {
+        match cmd {
+            "sendheaders" => Some(Self::SendHeaders),
+            "sendcmpct" => Some(Self::SendCompact),
+            "wtxidrelay" => Some(Self::WtxidRelay),
+            "sendaddrv2" => Some(Self::SendAddrV2),
+            _ => None,
+        }
+    }
+
+    /// Whether the feature may still be negotiated once the handshake is complete.
+    fn is_allowed_after_verack(&self) -> bool {
+        match self {
+            Self::SendHeaders | Self::SendCompact => true,
+            Self::WtxidRelay | Self::SendAddrV2 => false,
+        }
+    }
+}
+
+/// Features negotiated by a peer. See [`Feature`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PeerFeatures {
+    /// The peer sent `sendheaders`.
+    pub send_headers: bool,
+    /// The peer sent `sendcmpct`.
+    pub send_compact: bool,
+    /// The peer sent `wtxidrelay`.
+    pub wtxid_relay: bool,
+    /// The peer sent `sendaddrv2`.
+    pub send_addr_v2: bool,
 }
 
 /// Peer states.
@@ -124,6 +211,9 @@ pub struct Connection {
     pub link: Link,
     /// Connected since this time.
     pub since: LocalTime,
+    /// Number of messages received from the peer, by command. An unusual distribution
+    /// can point to a misbehaving or specialized peer.
+    pub received: BTreeMap<String, usize>,
 }
 
 /// A peer with connection and protocol information.
@@ -143,6 +233,10 @@ pub struct Peer {
     pub time_offset: TimeOffset,
     /// Whether this peer relays transactions.
     pub relay: bool,
+    /// Highest protocol version understood by the peer.
+    pub version: u32,
+    /// Features negotiated by the peer.
+    pub features: PeerFeatures,
 
     /// Peer nonce. Used to detect self-connections.
     nonce: u64,
@@ -168,6 +262,8 @@ pub struct PeerManager<U> {
     config: Config,
     connections: HashMap<net::SocketAddr, Connection>,
     peers: HashMap<PeerId, Peer>,
+    /// Whether we warned that our protocol version is outdated.
+    outdated: bool,
     upstream: U,
     rng: fastrand::Rng,
 }
@@ -182,6 +278,7 @@ impl<U: Handshake + SetTimeout + Disconnect + Events> PeerManager<U> {
             config,
             connections,
             peers,
+            outdated: false,
             upstream,
             rng,
         }
@@ -220,6 +317,7 @@ impl<U: Handshake + SetTimeout + Disconnect + Events> PeerManager<U> {
                 local_addr,
                 link,
                 since: local_time,
+                received: BTreeMap::new(),
             },
         );
 
@@ -234,7 +332,85 @@ impl<U: Handshake + SetTimeout + Disconnect + Events> PeerManager<U> {
             }
         }
         // Set a timeout for receiving the `version` message.
-        self.upstream.set_timeout(HANDSHAKE_TIMEOUT);
+        self.upstream.set_timeout(self.config.handshake_timeout);
+    }
+
+    /// Export the negotiated peers. Peers which haven't completed the handshake are left out.
+    pub fn export(&self) -> Vec<state::Peer> {
+        self.peers
+            .values()
+            .filter(|p| p.is_negotiated())
+            .map(|p| state::Peer {
+                addr: p.conn.addr,
+                local_addr: p.conn.local_addr,
+                link: p.conn.link,
+                height: p.height,
+                services: p.services,
+                user_agent: p.user_agent.clone(),
+                time_offset: p.time_offset,
+                relay: p.relay,
+                version: p.version,
+                nonce: p.nonce,
+                // Misbehavior is scored by the sync manager.
+                misbehavior: 0,
+            })
+            .collect()
+    }
+
+    /// Restore a peer exported by another peer manager. The peer is considered negotiated,
+    /// and no handshake messages are sent.
+    pub fn restore(&mut self, peer: state::Peer, local_time: LocalTime) -> &Peer {
+        let addr = peer.addr;
+
+        self.connections.remove(&addr);
+        self.peers.insert(
+            addr,
+            Peer {
+                conn: Connection {
+                    addr,
+                    local_addr: peer.local_addr,
+                    link: peer.link,
+                    since: local_time,
+                    received: BTreeMap::new(),
+                },
+                height: peer.height,
+                services: peer.services,
+                user_agent: peer.user_agent,
+                time_offset: peer.time_offset,
+                relay: peer.relay,
+                version: peer.version,
+                features: PeerFeatures::default(),
+                nonce: peer.nonce,
+                state: PeerState::Negotiated { since: local_time },
+            },
+        );
+        &self.peers[&addr]
+    }
+
+    /// Called when a message was received from a peer, before it is processed.
+    pub fn received_message(&mut self, addr: &PeerId, cmd: &str) {
+        let conn = match self.peers.get_mut(addr) {
+            Some(peer) => &mut peer.conn,
+            None => match self.connections.get_mut(addr) {
+                Some(conn) => conn,
+                None => return,
+            },
+        };
+        match conn.received.get_mut(cmd) {
+            Some(count) => *count += 1,
+            None => {
+                conn.received.insert(cmd.to_owned(), 1);
+            }
+        }
+    }
+
+    /// Get the number of messages received from a peer, by command.
+    pub fn received_messages(&self, addr: &PeerId) -> Option<&BTreeMap<String, usize>> {
+        self.peers
+            .get(addr)
+            .map(|p| &p.conn)
+            .or_else(|| self.connections.get(addr))
+            .map(|c| &c.received)
     }
 
     /// Called when a peer disconnected.
@@ -319,6 +495,13 @@ impl<U: Handshake + SetTimeout + Disconnect + Events> PeerManager<U> {
                         .disconnect(*addr, DisconnectReason::SelfConnection);
                 }
             }
+            // If another peer sent us the same nonce, it's likely the same node reached via
+            // a different address. Keep the older connection.
+            if self.peers.values().any(|p| p.nonce == nonce) {
+                return self
+                    .upstream
+                    .disconnect(*addr, DisconnectReason::DuplicateConnection);
+            }
 
             // Record the address this peer has of us.
             if let Ok(addr) = receiver.socket_addr() {
@@ -329,7 +512,7 @@ impl<U: Handshake + SetTimeout + Disconnect + Events> PeerManager<U> {
                 Link::Outbound => {
                     self.upstream
                         .verack(conn.addr)
-                        .set_timeout(HANDSHAKE_TIMEOUT);
+                        .set_timeout(self.config.handshake_timeout);
                 }
                 Link::Inbound => {
                     self.upstream
@@ -338,7 +521,7 @@ impl<U: Handshake + SetTimeout + Disconnect + Events> PeerManager<U> {
                             self.version(conn.addr, conn.local_addr, nonce, height, now),
                         )
                         .verack(conn.addr)
-                        .set_timeout(HANDSHAKE_TIMEOUT);
+                        .set_timeout(self.config.handshake_timeout);
                 }
             }
 
@@ -353,11 +536,37 @@ impl<U: Handshake + SetTimeout + Disconnect + Events> PeerManager<U> {
                     user_agent,
                     state: PeerState::AwaitingVerack { since: now },
                     relay,
+                    version,
+                    features: PeerFeatures::default(),
                 },
             );
         }
     }
 
+    /// Called when a feature negotiation message was received. Returns whether the
+    /// feature was recorded, ie. whether the message was valid at this stage of the
+    /// handshake. See the module documentation.
+    pub fn received_feature(&mut self, addr: &PeerId, feature: Feature) -> bool {
+        let peer = if let Some(peer) = self.peers.get_mut(addr) {
+            peer
+        } else {
+            // The peer hasn't sent its `version` message yet.
+            return false;
+        };
+        if peer.is_negotiated() && !feature.is_allowed_after_verack() {
+            return false;
+        }
+        let features = &mut peer.features;
+
+        match feature {
+            Feature::SendHeaders => features.send_headers = true,
+            Feature::SendCompact => features.send_compact = true,
+            Feature::WtxidRelay => features.wtxid_relay = true,
+            Feature::SendAddrV2 => features.send_addr_v2 = true,
+        }
+        true
+    }
+
     /// Called when a `verack` message was received.
     pub fn received_verack(&mut self, addr: &PeerId, local_time: LocalTime) -> Option<&Peer> {
         if let Some(peer) = self.peers.get_mut(addr) {
@@ -366,7 +575,9 @@ impl<U: Handshake + SetTimeout + Disconnect + Events> PeerManager<U> {
 
                 peer.state = PeerState::Negotiated { since: local_time };
 
-                return Some(peer);
+                self.check_version();
+
+                return self.peers.get(addr);
             } else {
                 self.upstream.disconnect(
                     *addr,
@@ -377,6 +588,35 @@ impl<U: Handshake + SetTimeout + Disconnect + Events> PeerManager<U> {
         None
     }
 
+    /// Warn if the median protocol version of our peers is notably higher than ours.
+    fn check_version(&mut self) {
+        if self.outdated {
+            return;
+        }
+        let mut versions = self
+            .peers
+            .values()
+            .filter(|p| p.is_negotiated())
+            .map(|p| p.version)
+            .collect::<Vec<_>>();
+
+        if versions.len() < MIN_VERSION_SAMPLE {
+            return;
+        }
+        versions.sort_unstable();
+
+        let median = versions[versions.len() / 2];
+        let ours = self.config.protocol_version;
+
+        if median > ours + MAX_VERSION_LAG {
+            self.outdated = true;
+            self.upstream.event(Event::OutdatedClient {
+                our_version: ours,
+                network_median: median,
+            });
+        }
+    }
+
     /// Called when a timeout was received.
     pub fn received_timeout(&mut self, local_time: LocalTime) {
         let mut timed_out = Vec::new();
@@ -384,7 +624,7 @@ impl<U: Handshake + SetTimeout + Disconnect + Events> PeerManager<U> {
         for (addr, peer) in self.peers.iter() {
             match peer.state {
                 PeerState::AwaitingVerack { since } => {
-                    if local_time - since >= HANDSHAKE_TIMEOUT {
+                    if local_time - since >= self.config.handshake_timeout {
                         timed_out.push(*addr);
                     }
                 }
@@ -392,14 +632,14 @@ impl<U: Handshake + SetTimeout + Disconnect + Events> PeerManager<U> {
             }
         }
         for (addr, conn) in self.connections.iter() {
-            if local_time - conn.since >= HANDSHAKE_TIMEOUT {
+            if local_time - conn.since >= self.config.handshake_timeout {
                 timed_out.push(*addr);
             }
         }
 
         for addr in timed_out {
             self.upstream
-                .disconnect(addr, DisconnectReason::PeerTimeout);
+                .disconnect(addr, DisconnectReason::PeerHandshakeTimeout);
         }
     }
 
@@ -414,6 +654,11 @@ impl<U: Handshake + SetTimeout + Disconnect + Events> PeerManager<U> {
     ) -> VersionMessage {
         let start_height = start_height as i32;
         let timestamp = local_time.block_time() as i64;
+        let sender = if self.config.accept_inbound {
+            local_addr
+        } else {
+            ([0, 0, 0, 0], 0).into()
+        };
 
         VersionMessage {
             // Our max supported protocol version.
@@ -425,7 +670,7 @@ impl<U: Handshake + SetTimeout + Disconnect + Events> PeerManager<U> {
             // Receiver address and services, as perceived by us.
             receiver: Address::new(&addr, ServiceFlags::NONE),
             // Local address (unreliable) and local services (same as `services` field)
-            sender: Address::new(&local_addr, self.config.services),
+            sender: Address::new(&sender, self.config.services),
             // A nonce to detect connections to self.
             nonce,
             // Our user agent string.
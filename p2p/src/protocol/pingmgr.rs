@@ -3,7 +3,7 @@ use std::collections::VecDeque;
 use std::net;
 
 use nakamoto_common::block::time::{LocalDuration, LocalTime};
-use nakamoto_common::collections::HashMap;
+use nakamoto_common::collections::{HashMap, HashSet};
 
 use crate::protocol::PeerId;
 
@@ -16,6 +16,8 @@ use super::{
 pub const PING_INTERVAL: LocalDuration = LocalDuration::from_mins(2);
 /// Time to wait to receive a pong when sending a ping.
 pub const PING_TIMEOUT: LocalDuration = LocalDuration::from_secs(30);
+/// Protocol version from which peers reply to `ping` with a `pong` (BIP 31).
+pub const BIP31_VERSION: u32 = 60001;
 
 /// Maximum number of latencies recorded per peer.
 const MAX_RECORDED_LATENCIES: usize = 64;
@@ -27,8 +29,17 @@ pub trait Ping {
 
 #[derive(Debug)]
 enum State {
-    AwaitingPong { nonce: u64, since: LocalTime },
-    Idle { since: LocalTime },
+    AwaitingPong {
+        nonce: u64,
+        since: LocalTime,
+    },
+    Idle {
+        since: LocalTime,
+    },
+    /// The peer predates BIP 31, and doesn't reply to pings. We ping it as a keepalive only.
+    Keepalive {
+        since: LocalTime,
+    },
 }
 
 #[derive(Debug)]
@@ -41,7 +52,6 @@ struct Peer {
 
 impl Peer {
     /// Calculate the average latency of this peer.
-    #[allow(dead_code)]
     fn latency(&self) -> LocalDuration {
         let sum: LocalDuration = self.latencies.iter().sum();
 
@@ -57,31 +67,46 @@ impl Peer {
 #[derive(Debug)]
 pub struct PingManager<U> {
     peers: HashMap<PeerId, Peer>,
+    /// Peers that are never disconnected for failing to respond to a `ping`.
+    persistent: HashSet<PeerId>,
     /// Random number generator.
     rng: fastrand::Rng,
     upstream: U,
 }
 
 impl<U: Ping + SetTimeout + Disconnect> PingManager<U> {
-    pub fn new(rng: fastrand::Rng, upstream: U) -> Self {
+    pub fn new(persistent: Vec<PeerId>, rng: fastrand::Rng, upstream: U) -> Self {
         let peers = HashMap::with_hasher(rng.clone().into());
+        let mut set = HashSet::with_hasher(rng.clone().into());
+        set.extend(persistent);
 
         Self {
             peers,
+            persistent: set,
             rng,
             upstream,
         }
     }
 
-    pub fn peer_negotiated(&mut self, address: PeerId, now: LocalTime) {
+    pub fn peer_negotiated(&mut self, address: PeerId, version: u32, now: LocalTime) {
         let nonce = self.rng.u64(..);
+        let state = if version < BIP31_VERSION {
+            self.upstream
+                .ping(address, nonce)
+                .set_timeout(PING_INTERVAL);
+
+            State::Keepalive { since: now }
+        } else {
+            self.upstream.ping(address, nonce);
+
+            State::AwaitingPong { nonce, since: now }
+        };
 
-        self.upstream.ping(address, nonce);
         self.peers.insert(
             address,
             Peer {
                 address,
-                state: State::AwaitingPong { nonce, since: now },
+                state,
                 latencies: VecDeque::new(),
             },
         );
@@ -97,8 +122,17 @@ impl<U: Ping + SetTimeout + Disconnect> PingManager<U> {
                 State::AwaitingPong { since, .. } => {
                     // A ping was sent and we're waiting for a `pong`. If too much
                     // time has passed, we consider this peer dead, and disconnect
-                    // from them.
-                    if now - since >= PING_TIMEOUT {
+                    // from them. Persistent peers are pinged again instead.
+                    if now - since >= PING_TIMEOUT && self.persistent.contains(&peer.address) {
+                        let nonce = self.rng.u64(..);
+
+                        self.upstream
+                            .ping(peer.address, nonce)
+                            .set_timeout(PING_TIMEOUT)
+                            .set_timeout(PING_INTERVAL);
+
+                        peer.state = State::AwaitingPong { nonce, since: now };
+                    } else if now - since >= PING_TIMEOUT {
                         self.upstream
                             .disconnect(peer.address, DisconnectReason::PeerTimeout);
                     }
@@ -117,6 +151,18 @@ impl<U: Ping + SetTimeout + Disconnect> PingManager<U> {
                         peer.state = State::AwaitingPong { nonce, since: now };
                     }
                 }
+                State::Keepalive { since } => {
+                    // There is no `pong` to wait for, so the peer is never timed out.
+                    if now - since >= PING_INTERVAL {
+                        let nonce = self.rng.u64(..);
+
+                        self.upstream
+                            .ping(peer.address, nonce)
+                            .set_timeout(PING_INTERVAL);
+
+                        peer.state = State::Keepalive { since: now };
+                    }
+                }
             }
         }
     }
@@ -125,21 +171,28 @@ impl<U: Ping + SetTimeout + Disconnect> PingManager<U> {
         self.upstream.pong(addr, nonce);
     }
 
-    pub fn received_pong(&mut self, addr: PeerId, nonce: u64, now: LocalTime) {
-        if let Some(peer) = self.peers.get_mut(&addr) {
-            match peer.state {
-                State::AwaitingPong {
-                    nonce: last_nonce,
-                    since,
-                } => {
-                    if nonce == last_nonce {
-                        peer.record_latency(now - since);
-                        peer.state = State::Idle { since: now };
-                    }
-                }
-                // Unsolicited or redundant `pong`. Ignore.
-                State::Idle { .. } => {}
+    /// Called when a `pong` was received. Returns the average latency of the peer, if the
+    /// `pong` was in response to our last `ping`.
+    pub fn received_pong(
+        &mut self,
+        addr: PeerId,
+        nonce: u64,
+        now: LocalTime,
+    ) -> Option<LocalDuration> {
+        let peer = self.peers.get_mut(&addr)?;
+
+        match peer.state {
+            State::AwaitingPong {
+                nonce: last_nonce,
+                since,
+            } if nonce == last_nonce => {
+                peer.record_latency(now - since);
+                peer.state = State::Idle { since: now };
+
+                Some(peer.latency())
             }
+            // Unsolicited or redundant `pong`. Ignore.
+            State::AwaitingPong { .. } | State::Idle { .. } | State::Keepalive { .. } => None,
         }
     }
 }
@@ -0,0 +1,65 @@
+//! Inference of whether we are reachable from the internet.
+//!
+//! There's no direct way for a node to tell whether others can connect to it, eg. whether
+//! port forwarding is set up. Instead, it's inferred from inbound connections: a peer that
+//! completes a handshake over an inbound connection shows we're reachable, while a long
+//! absence of inbound connections, when we accept them, suggests we aren't.
+//!
+use nakamoto_common::block::time::{LocalDuration, LocalTime};
+
+use crate::event::Event;
+
+use super::Link;
+
+/// How long to wait for an inbound connection before warning that there are none.
+pub const INBOUND_WINDOW: LocalDuration = LocalDuration::from_mins(30);
+
+/// Tracks whether we are reachable by peers.
+#[derive(Debug, Default)]
+pub struct Reachability {
+    /// Time since which we're waiting for inbound connections, if we accept them.
+    since: Option<LocalTime>,
+    /// Whether a peer negotiated an inbound connection.
+    reachable: bool,
+    /// Whether we warned about the absence of inbound connections.
+    warned: bool,
+}
+
+impl Reachability {
+    /// Create a new reachability tracker.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start waiting for inbound connections.
+    pub fn initialize(&mut self, now: LocalTime) {
+        self.since = Some(now);
+    }
+
+    /// Called when a peer completed the handshake. Returns an event on the first inbound
+    /// handshake.
+    pub fn peer_negotiated(&mut self, link: Link) -> Option<Event> {
+        if link.is_outbound() || self.reachable {
+            return None;
+        }
+        self.reachable = true;
+
+        Some(Event::InboundReachable)
+    }
+
+    /// Called periodically. Returns an event if no inbound handshake happened within
+    /// [`INBOUND_WINDOW`] of initialization.
+    pub fn tick(&mut self, now: LocalTime) -> Option<Event> {
+        if self.reachable || self.warned {
+            return None;
+        }
+        match self.since {
+            Some(since) if now - since >= INBOUND_WINDOW => {
+                self.warned = true;
+
+                Some(Event::NoInboundConnections(INBOUND_WINDOW))
+            }
+            _ => None,
+        }
+    }
+}
@@ -9,7 +9,9 @@ use nonempty::NonEmpty;
 use thiserror::Error;
 
 use bitcoin::network::constants::ServiceFlags;
-use bitcoin::network::message_filter::{CFHeaders, CFilter, GetCFHeaders, GetCFilters};
+use bitcoin::network::message_filter::{
+    CFCheckpt, CFHeaders, CFilter, GetCFCheckpt, GetCFHeaders, GetCFilters,
+};
 
 use nakamoto_common::block::filter::{self, BlockFilter, FilterHeader, Filters};
 use nakamoto_common::block::time::{Clock, LocalDuration, LocalTime};
@@ -32,6 +34,15 @@ const MAX_MESSAGE_CFHEADERS: usize = 2000;
 /// Maximum filters to be expected in a message.
 const MAX_MESSAGE_CFILTERS: usize = 1000;
 
+/// Interval between filter headers returned in a `cfcheckpt` message.
+const CFCHECKPT_INTERVAL: Height = 1000;
+
+/// Maximum number of filter requests we serve to a single peer, per `SERVE_WINDOW`.
+const MAX_SERVED_REQUESTS: usize = 32;
+
+/// Time window over which served filter requests are counted.
+const SERVE_WINDOW: LocalDuration = LocalDuration::from_mins(1);
+
 /// An error originating in the SPV manager.
 #[derive(Error, Debug)]
 pub enum Error {
@@ -169,6 +180,8 @@ pub trait SyncFilters {
     fn send_cfheaders(&self, addr: PeerId, headers: CFHeaders);
     /// Send a compact filter to a peer.
     fn send_cfilter(&self, addr: PeerId, filter: CFilter);
+    /// Send compact filter header checkpoints to a peer.
+    fn send_cfcheckpt(&self, addr: PeerId, checkpt: CFCheckpt);
 }
 
 /// The ability to emit SPV related events.
@@ -206,6 +219,8 @@ pub struct SpvManager<F, U> {
     peers: HashMap<PeerId, Peer>,
     filters: F,
     upstream: U,
+    /// Filter requests served to each peer, in the current window.
+    served: HashMap<PeerId, (LocalTime, usize)>,
     /// Last time we idled.
     last_idle: Option<LocalTime>,
     rng: fastrand::Rng,
@@ -215,10 +230,12 @@ impl<F: Filters, U: SyncFilters + Events + SetTimeout> SpvManager<F, U> {
     /// Create a new filter manager.
     pub fn new(config: Config, rng: fastrand::Rng, filters: F, upstream: U) -> Self {
         let peers = HashMap::with_hasher(rng.clone().into());
+        let served = HashMap::with_hasher(rng.clone().into());
 
         Self {
             config,
             peers,
+            served,
             upstream,
             filters,
             last_idle: None,
@@ -227,8 +244,28 @@ impl<F: Filters, U: SyncFilters + Events + SetTimeout> SpvManager<F, U> {
     }
 
     /// Initialize the spv manager. Should only be called once.
-    pub fn initialize<T: BlockTree>(&mut self, now: LocalTime, tree: &T) {
+    ///
+    /// The persisted filter header chain is checked against the block header chain: if the
+    /// block headers were re-organized, or rolled back while we were offline, the filter
+    /// headers past the last block the two chains agree on are discarded, so that they can
+    /// be re-synced.
+    pub fn initialize<T: BlockTree>(
+        &mut self,
+        now: LocalTime,
+        tree: &T,
+    ) -> Result<(), filter::Error> {
+        let mut height = Height::min(self.filters.height(), tree.height());
+
+        while height > 0 {
+            match self.filters.get_block_hash(height) {
+                Some(hash) if tree.get_block_hash(height) != Some(hash) => height -= 1,
+                _ => break,
+            }
+        }
+        self.rollback(height)?;
         self.idle(now, tree);
+
+        Ok(())
     }
 
     /// Called periodically. Triggers syncing if necessary.
@@ -245,9 +282,20 @@ impl<F: Filters, U: SyncFilters + Events + SetTimeout> SpvManager<F, U> {
         self.idle(now, tree);
     }
 
-    /// Rollback filter header chain by a given number of headers.
-    pub fn rollback(&mut self, n: usize) -> Result<(), filter::Error> {
-        self.filters.rollback(n)
+    /// Rollback the filter header chain to the given height. This is called when the
+    /// block header chain is re-organized, with the height of the fork point. Filter headers
+    /// above that height are invalid and are discarded.
+    pub fn rollback(&mut self, height: Height) -> Result<(), filter::Error> {
+        let filter_height = self.filters.height();
+
+        if filter_height <= height {
+            // The filter header chain hasn't caught up with the fork point yet: nothing to do.
+            return Ok(());
+        }
+        self.filters.rollback((filter_height - height) as usize)?;
+        self.upstream.event(Event::RollbackDetected(height + 1));
+
+        Ok(())
     }
 
     /// Send a `getcfilters` message to a random peer.
@@ -267,7 +315,7 @@ impl<F: Filters, U: SyncFilters + Events + SetTimeout> SpvManager<F, U> {
                 let peer = *peers.get(ix).unwrap(); // Can't fail.
 
                 // TODO: Return an error instead.
-                let stop_hash = tree.get_block_by_height(r.end).unwrap().block_hash();
+                let stop_hash = tree.get_block_hash(r.end).unwrap();
                 let timeout = self.config.request_timeout;
 
                 self.upstream
@@ -344,9 +392,13 @@ impl<F: Filters, U: SyncFilters + Events + SetTimeout> SpvManager<F, U> {
         let mut headers = Vec::with_capacity(count);
 
         // Create headers out of the hashes.
-        for filter_hash in hashes {
+        for (height, filter_hash) in (start_height + 1..).zip(hashes) {
+            let block_hash = tree
+                .get_block_hash(height)
+                .expect("heights up to the stop hash are part of the active chain");
+
             last_header = FilterHeader::new(filter_hash, &last_header);
-            headers.push((filter_hash, last_header));
+            headers.push((block_hash, filter_hash, last_header));
         }
         self.filters
             .import_headers(headers)
@@ -373,6 +425,7 @@ impl<F: Filters, U: SyncFilters + Events + SetTimeout> SpvManager<F, U> {
         &mut self,
         from: &PeerId,
         msg: GetCFHeaders,
+        now: LocalTime,
         tree: &T,
     ) -> Result<(), Error> {
         let from = *from;
@@ -395,7 +448,32 @@ impl<F: Filters, U: SyncFilters + Events + SetTimeout> SpvManager<F, U> {
             });
         };
 
-        let headers = self.filters.get_headers(start_height..stop_height);
+        if start_height > stop_height {
+            return Err(Error::InvalidMessage {
+                from,
+                reason: "getcfheaders: start height is greater than stop height",
+            });
+        }
+        if (stop_height - start_height) as usize >= MAX_MESSAGE_CFHEADERS {
+            return Err(Error::InvalidMessage {
+                from,
+                reason: "getcfheaders: requested range exceeds maximum",
+            });
+        }
+        if start_height == 0 || stop_height > self.filters.height() {
+            return Err(Error::Ignored {
+                msg: "getcfheaders",
+                from,
+            });
+        }
+        if self.is_rate_limited(from, now) {
+            return Err(Error::Ignored {
+                msg: "getcfheaders",
+                from,
+            });
+        }
+
+        let headers = self.filters.get_headers(start_height..stop_height + 1);
         if !headers.is_empty() {
             let hashes = headers.iter().map(|(hash, _)| *hash);
             let prev_header = self
@@ -474,6 +552,8 @@ impl<F: Filters, U: SyncFilters + Events + SetTimeout> SpvManager<F, U> {
             });
         }
 
+        // Keep the filter around, so that it can be served to other peers.
+        self.filters.import_filter(height, filter.clone())?;
         self.upstream.event(Event::FilterReceived {
             from,
             block_hash: msg.block_hash,
@@ -485,21 +565,143 @@ impl<F: Filters, U: SyncFilters + Events + SetTimeout> SpvManager<F, U> {
     }
 
     /// Handle `getcfilters` message.
+    ///
+    /// Responds with a `cfilter` message for every block in the requested range. Only the
+    /// filters we received from peers are stored, so requests for ranges we don't have all
+    /// the filters of are ignored.
     pub fn received_getcfilters<T: BlockTree>(
         &mut self,
-        _addr: &PeerId,
+        from: &PeerId,
         msg: GetCFilters,
-        _tree: &T,
-    ) {
+        now: LocalTime,
+        tree: &T,
+    ) -> Result<(), Error> {
+        let from = *from;
+
         if msg.filter_type != 0x0 {
-            return;
+            return Err(Error::InvalidMessage {
+                from,
+                reason: "getcfilters: invalid filter type",
+            });
+        }
+
+        let start_height = msg.start_height as Height;
+        let stop_height = if let Some((height, _)) = tree.get_block(&msg.stop_hash) {
+            height
+        } else {
+            return Err(Error::Ignored {
+                msg: "getcfilters",
+                from,
+            });
+        };
+
+        if start_height > stop_height {
+            return Err(Error::InvalidMessage {
+                from,
+                reason: "getcfilters: start height is greater than stop height",
+            });
+        }
+        if (stop_height - start_height) as usize >= MAX_MESSAGE_CFILTERS {
+            return Err(Error::InvalidMessage {
+                from,
+                reason: "getcfilters: requested range exceeds maximum",
+            });
         }
-        // TODO
+        let filters = (start_height..=stop_height)
+            .map(|height| {
+                Some((
+                    tree.get_block_hash(height)?,
+                    self.filters.get_filter(height)?,
+                ))
+            })
+            .collect::<Option<Vec<_>>>();
+        let filters = match filters {
+            Some(filters) if !self.is_rate_limited(from, now) => filters,
+            _ => {
+                return Err(Error::Ignored {
+                    msg: "getcfilters",
+                    from,
+                })
+            }
+        };
+
+        for (block_hash, filter) in filters {
+            self.upstream.send_cfilter(
+                from,
+                CFilter {
+                    filter_type: msg.filter_type,
+                    block_hash,
+                    filter: filter.content,
+                },
+            );
+        }
+        Ok(())
+    }
+
+    /// Handle a `getcfcheckpt` message from a peer.
+    ///
+    /// Responds with the filter headers at every `CFCHECKPT_INTERVAL` blocks, up to the stop
+    /// hash.
+    pub fn received_getcfcheckpt<T: BlockTree>(
+        &mut self,
+        from: &PeerId,
+        msg: GetCFCheckpt,
+        now: LocalTime,
+        tree: &T,
+    ) -> Result<(), Error> {
+        let from = *from;
+
+        if msg.filter_type != 0x0 {
+            return Err(Error::InvalidMessage {
+                from,
+                reason: "getcfcheckpt: invalid filter type",
+            });
+        }
+
+        let stop_height = match tree.get_block(&msg.stop_hash) {
+            Some((height, _)) if height <= self.filters.height() => height,
+            _ => {
+                // Either we don't know the stop block, or we haven't synced its filter
+                // header yet.
+                return Err(Error::Ignored {
+                    msg: "getcfcheckpt",
+                    from,
+                });
+            }
+        };
+        if self.is_rate_limited(from, now) {
+            return Err(Error::Ignored {
+                msg: "getcfcheckpt",
+                from,
+            });
+        }
+
+        let filter_headers = (1..)
+            .map(|i| i * CFCHECKPT_INTERVAL)
+            .take_while(|h| *h <= stop_height)
+            .map(|h| self.filters.get_header(h).map(|(_, header)| header.into()))
+            .collect::<Option<Vec<_>>>()
+            .ok_or(Error::Ignored {
+                msg: "getcfcheckpt",
+                from,
+            })?;
+
+        self.upstream.send_cfcheckpt(
+            from,
+            CFCheckpt {
+                filter_type: msg.filter_type,
+                stop_hash: msg.stop_hash,
+                filter_headers,
+            },
+        );
+
+        Ok(())
     }
 
     /// Called when a peer disconnected.
     pub fn peer_disconnected(&mut self, id: &PeerId) {
         self.peers.remove(id);
+        self.served.remove(id);
     }
 
     /// Called when a new peer was negotiated.
@@ -529,6 +731,23 @@ impl<F: Filters, U: SyncFilters + Events + SetTimeout> SpvManager<F, U> {
         self.sync(tree);
     }
 
+    /// Check whether a peer has exceeded its filter request allowance. If not, the request
+    /// is counted against it.
+    fn is_rate_limited(&mut self, peer: PeerId, now: LocalTime) -> bool {
+        let (since, count) = self.served.entry(peer).or_insert((now, 0));
+
+        if now - *since >= SERVE_WINDOW {
+            *since = now;
+            *count = 0;
+        }
+        if *count >= MAX_SERVED_REQUESTS {
+            return true;
+        }
+        *count += 1;
+
+        false
+    }
+
     /// Send a `getcfheaders` message to a random peer.
     pub fn send_getcfheaders<T: BlockTree>(
         &mut self,
@@ -547,11 +766,8 @@ impl<F: Filters, U: SyncFilters + Events + SetTimeout> SpvManager<F, U> {
         // Cap request to `MAX_MESSAGE_CFHEADERS`.
         let stop_hash = if count > MAX_MESSAGE_CFHEADERS {
             let stop_height = range.start + MAX_MESSAGE_CFHEADERS as Height - 1;
-            let stop_block = tree
-                .get_block_by_height(stop_height)
-                .expect("all headers up to the tip exist");
-
-            stop_block.block_hash()
+            tree.get_block_hash(stop_height)
+                .expect("all headers up to the tip exist")
         } else {
             let (hash, _) = tree.tip();
 
@@ -625,12 +841,14 @@ impl Iterator for HeightIterator {
 
 #[cfg(test)]
 mod tests {
+    use bitcoin::network::message::NetworkMessage;
     use bitcoin_hashes::hex::FromHex;
     use crossbeam_channel as chan;
 
     use nakamoto_chain::block::{cache::BlockCache, store};
     use nakamoto_chain::filter::cache::FilterCache;
     use nakamoto_common::block::filter::FilterHash;
+    use nakamoto_common::block::BlockHeader;
     use nakamoto_common::network::Network;
     use nakamoto_test::BITCOIN_HEADERS;
 
@@ -671,6 +889,25 @@ mod tests {
         &[1, 155, 155, 152],
     ];
 
+    /// The `cfheaders` message for the first 15 mainnet blocks.
+    fn cfheaders() -> CFHeaders {
+        CFHeaders {
+            filter_type: 0,
+            stop_hash: BlockHash::from_hex(
+                "00000000b3322c8c3ef7d2cf6da009a776e6a99ee65ec5a32f3f345712238473",
+            )
+            .unwrap(),
+            previous_filter: FilterHash::from_hex(
+                "02c2392180d0ce2b5b6f8b08d39a11ffe831c673311a3ecf77b97fc3f0303c9f",
+            )
+            .unwrap(),
+            filter_hashes: FILTER_HASHES
+                .iter()
+                .map(|h| FilterHash::from_hex(h).unwrap())
+                .collect(),
+        }
+    }
+
     #[test]
     fn test_receive_filters() {
         let rng = fastrand::Rng::new();
@@ -686,21 +923,7 @@ mod tests {
 
             BlockCache::from(store::Memory::new(BITCOIN_HEADERS.clone()), params, &[]).unwrap()
         };
-        let msg = CFHeaders {
-            filter_type: 0,
-            stop_hash: BlockHash::from_hex(
-                "00000000b3322c8c3ef7d2cf6da009a776e6a99ee65ec5a32f3f345712238473",
-            )
-            .unwrap(),
-            previous_filter: FilterHash::from_hex(
-                "02c2392180d0ce2b5b6f8b08d39a11ffe831c673311a3ecf77b97fc3f0303c9f",
-            )
-            .unwrap(),
-            filter_hashes: FILTER_HASHES
-                .iter()
-                .map(|h| FilterHash::from_hex(h).unwrap())
-                .collect(),
-        };
+        let msg = cfheaders();
 
         let mut spvmgr = SpvManager::new(Config::default(), rng, cache, upstream);
         let peer = &([0, 0, 0, 0], 0).into();
@@ -726,6 +949,313 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_serve_cfilters() {
+        let rng = fastrand::Rng::new();
+        let network = Network::Mainnet;
+        let cache = FilterCache::from(store::memory::Memory::genesis(network)).unwrap();
+        let (sender, receiver) = chan::unbounded();
+        let upstream = Channel::new(network, PROTOCOL_VERSION, "test", sender);
+        let tree = BlockCache::from(
+            store::Memory::new(BITCOIN_HEADERS.clone()),
+            network.params(),
+            &[],
+        )
+        .unwrap();
+        let msg = cfheaders();
+        let cfilters = FILTERS
+            .iter()
+            .zip(BITCOIN_HEADERS.iter())
+            .map(|(f, h)| CFilter {
+                filter_type: 0x0,
+                block_hash: h.block_hash(),
+                filter: f.to_vec(),
+            })
+            .collect::<Vec<_>>();
+        let time = LocalTime::now();
+
+        let mut spvmgr = SpvManager::new(Config::default(), rng, cache, upstream);
+        let peer = &([0, 0, 0, 0], 0).into();
+
+        spvmgr.received_cfheaders(peer, msg, &tree).unwrap();
+        for msg in cfilters.iter().cloned() {
+            spvmgr.received_cfilter(peer, msg, &tree).unwrap();
+        }
+        receiver.try_iter().for_each(drop);
+
+        spvmgr
+            .received_getcfilters(
+                peer,
+                GetCFilters {
+                    filter_type: 0,
+                    start_height: 1,
+                    stop_hash: cfilters[10].block_hash,
+                },
+                time,
+                &tree,
+            )
+            .unwrap();
+
+        let served = receiver
+            .try_iter()
+            .filter_map(|o| match o {
+                crate::protocol::Out::Message(_, raw) => match raw.payload {
+                    NetworkMessage::CFilter(filter) => Some(filter),
+                    _ => None,
+                },
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(served, cfilters[1..]);
+
+        // We don't have the filters for this range.
+        assert!(matches!(
+            spvmgr.received_getcfilters(
+                peer,
+                GetCFilters {
+                    filter_type: 0,
+                    start_height: 1,
+                    stop_hash: BITCOIN_HEADERS.get(12).unwrap().block_hash(),
+                },
+                time,
+                &tree,
+            ),
+            Err(Error::Ignored { .. })
+        ));
+        assert!(receiver.try_iter().next().is_none());
+    }
+
+    #[test]
+    fn test_rollback_invalidates_tail() {
+        let rng = fastrand::Rng::new();
+        let network = Network::Mainnet;
+        let cache = FilterCache::from(store::memory::Memory::genesis(network)).unwrap();
+        let (sender, receiver) = chan::unbounded();
+        let upstream = Channel::new(network, PROTOCOL_VERSION, "test", sender);
+        let tree = BlockCache::from(
+            store::Memory::new(BITCOIN_HEADERS.clone()),
+            network.params(),
+            &[],
+        )
+        .unwrap();
+        let msg = cfheaders();
+
+        let mut spvmgr = SpvManager::new(Config::default(), rng, cache, upstream);
+        let peer = &([0, 0, 0, 0], 0).into();
+
+        spvmgr.received_cfheaders(peer, msg, &tree).unwrap();
+        assert_eq!(spvmgr.filters.height(), 15);
+
+        let expected = spvmgr.filters.get_headers(1..11);
+
+        // A fork point above the filter tip doesn't affect the filter headers.
+        spvmgr.rollback(20).unwrap();
+        assert_eq!(spvmgr.filters.height(), 15);
+
+        // A fork point below the filter tip discards the filter headers above it.
+        spvmgr.rollback(10).unwrap();
+        assert_eq!(spvmgr.filters.height(), 10);
+        assert_eq!(spvmgr.filters.get_headers(1..11), expected);
+        spvmgr.filters.verify(network).unwrap();
+
+        assert!(receiver.try_iter().any(|o| matches!(
+            o,
+            crate::protocol::Out::Event(crate::event::Event::SpvManager(Event::RollbackDetected(
+                11
+            )))
+        )));
+    }
+
+    #[test]
+    fn test_initialize_checks_block_chain() {
+        use nakamoto_test::block::cache::model;
+        use nonempty::NonEmpty;
+
+        let rng = fastrand::Rng::new();
+        let network = Network::Mainnet;
+        let cache = FilterCache::from(store::memory::Memory::genesis(network)).unwrap();
+        let (sender, _receiver) = chan::unbounded();
+        let upstream = Channel::new(network, PROTOCOL_VERSION, "test", sender);
+        let tree = BlockCache::from(
+            store::Memory::new(BITCOIN_HEADERS.clone()),
+            network.params(),
+            &[],
+        )
+        .unwrap();
+        let msg = cfheaders();
+        let time = LocalTime::now();
+
+        let mut spvmgr = SpvManager::new(Config::default(), rng, cache, upstream);
+        let peer = &([0, 0, 0, 0], 0).into();
+
+        spvmgr.received_cfheaders(peer, msg, &tree).unwrap();
+        assert_eq!(spvmgr.filters.height(), 15);
+        assert_eq!(spvmgr.filters.get_block_hash(15), tree.get_block_hash(15));
+
+        // The filter headers match the block chain they were imported for.
+        spvmgr.initialize(time, &tree).unwrap();
+        assert_eq!(spvmgr.filters.height(), 15);
+
+        // The blocks above height 11 were re-organized while we were offline.
+        let mut fork = BITCOIN_HEADERS.iter().take(20).cloned().collect::<Vec<_>>();
+        for header in fork.iter_mut().skip(12) {
+            header.nonce = 0;
+        }
+        let fork = model::Cache::from(NonEmpty::from_vec(fork).unwrap());
+
+        spvmgr.initialize(time, &fork).unwrap();
+        assert_eq!(spvmgr.filters.height(), 11);
+        spvmgr.filters.verify(network).unwrap();
+
+        // The block chain was rolled back while we were offline.
+        let short = model::Cache::from(NonEmpty::from((
+            BITCOIN_HEADERS.head,
+            BITCOIN_HEADERS.tail[..8].to_vec(),
+        )));
+
+        spvmgr.initialize(time, &short).unwrap();
+        assert_eq!(spvmgr.filters.height(), 8);
+    }
+
+    #[test]
+    fn test_serve_cfheaders() {
+        let rng = fastrand::Rng::new();
+        let network = Network::Mainnet;
+        let cache = FilterCache::from(store::memory::Memory::genesis(network)).unwrap();
+        let (sender, receiver) = chan::unbounded();
+        let upstream = Channel::new(network, PROTOCOL_VERSION, "test", sender);
+        let tree = BlockCache::from(
+            store::Memory::new(BITCOIN_HEADERS.clone()),
+            network.params(),
+            &[],
+        )
+        .unwrap();
+        let msg = cfheaders();
+
+        let mut spvmgr = SpvManager::new(Config::default(), rng, cache, upstream);
+        let peer = &([0, 0, 0, 0], 0).into();
+        let time = LocalTime::from_secs(0);
+
+        spvmgr.received_cfheaders(peer, msg.clone(), &tree).unwrap();
+        receiver.try_iter().for_each(drop);
+
+        // Serving the window we just imported yields the same headers.
+        spvmgr
+            .received_getcfheaders(
+                peer,
+                GetCFHeaders {
+                    filter_type: 0,
+                    start_height: 1,
+                    stop_hash: msg.stop_hash,
+                },
+                time,
+                &tree,
+            )
+            .unwrap();
+
+        let served = receiver
+            .try_iter()
+            .find_map(|o| match o {
+                crate::protocol::Out::Message(_, raw) => match raw.payload {
+                    NetworkMessage::CFHeaders(headers) => Some(headers),
+                    _ => None,
+                },
+                _ => None,
+            })
+            .expect("`cfheaders` is sent back");
+        assert_eq!(served, msg);
+
+        // We don't have the filter headers for this range.
+        assert!(matches!(
+            spvmgr.received_getcfheaders(
+                peer,
+                GetCFHeaders {
+                    filter_type: 0,
+                    start_height: 1,
+                    stop_hash: BITCOIN_HEADERS.get(20).unwrap().block_hash(),
+                },
+                time,
+                &tree,
+            ),
+            Err(Error::Ignored { .. })
+        ));
+
+        // The header chain below is long enough to request more than the maximum.
+        let tree = {
+            let mut chain = NonEmpty::new(network.genesis());
+            for i in 0..=MAX_MESSAGE_CFHEADERS as u32 {
+                let prev = chain.last();
+                let header = BlockHeader {
+                    prev_blockhash: prev.block_hash(),
+                    nonce: i,
+                    ..*prev
+                };
+                chain.push(header);
+            }
+            nakamoto_test::block::cache::model::Cache::from(chain)
+        };
+        assert!(matches!(
+            spvmgr.received_getcfheaders(
+                peer,
+                GetCFHeaders {
+                    filter_type: 0,
+                    start_height: 1,
+                    stop_hash: tree.tip().0,
+                },
+                time,
+                &tree,
+            ),
+            Err(Error::InvalidMessage { .. })
+        ));
+        assert!(matches!(
+            spvmgr.received_getcfheaders(
+                peer,
+                GetCFHeaders {
+                    filter_type: 0,
+                    start_height: 2,
+                    stop_hash: tree.tip().0,
+                },
+                time,
+                &tree,
+            ),
+            Err(Error::Ignored { .. })
+        ));
+    }
+
+    #[test]
+    fn test_serve_rate_limit() {
+        let rng = fastrand::Rng::new();
+        let network = Network::Mainnet;
+        let cache = FilterCache::from(store::memory::Memory::genesis(network)).unwrap();
+        let (sender, _receiver) = chan::unbounded();
+        let upstream = Channel::new(network, PROTOCOL_VERSION, "test", sender);
+        let tree = BlockCache::from(
+            store::Memory::new(BITCOIN_HEADERS.clone()),
+            network.params(),
+            &[],
+        )
+        .unwrap();
+        let mut spvmgr = SpvManager::new(Config::default(), rng, cache, upstream);
+        let peer = &([0, 0, 0, 0], 0).into();
+        let time = LocalTime::from_secs(0);
+        let msg = GetCFCheckpt {
+            filter_type: 0,
+            stop_hash: network.genesis_hash(),
+        };
+
+        for _ in 0..MAX_SERVED_REQUESTS {
+            spvmgr
+                .received_getcfcheckpt(peer, msg.clone(), time, &tree)
+                .unwrap();
+        }
+        assert!(spvmgr
+            .received_getcfcheckpt(peer, msg.clone(), time, &tree)
+            .is_err());
+        assert!(spvmgr
+            .received_getcfcheckpt(peer, msg, time + SERVE_WINDOW, &tree)
+            .is_ok());
+    }
+
     #[test]
     fn test_height_iterator() {
         let mut it = super::HeightIterator {
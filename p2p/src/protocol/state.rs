@@ -0,0 +1,320 @@
+//! Export and restore of the transient protocol state.
+//!
+//! This allows a running protocol to be handed over to a new process, eg. during an
+//! upgrade, without losing track of the peers it is connected to. The peer sockets
+//! themselves are not part of the state: they are expected to be handed over separately,
+//! by the process supervising the client.
+//!
+//! The protocol's state falls in two categories:
+//!
+//! * *Persistent* state is already stored outside of the protocol, and is simply re-opened
+//!   by the new process: the block headers and filters in the block and filter stores,
+//!   and the address book in the peer store.
+//! * *Transient* state lives only in memory. Of this state, the following is exported:
+//!   - The set of negotiated peers, along with what they told us during the handshake.
+//!   - The misbehavior score of each peer. Peers are banned, ie. disconnected and removed
+//!     from the address book, once their score gets too high. Since the address book is
+//!     persistent, the scores make up the rest of the ban list.
+//!   - The in-flight header requests. Their responses arrive on the handed-over sockets,
+//!     and are processed by the new process as if it had sent the requests.
+//!
+//! Transient state that isn't exported is rebuilt on its own:
+//!
+//! * Connections that haven't completed their handshake should be closed by the old
+//!   process; the new process establishes new connections in their place.
+//! * Other in-flight requests, eg. for filters or blocks, are issued again once the peers
+//!   are restored.
+//!
+//! The state is encoded as JSON, and is versioned. States with a different version
+//! than [`VERSION`] are rejected.
+//!
+use std::net;
+
+use bitcoin::network::constants::ServiceFlags;
+
+use microserde as serde;
+use microserde::json::{Number, Object, Value};
+
+use thiserror::Error;
+
+use nakamoto_common::block::{BlockHash, Height};
+
+use super::Link;
+
+/// Version of the encoded state.
+pub const VERSION: u64 = 2;
+
+/// An error decoding the protocol state.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The state was encoded with an unsupported version.
+    #[error("unsupported state version {0}, expected {}", VERSION)]
+    Version(u64),
+    /// The state is malformed.
+    #[error("malformed state")]
+    Malformed,
+}
+
+impl From<serde::Error> for Error {
+    fn from(_: serde::Error) -> Self {
+        Self::Malformed
+    }
+}
+
+/// A negotiated peer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Peer {
+    /// Remote peer address.
+    pub addr: net::SocketAddr,
+    /// Local peer address.
+    pub local_addr: net::SocketAddr,
+    /// Whether this is an inbound or outbound peer connection.
+    pub link: Link,
+    /// The peer's best height.
+    pub height: Height,
+    /// The peer's services.
+    pub services: ServiceFlags,
+    /// Peer user agent string.
+    pub user_agent: String,
+    /// Offset in seconds, between this peer's clock and ours.
+    pub time_offset: i64,
+    /// Whether this peer relays transactions.
+    pub relay: bool,
+    /// Highest protocol version understood by the peer.
+    pub version: u32,
+    /// Peer nonce.
+    pub nonce: u64,
+    /// Misbehavior score of the peer.
+    pub misbehavior: u32,
+}
+
+impl Peer {
+    fn to_json(&self) -> Value {
+        let mut obj = Object::new();
+        let link = match self.link {
+            Link::Inbound => "inbound",
+            Link::Outbound => "outbound",
+        };
+
+        obj.insert("addr".to_owned(), Value::String(self.addr.to_string()));
+        obj.insert(
+            "local_addr".to_owned(),
+            Value::String(self.local_addr.to_string()),
+        );
+        obj.insert("link".to_owned(), Value::String(link.to_owned()));
+        obj.insert("height".to_owned(), Value::Number(Number::U64(self.height)));
+        obj.insert(
+            "services".to_owned(),
+            Value::Number(Number::U64(self.services.as_u64())),
+        );
+        obj.insert(
+            "user_agent".to_owned(),
+            Value::String(self.user_agent.clone()),
+        );
+        obj.insert(
+            "time_offset".to_owned(),
+            Value::Number(Number::I64(self.time_offset)),
+        );
+        obj.insert("relay".to_owned(), Value::Bool(self.relay));
+        obj.insert(
+            "version".to_owned(),
+            Value::Number(Number::U64(self.version as u64)),
+        );
+        obj.insert("nonce".to_owned(), Value::Number(Number::U64(self.nonce)));
+        obj.insert(
+            "misbehavior".to_owned(),
+            Value::Number(Number::U64(self.misbehavior as u64)),
+        );
+
+        Value::Object(obj)
+    }
+
+    fn from_json(v: &Value) -> Result<Self, Error> {
+        let obj = match v {
+            Value::Object(obj) => obj,
+            _ => return Err(Error::Malformed),
+        };
+        let link = match self::string(obj, "link")? {
+            "inbound" => Link::Inbound,
+            "outbound" => Link::Outbound,
+            _ => return Err(Error::Malformed),
+        };
+        let time_offset = match obj.get("time_offset") {
+            Some(Value::Number(Number::I64(n))) => *n,
+            Some(Value::Number(Number::U64(n))) if *n <= i64::MAX as u64 => *n as i64,
+            _ => return Err(Error::Malformed),
+        };
+        let relay = match obj.get("relay") {
+            Some(Value::Bool(b)) => *b,
+            _ => return Err(Error::Malformed),
+        };
+
+        Ok(Self {
+            addr: self::string(obj, "addr")?
+                .parse()
+                .map_err(|_| Error::Malformed)?,
+            local_addr: self::string(obj, "local_addr")?
+                .parse()
+                .map_err(|_| Error::Malformed)?,
+            link,
+            height: self::number(obj, "height")?,
+            services: ServiceFlags::from(self::number(obj, "services")?),
+            user_agent: self::string(obj, "user_agent")?.to_owned(),
+            time_offset,
+            relay,
+            version: self::u32(obj, "version")?,
+            nonce: self::number(obj, "nonce")?,
+            misbehavior: self::u32(obj, "misbehavior")?,
+        })
+    }
+}
+
+/// An in-flight `getheaders` request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    /// Peer the request was sent to.
+    pub addr: net::SocketAddr,
+    /// Locator hashes of the request.
+    pub locators: Vec<BlockHash>,
+    /// Stop hash of the request.
+    pub stop_hash: BlockHash,
+    /// Whether the peer is disconnected if the request times out.
+    pub disconnect_on_timeout: bool,
+}
+
+impl Request {
+    fn to_json(&self) -> Value {
+        let mut obj = Object::new();
+
+        obj.insert("addr".to_owned(), Value::String(self.addr.to_string()));
+        obj.insert(
+            "locators".to_owned(),
+            Value::Array(
+                self.locators
+                    .iter()
+                    .map(|h| Value::String(h.to_string()))
+                    .collect(),
+            ),
+        );
+        obj.insert(
+            "stop_hash".to_owned(),
+            Value::String(self.stop_hash.to_string()),
+        );
+        obj.insert(
+            "disconnect_on_timeout".to_owned(),
+            Value::Bool(self.disconnect_on_timeout),
+        );
+
+        Value::Object(obj)
+    }
+
+    fn from_json(v: &Value) -> Result<Self, Error> {
+        let obj = match v {
+            Value::Object(obj) => obj,
+            _ => return Err(Error::Malformed),
+        };
+        let locators = match obj.get("locators") {
+            Some(Value::Array(hashes)) => hashes
+                .iter()
+                .map(|h| match h {
+                    Value::String(h) => h.parse().map_err(|_| Error::Malformed),
+                    _ => Err(Error::Malformed),
+                })
+                .collect::<Result<_, _>>()?,
+            _ => return Err(Error::Malformed),
+        };
+        let disconnect_on_timeout = match obj.get("disconnect_on_timeout") {
+            Some(Value::Bool(b)) => *b,
+            _ => return Err(Error::Malformed),
+        };
+
+        Ok(Self {
+            addr: self::string(obj, "addr")?
+                .parse()
+                .map_err(|_| Error::Malformed)?,
+            locators,
+            stop_hash: self::string(obj, "stop_hash")?
+                .parse()
+                .map_err(|_| Error::Malformed)?,
+            disconnect_on_timeout,
+        })
+    }
+}
+
+/// The exported protocol state.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct State {
+    /// Negotiated peers.
+    pub peers: Vec<Peer>,
+    /// In-flight header requests to the negotiated peers.
+    pub requests: Vec<Request>,
+}
+
+impl State {
+    /// Encode the state.
+    pub fn encode(&self) -> String {
+        let mut obj = Object::new();
+
+        obj.insert("version".to_owned(), Value::Number(Number::U64(VERSION)));
+        obj.insert(
+            "peers".to_owned(),
+            Value::Array(self.peers.iter().map(|p| p.to_json()).collect()),
+        );
+        obj.insert(
+            "requests".to_owned(),
+            Value::Array(self.requests.iter().map(|r| r.to_json()).collect()),
+        );
+
+        serde::json::to_string(&Value::Object(obj))
+    }
+
+    /// Decode a state previously encoded with [`State::encode`].
+    pub fn decode(s: &str) -> Result<Self, Error> {
+        let obj = match serde::json::from_str::<Value>(s)? {
+            Value::Object(obj) => obj,
+            _ => return Err(Error::Malformed),
+        };
+        let version = self::number(&obj, "version")?;
+
+        if version != VERSION {
+            return Err(Error::Version(version));
+        }
+        let peers = match obj.get("peers") {
+            Some(Value::Array(peers)) => peers
+                .iter()
+                .map(Peer::from_json)
+                .collect::<Result<_, _>>()?,
+            _ => return Err(Error::Malformed),
+        };
+        let requests = match obj.get("requests") {
+            Some(Value::Array(requests)) => requests
+                .iter()
+                .map(Request::from_json)
+                .collect::<Result<_, _>>()?,
+            _ => return Err(Error::Malformed),
+        };
+
+        Ok(Self { peers, requests })
+    }
+}
+
+fn string<'a>(obj: &'a Object, key: &str) -> Result<&'a str, Error> {
+    match obj.get(key) {
+        Some(Value::String(s)) => Ok(s),
+        _ => Err(Error::Malformed),
+    }
+}
+
+fn number(obj: &Object, key: &str) -> Result<u64, Error> {
+    match obj.get(key) {
+        Some(Value::Number(Number::U64(n))) => Ok(*n),
+        _ => Err(Error::Malformed),
+    }
+}
+
+fn u32(obj: &Object, key: &str) -> Result<u32, Error> {
+    match self::number(obj, key)? {
+        n if n <= u32::MAX as u64 => Ok(n as u32),
+        _ => Err(Error::Malformed),
+    }
+}
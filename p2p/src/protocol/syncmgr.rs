@@ -2,6 +2,7 @@
 //! Manages header synchronization with peers.
 //!
 #![warn(missing_docs)]
+use std::collections::VecDeque;
 use std::sync::Arc;
 use std::time::SystemTime;
 
@@ -14,15 +15,19 @@ use bitcoin::Block;
 
 use nakamoto_common::block::store;
 use nakamoto_common::block::time::{Clock, LocalDuration, LocalTime};
-use nakamoto_common::block::tree::{BlockTree, Error, ImportResult};
-use nakamoto_common::block::{BlockHash, BlockHeader, Height};
+use nakamoto_common::block::tree::{self, BlockTree, Error, ImportResult};
+use nakamoto_common::block::{BlockHash, BlockHeader, Height, Work};
 use nakamoto_common::collections::HashMap;
 
 use super::channel::{Disconnect, SetTimeout};
-use super::{DisconnectReason, Link, Locators, PeerId, Timeout};
+use super::{state, DisconnectReason, Link, Locators, PeerId};
 
 /// How long to wait for a request, eg. `getheaders` to be fulfilled.
 pub const REQUEST_TIMEOUT: LocalDuration = LocalDuration::from_secs(30);
+/// Shortest time to wait for a request to a peer whose latency is known.
+pub const MIN_REQUEST_TIMEOUT: LocalDuration = LocalDuration::from_secs(5);
+/// Longest time to wait for a request to a peer whose latency is known.
+pub const MAX_REQUEST_TIMEOUT: LocalDuration = LocalDuration::from_mins(2);
 /// How long before the tip of the chain is considered stale. This takes into account
 /// that the block timestamp may have been set sometime in the future.
 pub const TIP_STALE_DURATION: LocalDuration = LocalDuration::from_mins(60 * 2);
@@ -37,6 +42,28 @@ pub const REQUIRED_SERVICES: ServiceFlags = ServiceFlags::NETWORK;
 const MAX_HEADERS_ANNOUNCED: usize = 8;
 /// How long to wait between checks for longer chains from peers.
 const PEER_SAMPLE_INTERVAL: LocalDuration = LocalDuration::from_mins(60);
+/// How far below its advertised height a peer may stop serving us headers, before
+/// we consider its response to be short.
+const WITHHOLDING_TOLERANCE: Height = 6;
+/// Number of consecutive short `headers` responses after which a peer is considered to
+/// be withholding its tip.
+const MAX_SHORT_RESPONSES: usize = 3;
+/// How much less work than ours, in blocks at the difficulty of our tip, the chain
+/// demonstrated by a peer may have before we disconnect it.
+const LOW_WORK_TOLERANCE: u32 = 6;
+/// Peers advertising a height this far below our tip, or a height of zero while we're past
+/// genesis, are presumed to be syncing themselves. Their height is treated as unknown until they serve us headers.
+const SYNCING_PEER_DEPTH: Height = 144;
+/// Misbehavior score at which a peer is disconnected.
+pub const MAX_MISBEHAVIOR: u32 = 100;
+/// Misbehavior score added for each offense.
+pub const MISBEHAVIOR_PENALTY: u32 = 20;
+/// Number of round-trips a peer is given to fulfill a request, on top of
+/// [`MIN_REQUEST_TIMEOUT`].
+const REQUEST_TIMEOUT_ROUND_TRIPS: u128 = 8;
+
+/// How long reorgs are kept track of, for the purpose of reorg statistics.
+const REORG_HISTORY: LocalDuration = LocalDuration::from_mins(60 * 24);
 
 /// The ability to get and send headers.
 pub trait SyncHeaders {
@@ -63,11 +90,46 @@ pub enum OnTimeout {
 #[derive(Debug)]
 struct PeerState {
     id: PeerId,
-    height: Height,
+    /// Best known height of the peer. `None` if the height it advertised can't be relied
+    /// on, and it hasn't served us headers since.
+    height: Option<Height>,
     tip: BlockHash,
     link: Link,
     last_active: Option<LocalTime>,
     last_asked: Option<Locators>,
+    /// Number of consecutive responses that stopped short of the peer's advertised height.
+    short_responses: usize,
+    /// Whether we checked the work of this peer's chain, on its first response.
+    work_checked: bool,
+    /// Misbehavior score. The peer is disconnected once it reaches [`MAX_MISBEHAVIOR`].
+    misbehavior: u32,
+    /// Measured round-trip latency of the peer, if known.
+    latency: Option<LocalDuration>,
+}
+
+impl PeerState {
+    /// Check whether this peer appears to be withholding headers from us.
+    fn is_withholding(&self) -> bool {
+        self.short_responses >= MAX_SHORT_RESPONSES
+    }
+}
+
+/// Statistics on recent reorgs of the active chain. The depth of a reorg is the number
+/// of blocks it reverted.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReorgStats {
+    /// Number of reorgs in the last hour.
+    pub last_hour: usize,
+    /// Depth of the deepest reorg in the last hour.
+    pub max_depth_last_hour: usize,
+    /// Number of reorgs in the last day.
+    pub last_day: usize,
+    /// Depth of the deepest reorg in the last day.
+    pub max_depth_last_day: usize,
+    /// Depth of the deepest reorg seen.
+    pub max_depth: usize,
+    /// Time of the last reorg.
+    pub last_reorg: Option<LocalTime>,
 }
 
 /// Sync manager configuration.
@@ -79,6 +141,9 @@ pub struct Config {
     pub request_timeout: LocalDuration,
     /// Consensus parameters.
     pub params: Params,
+    /// Whether to coalesce the changes to the active chain made within a protocol step
+    /// into a single event. See [`SyncManager::begin_step`].
+    pub coalesce_reorgs: bool,
 }
 
 /// The sync manager state.
@@ -98,6 +163,21 @@ pub struct SyncManager<U> {
     rng: fastrand::Rng,
     /// In-flight requests to peers.
     inflight: HashMap<PeerId, GetHeaders>,
+    /// Requests waiting for an in-flight request to the same peer to resolve.
+    /// Only the latest request to a given peer is kept.
+    queued: HashMap<PeerId, GetHeaders>,
+    /// If set, the only peer we request headers from.
+    pinned: Option<PeerId>,
+    /// Ranges of the active chain being fetched again from peers, on request.
+    refetching: HashMap<PeerId, Refetch>,
+    /// Changes to the active chain during the current protocol step, if coalescing.
+    coalesced: Option<ChainDiff>,
+    /// Time and depth of the reorgs of the last day, oldest first.
+    reorgs: VecDeque<(LocalTime, usize)>,
+    /// Depth of the deepest reorg seen.
+    max_reorg_depth: usize,
+    /// Time of the last reorg.
+    last_reorg: Option<LocalTime>,
     /// Upstream protocol channel.
     upstream: U,
 }
@@ -117,14 +197,63 @@ pub enum Event {
     BlockDiscovered(PeerId, BlockHash),
     /// Headers were imported successfully.
     HeadersImported(ImportResult),
+    /// The block tree emitted an event while importing headers, eg. because headers stored
+    /// outside of the active chain were evicted.
+    BlockTree(tree::Event),
     /// Started syncing with a peer.
     Syncing(PeerId),
     /// Finished syncing up to the specified hash and height.
     Synced(BlockHash, Height),
+    /// A single new block extended our chain while we were in sync with the network.
+    /// Unlike [`Event::HeadersImported`], this isn't emitted during initial sync.
+    NewBlock {
+        /// Height of the new block.
+        height: Height,
+        /// Hash of the new block.
+        hash: BlockHash,
+        /// Header of the new block.
+        header: BlockHeader,
+    },
+    /// A range of headers of the active chain was fetched again from a peer, and
+    /// imported. See [`SyncManager::refetch`].
+    HeadersRefetched {
+        /// The peer the headers were fetched from.
+        addr: PeerId,
+        /// Height of the first header of the range.
+        from: Height,
+        /// Height of the last header of the range.
+        to: Height,
+        /// Result of the import. The tip is unchanged if the headers matched ours.
+        result: ImportResult,
+    },
     /// A peer has timed out responding to a header request.
     TimedOut(PeerId),
     /// Potential stale tip detected on the active chain.
     StaleTipDetected(LocalTime),
+    /// A peer's responses consistently stop short of the height it advertised.
+    PeerWithholding {
+        /// The remote peer.
+        addr: PeerId,
+    },
+    /// The peer we're pinned to disconnected. Sync is stalled until it reconnects,
+    /// or the pin is lifted.
+    PinnedPeerDisconnected(PeerId),
+    /// The headers our block tree returned for a peer's `getheaders` request don't form
+    /// a contiguous chain. They weren't sent to the peer.
+    InvalidHeadersServed {
+        /// The peer that requested the headers.
+        addr: PeerId,
+        /// Index in the batch of the first header not extending its predecessor.
+        index: usize,
+    },
+    /// A peer sent a `headers` message whose headers don't each extend the one before
+    /// them. The headers were discarded.
+    NonContiguousHeadersReceived {
+        /// The remote peer.
+        addr: PeerId,
+        /// Index in the batch of the first header not extending its predecessor.
+        index: usize,
+    },
 }
 
 impl std::fmt::Display for Event {
@@ -146,13 +275,53 @@ impl std::fmt::Display for Event {
             Event::HeadersImported(import_result) => {
                 write!(fmt, "Headers imported: {:?}", &import_result)
             }
+            Event::BlockTree(event) => write!(fmt, "Block tree: {:?}", event),
             Event::Synced(hash, height) => {
                 write!(fmt, "Headers synced up to hash={} height={}", hash, height)
             }
             Event::Syncing(addr) => write!(fmt, "Syncing headers with {}", addr),
+            Event::NewBlock { height, hash, .. } => {
+                write!(fmt, "New block {} at height {}", hash, height)
+            }
             Event::BlockDiscovered(from, hash) => {
                 write!(fmt, "{}: Discovered new block: {}", from, &hash)
             }
+            Event::HeadersRefetched {
+                addr,
+                from,
+                to,
+                result,
+            } => {
+                write!(
+                    fmt,
+                    "{}: Refetched headers {}..={}: {:?}",
+                    addr, from, to, result
+                )
+            }
+            Event::PeerWithholding { addr } => {
+                write!(fmt, "{}: Peer appears to be withholding headers", addr)
+            }
+            Event::InvalidHeadersServed { addr, index } => {
+                write!(
+                    fmt,
+                    "{}: Headers served are not contiguous at index {}, not sending",
+                    addr, index
+                )
+            }
+            Event::NonContiguousHeadersReceived { addr, index } => {
+                write!(
+                    fmt,
+                    "{}: Received headers that are not contiguous at index {}",
+                    addr, index
+                )
+            }
+            Event::PinnedPeerDisconnected(addr) => {
+                write!(
+                    fmt,
+                    "{}: Pinned sync peer disconnected, sync is stalled",
+                    addr
+                )
+            }
             Event::StaleTipDetected(last_update) => {
                 let elapsed = LocalTime::from(SystemTime::now()) - *last_update;
 
@@ -182,6 +351,83 @@ pub struct GetHeaders {
     on_timeout: OnTimeout,
 }
 
+/// A range of headers of the active chain requested again from a peer.
+#[derive(Clone, Debug)]
+struct Refetch {
+    /// Height of the first header of the range.
+    from: Height,
+    /// Height of the last header of the range.
+    to: Height,
+    /// Locators of the request sent for the range.
+    locators: Locators,
+}
+
+/// The net change to the active chain over several imports.
+#[derive(Debug, Clone)]
+struct ChainDiff {
+    /// Height of the active chain as of the latest import.
+    height: Height,
+    /// The tip of the active chain, if it changed.
+    tip: Option<(BlockHash, Height)>,
+    /// Blocks that were disconnected from the active chain, in height order.
+    disconnected: Vec<BlockHash>,
+    /// Blocks that were connected to the active chain, in height order.
+    connected: Vec<BlockHash>,
+}
+
+impl ChainDiff {
+    /// Start tracking changes to an active chain of the given height.
+    fn new(height: Height) -> Self {
+        Self {
+            height,
+            tip: None,
+            disconnected: Vec::new(),
+            connected: Vec::new(),
+        }
+    }
+
+    /// Apply the result of an import to the given tree. Blocks that were connected and
+    /// then disconnected, or the other way round, cancel out.
+    fn apply<T: BlockTree>(&mut self, result: &ImportResult, tree: &T) {
+        let (tip, height, reverted) = match result {
+            ImportResult::TipChanged(tip, height, reverted) => (*tip, *height, reverted),
+            ImportResult::TipUnchanged => return,
+        };
+        let fork = self.height.saturating_sub(reverted.len() as Height);
+        let mut disconnected = Vec::new();
+
+        for hash in reverted {
+            match self.connected.iter().position(|h| h == hash) {
+                Some(ix) => {
+                    self.connected.remove(ix);
+                }
+                None => disconnected.push(*hash),
+            }
+        }
+        // Any block disconnected by this import is lower than the ones disconnected before.
+        self.disconnected.splice(0..0, disconnected);
+
+        for hash in (fork + 1..=height).filter_map(|h| tree.get_block_hash(h)) {
+            match self.disconnected.iter().position(|h| *h == hash) {
+                Some(ix) => {
+                    self.disconnected.remove(ix);
+                }
+                None => self.connected.push(hash),
+            }
+        }
+        self.height = height;
+        self.tip = Some((tip, height));
+    }
+
+    /// Get the net change as an import result.
+    fn result(self) -> ImportResult {
+        match self.tip {
+            Some((tip, height)) => ImportResult::TipChanged(tip, height, self.disconnected),
+            None => ImportResult::TipUnchanged,
+        }
+    }
+}
+
 /// `headers` broadcast.
 #[derive(Debug)]
 pub struct SendHeaders {
@@ -199,6 +445,8 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
         let last_peer_sample = None;
         let last_idle = None;
         let inflight = HashMap::with_hasher(rng.clone().into());
+        let queued = HashMap::with_hasher(rng.clone().into());
+        let refetching = HashMap::with_hasher(rng.clone().into());
 
         Self {
             peers,
@@ -208,6 +456,13 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
             last_idle,
             rng,
             inflight,
+            queued,
+            pinned: None,
+            refetching,
+            coalesced: None,
+            reorgs: VecDeque::new(),
+            max_reorg_depth: 0,
+            last_reorg: None,
             upstream,
         }
     }
@@ -239,6 +494,13 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
         if link.is_outbound() && !services.has(REQUIRED_SERVICES) {
             return;
         }
+        let height = if height < tree.height()
+            && (height == 0 || height + SYNCING_PEER_DEPTH < tree.height())
+        {
+            None
+        } else {
+            Some(height)
+        };
         self.register(id, height, link);
         self.upstream.negotiate(id);
         self.sync(clock.local_time(), tree);
@@ -247,6 +509,90 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
     /// Called when a peer disconnected.
     pub fn peer_disconnected(&mut self, id: &PeerId) {
         self.unregister(id);
+
+        if self.pinned == Some(*id) {
+            self.upstream.event(Event::PinnedPeerDisconnected(*id));
+        }
+    }
+
+    /// Get the peer we're pinned to, if any.
+    pub fn pinned(&self) -> Option<PeerId> {
+        self.pinned
+    }
+
+    /// Get statistics on recent reorgs.
+    pub fn reorg_stats(&self, now: LocalTime) -> ReorgStats {
+        let mut stats = ReorgStats {
+            max_depth: self.max_reorg_depth,
+            last_reorg: self.last_reorg,
+            ..ReorgStats::default()
+        };
+
+        for (time, depth) in &self.reorgs {
+            if now - *time <= REORG_HISTORY {
+                stats.last_day += 1;
+                stats.max_depth_last_day = stats.max_depth_last_day.max(*depth);
+            }
+            if now - *time <= LocalDuration::from_mins(60) {
+                stats.last_hour += 1;
+                stats.max_depth_last_hour = stats.max_depth_last_hour.max(*depth);
+            }
+        }
+        stats
+    }
+
+    /// Restrict header requests to the given peer, or lift the restriction with `None`.
+    /// Requests already in-flight to other peers are left to complete.
+    pub fn pin<T: BlockTree>(&mut self, peer: Option<PeerId>, now: LocalTime, tree: &T) {
+        self.pinned = peer;
+        self.queued
+            .retain(|addr, _| peer.is_none() || peer == Some(*addr));
+        self.sync(now, tree);
+    }
+
+    /// Fetch the headers of the active chain from height `from` to `to` again from the
+    /// given peer, eg. if they are suspected to be wrong. The headers received in response
+    /// are imported: if they match ours, nothing changes, otherwise they are treated like
+    /// a fork, and replace ours if they carry more work.
+    ///
+    /// Returns `false` if the range isn't part of the active chain, or if the peer isn't
+    /// connected. Genesis can't be refetched.
+    pub fn refetch<T: BlockTree>(
+        &mut self,
+        addr: PeerId,
+        from: Height,
+        to: Height,
+        now: LocalTime,
+        tree: &T,
+    ) -> bool {
+        if from == 0 || from > to || to > tree.height() {
+            return false;
+        }
+        let peer = if let Some(peer) = self.peers.get_mut(&addr) {
+            peer
+        } else {
+            return false;
+        };
+        let (anchor, stop) = match (tree.get_block_hash(from - 1), tree.get_block_hash(to)) {
+            (Some(anchor), Some(stop)) => (anchor, stop),
+            _ => return false,
+        };
+        let locators = (vec![anchor], stop);
+
+        // We may have asked for this exact range before, but this time it's on purpose.
+        peer.last_asked = None;
+
+        self.refetching.insert(
+            addr,
+            Refetch {
+                from,
+                to,
+                locators: locators.clone(),
+            },
+        );
+        self.request(addr, locators, now, OnTimeout::Ignore);
+
+        true
     }
 
     /// Called when we received a `getheaders` message from a peer.
@@ -266,6 +612,23 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
         if headers.is_empty() {
             return;
         }
+        // Headers are sent in height order, each extending the one before it. Since they
+        // don't carry their height, sending them otherwise would corrupt the peer's chain.
+        // Guard against block tree implementations breaking this.
+        if let Some(index) = headers
+            .windows(2)
+            .position(|w| w[1].prev_blockhash != w[0].block_hash())
+        {
+            self.upstream.event(Event::InvalidHeadersServed {
+                addr: *addr,
+                index: index + 1,
+            });
+            debug_assert!(
+                false,
+                "SyncManager::received_getheaders: headers served are not contiguous"
+            );
+            return;
+        }
         self.upstream.send_headers(*addr, headers);
     }
 
@@ -276,23 +639,126 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
         context: &C,
         tree: &mut T,
     ) -> Result<ImportResult, Error> {
-        match tree.import_blocks(blocks, context) {
-            Ok(ImportResult::TipChanged(tip, height, reverted)) => {
-                let result = ImportResult::TipChanged(tip, height, reverted);
+        let mut diff = ChainDiff::new(tree.height());
+        let mut segments: Vec<Vec<BlockHeader>> = Vec::new();
+
+        // Headers that each extend the one before them are imported together. Each run
+        // of headers is imported on its own, so that the changes it makes to the active
+        // chain are reported accurately.
+        for header in blocks {
+            match segments.last_mut() {
+                Some(segment)
+                    if segment.last().map(|h| h.block_hash()) == Some(header.prev_blockhash) =>
+                {
+                    segment.push(header);
+                }
+                _ => segments.push(vec![header]),
+            }
+        }
 
-                self.upstream.event(Event::HeadersImported(result.clone()));
-                self.upstream.event(Event::Synced(tip, height));
-                self.broadcast_tip(&tip, tree);
+        for segment in segments {
+            let result = tree.import_blocks(segment.into_iter(), context);
+            self.tree_events(None, context.local_time(), tree);
+            let result = result?;
+
+            if let ImportResult::TipChanged(tip, height, reverted) = &result {
+                self.record_reorg(reverted.len(), context.local_time());
+                self.headers_imported(result.clone(), tree);
+                self.upstream.event(Event::Synced(*tip, *height));
+                self.broadcast_tip(tip, tree);
+            } else {
+                self.headers_imported(result.clone(), tree);
+            }
+            diff.apply(&result, tree);
+        }
+        Ok(diff.result())
+    }
+
+    /// Start coalescing the changes made to the active chain, if configured. Until
+    /// [`SyncManager::end_step`] is called, imports that change the tip don't emit
+    /// [`Event::HeadersImported`]. Instead, a single event for the net change is emitted
+    /// at the end of the step: its reverted blocks are the blocks of the initial chain
+    /// that are no longer part of the active chain. The blocks connected in their place
+    /// are the blocks of the active chain above the fork point.
+    pub fn begin_step<T: BlockTree>(&mut self, tree: &T) {
+        if self.config.coalesce_reorgs {
+            self.coalesced = Some(ChainDiff::new(tree.height()));
+        }
+    }
+
+    /// Emit the net change to the active chain since [`SyncManager::begin_step`], if any.
+    pub fn end_step(&mut self) {
+        if let Some(diff) = self.coalesced.take() {
+            if let result @ ImportResult::TipChanged(..) = diff.result() {
+                self.upstream.event(Event::HeadersImported(result));
+            }
+        }
+    }
+
+    /// Report the result of an import, or record it if we're coalescing.
+    fn headers_imported<T: BlockTree>(&mut self, result: ImportResult, tree: &T) {
+        match &mut self.coalesced {
+            Some(diff) if matches!(result, ImportResult::TipChanged(..)) => {
+                diff.apply(&result, tree);
+            }
+            _ => self.upstream.event(Event::HeadersImported(result)),
+        }
+    }
 
-                Ok(result)
+    /// Report the events emitted by the block tree since they were last reported.
+    ///
+    /// Orphans received from a peer prompt a request to that peer for the headers leading
+    /// up to them from their missing parent, unless such a request is already underway.
+    /// Returns whether any orphans were received from the peer.
+    fn tree_events<T: BlockTree>(
+        &mut self,
+        from: Option<&PeerId>,
+        now: LocalTime,
+        tree: &mut T,
+    ) -> bool {
+        // The last orphan received for each missing parent, in order of reception. Since
+        // headers are imported in order, it is the one furthest from the missing parent.
+        let mut orphans: Vec<(BlockHash, BlockHash)> = Vec::new();
+
+        for event in tree.events() {
+            if let tree::Event::OrphanReceived {
+                hash,
+                missing_parent,
+            } = &event
+            {
+                match orphans
+                    .iter_mut()
+                    .find(|(_, parent)| parent == missing_parent)
+                {
+                    Some((last, _)) => *last = *hash,
+                    None => orphans.push((*hash, *missing_parent)),
+                }
             }
-            Ok(result @ ImportResult::TipUnchanged) => {
-                self.upstream.event(Event::HeadersImported(result.clone()));
+            self.upstream.event(Event::BlockTree(event));
+        }
 
-                Ok(result)
+        let from = match from {
+            Some(from) if !orphans.is_empty() => from,
+            _ => return false,
+        };
+        for (hash, _) in orphans {
+            let locators = (tree.locator_hashes(tree.height()), hash);
+            let pending = self
+                .inflight
+                .get(from)
+                .into_iter()
+                .chain(self.queued.get(from))
+                .any(|req| req.locators.1 == hash);
+            let asked = self
+                .peers
+                .get(from)
+                .is_some_and(|p| p.last_asked.as_ref() == Some(&locators));
+
+            if !pending && !asked {
+                self.request(*from, locators, now, OnTimeout::Ignore);
             }
-            Err(err) => Err(err),
         }
+        true
     }
 
     /// Called when a block is received from a peer.
@@ -313,28 +779,171 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
         clock: &impl Clock,
         tree: &mut T,
     ) -> Result<ImportResult, store::Error> {
-        let headers = if let Some(headers) = NonEmpty::from_vec(headers) {
-            headers
-        } else {
+        // Headers within a message must each extend the one before them. This is checked
+        // before anything else, since none of the headers can be trusted otherwise.
+        if let Some(index) = headers
+            .windows(2)
+            .position(|w| w[1].prev_blockhash != w[0].block_hash())
+        {
+            if self.peers.contains_key(from) {
+                self.inflight.remove(from);
+                self.upstream.event(Event::NonContiguousHeadersReceived {
+                    addr: *from,
+                    index: index + 1,
+                });
+                self.record_misbehavior(from);
+                self.dequeue(from, clock.local_time());
+            }
             return Ok(ImportResult::TipUnchanged);
+        }
+        let request = self.inflight.get(from).cloned();
+        let best = headers.last().map(|h| h.block_hash());
+        let length = headers.len();
+        let initial = self.is_initial_sync(tree, clock);
+        let height = tree.height();
+
+        if let Some(refetch) = self.refetching.get(from) {
+            if request.as_ref().map(|r| &r.locators) == Some(&refetch.locators) {
+                let refetch = self.refetching.remove(from).unwrap();
+
+                return self.received_refetch(from, refetch, headers, clock, tree);
+            }
+        }
+        let low_work =
+            request.is_some() && tree.is_caught_up(clock) && self.is_low_work(from, &headers, tree);
+        let result = self.process_headers(from, headers, clock, tree);
+        self.tree_events(Some(from), clock.local_time(), tree);
+
+        // Peers that can't help us are disconnected, unless we're still syncing.
+        if low_work {
+            self.unregister(from);
+            self.upstream
+                .disconnect(*from, DisconnectReason::PeerChainWork);
+
+            return result;
+        }
+
+        if let Ok(ImportResult::TipChanged(_, _, reverted)) = &result {
+            self.record_reorg(reverted.len(), clock.local_time());
+        }
+
+        // Announce blocks that extend our chain one at a time, once we're in sync.
+        if let Ok(ImportResult::TipChanged(hash, new_height, reverted)) = &result {
+            if !initial && reverted.is_empty() && *new_height == height + 1 {
+                if let Some(header) = tree.get_block_by_height(*new_height) {
+                    self.upstream.event(Event::NewBlock {
+                        height: *new_height,
+                        hash: *hash,
+                        header: *header,
+                    });
+                }
+            }
+        }
+        if let Some(request) = request {
+            self.check_withholding(from, &request, best, length, tree);
+        }
+        // Now that the in-flight request to this peer is resolved, we can send
+        // any request that was waiting on it.
+        self.dequeue(from, clock.local_time());
+
+        result
+    }
+
+    /// Import headers received in response to a refetch.
+    fn received_refetch<T: BlockTree>(
+        &mut self,
+        from: &PeerId,
+        refetch: Refetch,
+        headers: Vec<BlockHeader>,
+        clock: &impl Clock,
+        tree: &mut T,
+    ) -> Result<ImportResult, store::Error> {
+        self.inflight.remove(from);
+
+        if let Some(peer) = self.peers.get_mut(from) {
+            peer.last_active = Some(clock.local_time());
+        }
+        self.upstream
+            .event(Event::HeadersReceived(*from, headers.len()));
+
+        let result = match headers.last() {
+            // The headers are contiguous, so if the last one is on our active chain, they
+            // all are: they match ours.
+            Some(last) if !tree.contains(&last.block_hash()) => {
+                let result = tree.import_blocks(headers.into_iter(), clock);
+                self.tree_events(Some(from), clock.local_time(), tree);
+
+                match result {
+                    Ok(result) => {
+                        if let ImportResult::TipChanged(_, _, reverted) = &result {
+                            self.last_tip_update = Some(clock.local_time());
+                            self.record_reorg(reverted.len(), clock.local_time());
+                        }
+                        self.headers_imported(result.clone(), tree);
+
+                        Ok(result)
+                    }
+                    Err(err) => self
+                        .handle_error(from, err)
+                        .map(|()| ImportResult::TipUnchanged),
+                }
+            }
+            _ => Ok(ImportResult::TipUnchanged),
         };
 
-        let length = headers.len();
-        let best = headers.last().block_hash();
+        if let Ok(result) = &result {
+            self.upstream.event(Event::HeadersRefetched {
+                addr: *from,
+                from: refetch.from,
+                to: refetch.to,
+                result: result.clone(),
+            });
+        }
+        self.dequeue(from, clock.local_time());
+
+        result
+    }
 
+    fn process_headers<T: BlockTree>(
+        &mut self,
+        from: &PeerId,
+        headers: Vec<BlockHeader>,
+        clock: &impl Clock,
+        tree: &mut T,
+    ) -> Result<ImportResult, store::Error> {
         if let Some(peer) = self.peers.get_mut(from) {
             peer.last_active = Some(clock.local_time());
         } else {
             return Ok(ImportResult::TipUnchanged);
         }
+        let inflight = self.inflight.remove(from);
+
+        let headers = if let Some(headers) = NonEmpty::from_vec(headers) {
+            headers
+        } else {
+            return Ok(ImportResult::TipUnchanged);
+        };
+
+        let length = headers.len();
+        let best = headers.last().block_hash();
+
         self.upstream
             .event(Event::HeadersReceived(*from, headers.len()));
 
+        // Headers on our active chain tell us how far along the peer's chain is.
+        if let Some((height, _)) = tree.get_block(&best) {
+            let peer = self.peers.get_mut(from).unwrap();
+
+            if peer.height.is_none_or(|h| height > h) {
+                peer.tip = best;
+                peer.height = Some(height);
+            }
+        }
         if tree.contains(&best) {
             return Ok(ImportResult::TipUnchanged);
         }
 
-        match self.inflight.remove(from) {
+        match inflight {
             Some(GetHeaders { locators, .. })
                 if headers
                     .iter()
@@ -347,16 +956,15 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
                 let result = self.extend_chain(headers, clock, tree);
 
                 if let Ok(ref imported) = result {
-                    self.upstream
-                        .event(Event::HeadersImported(imported.clone()));
+                    self.headers_imported(imported.clone(), tree);
                 }
 
                 if let Ok(ImportResult::TipChanged(tip, height, _)) = result {
                     let peer = self.peers.get_mut(from).unwrap();
 
-                    if height > peer.height {
+                    if peer.height.is_none_or(|h| height > h) {
                         peer.tip = tip;
-                        peer.height = height;
+                        peer.height = Some(height);
                     }
                 }
 
@@ -376,17 +984,13 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
                             self.broadcast_tip(&tip, tree);
                             self.sync(clock.local_time(), tree);
                         } else {
-                            // TODO: If we're already in the state of asking for this header, don't
-                            // ask again.
                             // TODO: Should we use stop-hash for the single locator?
                             let locators = (vec![tip], BlockHash::default());
-                            let timeout = self.config.request_timeout;
 
                             self.request(
                                 *from,
                                 locators,
                                 clock.local_time(),
-                                timeout,
                                 OnTimeout::Disconnect,
                             );
                         }
@@ -404,37 +1008,30 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
 
                 match tree.import_blocks(headers.into_iter(), clock) {
                     Ok(import_result @ ImportResult::TipUnchanged) => {
-                        self.upstream
-                            .event(Event::HeadersImported(import_result.clone()));
+                        self.headers_imported(import_result.clone(), tree);
 
-                        // Try to find a common ancestor that leads up to the first header in
+                        // Orphans are requested from their missing parent. Otherwise, try
+                        // to find a common ancestor that leads up to the first header in
                         // the list we received.
-                        let locators = (tree.locator_hashes(tree.height()), root);
-                        let timeout = self.config.request_timeout;
-
-                        self.request(
-                            *from,
-                            locators,
-                            clock.local_time(),
-                            timeout,
-                            OnTimeout::Ignore,
-                        );
+                        if !self.tree_events(Some(from), clock.local_time(), tree) {
+                            let locators = (tree.locator_hashes(tree.height()), root);
+
+                            self.request(*from, locators, clock.local_time(), OnTimeout::Ignore);
+                        }
 
                         Ok(import_result)
                     }
                     Ok(ImportResult::TipChanged(tip, height, reverted)) => {
                         let peer = self.peers.get_mut(from).unwrap();
-                        if height > peer.height {
+                        if peer.height.is_none_or(|h| height > h) {
                             peer.tip = tip;
-                            peer.height = height;
+                            peer.height = Some(height);
                         }
 
-                        self.upstream
-                            .event(Event::HeadersImported(ImportResult::TipChanged(
-                                tip,
-                                height,
-                                reverted.clone(),
-                            )));
+                        self.headers_imported(
+                            ImportResult::TipChanged(tip, height, reverted.clone()),
+                            tree,
+                        );
 
                         Ok(ImportResult::TipChanged(tip, height, reverted))
                     }
@@ -455,19 +1052,25 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
         }
     }
 
+    /// Send a `getheaders` request to a peer.
+    ///
+    /// We only ever have one outstanding request per peer: if a request to this peer is
+    /// already in-flight, the new request is queued until the in-flight one is resolved,
+    /// either by a response or a timeout. A queued request replaces any previously queued
+    /// request to the same peer.
     fn request(
         &mut self,
         addr: PeerId,
         locators: Locators,
         sent_at: LocalTime,
-        timeout: Timeout,
         on_timeout: OnTimeout,
     ) {
-        if let Some(peer) = self.peers.get_mut(&addr) {
-            debug_assert!(peer.last_asked.as_ref() != Some(&locators));
-
-            peer.last_asked = Some(locators.clone());
+        let timeout = self.request_timeout(&addr);
 
+        if self.pinned.is_some() && self.pinned != Some(addr) {
+            return;
+        }
+        if let Some(peer) = self.peers.get_mut(&addr) {
             let req = GetHeaders {
                 addr,
                 locators,
@@ -476,12 +1079,118 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
                 on_timeout,
             };
 
+            if self.inflight.contains_key(&addr) {
+                self.queued.insert(addr, req);
+                return;
+            }
+            debug_assert!(peer.last_asked.as_ref() != Some(&req.locators));
+
+            peer.last_asked = Some(req.locators.clone());
+
             self.inflight.insert(addr, req.clone());
             self.upstream.get_headers(req.addr, req.locators);
             self.upstream.set_timeout(req.timeout);
         }
     }
 
+    /// Check whether a response to one of our requests stops short of the peer's
+    /// advertised height, and flag the peer if this keeps happening.
+    fn check_withholding<T: BlockTree>(
+        &mut self,
+        from: &PeerId,
+        request: &GetHeaders,
+        best: Option<BlockHash>,
+        count: usize,
+        tree: &T,
+    ) {
+        // A full batch means there are more headers to come.
+        if count >= self.config.max_message_headers {
+            return;
+        }
+        // We asked for headers up to a specific block, and got them.
+        if best.is_some() && best == Some(request.locators.1) {
+            return;
+        }
+        // If the peer didn't send anything, it didn't get us past our latest locator.
+        let delivered = if let Some((height, _)) = best
+            .or_else(|| request.locators.0.first().copied())
+            .and_then(|hash| tree.get_block(&hash))
+        {
+            height
+        } else {
+            // The headers aren't on our active chain.
+            return;
+        };
+
+        if let Some(peer) = self.peers.get_mut(from) {
+            let advertised = if let Some(height) = peer.height {
+                height
+            } else {
+                // We don't know how far the peer's chain goes.
+                return;
+            };
+            if delivered + WITHHOLDING_TOLERANCE < advertised {
+                peer.short_responses += 1;
+
+                if peer.short_responses == MAX_SHORT_RESPONSES {
+                    self.upstream.event(Event::PeerWithholding { addr: *from });
+                }
+            } else {
+                peer.short_responses = 0;
+            }
+        }
+    }
+
+    /// Check whether a peer's chain, as demonstrated by its first response to our request
+    /// for headers, has less work than ours, by more than [`LOW_WORK_TOLERANCE`] blocks.
+    fn is_low_work<T: BlockTree>(
+        &mut self,
+        from: &PeerId,
+        headers: &[BlockHeader],
+        tree: &T,
+    ) -> bool {
+        match self.peers.get_mut(from) {
+            Some(peer) if !peer.work_checked => peer.work_checked = true,
+            _ => return false,
+        }
+        let height = tree.height();
+        // The height at which the peer's chain forks off ours. If the peer didn't send
+        // anything, it has our tip.
+        let fork = match headers.first() {
+            Some(first) => match tree.get_block(&first.prev_blockhash) {
+                Some((height, _)) => height,
+                None => return false,
+            },
+            None => height,
+        };
+        let ours = (fork + 1..=height)
+            .filter_map(|h| tree.get_block_by_height(h))
+            .fold(Work::default(), |work, h| work + h.work());
+        let theirs = headers
+            .iter()
+            .fold(Work::default(), |work, h| work + h.work());
+        let (_, tip) = tree.tip();
+
+        ours > theirs + tip.work().mul_u32(LOW_WORK_TOLERANCE)
+    }
+
+    /// Send the request queued for the given peer, if any, and if it's still relevant.
+    fn dequeue(&mut self, addr: &PeerId, now: LocalTime) {
+        if self.inflight.contains_key(addr) {
+            return;
+        }
+        if let Some(req) = self.queued.remove(addr) {
+            let asked = self
+                .peers
+                .get(addr)
+                .map(|p| p.last_asked.as_ref() == Some(&req.locators));
+
+            if asked == Some(false) {
+                self.request(req.addr, req.locators, now, req.on_timeout);
+            }
+        }
+    }
+
     fn extend_chain<T: BlockTree>(
         &mut self,
         headers: NonEmpty<BlockHeader>,
@@ -544,28 +1253,42 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
 
         if let Some(stop_hash) = best_block {
             let locators = (tree.locator_hashes(tree.height()), *stop_hash);
-            let timeout = self.config.request_timeout;
 
             // Try to find headers leading up to the `inv` entry.
 
-            self.request(
-                addr,
-                locators,
-                clock.local_time(),
-                timeout,
-                OnTimeout::Ignore,
-            );
+            self.request(addr, locators, clock.local_time(), OnTimeout::Ignore);
+        }
+    }
+
+    /// Called when a compact block (BIP 152) is received from a peer. Since we can't
+    /// decode compact blocks, nor have the transactions needed to reconstruct them, we fall
+    /// back to asking the peer for the headers leading up to the block, after which the
+    /// block can be requested in full.
+    ///
+    /// We never ask for compact blocks, so the peer is also penalized for sending one.
+    pub fn received_compact_block<T: BlockTree, C: Clock>(
+        &mut self,
+        addr: PeerId,
+        clock: &C,
+        tree: &T,
+    ) {
+        if !self.peers.contains_key(&addr) {
+            return;
         }
+        self.record_misbehavior(&addr);
+
+        let locators = (tree.locator_hashes(tree.height()), BlockHash::default());
+
+        self.request(addr, locators, clock.local_time(), OnTimeout::Ignore);
     }
 
     /// Called when we received a timeout.
     pub fn received_timeout<T: BlockTree>(&mut self, local_time: LocalTime, tree: &T) {
-        let timeout = self.config.request_timeout;
         let timed_out = self
             .inflight
             .iter()
             .filter_map(|(peer, req)| {
-                if local_time - req.sent_at >= timeout {
+                if local_time - req.sent_at >= req.timeout {
                     Some((*peer, req.on_timeout))
                 } else {
                     None
@@ -574,7 +1297,11 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
             .collect::<Vec<_>>();
 
         for (peer, on_timeout) in &timed_out {
-            self.inflight.remove(&peer);
+            if let Some(req) = self.inflight.remove(&peer) {
+                if self.refetching.get(peer).map(|r| &r.locators) == Some(&req.locators) {
+                    self.refetching.remove(peer);
+                }
+            }
 
             match on_timeout {
                 OnTimeout::Disconnect => {
@@ -584,6 +1311,7 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
                 }
                 OnTimeout::Ignore => {
                     // It's likely that the peer just didn't have the requested header.
+                    self.dequeue(peer, local_time);
                 }
             }
             self.upstream.event(Event::TimedOut(*peer));
@@ -599,7 +1327,7 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
 
     /// Get the best known height out of all our peers.
     pub fn best_height(&self) -> Option<Height> {
-        self.peers.iter().map(|(_, p)| p.height).max()
+        self.peers.values().filter_map(|p| p.height).max()
     }
 
     ///////////////////////////////////////////////////////////////////////////
@@ -624,15 +1352,120 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
             }
 
             // Harmless errors can be ignored.
-            Error::DuplicateBlock(_) | Error::BlockMissing(_) => Ok(()),
+            Error::DuplicateBlock(_)
+            | Error::BlockMissing(_)
+            | Error::DisjointChains(_)
+            | Error::DiffBaseMismatch(_, _) => Ok(()),
 
             // TODO: This will be removed.
-            Error::BlockImportAborted(_, _, _) => Ok(()),
+            Error::BlockImportAborted(err, _, _) => self.handle_error(from, *err),
+        }
+    }
+
+    /// Keep track of a tip change that reverted the given number of blocks.
+    fn record_reorg(&mut self, depth: usize, now: LocalTime) {
+        if depth == 0 {
+            return;
+        }
+        while let Some((time, _)) = self.reorgs.front() {
+            if now - *time > REORG_HISTORY {
+                self.reorgs.pop_front();
+            } else {
+                break;
+            }
         }
+        self.reorgs.push_back((now, depth));
+        self.max_reorg_depth = self.max_reorg_depth.max(depth);
+        self.last_reorg = Some(now);
     }
 
-    fn record_misbehavior(&mut self, _peer: &PeerId) {
-        // TODO
+    /// Get the misbehavior score of a peer.
+    pub fn misbehavior(&self, addr: &PeerId) -> Option<u32> {
+        self.peers.get(addr).map(|p| p.misbehavior)
+    }
+
+    /// Export the in-flight requests to our peers.
+    pub fn export_requests(&self) -> Vec<state::Request> {
+        self.inflight
+            .values()
+            .map(|req| state::Request {
+                addr: req.addr,
+                locators: req.locators.0.clone(),
+                stop_hash: req.locators.1,
+                disconnect_on_timeout: matches!(req.on_timeout, OnTimeout::Disconnect),
+            })
+            .collect()
+    }
+
+    /// Restore a request exported by another sync manager, as if it was sent at the given
+    /// time. The request isn't sent again. Should be followed by restoring the peer the
+    /// request was sent to, see [`SyncManager::restore_peer`].
+    pub fn restore_request(&mut self, request: state::Request, sent_at: LocalTime) {
+        let req = GetHeaders {
+            addr: request.addr,
+            locators: (request.locators, request.stop_hash),
+            timeout: self.request_timeout(&request.addr),
+            sent_at,
+            on_timeout: if request.disconnect_on_timeout {
+                OnTimeout::Disconnect
+            } else {
+                OnTimeout::Ignore
+            },
+        };
+        self.upstream.set_timeout(req.timeout);
+        self.inflight.insert(req.addr, req);
+    }
+
+    /// Restore the state of a negotiated peer exported by another sync manager. Requests
+    /// restored for peers we don't sync with are dropped.
+    pub fn restore_peer(&mut self, addr: &PeerId, misbehavior: u32) {
+        match self.peers.get_mut(addr) {
+            Some(peer) => {
+                peer.misbehavior = misbehavior;
+                peer.last_asked = self.inflight.get(addr).map(|r| r.locators.clone());
+            }
+            None => {
+                self.inflight.remove(addr);
+            }
+        }
+    }
+
+    /// Called when the round-trip latency of a peer was measured. Requests to the peer are
+    /// then timed out according to its latency.
+    pub fn peer_latency(&mut self, addr: &PeerId, latency: LocalDuration) {
+        if let Some(peer) = self.peers.get_mut(addr) {
+            peer.latency = Some(latency);
+        }
+    }
+
+    /// Get the time to wait for a request to the given peer to be fulfilled. This is a
+    /// multiple of the peer's latency, within [`MIN_REQUEST_TIMEOUT`] and
+    /// [`MAX_REQUEST_TIMEOUT`], or the configured request timeout if its latency isn't known.
+    pub fn request_timeout(&self, addr: &PeerId) -> LocalDuration {
+        match self.peers.get(addr).and_then(|p| p.latency) {
+            Some(latency) => {
+                let timeout = MIN_REQUEST_TIMEOUT.as_millis()
+                    + latency.as_millis() * REQUEST_TIMEOUT_ROUND_TRIPS;
+
+                LocalDuration::from_millis(timeout.min(MAX_REQUEST_TIMEOUT.as_millis()))
+            }
+            None => self.config.request_timeout,
+        }
+    }
+
+    /// Penalize a peer for misbehaving, and disconnect it if its score gets too high.
+    fn record_misbehavior(&mut self, addr: &PeerId) {
+        if let Some(peer) = self.peers.get_mut(addr) {
+            peer.misbehavior += MISBEHAVIOR_PENALTY;
+
+            if peer.misbehavior >= MAX_MISBEHAVIOR {
+                self.unregister(addr);
+                self.upstream.disconnect(
+                    *addr,
+                    DisconnectReason::PeerMisbehaving("misbehavior score too high"),
+                );
+            }
+        }
     }
 
     /// Check whether our current tip is stale.
@@ -659,15 +1492,23 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
         None
     }
 
+    /// Are we in the initial block download, ie. is our tip too old for us to be caught up
+    /// with the network? This doesn't depend on the heights advertised by our peers, so that
+    /// a single peer can't keep us in it.
+    fn is_initial_sync<T: BlockTree>(&self, tree: &T, clock: &impl Clock) -> bool {
+        !tree.is_caught_up(clock)
+    }
+
     /// Are we currently syncing?
     fn is_syncing(&self) -> bool {
         !self.inflight.is_empty()
     }
 
     /// Register a new peer.
-    fn register(&mut self, id: PeerId, height: Height, link: Link) {
+    fn register(&mut self, id: PeerId, height: Option<Height>, link: Link) {
         let last_active = None;
         let last_asked = None;
+        let short_responses = 0;
         let tip = BlockHash::default();
 
         self.peers.insert(
@@ -679,6 +1520,10 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
                 link,
                 last_active,
                 last_asked,
+                short_responses,
+                work_checked: false,
+                misbehavior: 0,
+                latency: None,
             },
         );
     }
@@ -686,20 +1531,30 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
     /// Unregister a peer.
     fn unregister(&mut self, id: &PeerId) {
         self.peers.remove(id);
+        self.queued.remove(id);
+        self.refetching.remove(id);
     }
 
     /// Pick a random peer we could sync with using the given locators.
+    /// Peers known to be ahead of us are preferred over peers of unknown height. Peers that
+    /// appear to be withholding headers are only picked as a last resort.
     fn random_sync_candidate<T: BlockTree>(
         &self,
         locators: &[BlockHash],
         tree: &T,
     ) -> Option<&PeerState> {
-        let candidates = self
+        let (withholding, candidates): (Vec<_>, Vec<_>) = self
             .peers
             .values()
-            .filter(|p| self.is_sync_candidate(p, locators, tree));
-
-        if let Some(peers) = NonEmpty::from_vec(candidates.collect()) {
+            .filter(|p| self.is_sync_candidate(p, locators, tree))
+            .partition(|p| p.is_withholding());
+        let (ahead, unknown): (Vec<_>, Vec<_>) =
+            candidates.into_iter().partition(|p| p.height.is_some());
+
+        if let Some(peers) = NonEmpty::from_vec(ahead)
+            .or_else(|| NonEmpty::from_vec(unknown))
+            .or_else(|| NonEmpty::from_vec(withholding))
+        {
             let ix = self.rng.usize(..peers.len());
 
             return peers.get(ix).cloned();
@@ -716,12 +1571,18 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
         tree: &T,
     ) -> bool {
         peer.link.is_outbound()
-            && peer.height > tree.height()
+            && (self.pinned.is_none() || self.pinned == Some(peer.id))
+            && peer.height.is_none_or(|h| h > tree.height())
             && !self.inflight.contains_key(&peer.id)
             && peer.last_asked.as_ref().map_or(true, |l| l.0 != locators)
     }
 
     /// Check whether or not we are in sync with the network.
+    ///
+    /// Unlike [`SyncManager::is_initial_sync`], this compares our height to that of our peers
+    /// rather than checking the age of our tip, since it decides whether there are headers
+    /// left to ask our peers for: a tip that is recent enough to be caught up can still be a
+    /// few blocks behind, and on networks where blocks are rare, no tip is ever recent enough.
     fn is_synced<T: BlockTree>(&mut self, now: LocalTime, tree: &T) -> bool {
         if let Some(last_update) = self.stale_tip(now, tree) {
             self.upstream.event(Event::StaleTipDetected(last_update));
@@ -731,7 +1592,7 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
         let height = tree.height();
 
         // Find the peer with the longest chain and compare our height to it.
-        if let Some(peer_height) = self.peers.values().map(|p| p.height).max() {
+        if let Some(peer_height) = self.best_height() {
             return height >= peer_height;
         }
 
@@ -778,10 +1639,9 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
         }
 
         if let Some(peer) = self.random_sync_candidate(&locators.0, tree) {
-            let timeout = self.config.request_timeout;
             let addr = peer.id;
 
-            self.request(addr, locators, now, timeout, OnTimeout::Ignore);
+            self.request(addr, locators, now, OnTimeout::Ignore);
             self.upstream.event(Event::Syncing(addr));
         } else {
             // TODO: No peer found to sync.. emit event.
@@ -793,7 +1653,7 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
         if let Some((height, best)) = tree.get_block(hash) {
             for (addr, peer) in &self.peers {
                 // TODO: Don't broadcast to peer that is currently syncing?
-                if peer.link == Link::Inbound && height > peer.height {
+                if peer.link == Link::Inbound && peer.height.is_none_or(|h| height > h) {
                     self.upstream.send_headers(*addr, vec![*best]);
                 }
             }
@@ -815,7 +1675,6 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
                 addr,
                 (locators.clone(), BlockHash::default()),
                 now,
-                self.config.request_timeout,
                 OnTimeout::Ignore,
             );
         }
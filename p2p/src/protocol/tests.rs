@@ -7,12 +7,14 @@ use simulator::PeerConfig;
 
 use bitcoin::consensus::params::Params;
 use bitcoin::network::message_blockdata::Inventory;
+use bitcoin::network::message_filter::GetCFCheckpt;
 use bitcoin::network::Address;
 use bitcoin_hashes::hex::FromHex;
 
 use std::collections::{HashMap, VecDeque};
 use std::time::SystemTime;
 
+use arbitrary::Arbitrary;
 use nonempty::NonEmpty;
 use quickcheck_macros::quickcheck;
 
@@ -20,6 +22,7 @@ use nakamoto_chain::block::cache::BlockCache;
 use nakamoto_chain::block::store;
 use nakamoto_common::block::filter::FilterHeader;
 use nakamoto_common::block::store::{Genesis, Store};
+use nakamoto_common::block::tree;
 use nakamoto_common::block::BlockHeader;
 use nakamoto_common::p2p::peer::{KnownAddress, Source};
 
@@ -27,7 +30,7 @@ use nakamoto_test::block::cache::model;
 use nakamoto_test::logger;
 use nakamoto_test::BITCOIN_HEADERS;
 
-use crate::protocol::{connmgr, pingmgr, Builder, Protocol};
+use crate::protocol::{connmgr, fuzz, pingmgr, reachability, throughput, Builder, Protocol};
 
 fn payload(o: &Out) -> Option<(net::SocketAddr, &NetworkMessage)> {
     match o {
@@ -46,6 +49,7 @@ mod setup {
             network: network::Network::Mainnet,
             params: Params::new(network::Network::Mainnet.into()),
             connect: vec![],
+            persistent_peers: vec![],
             // Pretend that we're a full-node, to fool connections
             // between instances of this protocol in tests.
             services: ServiceFlags::NETWORK | ServiceFlags::COMPACT_FILTERS,
@@ -53,7 +57,21 @@ mod setup {
             protocol_version: PROTOCOL_VERSION,
             target_outbound_peers: 8,
             max_inbound_peers: 8,
+            accept_inbound: true,
+            max_connections: connmgr::MAX_CONNECTIONS,
+            connect_timeout: connmgr::CONNECTION_TIMEOUT,
+            handshake_timeout: peermgr::HANDSHAKE_TIMEOUT,
+            max_concurrent_handshakes: connmgr::MAX_CONCURRENT_HANDSHAKES,
+            networks: vec![],
+            onion_proxy: None,
+            fixed_seeds: vec![],
+            ignored_commands: IGNORED_COMMANDS.iter().map(|c| (*c).to_owned()).collect(),
+            unknown_messages: UnknownMessagePolicy::Disconnect,
+            halt_on_clock_skew: false,
+            coalesce_reorgs: false,
+            event_overflow: event::Overflow::default(),
             user_agent: USER_AGENT,
+            features: Features::default(),
             whitelist: Whitelist {
                 addr: HashSet::new(),
                 user_agent: vec![USER_AGENT.to_owned()].into_iter().collect(),
@@ -203,6 +221,7 @@ mod setup {
                 // between instances of this protocol in tests.
                 services: config.required_services,
                 target: peer_cfg.name,
+                fixed_seeds: vec![],
                 ..config.clone()
             };
             configure(&mut cfg);
@@ -450,6 +469,63 @@ fn test_getheaders_timeout() {
         .expect("a timer should be returned");
 }
 
+#[test]
+fn test_getheaders_one_inflight() {
+    let network = Network::Mainnet;
+    let msg = message::Builder::new(network);
+    let ((mut local, _, rx), (_, remote_addr, _), local_time) = setup::pair(network);
+    // Some hashes for nonexistent blocks.
+    let hashes = [
+        BlockHash::from_hex("0000000000b7b2c71f2a345e3a4fc328bf5bbb436012afca590b1a11466e2206")
+            .unwrap(),
+        BlockHash::from_hex("00000000000000000002a23d6df20eecec15b21d32c75833cce28f113de888b7")
+            .unwrap(),
+    ];
+    let getheaders = |o: &Out| -> Option<BlockHash> {
+        match payload(o) {
+            Some((addr, NetworkMessage::GetHeaders(m))) if addr == remote_addr => Some(m.stop_hash),
+            _ => None,
+        }
+    };
+
+    local.step(
+        Input::Received(
+            remote_addr,
+            msg.raw(NetworkMessage::Inv(vec![Inventory::Block(hashes[0])])),
+        ),
+        local_time,
+    );
+    assert_eq!(
+        rx.try_iter()
+            .filter_map(|o| getheaders(&o))
+            .collect::<Vec<_>>(),
+        vec![hashes[0]]
+    );
+
+    // A second announcement while the first request is outstanding doesn't trigger
+    // another `getheaders`.
+    local.step(
+        Input::Received(
+            remote_addr,
+            msg.raw(NetworkMessage::Inv(vec![Inventory::Block(hashes[1])])),
+        ),
+        local_time,
+    );
+    assert!(
+        rx.try_iter().all(|o| getheaders(&o).is_none()),
+        "a `getheaders` message should not be sent"
+    );
+
+    // Once the outstanding request times out, the queued request is sent.
+    local.step(Input::Timeout, local_time + syncmgr::REQUEST_TIMEOUT);
+    assert_eq!(
+        rx.try_iter()
+            .filter_map(|o| getheaders(&o))
+            .collect::<Vec<_>>(),
+        vec![hashes[1]]
+    );
+}
+
 #[quickcheck]
 fn test_maintain_connections(seed: u64) {
     const TARGET_PEERS: usize = 2;
@@ -572,21 +648,34 @@ fn test_getheaders_retry(seed: u64) {
 
     let ask = sim.peers.len() - 1;
 
-    // Peers that have been asked.
-    let mut asked = HashSet::new();
+    // Peers that have been asked. This includes the peer Alice started syncing with
+    // when connecting, since that request is still outstanding.
+    let mut asked = sim
+        .events(&alice)
+        .filter_map(|e| match e {
+            Event::SyncManager(syncmgr::Event::Syncing(addr)) => Some(addr),
+            _ => None,
+        })
+        .collect::<HashSet<_>>();
+
+    // Pick a peer we don't have an outstanding request with.
+    let from = *[bob, olive, fred]
+        .iter()
+        .find(|p| !asked.contains(p))
+        .unwrap();
 
     // Trigger a `getheaders` by sending an inventory message to Alice.
     let result = sim.input(
         &alice,
         Input::Received(
-            bob,
+            from,
             msg.raw(NetworkMessage::Inv(vec![Inventory::Block(hash)])),
         ),
     );
 
     // The first time we ask for headers, we ask the peer who sent us the `inv` message.
     let (addr, _) = result.message(|_, m| matches!(m, NetworkMessage::GetHeaders(_)));
-    assert_eq!(addr, bob);
+    assert_eq!(addr, from);
 
     asked.insert(addr);
     result.schedule(&mut sim);
@@ -633,11 +722,11 @@ fn test_handshake_version_timeout() {
 
         instance.step(Input::Timeout, time + peermgr::HANDSHAKE_TIMEOUT);
         assert!(rx.iter().any(
-            |o| matches!(o, Out::Disconnect(a, DisconnectReason::PeerTimeout) if a == remote)
+            |o| matches!(o, Out::Disconnect(a, DisconnectReason::PeerHandshakeTimeout) if a == remote)
         ));
 
         instance.step(
-            Input::Disconnected(remote, DisconnectReason::PeerTimeout),
+            Input::Disconnected(remote, DisconnectReason::PeerHandshakeTimeout),
             time,
         );
     }
@@ -681,16 +770,117 @@ fn test_handshake_verack_timeout() {
 
         instance.step(Input::Timeout, time);
         assert!(rx.iter().any(
-            |o| matches!(o, Out::Disconnect(a, DisconnectReason::PeerTimeout) if a == remote)
+            |o| matches!(o, Out::Disconnect(a, DisconnectReason::PeerHandshakeTimeout) if a == remote)
         ));
 
         instance.step(
-            Input::Disconnected(remote, DisconnectReason::PeerTimeout),
+            Input::Disconnected(remote, DisconnectReason::PeerHandshakeTimeout),
             time,
         );
     }
 }
 
+#[test]
+fn test_handshake_timeout_discards_peer() {
+    let network = Network::Mainnet;
+    let (mut instance, rx, time) = setup::singleton(network);
+
+    let remote: net::SocketAddr = ([131, 31, 11, 33], 11111).into();
+    let local = ([0, 0, 0, 0], 0).into();
+
+    instance.addrmgr.insert(
+        std::iter::once((0, Address::new(&remote, setup::CONFIG.required_services))),
+        Source::Dns,
+    );
+    instance.step(
+        Input::Connected {
+            addr: remote,
+            local_addr: local,
+            link: Link::Outbound,
+        },
+        time,
+    );
+    rx.try_iter().for_each(drop);
+
+    // The connection was established, so the connect timeout doesn't apply.
+    instance.step(
+        Input::Timeout,
+        time + instance.connmgr.config.connect_timeout,
+    );
+    assert!(!rx.try_iter().any(|o| matches!(o, Out::Disconnect(..))));
+
+    instance.step(Input::Timeout, time + peermgr::HANDSHAKE_TIMEOUT);
+    assert!(rx.try_iter().any(
+        |o| matches!(o, Out::Disconnect(a, DisconnectReason::PeerHandshakeTimeout) if a == remote)
+    ));
+    instance.step(
+        Input::Disconnected(remote, DisconnectReason::PeerHandshakeTimeout),
+        time,
+    );
+    assert!(
+        instance
+            .addrmgr
+            .iter()
+            .all(|a| a.socket_addr().unwrap() != remote),
+        "The peer is removed from the address book"
+    );
+}
+
+#[test]
+fn test_connect_timeout() {
+    let network = Network::Mainnet;
+    let (mut instance, rx, time) = setup::singleton(network);
+
+    let bob: net::SocketAddr = ([131, 31, 11, 33], 8333).into();
+    let olive: net::SocketAddr = ([131, 31, 11, 34], 8333).into();
+
+    instance.step(Input::Command(Command::Connect(bob)), time);
+
+    let timeout = rx
+        .try_iter()
+        .find_map(|o| match o {
+            Out::Connect(addr, timeout) if addr == bob => Some(timeout),
+            _ => None,
+        })
+        .expect("Alice connects to bob");
+    assert_eq!(timeout, instance.connmgr.config.connect_timeout);
+
+    // Before the timeout elapses, the connection attempt is left alone.
+    instance.step(Input::Timeout, time + timeout - LocalDuration::from_secs(1));
+    assert!(!rx.try_iter().any(|o| matches!(o, Out::Disconnect(..))));
+
+    instance.addrmgr.insert(
+        std::iter::once((0, Address::new(&olive, setup::CONFIG.required_services))),
+        Source::Dns,
+    );
+    instance.step(Input::Timeout, time + timeout);
+
+    let outputs = rx.try_iter().collect::<Vec<_>>();
+    assert!(outputs.iter().any(
+        |o| matches!(o, Out::Disconnect(a, DisconnectReason::ConnectionTimeout) if *a == bob)
+    ));
+    assert!(
+        outputs
+            .iter()
+            .any(|o| matches!(o, Out::Connect(a, _) if *a == olive)),
+        "Alice tries another peer"
+    );
+    assert!(outputs.iter().any(|o| matches!(
+        o,
+        Out::Event(Event::ConnManager(connmgr::Event::ConnectionTimeout(a))) if *a == bob
+    )));
+
+    // The socket of the aborted attempt is eventually cleaned up.
+    instance.step(
+        Input::Disconnected(bob, DisconnectReason::ConnectionTimeout),
+        time + timeout,
+    );
+    assert!(!rx.try_iter().any(|o| matches!(
+        o,
+        Out::Event(Event::ConnManager(connmgr::Event::Disconnected(_)))
+    )));
+}
+
 #[test]
 fn test_handshake_initial_messages() {
     let network = Network::Mainnet;
@@ -935,6 +1125,112 @@ fn test_stale_tip() {
         .event(|e| matches!(e, Event::SyncManager(syncmgr::Event::StaleTipDetected(_))));
 }
 
+#[test]
+fn test_peer_withholding() {
+    let network = Network::Mainnet;
+    let msg = message::Builder::new(network);
+    let chain = NonEmpty::new(network.genesis());
+    let mut sim = simulator::Net {
+        network,
+        peers: vec![
+            PeerConfig::new("alice", chain.clone()),
+            PeerConfig::new("bob", chain),
+        ],
+        configure: |cfg| {
+            cfg.target_outbound_peers = 1;
+            cfg.whitelist = setup::CONFIG.whitelist.clone();
+        },
+        initialize: false,
+        ..Default::default()
+    }
+    .into();
+
+    let time = sim.time;
+    let alice = sim.get("alice");
+    let bob = sim.get("bob");
+    let height = 100;
+    let headers = BITCOIN_HEADERS
+        .iter()
+        .take(height + 1)
+        .cloned()
+        .collect::<Vec<_>>();
+
+    // Bob advertises a chain of height 100.
+    let version = sim
+        .peer("bob")
+        .protocol
+        .peermgr
+        .version(alice, bob, 1, height as Height, time);
+
+    sim.input(
+        &alice,
+        Input::Connected {
+            addr: bob,
+            local_addr: alice,
+            link: Link::Outbound,
+        },
+    );
+    sim.input(
+        &alice,
+        Input::Received(bob, msg.raw(NetworkMessage::Version(version))),
+    );
+    sim.input(
+        &alice,
+        Input::Received(bob, msg.raw(NetworkMessage::Verack)),
+    )
+    .message(|_, msg| matches!(msg, NetworkMessage::GetHeaders(_)));
+
+    // Bob only serves headers up to height 50.
+    let result = sim.input(
+        &alice,
+        Input::Received(
+            bob,
+            msg.raw(NetworkMessage::Headers(headers[1..=height - 50].to_vec())),
+        ),
+    );
+    assert_eq!(
+        sim.peer("alice").protocol.tree.height(),
+        (height - 50) as Height
+    );
+    result.message(|_, msg| matches!(msg, NetworkMessage::GetHeaders(_)));
+
+    // Bob doesn't serve the rest.
+    sim.input(
+        &alice,
+        Input::Received(bob, msg.raw(NetworkMessage::Headers(vec![]))),
+    )
+    .all(|o| {
+        !matches!(
+            o,
+            Out::Event(Event::SyncManager(syncmgr::Event::PeerWithholding { .. }))
+        )
+    })
+    .expect("Bob isn't flagged yet");
+
+    // Bob announces a block, but still doesn't serve it.
+    sim.input(
+        &alice,
+        Input::Received(
+            bob,
+            msg.raw(NetworkMessage::Inv(vec![Inventory::Block(
+                headers[height - 40].block_hash(),
+            )])),
+        ),
+    )
+    .message(|_, msg| matches!(msg, NetworkMessage::GetHeaders(_)));
+
+    sim.input(
+        &alice,
+        Input::Received(bob, msg.raw(NetworkMessage::Headers(vec![]))),
+    )
+    .event(|e| {
+        matches!(
+            e,
+            Event::SyncManager(syncmgr::Event::PeerWithholding { addr }) if addr == &bob
+        )
+    });
+}
+
 #[test]
 fn test_addrs() {
     let network = Network::Mainnet;
@@ -1064,3 +1360,3340 @@ fn prop_connect_timeout(seed: u64) {
         })
         .expect("Alice tries to connect to another peer");
 }
+
+#[test]
+fn test_compact_filters_disabled() {
+    let network = Network::Mainnet;
+    let chain = NonEmpty::from_vec(BITCOIN_HEADERS.iter().take(8).cloned().collect()).unwrap();
+    let mut sim = simulator::Net {
+        network,
+        peers: vec![
+            PeerConfig::new("alice", chain.clone()),
+            PeerConfig::new("bob", chain),
+        ],
+        configure: |cfg| {
+            cfg.services = ServiceFlags::NETWORK | ServiceFlags::COMPACT_FILTERS;
+            cfg.whitelist = setup::CONFIG.whitelist.clone();
+
+            if cfg.target == "alice" {
+                cfg.features.remove(Features::COMPACT_FILTERS);
+            }
+        },
+        ..Default::default()
+    }
+    .into();
+
+    // Run handshake. Alice connects to Bob, who has compact filters enabled.
+    sim.step();
+
+    let alice = sim.get("alice");
+    let bob = sim.get("bob");
+
+    for e in sim.events(&bob) {
+        if let Event::Received(from, msg) = e {
+            assert_eq!(from, alice);
+
+            match msg {
+                NetworkMessage::Version(version) => assert!(
+                    !version.services.has(ServiceFlags::COMPACT_FILTERS),
+                    "Alice doesn't advertise compact filters"
+                ),
+                NetworkMessage::GetCFHeaders(_) | NetworkMessage::GetCFilters(_) => {
+                    panic!("Alice doesn't request filters from Bob")
+                }
+                _ => {}
+            }
+        }
+    }
+
+    sim.input(&alice, Input::Command(Command::GetFilters(1..4)))
+        .all(|o| !matches!(payload(o), Some((_, NetworkMessage::GetCFilters(_)))))
+        .expect("Alice doesn't send `getcfilters`");
+}
+
+#[test]
+fn test_serve_compact_filters() {
+    let network = Network::Mainnet;
+    let msg = message::Builder::new(network);
+    let chain = NonEmpty::from_vec(BITCOIN_HEADERS.iter().take(8).cloned().collect()).unwrap();
+    let mut sim = simulator::Net {
+        network,
+        peers: vec![
+            PeerConfig::new("alice", chain.clone()),
+            PeerConfig::new("bob", chain),
+        ],
+        configure: |cfg| {
+            cfg.services = ServiceFlags::NETWORK;
+            cfg.required_services = ServiceFlags::NETWORK;
+            cfg.whitelist = setup::CONFIG.whitelist.clone();
+
+            if cfg.target == "alice" {
+                cfg.features.add(Features::SERVE_FILTERS);
+            }
+        },
+        ..Default::default()
+    }
+    .into();
+
+    // Run handshake. Alice serves compact filters, Bob doesn't.
+    sim.step();
+
+    let alice = sim.get("alice");
+    let bob = sim.get("bob");
+
+    for (from, to, serving) in [(alice, bob, true), (bob, alice, false)].iter().copied() {
+        let version = sim
+            .events(&to)
+            .find_map(|e| match e {
+                Event::Received(addr, NetworkMessage::Version(version)) if addr == from => {
+                    Some(version)
+                }
+                _ => None,
+            })
+            .expect("a `version` message was received");
+
+        assert_eq!(
+            version.services.has(ServiceFlags::COMPACT_FILTERS),
+            serving,
+            "Compact filters are only advertised if they're served"
+        );
+
+        let getcfcheckpt = NetworkMessage::GetCFCheckpt(GetCFCheckpt {
+            filter_type: 0,
+            stop_hash: network.genesis_hash(),
+        });
+        let replied = sim
+            .input(&from, Input::Received(to, msg.raw(getcfcheckpt)))
+            .any(|o| matches!(payload(o), Some((_, NetworkMessage::CFCheckpt(_)))))
+            .is_some();
+
+        assert_eq!(
+            replied, serving,
+            "Compact filter requests are only answered if filters are served"
+        );
+    }
+}
+
+#[test]
+fn test_compact_filters_disabled_reorg() {
+    let network = Network::Regtest;
+    let genesis = network.genesis();
+    let time = LocalTime::from_secs(genesis.time as u64 + 60 * 60 * 24);
+    let msg = message::Builder::new(network);
+    let local = ([0, 0, 0, 0], 0).into();
+    let bob: PeerId = ([131, 31, 11, 33], 18444).into();
+    let chain = |len: usize, seed: u32| {
+        let mut headers: Vec<BlockHeader> = Vec::new();
+        for _ in 0..len {
+            let prev = headers.last().unwrap_or(&genesis);
+            let mut header = BlockHeader {
+                prev_blockhash: prev.block_hash(),
+                time: prev.time + 600 + seed,
+                nonce: 0,
+                ..*prev
+            };
+            nakamoto_test::block::solve(&mut header);
+            headers.push(header);
+        }
+        headers
+    };
+
+    // g <- a1 <- a2 <- a3
+    // g <- b1 <- b2 <- b3 <- b4
+    let a = chain(3, 0);
+    let b = chain(4, 1);
+
+    for enabled in [false, true].iter().copied() {
+        let mut filters = model::FilterCache::new(FilterHeader::genesis(network));
+        filters
+            .import_headers(
+                a.iter()
+                    .map(|h| (h.block_hash(), Default::default(), Default::default()))
+                    .collect(),
+            )
+            .unwrap();
+
+        let (tx, rx) = chan::unbounded();
+        let mut alice = Builder {
+            cache: BlockCache::from(
+                store::Memory::new(NonEmpty::from((genesis, a.clone()))),
+                network.params(),
+                &[],
+            )
+            .unwrap(),
+            clock: AdjustedTime::new(time),
+            filters,
+            peers: HashMap::new(),
+            rng: fastrand::Rng::new(),
+            cfg: Config {
+                network,
+                params: network.params(),
+                ..setup::CONFIG.clone()
+            },
+        }
+        .build(tx);
+
+        if !enabled {
+            alice.features.remove(Features::COMPACT_FILTERS);
+        }
+        alice.step(
+            Input::Connected {
+                addr: bob,
+                local_addr: local,
+                link: Link::Outbound,
+            },
+            time,
+        );
+        alice.step(
+            Input::Received(
+                bob,
+                msg.raw(NetworkMessage::Version(
+                    alice.peermgr.version(local, bob, 42, 4, time),
+                )),
+            ),
+            time,
+        );
+        alice.step(Input::Received(bob, msg.raw(NetworkMessage::Verack)), time);
+        alice.step(
+            Input::Received(bob, msg.raw(NetworkMessage::Headers(vec![]))),
+            time,
+        );
+        rx.try_iter().for_each(drop);
+
+        // Bob announces a heavier chain, which re-orgs Alice's chain down to the genesis.
+        alice.step(
+            Input::Received(bob, msg.raw(NetworkMessage::Headers(b.clone()))),
+            time,
+        );
+        assert_eq!(alice.tree.tip().0, b[3].block_hash());
+
+        let outputs = rx.try_iter().collect::<Vec<_>>();
+        let rolled_back = outputs.iter().any(|o| {
+            matches!(
+                o,
+                Out::Event(Event::SpvManager(spvmgr::Event::RollbackDetected(1)))
+            )
+        });
+        let synced = outputs
+            .iter()
+            .any(|o| matches!(payload(o), Some((_, NetworkMessage::GetCFHeaders(_)))));
+
+        assert_eq!(
+            rolled_back, enabled,
+            "Filter headers are only rolled back if compact filters are enabled"
+        );
+        assert_eq!(
+            synced, enabled,
+            "Filter headers are only synced if compact filters are enabled"
+        );
+    }
+}
+
+#[test]
+fn test_inject_message() {
+    let network = Network::Mainnet;
+    let time = LocalTime::from_secs(network.genesis().time as u64);
+    let tree = BlockCache::from(
+        store::Memory::new(NonEmpty::new(network.genesis())),
+        network.params(),
+        &[],
+    )
+    .unwrap();
+    let builder = Builder {
+        cache: tree,
+        clock: AdjustedTime::new(time),
+        filters: model::FilterCache::new(FilterHeader::genesis(network)),
+        peers: HashMap::new(),
+        rng: fastrand::Rng::new(),
+        cfg: setup::CONFIG.clone(),
+    };
+
+    let (alice_tx, alice_rx) = chan::unbounded();
+    let (bob_tx, bob_rx) = chan::unbounded();
+
+    let mut alice = builder.clone().build(alice_tx);
+    let mut bob = builder.build(bob_tx);
+
+    let alice_addr = ([152, 168, 3, 33], 3333).into();
+    let bob_addr = ([152, 168, 7, 77], 7777).into();
+
+    simulator::handshake(
+        &mut alice,
+        alice_addr,
+        alice_rx.clone(),
+        &mut bob,
+        bob_addr,
+        bob_rx,
+        time,
+    );
+
+    // A header with an invalid proof-of-work.
+    let mut header = *BITCOIN_HEADERS.get(1).unwrap();
+    header.nonce += 1;
+
+    alice.step(
+        Input::Command(Command::InjectMessage(
+            bob_addr,
+            NetworkMessage::Headers(vec![header]),
+        )),
+        time,
+    );
+
+    alice_rx
+        .try_iter()
+        .find(|o| {
+            matches!(
+                o,
+                Out::Event(Event::SyncManager(syncmgr::Event::InvalidHeadersReceived(addr, _)))
+                if addr == &bob_addr
+            )
+        })
+        .expect("Bob's headers are considered invalid");
+}
+
+#[test]
+fn test_block_tree_events() {
+    let network = Network::Mainnet;
+    let time = LocalTime::from_secs(network.genesis().time as u64);
+    let tree = BlockCache::from(
+        store::Memory::new(NonEmpty::new(network.genesis())),
+        network.params(),
+        &[],
+    )
+    .unwrap();
+    let builder = Builder {
+        cache: tree,
+        clock: AdjustedTime::new(time),
+        filters: model::FilterCache::new(FilterHeader::genesis(network)),
+        peers: HashMap::new(),
+        rng: fastrand::Rng::new(),
+        cfg: setup::CONFIG.clone(),
+    };
+
+    let (alice_tx, alice_rx) = chan::unbounded();
+    let (bob_tx, bob_rx) = chan::unbounded();
+
+    let mut alice = builder.clone().build(alice_tx);
+    let mut bob = builder.build(bob_tx);
+
+    let alice_addr = ([152, 168, 3, 33], 3333).into();
+    let bob_addr = ([152, 168, 7, 77], 7777).into();
+
+    simulator::handshake(
+        &mut alice,
+        alice_addr,
+        alice_rx.clone(),
+        &mut bob,
+        bob_addr,
+        bob_rx,
+        time,
+    );
+
+    // Headers whose parent Alice doesn't know about.
+    let parent = BITCOIN_HEADERS.get(1).unwrap().block_hash();
+    let orphan = *BITCOIN_HEADERS.get(2).unwrap();
+    let tip = *BITCOIN_HEADERS.get(3).unwrap();
+
+    alice.step(
+        Input::Command(Command::InjectMessage(
+            bob_addr,
+            NetworkMessage::Headers(vec![orphan, tip]),
+        )),
+        time,
+    );
+
+    let outputs = alice_rx.try_iter().collect::<Vec<_>>();
+    let getheaders = outputs
+        .iter()
+        .filter_map(|o| match payload(o) {
+            Some((addr, NetworkMessage::GetHeaders(m))) if addr == bob_addr => Some(m.stop_hash),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    outputs
+        .iter()
+        .find(|o| {
+            matches!(
+                o,
+                Out::Event(Event::SyncManager(syncmgr::Event::BlockTree(
+                    tree::Event::OrphanReceived { hash, missing_parent }
+                )))
+                if *hash == orphan.block_hash() && *missing_parent == parent
+            )
+        })
+        .expect("The block tree event is reported");
+
+    assert_eq!(
+        getheaders,
+        vec![tip.block_hash()],
+        "Alice asks Bob once for the headers from the missing parent to the last orphan"
+    );
+    assert!(alice.tree.events().is_empty(), "Events don't accumulate");
+}
+
+#[test]
+fn test_pin_sync_peer() {
+    let network = Network::Mainnet;
+    let msg = message::Builder::new(network);
+    let (mut alice, rx, time) = setup::singleton(network);
+    let local = ([0, 0, 0, 0], 0).into();
+    let bob: PeerId = ([131, 31, 11, 33], 8333).into();
+    let carol: PeerId = ([131, 31, 11, 34], 8333).into();
+    // Some hash for a nonexistent block.
+    let hash =
+        BlockHash::from_hex("0000000000b7b2c71f2a345e3a4fc328bf5bbb436012afca590b1a11466e2206")
+            .unwrap();
+    let getheaders = |rx: &chan::Receiver<Out>| -> Vec<PeerId> {
+        rx.try_iter()
+            .filter_map(|o| match payload(&o) {
+                Some((addr, NetworkMessage::GetHeaders(_))) => Some(addr),
+                _ => None,
+            })
+            .collect()
+    };
+
+    alice.step(Input::Command(Command::PinSyncPeer(Some(bob))), time);
+
+    // Carol advertises a much longer chain than bob.
+    for (nonce, (peer, height)) in [(bob, 10), (carol, 1000)].iter().enumerate() {
+        let version = alice
+            .peermgr
+            .version(local, *peer, nonce as u64 + 1, *height, time);
+
+        alice.step(
+            Input::Connected {
+                addr: *peer,
+                local_addr: local,
+                link: Link::Outbound,
+            },
+            time,
+        );
+        alice.step(
+            Input::Received(*peer, msg.raw(NetworkMessage::Version(version))),
+            time,
+        );
+        alice.step(
+            Input::Received(*peer, msg.raw(NetworkMessage::Verack)),
+            time,
+        );
+    }
+    alice.step(
+        Input::Received(
+            carol,
+            msg.raw(NetworkMessage::Inv(vec![Inventory::Block(hash)])),
+        ),
+        time,
+    );
+    alice.step(Input::Timeout, time + syncmgr::REQUEST_TIMEOUT);
+
+    let asked = getheaders(&rx);
+    assert!(!asked.is_empty());
+    assert!(
+        asked.iter().all(|a| *a == bob),
+        "only bob is asked for headers"
+    );
+
+    // When bob disconnects, we don't switch to carol.
+    alice.step(
+        Input::Disconnected(bob, DisconnectReason::PeerTimeout),
+        time + syncmgr::REQUEST_TIMEOUT,
+    );
+    assert!(rx.try_iter().any(|o| matches!(
+        o,
+        Out::Event(Event::SyncManager(syncmgr::Event::PinnedPeerDisconnected(addr))) if addr == bob
+    )));
+    alice.step(
+        Input::Timeout,
+        time + syncmgr::REQUEST_TIMEOUT + syncmgr::REQUEST_TIMEOUT,
+    );
+    assert!(getheaders(&rx).is_empty(), "sync is stalled");
+
+    // Lifting the pin lets us sync with carol.
+    alice.step(
+        Input::Command(Command::PinSyncPeer(None)),
+        time + syncmgr::REQUEST_TIMEOUT + syncmgr::REQUEST_TIMEOUT,
+    );
+    assert_eq!(getheaders(&rx), vec![carol]);
+}
+
+#[test]
+fn test_duplicate_connection() {
+    let network = Network::Mainnet;
+    let msg = message::Builder::new(network);
+    let (mut alice, rx, time) = setup::singleton(network);
+    let local = ([0, 0, 0, 0], 0).into();
+    let bob: PeerId = ([131, 31, 11, 33], 8333).into();
+    let carol: PeerId = ([131, 31, 11, 34], 8333).into();
+
+    alice.step(
+        Input::Connected {
+            addr: bob,
+            local_addr: local,
+            link: Link::Outbound,
+        },
+        time,
+    );
+    alice.step(
+        Input::Received(
+            bob,
+            msg.raw(NetworkMessage::Version(
+                alice.peermgr.version(local, bob, 42, 0, time),
+            )),
+        ),
+        time,
+    );
+    alice.step(Input::Received(bob, msg.raw(NetworkMessage::Verack)), time);
+    rx.try_iter().for_each(drop);
+
+    // A second connection to bob is dropped, and the first one is kept.
+    alice.step(
+        Input::Connected {
+            addr: bob,
+            local_addr: local,
+            link: Link::Inbound,
+        },
+        time,
+    );
+    assert!(rx.try_iter().any(|o| matches!(
+        o,
+        Out::Disconnect(addr, DisconnectReason::DuplicateConnection) if addr == bob
+    )));
+    alice.step(
+        Input::Disconnected(bob, DisconnectReason::DuplicateConnection),
+        time,
+    );
+    assert!(alice.connmgr.is_connected(&bob));
+    assert!(alice.peermgr.outbound().any(|p| p.address() == bob));
+
+    // Carol sends us the same nonce as bob, she is likely the same node.
+    alice.step(
+        Input::Connected {
+            addr: carol,
+            local_addr: local,
+            link: Link::Inbound,
+        },
+        time,
+    );
+    alice.step(
+        Input::Received(
+            carol,
+            msg.raw(NetworkMessage::Version(
+                alice.peermgr.version(local, carol, 42, 0, time),
+            )),
+        ),
+        time,
+    );
+    assert!(rx.try_iter().any(|o| matches!(
+        o,
+        Out::Disconnect(addr, DisconnectReason::DuplicateConnection) if addr == carol
+    )));
+    alice.step(
+        Input::Disconnected(carol, DisconnectReason::DuplicateConnection),
+        time,
+    );
+    assert!(!alice.connmgr.is_connected(&carol));
+    assert!(alice.connmgr.is_connected(&bob));
+}
+
+#[test]
+fn test_bootstrap_fixed_seeds() {
+    let network = Network::Mainnet;
+    let seeds: Vec<PeerId> = vec![
+        ([88, 88, 88, 88], 8333).into(),
+        ([99, 99, 99, 99], 8333).into(),
+    ];
+    let genesis = network.genesis();
+    let time = LocalTime::from_secs(genesis.time as u64);
+    let builder = |fixed_seeds: Vec<PeerId>| Builder {
+        cache: model::Cache::new(genesis),
+        clock: AdjustedTime::new(time),
+        filters: model::FilterCache::new(FilterHeader::genesis(network)),
+        peers: HashMap::new(),
+        rng: fastrand::Rng::new(),
+        cfg: Config {
+            fixed_seeds,
+            ..setup::CONFIG.clone()
+        },
+    };
+
+    // With an empty address book, the fixed seeds are dialed.
+    let (tx, rx) = chan::unbounded();
+    let mut alice = builder(seeds.clone()).build(tx);
+    alice.initialize(time).unwrap();
+
+    let dialed = rx
+        .try_iter()
+        .filter_map(|o| match o {
+            Out::Connect(addr, _) => Some(addr),
+            _ => None,
+        })
+        .collect::<HashSet<_>>();
+    assert_eq!(dialed, seeds.into_iter().collect());
+
+    // Without fixed seeds, bootstrapping fails.
+    let (tx, rx) = chan::unbounded();
+    let mut alice = builder(vec![]).build(tx);
+    alice.initialize(time).unwrap();
+
+    assert!(rx.try_iter().any(|o| matches!(
+        o,
+        Out::Event(Event::AddrManager(addrmgr::Event::BootstrapFailed))
+    )));
+}
+
+#[test]
+fn test_get_tip_age() {
+    let network = Network::Mainnet;
+    let (mut alice, _rx, _) = setup::singleton(network);
+    let genesis = network.genesis();
+    let time = LocalTime::from_secs(genesis.time as u64) + LocalDuration::from_mins(60);
+    let get_tip_age = |alice: &mut Protocol<_, _, _>| {
+        let (tx, rx) = chan::bounded(1);
+        alice.step(Input::Command(Command::GetTipAge(tx)), time);
+        rx.try_recv().unwrap()
+    };
+
+    assert_eq!(get_tip_age(&mut alice), LocalDuration::from_mins(60));
+
+    // Once peers report clocks ten minutes ahead of ours, the tip is ten minutes older.
+    for i in 0..4 {
+        let peer: PeerId = ([131, 31, 11, i], 8333).into();
+        alice.clock.record_offset(peer, 600);
+    }
+    assert_eq!(get_tip_age(&mut alice), LocalDuration::from_mins(70));
+}
+
+#[test]
+fn test_get_chain_work() {
+    let (mut alice, _rx, time) = setup::singleton(Network::Mainnet);
+    let get_chain_work = |alice: &mut Protocol<_, _, _>| {
+        let (tx, rx) = chan::bounded(1);
+        alice.step(Input::Command(Command::GetChainWork(tx)), time);
+        rx.try_recv().unwrap()
+    };
+
+    // With only the genesis block, the work is that of a minimum difficulty block.
+    let (work, formatted) = get_chain_work(&mut alice);
+    assert_eq!(work, BITCOIN_HEADERS.head.work());
+    assert_eq!(formatted, "4.30e9 hashes (2^32.00)");
+
+    let mut prev = (work, block::work_log2(work));
+    for header in BITCOIN_HEADERS.tail.iter().take(16) {
+        let (tx, _) = chan::bounded(1);
+        alice.step(
+            Input::Command(Command::ImportHeaders(vec![*header], tx)),
+            time,
+        );
+
+        let (work, formatted) = get_chain_work(&mut alice);
+        let log2 = block::work_log2(work);
+
+        assert!(work > prev.0);
+        assert!(log2 > prev.1);
+        assert_eq!(formatted, block::format_work(work));
+
+        prev = (work, log2);
+    }
+}
+
+#[test]
+fn test_get_reorg_stats() {
+    let network = Network::Regtest;
+    let genesis = network.genesis();
+    let mut time = LocalTime::from_secs(genesis.time as u64 + 60 * 60 * 24);
+    let tree = BlockCache::from(
+        store::Memory::new(NonEmpty::new(genesis)),
+        network.params(),
+        &[],
+    )
+    .unwrap();
+    let builder = Builder {
+        cache: tree,
+        clock: AdjustedTime::new(time),
+        filters: model::FilterCache::new(FilterHeader::genesis(network)),
+        peers: HashMap::new(),
+        rng: fastrand::Rng::new(),
+        cfg: Config {
+            network,
+            params: network.params(),
+            ..setup::CONFIG.clone()
+        },
+    };
+    let (tx, _rx) = chan::unbounded();
+    let mut alice = builder.build(tx);
+
+    // Build a chain of the given length on top of a header. Different seeds yield
+    // different chains.
+    let chain = |prev: &BlockHeader, len: usize, seed: u32| {
+        let mut headers: Vec<BlockHeader> = Vec::new();
+        for _ in 0..len {
+            let prev = headers.last().unwrap_or(prev);
+            let mut header = BlockHeader {
+                version: 1,
+                prev_blockhash: prev.block_hash(),
+                merkle_root: Default::default(),
+                time: prev.time + 600 + seed,
+                bits: prev.bits,
+                nonce: 0,
+            };
+            nakamoto_test::block::solve(&mut header);
+            headers.push(header);
+        }
+        headers
+    };
+    let import = |alice: &mut Protocol<_, _, _>, headers: Vec<BlockHeader>, time| {
+        let (tx, rx) = chan::bounded(1);
+        alice.step(Input::Command(Command::ImportHeaders(headers, tx)), time);
+        rx.try_recv().unwrap().unwrap()
+    };
+    let get_reorg_stats = |alice: &mut Protocol<_, _, _>, time| {
+        let (tx, rx) = chan::bounded(1);
+        alice.step(Input::Command(Command::GetReorgStats(tx)), time);
+        rx.try_recv().unwrap()
+    };
+
+    assert_eq!(
+        get_reorg_stats(&mut alice, time),
+        syncmgr::ReorgStats::default()
+    );
+
+    // g <- a1 <- a2 <- a3
+    // g <- b1 <- b2 <- b3 <- b4
+    //             b2 <- c3 <- c4 <- c5
+    let a = chain(&genesis, 3, 0);
+    let b = chain(&genesis, 4, 1);
+    let c = chain(&b[1], 3, 2);
+
+    // Import the forks out of order, so that the reorg happens in one step, once the
+    // first block of the fork is received.
+    import(&mut alice, a, time);
+    import(&mut alice, b[1..].to_vec(), time);
+    assert!(matches!(
+        import(&mut alice, vec![b[0]], time),
+        ImportResult::TipChanged(_, 4, reverted) if reverted.len() == 3
+    ));
+    let first = time;
+
+    time = time + LocalDuration::from_mins(60 * 2);
+    import(&mut alice, c[1..].to_vec(), time);
+    assert!(matches!(
+        import(&mut alice, vec![c[0]], time),
+        ImportResult::TipChanged(_, 5, reverted) if reverted.len() == 2
+    ));
+
+    assert_eq!(
+        get_reorg_stats(&mut alice, time),
+        syncmgr::ReorgStats {
+            last_hour: 1,
+            max_depth_last_hour: 2,
+            last_day: 2,
+            max_depth_last_day: 3,
+            max_depth: 3,
+            last_reorg: Some(time),
+        }
+    );
+
+    // Reorgs older than a day are only accounted for in the deepest reorg seen.
+    let later = first + LocalDuration::from_mins(60 * 25);
+    assert_eq!(
+        get_reorg_stats(&mut alice, later),
+        syncmgr::ReorgStats {
+            last_hour: 0,
+            max_depth_last_hour: 0,
+            last_day: 1,
+            max_depth_last_day: 2,
+            max_depth: 3,
+            last_reorg: Some(time),
+        }
+    );
+}
+
+#[test]
+fn test_multiplex() {
+    use crate::protocol::multiplex::{Multiplexer, Peer};
+    use crate::reactor::{Machine, PeerKey};
+
+    let time = LocalTime::from(SystemTime::now());
+    let local: PeerId = ([0, 0, 0, 0], 8333).into();
+    let alice: PeerId = ([88, 88, 88, 88], 8333).into();
+    let bob: PeerId = ([99, 99, 99, 99], 18444).into();
+    let carol: PeerId = ([77, 77, 77, 77], 18444).into();
+    let builder = |network: Network, chain: NonEmpty<BlockHeader>| Builder {
+        cache: model::Cache::from(chain),
+        filters: model::FilterCache::new(FilterHeader::genesis(network)),
+        peers: HashMap::new(),
+        clock: AdjustedTime::new(time),
+        rng: fastrand::Rng::new(),
+        cfg: Config {
+            network,
+            params: Params::new(network.into()),
+            ..setup::CONFIG.clone()
+        },
+    };
+
+    // Alice serves a mainnet chain, bob a regtest chain, carol has no blocks.
+    let mainnet = NonEmpty::from_slice(&BITCOIN_HEADERS.tail[..16])
+        .map(|tail| NonEmpty::from((BITCOIN_HEADERS.head, tail.into())))
+        .unwrap();
+    let mut regtest = NonEmpty::new(Network::Regtest.genesis());
+    for _ in 0..8 {
+        let tip = regtest.last();
+        let mut header = BlockHeader {
+            version: 1,
+            prev_blockhash: tip.block_hash(),
+            merkle_root: Default::default(),
+            bits: tip.bits,
+            time: tip.time + 600,
+            nonce: 0,
+        };
+        nakamoto_test::block::solve(&mut header);
+        regtest.push(header);
+    }
+
+    let (tx, rx) = chan::unbounded();
+    let mut mux = Multiplexer::new(
+        vec![
+            builder(Network::Mainnet, NonEmpty::new(Network::Mainnet.genesis())),
+            builder(Network::Regtest, NonEmpty::new(Network::Regtest.genesis())),
+        ],
+        tx,
+    );
+    let mut remotes = vec![
+        (alice, mainnet),
+        (bob, regtest),
+        (carol, NonEmpty::new(Network::Regtest.genesis())),
+    ]
+    .into_iter()
+    .zip(vec![Network::Mainnet, Network::Regtest, Network::Regtest])
+    .map(|((addr, chain), network)| {
+        let (tx, rx) = chan::unbounded();
+        (addr, builder(network, chain).build(tx), rx)
+    })
+    .collect::<Vec<_>>();
+
+    mux.initialize(time).unwrap();
+    for (_, remote, _) in remotes.iter_mut() {
+        remote.initialize(time).unwrap();
+    }
+
+    // We connect to alice and bob from the mainnet and regtest instances respectively,
+    // and carol connects to us.
+    mux.command(Network::Mainnet, Command::Connect(alice), time);
+    mux.command(Network::Regtest, Command::Connect(bob), time);
+
+    let peers = vec![
+        Peer {
+            instance: Some(0),
+            addr: alice,
+        },
+        Peer {
+            instance: Some(1),
+            addr: bob,
+        },
+        Peer::inbound(carol),
+    ];
+    assert_eq!(
+        rx.try_iter()
+            .filter_map(|o| match o {
+                Out::Connect(peer, _) => Some(peer),
+                _ => None,
+            })
+            .collect::<Vec<_>>(),
+        peers[..2]
+    );
+
+    for (i, (addr, remote, _)) in remotes.iter_mut().enumerate() {
+        let (ours, theirs) = if i < 2 {
+            (Link::Outbound, Link::Inbound)
+        } else {
+            (Link::Inbound, Link::Outbound)
+        };
+        mux.step(
+            Input::Connected {
+                addr: peers[i],
+                local_addr: local,
+                link: ours,
+            },
+            time,
+        );
+        remote.step(
+            Input::Connected {
+                addr: local,
+                local_addr: *addr,
+                link: theirs,
+            },
+            time,
+        );
+    }
+
+    // Relay messages until everyone is quiet.
+    loop {
+        let mut idle = true;
+
+        for out in rx.try_iter().collect::<Vec<_>>() {
+            if let Out::Message(peer, msg) = out {
+                let i = peers.iter().position(|p| *p == peer).unwrap();
+
+                remotes[i].1.step(Input::Received(local, msg), time);
+                idle = false;
+            }
+        }
+        for (i, (_, _, remote_rx)) in remotes.iter().enumerate() {
+            for out in remote_rx.try_iter() {
+                if let Out::Message(_, msg) = out {
+                    mux.step(Input::Received(peers[i], msg), time);
+                    idle = false;
+                }
+            }
+        }
+        if idle {
+            break;
+        }
+    }
+
+    // Each instance synced its own network, and carol was routed to the regtest instance.
+    let mainnet = mux.instance(0).unwrap();
+    let regtest = mux.instance(1).unwrap();
+
+    assert_eq!(mainnet.tree.height(), 16);
+    assert_eq!(mainnet.tree.tip(), remotes[0].1.tree.tip());
+    assert_eq!(regtest.tree.height(), 8);
+    assert_eq!(regtest.tree.tip(), remotes[1].1.tree.tip());
+    assert!(regtest.peermgr.peers().any(|p| p.conn.addr == carol));
+    assert!(!mainnet.peermgr.peers().any(|p| p.conn.addr == carol));
+
+    // The regtest instance can connect to alice's address too: its peer is namespaced,
+    // so it doesn't collide with the mainnet instance's.
+    mux.command(Network::Regtest, Command::Connect(alice), time);
+    assert_eq!(
+        rx.try_iter()
+            .filter_map(|o| match o {
+                Out::Connect(peer, _) => Some(peer),
+                _ => None,
+            })
+            .collect::<Vec<_>>(),
+        vec![Peer {
+            instance: Some(1),
+            addr: alice,
+        }]
+    );
+    mux.step(
+        Input::Disconnected(
+            Peer {
+                instance: Some(1),
+                addr: alice,
+            },
+            DisconnectReason::PeerTimeout,
+        ),
+        time,
+    );
+    let mainnet = mux.instance(0).unwrap();
+    assert!(mainnet.peermgr.peers().any(|p| p.conn.addr == alice));
+}
+
+#[quickcheck]
+fn prop_fuzz_step(data: Vec<u8>) {
+    let (mut protocol, rx, time) = setup::singleton(Network::Mainnet);
+    let steps = Vec::arbitrary_take_rest(arbitrary::Unstructured::new(&data));
+
+    protocol.initialize(time).unwrap();
+    fuzz::step(&mut protocol, &rx, time, steps.unwrap_or_default());
+}
+
+#[test]
+fn test_fuzz_reproducer() {
+    use fuzz::{Action, Message, Step};
+
+    let (mut protocol, rx, time) = setup::singleton(Network::Mainnet);
+    let step = |elapsed, action| Step {
+        elapsed,
+        peer: 1,
+        action,
+    };
+    let steps = vec![
+        // A peer connects (outbound), sends its `version` and `verack`.
+        step(0, Action::Connect { outbound: true }),
+        step(
+            1,
+            Action::Receive(Message::Version {
+                nonce: 7,
+                height: 32,
+            }),
+        ),
+        step(1, Action::Receive(Message::Verack)),
+        // It pings us and announces a block we don't know about.
+        step(1, Action::Receive(Message::Ping(1))),
+        step(1, Action::Receive(Message::Inv(vec![[0x11; 32]]))),
+        // Time passes, and the peer disconnects.
+        step(255, Action::Timeout),
+        step(0, Action::Disconnect),
+    ];
+
+    protocol.initialize(time).unwrap();
+    fuzz::step(&mut protocol, &rx, time, steps);
+}
+
+#[test]
+fn test_fuzz_corpus() {
+    let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("../fuzz/corpus/protocol_step");
+    let mut seeds = 0;
+
+    for entry in std::fs::read_dir(dir).unwrap() {
+        let data = std::fs::read(entry.unwrap().path()).unwrap();
+        let steps: Vec<fuzz::Step> =
+            Vec::arbitrary_take_rest(arbitrary::Unstructured::new(&data)).unwrap();
+        let (mut protocol, rx, time) = setup::singleton(Network::Mainnet);
+
+        assert!(steps
+            .iter()
+            .any(|s| matches!(s.action, fuzz::Action::Receive(fuzz::Message::Verack))));
+
+        protocol.initialize(time).unwrap();
+        fuzz::step(&mut protocol, &rx, time, steps);
+        seeds += 1;
+    }
+    assert!(seeds > 0);
+}
+
+#[test]
+fn test_new_block_event() {
+    let network = Network::Mainnet;
+    let msg = message::Builder::new(network);
+    let (mut alice, rx, _) = setup::singleton(network);
+    // Our clock is set to the time of the last block we'll receive.
+    let time = LocalTime::from_secs(BITCOIN_HEADERS.tail[16].time as u64);
+    let local = ([0, 0, 0, 0], 0).into();
+    let bob: PeerId = ([131, 31, 11, 33], 8333).into();
+    let new_blocks = |rx: &chan::Receiver<Out>| {
+        rx.try_iter()
+            .filter_map(|o| match o {
+                Out::Event(Event::SyncManager(syncmgr::Event::NewBlock {
+                    height,
+                    hash,
+                    header,
+                })) => Some((height, hash, header)),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+    };
+
+    alice.step(
+        Input::Connected {
+            addr: bob,
+            local_addr: local,
+            link: Link::Outbound,
+        },
+        time,
+    );
+    alice.step(
+        Input::Received(
+            bob,
+            msg.raw(NetworkMessage::Version(
+                // Bob advertises a higher height than what he'll send us.
+                alice.peermgr.version(local, bob, 42, 1000, time),
+            )),
+        ),
+        time,
+    );
+    alice.step(Input::Received(bob, msg.raw(NetworkMessage::Verack)), time);
+    rx.try_iter().for_each(drop);
+
+    // Initial sync: our tip is too old for us to be caught up, so no new block is
+    // announced, whether the headers are imported one at a time or in bulk.
+    alice.step(
+        Input::Received(
+            bob,
+            msg.raw(NetworkMessage::Headers(BITCOIN_HEADERS.tail[..1].to_vec())),
+        ),
+        time,
+    );
+    alice.step(
+        Input::Received(
+            bob,
+            msg.raw(NetworkMessage::Headers(
+                BITCOIN_HEADERS.tail[1..16].to_vec(),
+            )),
+        ),
+        time,
+    );
+    assert_eq!(alice.tree.height(), 16);
+    assert!(new_blocks(&rx).is_empty());
+
+    // Once in sync, a single new block is announced, even though Bob still advertises
+    // a higher height than ours.
+    let header = BITCOIN_HEADERS.tail[16];
+    alice.step(
+        Input::Received(bob, msg.raw(NetworkMessage::Headers(vec![header]))),
+        time,
+    );
+    assert_eq!(alice.tree.height(), 17);
+    assert_eq!(new_blocks(&rx), vec![(17, header.block_hash(), header)]);
+}
+
+#[test]
+fn test_initial_sync_adjusted_time() {
+    use nakamoto_common::block::time::MAX_TIP_AGE;
+
+    let network = Network::Mainnet;
+    let msg = message::Builder::new(network);
+    let (mut alice, rx, _) = setup::singleton(network);
+    let local = ([0, 0, 0, 0], 0).into();
+    let bob: PeerId = ([131, 31, 11, 33], 8333).into();
+    let headers = &BITCOIN_HEADERS.tail;
+    // According to our local clock, a tip mined at the time of the 17th block is just over
+    // the maximum tip age.
+    let time = LocalTime::from_secs((headers[16].time + MAX_TIP_AGE + 30 * 60) as u64);
+    let new_blocks = |rx: &chan::Receiver<Out>| {
+        rx.try_iter()
+            .filter(|o| {
+                matches!(
+                    o,
+                    Out::Event(Event::SyncManager(syncmgr::Event::NewBlock { .. }))
+                )
+            })
+            .count()
+    };
+
+    alice.step(
+        Input::Connected {
+            addr: bob,
+            local_addr: local,
+            link: Link::Outbound,
+        },
+        time,
+    );
+    alice.step(
+        Input::Received(
+            bob,
+            msg.raw(NetworkMessage::Version(
+                alice.peermgr.version(local, bob, 42, 18, time),
+            )),
+        ),
+        time,
+    );
+    alice.step(Input::Received(bob, msg.raw(NetworkMessage::Verack)), time);
+    alice.step(
+        Input::Received(
+            bob,
+            msg.raw(NetworkMessage::Headers(headers[..16].to_vec())),
+        ),
+        time,
+    );
+    rx.try_iter().for_each(drop);
+
+    // Going by our local clock, we're still in the initial sync.
+    alice.step(
+        Input::Received(bob, msg.raw(NetworkMessage::Headers(vec![headers[16]]))),
+        time,
+    );
+    assert_eq!(alice.tree.height(), 17);
+    assert_eq!(new_blocks(&rx), 0);
+
+    // Our peers consistently report that our clock is an hour ahead. Going by the
+    // network-adjusted time, we're caught up, and new blocks are announced.
+    for i in 0..4 {
+        let peer: PeerId = ([131, 31, 12, i], 8333).into();
+        alice.clock.record_offset(peer, -60 * 60);
+    }
+    alice.step(
+        Input::Received(bob, msg.raw(NetworkMessage::Headers(vec![headers[17]]))),
+        time,
+    );
+    assert_eq!(alice.tree.height(), 18);
+    assert_eq!(new_blocks(&rx), 1);
+}
+
+#[test]
+fn test_max_concurrent_handshakes() {
+    let network = Network::Mainnet;
+    let msg = message::Builder::new(network);
+    let (mut alice, rx, time) = setup::singleton(network);
+    let local = ([0, 0, 0, 0], 0).into();
+    let connects = |rx: &chan::Receiver<Out>| {
+        rx.try_iter()
+            .filter_map(|o| match o {
+                Out::Connect(addr, _) => Some(addr),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+    };
+
+    alice.connmgr.config.max_concurrent_handshakes = 2;
+    alice.addrmgr.insert(
+        (1..=8).map(|i| {
+            let addr: PeerId = ([131, i * 10, 11, 33], 8333).into();
+            (0, Address::new(&addr, setup::CONFIG.required_services))
+        }),
+        Source::Dns,
+    );
+    alice.initialize(time).unwrap();
+
+    let pending = connects(&rx);
+    assert_eq!(
+        pending.len(),
+        2,
+        "only two connections are attempted at first"
+    );
+
+    // Establishing the connections doesn't free up any slots, until the handshake completes.
+    for addr in &pending {
+        alice.step(
+            Input::Connected {
+                addr: *addr,
+                local_addr: local,
+                link: Link::Outbound,
+            },
+            time,
+        );
+    }
+    assert!(connects(&rx).is_empty());
+
+    let bob = pending[0];
+    alice.step(
+        Input::Received(
+            bob,
+            msg.raw(NetworkMessage::Version(
+                alice.peermgr.version(local, bob, 42, 0, time),
+            )),
+        ),
+        time,
+    );
+    alice.step(Input::Received(bob, msg.raw(NetworkMessage::Verack)), time);
+
+    let next = connects(&rx);
+    assert_eq!(
+        next.len(),
+        1,
+        "a new connection is attempted in place of bob"
+    );
+    assert!(!pending.contains(&next[0]));
+}
+
+#[test]
+fn test_state_export_restore() {
+    use crate::protocol::state::{self, State};
+
+    let network = Network::Mainnet;
+    let msg = message::Builder::new(network);
+    let (mut alice, _rx, time) = setup::singleton(network);
+    let local = ([0, 0, 0, 0], 0).into();
+    let bob: PeerId = ([131, 31, 11, 33], 8333).into();
+    let carol: PeerId = ([131, 31, 11, 34], 8333).into();
+    let olive: PeerId = ([131, 31, 11, 35], 8333).into();
+
+    for (i, (addr, link)) in [
+        (bob, Link::Outbound),
+        (carol, Link::Inbound),
+        (olive, Link::Outbound),
+    ]
+    .iter()
+    .enumerate()
+    {
+        alice.step(
+            Input::Connected {
+                addr: *addr,
+                local_addr: local,
+                link: *link,
+            },
+            time,
+        );
+        // Olive doesn't complete the handshake.
+        if *addr == olive {
+            continue;
+        }
+        alice.step(
+            Input::Received(
+                *addr,
+                msg.raw(NetworkMessage::Version(alice.peermgr.version(
+                    local,
+                    *addr,
+                    i as u64 + 1,
+                    42,
+                    time,
+                ))),
+            ),
+            time,
+        );
+        alice.step(
+            Input::Received(*addr, msg.raw(NetworkMessage::Verack)),
+            time,
+        );
+    }
+    // Carol misbehaves by sending headers that don't connect to each other.
+    alice.step(
+        Input::Received(
+            carol,
+            msg.raw(NetworkMessage::Headers(vec![
+                *BITCOIN_HEADERS.get(2).unwrap(),
+                *BITCOIN_HEADERS.get(1).unwrap(),
+            ])),
+        ),
+        time,
+    );
+    let score = alice.syncmgr.misbehavior(&carol).unwrap();
+    assert!(score > 0);
+
+    let exported = alice.export_state();
+    let peers = exported
+        .peers
+        .iter()
+        .map(|p| (p.addr, p.misbehavior))
+        .collect::<Vec<_>>();
+
+    assert_eq!(
+        peers,
+        vec![(bob, 0), (carol, score)],
+        "only negotiated peers are exported, along with their misbehavior"
+    );
+    assert!(
+        !exported.requests.is_empty(),
+        "alice is syncing with her peers: {:?}",
+        exported.requests
+    );
+
+    let decoded = State::decode(&exported.encode()).unwrap();
+    assert_eq!(decoded, exported);
+
+    // Restore the state in a new protocol instance.
+    let (mut restored, rx, time) = setup::singleton(network);
+    restored.restore_state(decoded, time);
+
+    let mut peers = restored
+        .peermgr
+        .peers()
+        .filter(|p| p.is_negotiated())
+        .map(|p| (p.address(), p.conn.link, p.height))
+        .collect::<Vec<_>>();
+    peers.sort_by_key(|(addr, _, _)| *addr);
+
+    assert_eq!(
+        peers,
+        vec![(bob, Link::Outbound, 42), (carol, Link::Inbound, 42)]
+    );
+    assert!(restored.connmgr.is_connected(&bob));
+    assert!(restored.connmgr.is_connected(&carol));
+    assert_eq!(restored.syncmgr.misbehavior(&carol), Some(score));
+    assert!(
+        !rx.try_iter().any(|o| matches!(
+            payload(&o),
+            Some((_, NetworkMessage::Version(_)))
+                | Some((_, NetworkMessage::Verack))
+                | Some((_, NetworkMessage::GetHeaders(_)))
+        )),
+        "no handshake messages or requests are sent to restored peers"
+    );
+    assert_eq!(restored.export_state(), exported);
+
+    // Carol's restored score counts towards her ban.
+    for _ in 0..(syncmgr::MAX_MISBEHAVIOR - score) / syncmgr::MISBEHAVIOR_PENALTY {
+        restored.step(
+            Input::Received(
+                carol,
+                msg.raw(NetworkMessage::Headers(vec![
+                    *BITCOIN_HEADERS.get(2).unwrap(),
+                    *BITCOIN_HEADERS.get(1).unwrap(),
+                ])),
+            ),
+            time,
+        );
+    }
+    assert!(
+        rx.try_iter().any(|o| matches!(
+            o,
+            Out::Disconnect(addr, DisconnectReason::PeerMisbehaving(_)) if addr == carol
+        )),
+        "carol is banned"
+    );
+
+    // States with another version are rejected.
+    let encoded = exported
+        .encode()
+        .replace(&format!("\"version\":{}", state::VERSION), "\"version\":0");
+    assert_eq!(State::decode(&encoded), Err(state::Error::Version(0)));
+    assert_eq!(State::decode("{}"), Err(state::Error::Malformed));
+}
+
+#[test]
+#[cfg_attr(
+    debug_assertions,
+    should_panic(expected = "headers served are not contiguous")
+)]
+fn test_getheaders_misordered() {
+    let network = Network::Mainnet;
+    let msg = message::Builder::new(network);
+    let (mut alice, rx, time) = setup::singleton(network);
+    let local = ([0, 0, 0, 0], 0).into();
+    let bob: PeerId = ([131, 31, 11, 33], 8333).into();
+
+    // A faulty block tree, which returns its headers out of order.
+    let mut chain = NonEmpty::from((BITCOIN_HEADERS.head, BITCOIN_HEADERS.tail[..5].to_vec()));
+    chain.tail.swap(1, 2);
+    alice.tree = model::Cache::from(chain);
+
+    alice.step(
+        Input::Connected {
+            addr: bob,
+            local_addr: local,
+            link: Link::Inbound,
+        },
+        time,
+    );
+    alice.step(
+        Input::Received(
+            bob,
+            msg.raw(NetworkMessage::Version(
+                alice.peermgr.version(local, bob, 42, 0, time),
+            )),
+        ),
+        time,
+    );
+    alice.step(Input::Received(bob, msg.raw(NetworkMessage::Verack)), time);
+    rx.try_iter().for_each(drop);
+
+    alice.step(
+        Input::Received(
+            bob,
+            msg.raw(NetworkMessage::GetHeaders(GetHeadersMessage {
+                version: PROTOCOL_VERSION,
+                locator_hashes: vec![network.genesis_hash()],
+                stop_hash: BlockHash::default(),
+            })),
+        ),
+        time,
+    );
+
+    // Only reached in release builds.
+    let outputs = rx.try_iter().collect::<Vec<_>>();
+    assert!(outputs
+        .iter()
+        .all(|o| !matches!(payload(o), Some((_, NetworkMessage::Headers(_))))));
+    assert!(outputs.iter().any(|o| matches!(
+        o,
+        Out::Event(Event::SyncManager(syncmgr::Event::InvalidHeadersServed { addr, index: 1 }))
+            if *addr == bob
+    )));
+}
+
+#[test]
+fn test_unrecognized_messages() {
+    let network = Network::Mainnet;
+    let local = ([0, 0, 0, 0], 0).into();
+    let bob: PeerId = ([131, 31, 11, 33], 8333).into();
+    let disconnected = |rx: &chan::Receiver<Out>| {
+        rx.try_iter()
+            .any(|o| matches!(o, Out::Disconnect(addr, DisconnectReason::PeerMisbehaving(_)) if addr == bob))
+    };
+
+    for policy in &[
+        UnknownMessagePolicy::Disconnect,
+        UnknownMessagePolicy::Ignore,
+    ] {
+        let (mut alice, rx, time) = setup::singleton(network);
+        alice.unknown_messages = *policy;
+
+        alice.step(
+            Input::Connected {
+                addr: bob,
+                local_addr: local,
+                link: Link::Inbound,
+            },
+            time,
+        );
+        rx.try_iter().for_each(drop);
+
+        // Messages with ignored commands are ignored, whatever the policy.
+        alice.step(Input::Unrecognized(bob, String::from("sendcmpct")), time);
+        alice.step(Input::Unrecognized(bob, String::from("protoconf")), time);
+        assert!(!disconnected(&rx));
+
+        // Other messages follow the policy.
+        alice.step(Input::Unrecognized(bob, String::from("bogus")), time);
+        assert_eq!(
+            disconnected(&rx),
+            *policy == UnknownMessagePolicy::Disconnect,
+            "{:?}",
+            policy
+        );
+    }
+}
+
+#[test]
+fn test_compact_block_fallback() {
+    let network = Network::Mainnet;
+    let (mut alice, rx, time) = setup::singleton(network);
+    let local = ([0, 0, 0, 0], 0).into();
+    let bob: PeerId = ([131, 31, 11, 33], 8333).into();
+    let msg = message::Builder::new(network);
+    let header = *BITCOIN_HEADERS.get(1).unwrap();
+    let block = bitcoin::Block {
+        header,
+        txdata: vec![],
+    };
+
+    alice.step(Input::Connecting { addr: bob }, time);
+    alice.step(
+        Input::Connected {
+            addr: bob,
+            local_addr: local,
+            link: Link::Outbound,
+        },
+        time,
+    );
+    alice.step(
+        Input::Received(
+            bob,
+            msg.raw(NetworkMessage::Version(
+                alice.peermgr.version(local, bob, 42, 0, time),
+            )),
+        ),
+        time,
+    );
+    alice.step(Input::Received(bob, msg.raw(NetworkMessage::Verack)), time);
+    rx.try_iter().for_each(drop);
+
+    // We can't reconstruct compact blocks, so we ask for the headers leading up to it.
+    alice.step(Input::Unrecognized(bob, String::from("cmpctblock")), time);
+    assert!(rx.try_iter().any(|o| matches!(
+        payload(&o),
+        Some((addr, NetworkMessage::GetHeaders(_))) if addr == bob
+    )));
+
+    alice.step(
+        Input::Received(bob, msg.raw(NetworkMessage::Headers(vec![header]))),
+        time,
+    );
+    assert_eq!(alice.tree.height(), 1);
+
+    // The block can then be requested in full.
+    alice.step(Input::Command(Command::GetBlock(header.block_hash())), time);
+    assert!(rx.try_iter().any(|o| matches!(
+        payload(&o),
+        Some((addr, NetworkMessage::GetData(inv)))
+            if addr == bob && inv == &[Inventory::Block(header.block_hash())]
+    )));
+
+    alice.step(
+        Input::Received(bob, msg.raw(NetworkMessage::Block(block))),
+        time,
+    );
+    assert!(rx.try_iter().any(|o| matches!(
+        o,
+        Out::Event(Event::SyncManager(syncmgr::Event::BlockReceived(addr, _, 1))) if addr == bob
+    )));
+}
+
+#[test]
+fn test_low_work_peer_disconnected() {
+    let network = Network::Mainnet;
+    let local = ([0, 0, 0, 0], 0).into();
+    let msg = message::Builder::new(network);
+    let chain = NonEmpty::from((BITCOIN_HEADERS.head, BITCOIN_HEADERS.tail[..20].to_vec()));
+    let time = LocalTime::from_block_time(chain.last().time);
+
+    // A chain of the given length forking off ours at height 5.
+    let fork = |len: usize| {
+        let mut headers: Vec<BlockHeader> = Vec::new();
+        for header in &BITCOIN_HEADERS.tail[5..5 + len] {
+            let mut header = *header;
+            header.prev_blockhash = headers
+                .last()
+                .map_or(BITCOIN_HEADERS.tail[4].block_hash(), |h| h.block_hash());
+            header.nonce += 1;
+            headers.push(header);
+        }
+        headers
+    };
+
+    // Our chain has 15 more blocks than the fork point. With 8 blocks, the peer's chain
+    // has too little work, with 10, it's within the tolerance.
+    for (len, disconnected) in &[(8, true), (10, false)] {
+        let (mut alice, rx, _) = setup::singleton(network);
+        let bob: PeerId = ([131, 31, 11, 33], 8333).into();
+        alice.tree = model::Cache::from(chain.clone());
+
+        alice.step(Input::Connecting { addr: bob }, time);
+        alice.step(
+            Input::Connected {
+                addr: bob,
+                local_addr: local,
+                link: Link::Outbound,
+            },
+            time,
+        );
+        alice.step(
+            Input::Received(
+                bob,
+                msg.raw(NetworkMessage::Version(
+                    alice.peermgr.version(local, bob, 42, 25, time),
+                )),
+            ),
+            time,
+        );
+        alice.step(Input::Received(bob, msg.raw(NetworkMessage::Verack)), time);
+        assert!(rx.try_iter().any(|o| matches!(
+            payload(&o),
+            Some((addr, NetworkMessage::GetHeaders(_))) if addr == bob
+        )));
+
+        alice.step(
+            Input::Received(bob, msg.raw(NetworkMessage::Headers(fork(*len)))),
+            time,
+        );
+        assert_eq!(
+            rx.try_iter().any(|o| matches!(
+                o,
+                Out::Disconnect(addr, DisconnectReason::PeerChainWork) if addr == bob
+            )),
+            *disconnected,
+            "{} headers",
+            len
+        );
+    }
+}
+
+#[test]
+fn test_outdated_client() {
+    let network = Network::Mainnet;
+    let (mut alice, rx, time) = setup::singleton(network);
+    let local = ([0, 0, 0, 0], 0).into();
+    let msg = message::Builder::new(network);
+    let outdated = |rx: &chan::Receiver<Out>| {
+        rx.try_iter()
+            .filter(|o| {
+                matches!(
+                    o,
+                    Out::Event(Event::PeerManager(peermgr::Event::OutdatedClient {
+                        our_version: PROTOCOL_VERSION,
+                        network_median: 70016,
+                    }))
+                )
+            })
+            .count()
+    };
+    let mut events = 0;
+
+    for (i, version) in [PROTOCOL_VERSION, 70016, 70016, 70016, 70016]
+        .iter()
+        .enumerate()
+    {
+        let peer: PeerId = ([131, 31, 11, i as u8 + 1], 8333).into();
+        let mut version_msg = alice.peermgr.version(local, peer, i as u64 + 1, 0, time);
+        version_msg.version = *version;
+
+        alice.step(
+            Input::Connected {
+                addr: peer,
+                local_addr: local,
+                link: Link::Inbound,
+            },
+            time,
+        );
+        alice.step(
+            Input::Received(peer, msg.raw(NetworkMessage::Version(version_msg))),
+            time,
+        );
+        alice.step(Input::Received(peer, msg.raw(NetworkMessage::Verack)), time);
+
+        let count = outdated(&rx);
+        // The warning is emitted once most of our peers are on a higher version.
+        assert_eq!(count, if i == 2 { 1 } else { 0 }, "peer #{}", i);
+        events += count;
+    }
+    assert_eq!(events, 1);
+}
+
+#[test]
+fn test_feature_negotiation() {
+    let network = Network::Mainnet;
+    let (mut alice, _rx, time) = setup::singleton(network);
+    let local = ([0, 0, 0, 0], 0).into();
+    let bob: PeerId = ([131, 31, 11, 33], 8333).into();
+    let msg = message::Builder::new(network);
+    let features = |alice: &Protocol<_, _, _>| {
+        alice
+            .peermgr
+            .peers()
+            .find(|p| p.address() == bob)
+            .map(|p| p.features)
+    };
+
+    alice.step(Input::Connecting { addr: bob }, time);
+    alice.step(
+        Input::Connected {
+            addr: bob,
+            local_addr: local,
+            link: Link::Outbound,
+        },
+        time,
+    );
+    // Before `version`, feature negotiation isn't valid.
+    alice.step(Input::Unrecognized(bob, String::from("wtxidrelay")), time);
+    assert_eq!(features(&alice), None);
+
+    alice.step(
+        Input::Received(
+            bob,
+            msg.raw(NetworkMessage::Version(
+                alice.peermgr.version(local, bob, 42, 0, time),
+            )),
+        ),
+        time,
+    );
+    assert_eq!(features(&alice), Some(peermgr::PeerFeatures::default()));
+
+    // Between `version` and `verack`, all features can be negotiated.
+    alice.step(Input::Unrecognized(bob, String::from("wtxidrelay")), time);
+    alice.step(Input::Unrecognized(bob, String::from("sendaddrv2")), time);
+    alice.step(
+        Input::Received(bob, msg.raw(NetworkMessage::SendHeaders)),
+        time,
+    );
+    assert_eq!(
+        features(&alice),
+        Some(peermgr::PeerFeatures {
+            send_headers: true,
+            send_compact: false,
+            wtxid_relay: true,
+            send_addr_v2: true,
+        })
+    );
+
+    alice.step(Input::Received(bob, msg.raw(NetworkMessage::Verack)), time);
+    assert!(alice.peermgr.outbound().any(|p| p.address() == bob));
+
+    // After `verack`, only some features can.
+    alice.step(Input::Unrecognized(bob, String::from("sendcmpct")), time);
+    assert!(features(&alice).unwrap().send_compact);
+
+    for feature in &[peermgr::Feature::WtxidRelay, peermgr::Feature::SendAddrV2] {
+        assert!(!alice.peermgr.received_feature(&bob, *feature));
+    }
+}
+
+#[test]
+fn test_get_mempool_info() {
+    use bitcoin::{OutPoint, Script, TxIn, TxOut};
+
+    let network = Network::Mainnet;
+    let (mut alice, _rx, time) = setup::singleton(network);
+    let local = ([0, 0, 0, 0], 0).into();
+    let bob: PeerId = ([131, 31, 11, 33], 8333).into();
+    let msg = message::Builder::new(network);
+    let tx = |input: OutPoint, value: u64| Transaction {
+        version: 2,
+        lock_time: 0,
+        input: vec![TxIn {
+            previous_output: input,
+            script_sig: Script::new(),
+            sequence: 0xffffffff,
+            witness: vec![],
+        }],
+        output: vec![TxOut {
+            value,
+            script_pubkey: Script::new(),
+        }],
+    };
+    let get_mempool_info = |alice: &mut Protocol<_, _, _>| {
+        let (tx, rx) = chan::bounded(1);
+        alice.step(Input::Command(Command::GetMemPoolInfo(tx)), time);
+        rx.try_recv().unwrap()
+    };
+
+    alice.step(
+        Input::Connected {
+            addr: bob,
+            local_addr: local,
+            link: Link::Inbound,
+        },
+        time,
+    );
+    assert_eq!(
+        get_mempool_info(&mut alice),
+        mempool::MemPoolInfo {
+            size: 0,
+            bytes: 0,
+            min_fee_rate: None,
+        }
+    );
+
+    // The fee of `b` is known, since it spends an output of `a`.
+    let a = tx(OutPoint::default(), 100_000);
+    let b = tx(OutPoint::new(a.txid(), 0), 90_000);
+    let c = tx(OutPoint::new(a.txid(), 1), 50_000);
+
+    for tx in &[&a, &b, &c, &a] {
+        alice.step(
+            Input::Received(bob, msg.raw(NetworkMessage::Tx((*tx).clone()))),
+            time,
+        );
+    }
+    let info = get_mempool_info(&mut alice);
+    let vsize = b.get_weight().div_ceil(4);
+
+    assert_eq!(info.size, 3);
+    assert_eq!(info.bytes, a.get_size() + b.get_size() + c.get_size());
+    assert_eq!(info.min_fee_rate, Some(10_000. / vsize as f64));
+
+    // Once `a` is confirmed, it's no longer tracked.
+    let block = bitcoin::Block {
+        header: *BITCOIN_HEADERS.get(1).unwrap(),
+        txdata: vec![a.clone()],
+    };
+    alice.step(
+        Input::Received(bob, msg.raw(NetworkMessage::Block(block))),
+        time,
+    );
+    let info = get_mempool_info(&mut alice);
+
+    assert_eq!(info.size, 2);
+    assert_eq!(info.bytes, b.get_size() + c.get_size());
+    assert_eq!(info.min_fee_rate, None);
+}
+
+#[test]
+fn test_block_download_priority() {
+    let network = Network::Mainnet;
+    let (mut alice, rx, time) = setup::singleton(network);
+    let local = ([0, 0, 0, 0], 0).into();
+    let bob: PeerId = ([131, 31, 11, 33], 8333).into();
+    let msg = message::Builder::new(network);
+    let headers = BITCOIN_HEADERS
+        .iter()
+        .take(101)
+        .cloned()
+        .collect::<Vec<_>>();
+    let chain = NonEmpty::from_vec(headers.clone()).unwrap();
+    let requested = |rx: &chan::Receiver<Out>| {
+        rx.try_iter()
+            .filter_map(|o| match payload(&o) {
+                Some((addr, NetworkMessage::GetData(inv))) if addr == bob => Some(inv.clone()),
+                _ => None,
+            })
+            .flatten()
+            .collect::<Vec<_>>()
+    };
+    let block = |height: usize| bitcoin::Block {
+        header: headers[height],
+        txdata: vec![],
+    };
+
+    alice.tree = model::Cache::from(chain.clone());
+    alice.step(Input::Connecting { addr: bob }, time);
+    alice.step(
+        Input::Connected {
+            addr: bob,
+            local_addr: local,
+            link: Link::Outbound,
+        },
+        time,
+    );
+    alice.step(
+        Input::Received(
+            bob,
+            msg.raw(NetworkMessage::Version(
+                alice.peermgr.version(local, bob, 42, 100, time),
+            )),
+        ),
+        time,
+    );
+    alice.step(Input::Received(bob, msg.raw(NetworkMessage::Verack)), time);
+    rx.try_iter().for_each(drop);
+
+    // A rescan asks for more historical blocks than can be requested at once.
+    for header in headers[1..=20].iter() {
+        alice.step(Input::Command(Command::GetBlock(header.block_hash())), time);
+    }
+    assert_eq!(
+        requested(&rx),
+        (1..=blockmgr::MAX_INFLIGHT_BLOCKS)
+            .map(|h| Inventory::Block(headers[h].block_hash()))
+            .collect::<Vec<_>>()
+    );
+
+    // A new tip arrives mid-rescan. It's queued, since there's no capacity left.
+    let tip = chain.last().block_hash();
+    alice.step(Input::Command(Command::GetBlock(tip)), time);
+    assert!(requested(&rx).is_empty());
+
+    // As soon as a request is fulfilled, the tip's block is requested, ahead of the
+    // remaining historical blocks.
+    alice.step(
+        Input::Received(bob, msg.raw(NetworkMessage::Block(block(1)))),
+        time,
+    );
+    assert_eq!(requested(&rx), vec![Inventory::Block(tip)]);
+
+    alice.step(
+        Input::Received(bob, msg.raw(NetworkMessage::Block(block(2)))),
+        time,
+    );
+    assert_eq!(
+        requested(&rx),
+        vec![Inventory::Block(
+            headers[blockmgr::MAX_INFLIGHT_BLOCKS + 1].block_hash()
+        )]
+    );
+}
+
+#[test]
+fn test_persistent_peers() {
+    let network = Network::Mainnet;
+    let genesis = network.genesis();
+    let time = LocalTime::from_secs(genesis.time as u64);
+    let local = ([0, 0, 0, 0], 0).into();
+    let bob: PeerId = ([131, 31, 11, 33], 8333).into();
+    let msg = message::Builder::new(network);
+    let (tx, rx) = chan::unbounded();
+
+    let mut alice = Builder {
+        cache: model::Cache::new(genesis),
+        clock: AdjustedTime::new(time),
+        filters: model::FilterCache::new(FilterHeader::genesis(network)),
+        peers: HashMap::new(),
+        rng: fastrand::Rng::new(),
+        cfg: Config {
+            persistent_peers: vec![bob],
+            ..setup::CONFIG.clone()
+        },
+    }
+    .build(tx);
+
+    alice.initialize(time).unwrap();
+    assert!(
+        rx.try_iter()
+            .any(|o| matches!(o, Out::Connect(addr, _) if addr == bob)),
+        "Alice dials the persistent peer on startup"
+    );
+
+    alice.step(
+        Input::Connected {
+            addr: bob,
+            local_addr: local,
+            link: Link::Outbound,
+        },
+        time,
+    );
+    alice.step(
+        Input::Received(
+            bob,
+            msg.raw(NetworkMessage::Version(
+                alice.peermgr.version(local, bob, 42, 0, time),
+            )),
+        ),
+        time,
+    );
+    alice.step(Input::Received(bob, msg.raw(NetworkMessage::Verack)), time);
+    assert!(alice.connmgr.is_connected(&bob));
+    rx.try_iter().for_each(drop);
+
+    // Bob doesn't respond to pings, but isn't disconnected for it.
+    let time = time + pingmgr::PING_TIMEOUT;
+    alice.step(Input::Timeout, time);
+
+    let outputs = rx.try_iter().collect::<Vec<_>>();
+    assert!(!outputs
+        .iter()
+        .any(|o| matches!(o, Out::Disconnect(addr, _) if *addr == bob)));
+    assert!(
+        outputs
+            .iter()
+            .any(|o| matches!(payload(o), Some((addr, NetworkMessage::Ping(_))) if addr == bob)),
+        "Alice pings Bob again"
+    );
+
+    // When the connection drops, Alice reconnects right away.
+    alice.step(
+        Input::Disconnected(bob, DisconnectReason::PeerTimeout),
+        time,
+    );
+    assert!(
+        rx.try_iter()
+            .any(|o| matches!(o, Out::Connect(addr, _) if addr == bob)),
+        "Alice reconnects to Bob"
+    );
+}
+
+#[test]
+fn test_zero_start_height() {
+    let network = Network::Mainnet;
+    let (mut alice, rx, time) = setup::singleton(network);
+    let local = ([0, 0, 0, 0], 0).into();
+    let bob: PeerId = ([131, 31, 11, 33], 8333).into();
+    let msg = message::Builder::new(network);
+    let headers = BITCOIN_HEADERS.iter().take(11).cloned().collect::<Vec<_>>();
+
+    alice.tree = model::Cache::from(NonEmpty::from_vec(headers.clone()).unwrap());
+
+    alice.step(Input::Connecting { addr: bob }, time);
+    alice.step(
+        Input::Connected {
+            addr: bob,
+            local_addr: local,
+            link: Link::Outbound,
+        },
+        time,
+    );
+    // Bob is still syncing, and advertises a height of zero.
+    alice.step(
+        Input::Received(
+            bob,
+            msg.raw(NetworkMessage::Version(
+                alice.peermgr.version(local, bob, 42, 0, time),
+            )),
+        ),
+        time,
+    );
+    alice.step(Input::Received(bob, msg.raw(NetworkMessage::Verack)), time);
+
+    // Bob's height is unknown, rather than behind ours, so he's still asked for headers.
+    assert_eq!(alice.syncmgr.best_height(), None);
+    assert!(rx.try_iter().any(|o| matches!(
+        payload(&o),
+        Some((addr, NetworkMessage::GetHeaders(_))) if addr == bob
+    )));
+
+    // Bob serves headers up to our tip, which tells us his actual height.
+    alice.step(
+        Input::Received(bob, msg.raw(NetworkMessage::Headers(headers[1..].to_vec()))),
+        time,
+    );
+    assert_eq!(alice.syncmgr.best_height(), Some(10));
+}
+
+#[test]
+fn test_inbound_disabled() {
+    let network = Network::Mainnet;
+    let genesis = network.genesis();
+    let time = LocalTime::from_secs(genesis.time as u64);
+    let local = ([131, 31, 11, 1], 8333).into();
+    let bob: PeerId = ([131, 31, 11, 33], 8333).into();
+    let olive: PeerId = ([131, 31, 11, 34], 8333).into();
+    let (tx, rx) = chan::unbounded();
+
+    let mut alice = Builder {
+        cache: model::Cache::new(genesis),
+        clock: AdjustedTime::new(time),
+        filters: model::FilterCache::new(FilterHeader::genesis(network)),
+        peers: HashMap::new(),
+        rng: fastrand::Rng::new(),
+        cfg: Config {
+            accept_inbound: false,
+            ..setup::CONFIG.clone()
+        },
+    }
+    .build(tx);
+
+    alice.step(
+        Input::Connected {
+            addr: bob,
+            local_addr: local,
+            link: Link::Inbound,
+        },
+        time,
+    );
+    assert!(rx.try_iter().any(|o| matches!(
+        o,
+        Out::Disconnect(addr, DisconnectReason::InboundDisabled) if addr == bob
+    )));
+    assert!(!alice.connmgr.is_connected(&bob));
+
+    // Outbound connections are unaffected, but we don't tell peers where to reach us.
+    alice.step(Input::Connecting { addr: olive }, time);
+    alice.step(
+        Input::Connected {
+            addr: olive,
+            local_addr: local,
+            link: Link::Outbound,
+        },
+        time,
+    );
+    let version = rx
+        .try_iter()
+        .find_map(|o| match payload(&o) {
+            Some((addr, NetworkMessage::Version(v))) if addr == olive => Some(v.clone()),
+            _ => None,
+        })
+        .expect("Alice sends a `version` to Olive");
+
+    assert!(alice.connmgr.is_connected(&olive));
+    assert!(version.sender.socket_addr().unwrap().ip().is_unspecified());
+    assert_eq!(version.sender.port, 0);
+}
+
+#[test]
+fn test_ping_pre_bip31() {
+    let network = Network::Mainnet;
+    let (mut alice, rx, time) = setup::singleton(network);
+    let bob: PeerId = ([131, 31, 11, 33], 8333).into();
+    let pings = |rx: &chan::Receiver<Out>| {
+        rx.try_iter()
+            .filter(|o| matches!(payload(o), Some((addr, NetworkMessage::Ping(_))) if addr == bob))
+            .count()
+    };
+
+    // Peers this old are refused during the handshake, so we register Bob directly.
+    alice
+        .pingmgr
+        .peer_negotiated(bob, pingmgr::BIP31_VERSION - 1, time);
+    assert_eq!(pings(&rx), 1);
+
+    // Bob never replies with a `pong`, but keeps being pinged as a keepalive.
+    let mut time = time;
+    for _ in 0..3 {
+        time = time + pingmgr::PING_INTERVAL;
+        alice.step(Input::Timeout, time);
+
+        let outputs = rx.try_iter().collect::<Vec<_>>();
+        assert!(!outputs
+            .iter()
+            .any(|o| matches!(o, Out::Disconnect(addr, _) if *addr == bob)));
+        assert!(outputs
+            .iter()
+            .any(|o| matches!(payload(o), Some((addr, NetworkMessage::Ping(_))) if addr == bob)));
+    }
+}
+
+#[test]
+fn test_sync_speed() {
+    let network = Network::Mainnet;
+    let (mut alice, rx, mut time) = setup::singleton(network);
+    let local = ([0, 0, 0, 0], 0).into();
+    let bob: PeerId = ([131, 31, 11, 33], 8333).into();
+    let msg = message::Builder::new(network);
+    let headers = BITCOIN_HEADERS
+        .tail
+        .iter()
+        .take(100)
+        .cloned()
+        .collect::<Vec<_>>();
+    let speed = |alice: &mut Protocol<_, _, _>, time| {
+        let (tx, rx) = chan::bounded(1);
+
+        alice.step(Input::Command(Command::GetSyncSpeed(tx)), time);
+        rx.recv().unwrap()
+    };
+
+    alice.step(Input::Connecting { addr: bob }, time);
+    alice.step(
+        Input::Connected {
+            addr: bob,
+            local_addr: local,
+            link: Link::Outbound,
+        },
+        time,
+    );
+    alice.step(
+        Input::Received(
+            bob,
+            msg.raw(NetworkMessage::Version(
+                alice.peermgr.version(local, bob, 42, 0, time),
+            )),
+        ),
+        time,
+    );
+    alice.step(Input::Received(bob, msg.raw(NetworkMessage::Verack)), time);
+    rx.try_iter().for_each(drop);
+
+    assert_eq!(speed(&mut alice, time), throughput::SyncSpeed::default());
+
+    // Bob sends us five headers every second, and a block every other second.
+    for (i, chunk) in headers.chunks(5).enumerate() {
+        alice.step(
+            Input::Received(bob, msg.raw(NetworkMessage::Headers(chunk.to_vec()))),
+            time,
+        );
+        if i % 2 == 0 {
+            let block = bitcoin::Block {
+                header: chunk[0],
+                txdata: vec![],
+            };
+            alice.step(
+                Input::Received(bob, msg.raw(NetworkMessage::Block(block))),
+                time,
+            );
+        }
+        time = time + LocalDuration::from_secs(1);
+    }
+    assert_eq!(alice.tree.height(), 100);
+
+    let current = speed(&mut alice, time);
+    assert!((4.5..=5.5).contains(&current.headers_per_sec));
+    assert!((0.4..=0.6).contains(&current.blocks_per_sec));
+    assert!(current.block_bytes_per_sec > 0.);
+
+    // Once nothing is received for a whole window, the throughput drops to zero.
+    let idle = speed(&mut alice, time + throughput::WINDOW);
+    assert_eq!(idle.headers_per_sec, 0.);
+    assert_eq!(idle.blocks_per_sec, 0.);
+}
+
+#[test]
+fn test_get_header_page() {
+    let network = Network::Mainnet;
+    let (mut alice, _rx, time) = setup::singleton(network);
+    let chain = BITCOIN_HEADERS.iter().take(25).cloned().collect::<Vec<_>>();
+    let get_page = |alice: &mut Protocol<_, _, _>, start, count| {
+        let (tx, rx) = chan::bounded(1);
+
+        alice.step(
+            Input::Command(Command::GetHeaderPage(start, count, tx)),
+            time,
+        );
+        rx.recv().unwrap()
+    };
+
+    alice.tree = model::Cache::from(NonEmpty::from_vec(chain.clone()).unwrap());
+
+    // Paging through the chain yields all of its headers, in order.
+    let mut headers = Vec::new();
+    let mut start = 0;
+    loop {
+        let (page, height) = get_page(&mut alice, start, 7);
+
+        assert_eq!(height, 24);
+        assert!(page.len() <= 7);
+
+        if page.is_empty() {
+            break;
+        }
+        start += page.len() as Height;
+        headers.extend(page);
+    }
+    assert_eq!(headers, chain);
+
+    // Starting beyond the tip yields an empty page.
+    assert_eq!(get_page(&mut alice, 25, 10), (vec![], 24));
+    assert_eq!(get_page(&mut alice, Height::MAX, 10), (vec![], 24));
+
+    // Pages are capped in size.
+    let mut long = chain;
+    for nonce in 0..MAX_HEADER_PAGE as u32 {
+        let prev = *long.last().unwrap();
+
+        long.push(BlockHeader {
+            prev_blockhash: prev.block_hash(),
+            nonce,
+            ..prev
+        });
+    }
+    alice.tree = model::Cache::from(NonEmpty::from_vec(long.clone()).unwrap());
+
+    let (page, _) = get_page(&mut alice, 0, usize::MAX);
+    assert_eq!(page.len(), MAX_HEADER_PAGE);
+    assert_eq!(page[..], long[..MAX_HEADER_PAGE]);
+}
+
+#[test]
+fn test_non_contiguous_headers() {
+    let network = Network::Mainnet;
+    let (mut alice, rx, time) = setup::singleton(network);
+    let local = ([0, 0, 0, 0], 0).into();
+    let bob: PeerId = ([131, 31, 11, 33], 8333).into();
+    let msg = message::Builder::new(network);
+
+    alice.step(Input::Connecting { addr: bob }, time);
+    alice.step(
+        Input::Connected {
+            addr: bob,
+            local_addr: local,
+            link: Link::Outbound,
+        },
+        time,
+    );
+    alice.step(
+        Input::Received(
+            bob,
+            msg.raw(NetworkMessage::Version(
+                alice.peermgr.version(local, bob, 42, 0, time),
+            )),
+        ),
+        time,
+    );
+    alice.step(Input::Received(bob, msg.raw(NetworkMessage::Verack)), time);
+    rx.try_iter().for_each(drop);
+    assert_eq!(alice.syncmgr.misbehavior(&bob), Some(0));
+
+    // The header at index 5 doesn't extend the one at index 4.
+    let mut headers = BITCOIN_HEADERS
+        .tail
+        .iter()
+        .take(8)
+        .cloned()
+        .collect::<Vec<_>>();
+    headers.remove(5);
+
+    alice.step(
+        Input::Received(bob, msg.raw(NetworkMessage::Headers(headers))),
+        time,
+    );
+    assert!(rx.try_iter().any(|o| matches!(
+        o,
+        Out::Event(Event::SyncManager(syncmgr::Event::NonContiguousHeadersReceived {
+            addr,
+            index: 5,
+        })) if addr == bob
+    )));
+    assert_eq!(alice.syncmgr.misbehavior(&bob), Some(20));
+    assert_eq!(alice.tree.height(), 0, "none of the headers are imported");
+
+    // Peers that keep misbehaving are disconnected.
+    let mut headers = BITCOIN_HEADERS
+        .tail
+        .iter()
+        .take(3)
+        .cloned()
+        .collect::<Vec<_>>();
+    headers.swap(1, 2);
+
+    for _ in 0..4 {
+        alice.step(
+            Input::Received(bob, msg.raw(NetworkMessage::Headers(headers.clone()))),
+            time,
+        );
+    }
+    assert!(rx.try_iter().any(|o| matches!(
+        o,
+        Out::Disconnect(addr, DisconnectReason::PeerMisbehaving(_)) if addr == bob
+    )));
+    assert_eq!(alice.syncmgr.misbehavior(&bob), None);
+}
+
+#[test]
+fn test_background_validation() {
+    use nakamoto_chain::block::cache::ChainConfig;
+
+    let network = Network::Regtest;
+    let genesis = network.genesis();
+    let next = |prev: &BlockHeader, time: u32| {
+        let mut header = BlockHeader {
+            prev_blockhash: prev.block_hash(),
+            time,
+            ..*prev
+        };
+        nakamoto_test::block::solve(&mut header);
+        header
+    };
+    let mut headers: Vec<BlockHeader> = Vec::new();
+    for _ in 0..1100 {
+        let prev = headers.last().unwrap_or(&genesis);
+        headers.push(next(prev, prev.time + 600));
+    }
+    let time = LocalTime::from_secs(headers.last().unwrap().time as u64);
+    let build = |headers: &[BlockHeader], assume_valid| {
+        let clock = AdjustedTime::<PeerId>::new(time);
+        let mut tree = BlockCache::from(
+            store::Memory::new(NonEmpty::new(genesis)),
+            network.params(),
+            &[],
+        )
+        .unwrap()
+        .with_config(ChainConfig {
+            assume_valid: Some(assume_valid),
+            ..ChainConfig::default()
+        })
+        .unwrap();
+        tree.import_blocks(headers.iter().cloned(), &clock).unwrap();
+
+        let (tx, rx) = chan::unbounded();
+        let alice = Builder {
+            cache: tree,
+            clock,
+            filters: model::FilterCache::new(FilterHeader::genesis(network)),
+            peers: HashMap::new(),
+            rng: fastrand::Rng::new(),
+            cfg: setup::CONFIG.clone(),
+        }
+        .build(tx);
+
+        (alice, rx)
+    };
+
+    // The assumed-valid region of a good chain is validated in the background.
+    let (mut alice, rx) = build(&headers, (1000, headers[999].block_hash()));
+
+    alice.step(Input::Timeout, time);
+    assert!(rx
+        .try_iter()
+        .any(|o| matches!(o, Out::Event(Event::BackgroundValidationComplete(1000)))));
+
+    alice.step(Input::Timeout, time);
+    assert!(
+        !rx.try_iter().any(|o| matches!(
+            o,
+            Out::Event(Event::BackgroundValidationComplete(_) | Event::ValidationFailure(..))
+        )),
+        "validation only completes once"
+    );
+
+    // A header with an invalid timestamp is only caught by the background validation.
+    let mut invalid = headers.clone();
+    invalid[499] = next(&invalid[498], genesis.time);
+    for i in 500..invalid.len() {
+        invalid[i] = next(&invalid[i - 1], invalid[i].time);
+    }
+    let (mut alice, rx) = build(&invalid[..1000], (1000, invalid[999].block_hash()));
+    assert_eq!(alice.tree.height(), 1000);
+
+    alice.step(Input::Timeout, time);
+    assert!(rx.try_iter().any(|o| matches!(
+        o,
+        Out::Event(Event::ValidationFailure(500, hash, _)) if hash == invalid[499].block_hash()
+    )));
+}
+
+#[test]
+fn test_max_connections() {
+    let network = Network::Mainnet;
+    let genesis = network.genesis();
+    let mut time = LocalTime::from_secs(genesis.time as u64);
+    let local = ([131, 31, 11, 1], 8333).into();
+    let services = ServiceFlags::NETWORK | ServiceFlags::COMPACT_FILTERS;
+    let peers = (1..=32)
+        .map(|i| {
+            let addr: PeerId = ([88, 13, 17, i], 8333).into();
+            (
+                addr.ip(),
+                KnownAddress::new(Address::new(&addr, services), Source::Dns),
+            )
+        })
+        .collect::<HashMap<_, _>>();
+    let (tx, rx) = chan::unbounded();
+
+    let mut alice = Builder {
+        cache: model::Cache::new(genesis),
+        clock: AdjustedTime::new(time),
+        filters: model::FilterCache::new(FilterHeader::genesis(network)),
+        peers,
+        rng: fastrand::Rng::new(),
+        cfg: Config {
+            max_connections: 8,
+            target_outbound_peers: 32,
+            max_concurrent_handshakes: 32,
+            ..setup::CONFIG.clone()
+        },
+    }
+    .build(tx);
+
+    alice.initialize(time).unwrap();
+
+    let dialed = rx
+        .try_iter()
+        .filter_map(|o| match o {
+            Out::Connect(addr, _) => Some(addr),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(dialed.len(), 8, "Alice stops dialing at the ceiling");
+
+    for addr in &dialed {
+        alice.step(
+            Input::Connected {
+                addr: *addr,
+                local_addr: local,
+                link: Link::Outbound,
+            },
+            time,
+        );
+    }
+    assert_eq!(alice.connmgr.outbound_peers().count(), 8);
+
+    // Inbound connections are refused, even though the inbound limit isn't reached.
+    let bob: PeerId = ([131, 31, 11, 33], 8333).into();
+    alice.step(
+        Input::Connected {
+            addr: bob,
+            local_addr: local,
+            link: Link::Inbound,
+        },
+        time,
+    );
+    assert!(rx.try_iter().any(|o| matches!(
+        o,
+        Out::Disconnect(addr, DisconnectReason::ConnectionLimit) if addr == bob
+    )));
+    assert!(!alice.connmgr.is_connected(&bob));
+
+    // No new peers are dialed while we're at the ceiling.
+    time = time + connmgr::IDLE_TIMEOUT;
+    alice.step(Input::Timeout, time);
+    alice.step(Input::Command(Command::Connect(bob)), time);
+    assert!(!rx.try_iter().any(|o| matches!(o, Out::Connect(..))));
+
+    // Once a peer disconnects, a slot frees up.
+    alice.step(
+        Input::Disconnected(dialed[0], DisconnectReason::Command),
+        time,
+    );
+    assert_eq!(
+        rx.try_iter()
+            .filter(|o| matches!(o, Out::Connect(..)))
+            .count(),
+        1
+    );
+}
+
+#[test]
+fn test_deterministic_outputs() {
+    let network = Network::Mainnet;
+    let genesis = network.genesis();
+    let time = LocalTime::from_secs(genesis.time as u64);
+    let local = ([131, 31, 11, 1], 8333).into();
+    let msg = message::Builder::new(network);
+    let services = ServiceFlags::NETWORK | ServiceFlags::COMPACT_FILTERS;
+    let peers = (1..=32)
+        .map(|i| {
+            let addr: PeerId = ([88, 13, 17, i], 8333).into();
+            (
+                addr.ip(),
+                KnownAddress::new(Address::new(&addr, services), Source::Dns),
+            )
+        })
+        .collect::<HashMap<_, _>>();
+    let builder = |seed| Builder {
+        cache: model::Cache::new(genesis),
+        clock: AdjustedTime::new(time),
+        filters: model::FilterCache::new(FilterHeader::genesis(network)),
+        peers: peers.clone(),
+        rng: fastrand::Rng::with_seed(seed),
+        cfg: setup::CONFIG.clone(),
+    };
+
+    // Inputs from a handful of peers, some of which time out or disconnect.
+    let (tx, _rx) = chan::unbounded();
+    let remote = builder(1).build(tx);
+    let mut inputs = Vec::new();
+    for i in 1..=6 {
+        let addr: PeerId = ([88, 13, 17, i], 8333).into();
+        let link = if i % 2 == 0 {
+            Link::Outbound
+        } else {
+            Link::Inbound
+        };
+        let version = remote
+            .peermgr
+            .version(local, addr, i as u64, i as Height, time);
+
+        inputs.push((
+            Input::Connected {
+                addr,
+                local_addr: local,
+                link,
+            },
+            time,
+        ));
+        inputs.push((
+            Input::Received(addr, msg.raw(NetworkMessage::Version(version))),
+            time,
+        ));
+        inputs.push((Input::Received(addr, msg.raw(NetworkMessage::Verack)), time));
+    }
+    let headers = BITCOIN_HEADERS
+        .tail
+        .iter()
+        .take(8)
+        .cloned()
+        .collect::<Vec<_>>();
+    let (first, _) = inputs[0].clone();
+    if let Input::Connected { addr, .. } = first {
+        inputs.push((
+            Input::Received(addr, msg.raw(NetworkMessage::Headers(headers))),
+            time,
+        ));
+        inputs.push((Input::Disconnected(addr, DisconnectReason::Command), time));
+    }
+    for secs in [3, 60, 120, 180] {
+        inputs.push((Input::Timeout, time + LocalDuration::from_secs(secs)));
+    }
+
+    let run = |seed| {
+        let (tx, rx) = chan::unbounded();
+        let mut alice = builder(seed).build(tx);
+
+        alice.initialize(time).unwrap();
+        for (input, time) in inputs.iter().cloned() {
+            alice.step(input, time);
+        }
+        rx.try_iter()
+            .map(|o| match o {
+                Out::Message(addr, msg) => {
+                    let mut bytes = addr.to_string().into_bytes();
+                    bytes.extend(bitcoin::consensus::encode::serialize(&msg));
+                    bytes
+                }
+                other => format!("{:?}", other).into_bytes(),
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let outputs = run(42);
+    assert!(outputs.len() > inputs.len());
+    assert_eq!(outputs, run(42));
+}
+
+#[test]
+fn test_latency_request_timeout() {
+    let network = Network::Mainnet;
+    let (mut alice, rx, time) = setup::singleton(network);
+    let local = ([0, 0, 0, 0], 0).into();
+    let bob: PeerId = ([131, 31, 11, 33], 8333).into();
+    let carol: PeerId = ([131, 31, 11, 34], 8333).into();
+    let msg = message::Builder::new(network);
+    let mut nonces = HashMap::new();
+
+    for (nonce, peer) in [bob, carol].iter().copied().enumerate() {
+        alice.step(Input::Connecting { addr: peer }, time);
+        alice.step(
+            Input::Connected {
+                addr: peer,
+                local_addr: local,
+                link: Link::Outbound,
+            },
+            time,
+        );
+        alice.step(
+            Input::Received(
+                peer,
+                msg.raw(NetworkMessage::Version(alice.peermgr.version(
+                    local,
+                    peer,
+                    nonce as u64,
+                    0,
+                    time,
+                ))),
+            ),
+            time,
+        );
+        alice.step(Input::Received(peer, msg.raw(NetworkMessage::Verack)), time);
+    }
+    for o in rx.try_iter() {
+        if let Some((addr, NetworkMessage::Ping(nonce))) = payload(&o) {
+            nonces.insert(addr, *nonce);
+        }
+    }
+    assert_eq!(
+        alice.syncmgr.request_timeout(&bob),
+        syncmgr::REQUEST_TIMEOUT,
+        "Without a latency measurement, the default timeout is used"
+    );
+
+    // Bob replies to our ping quickly, Carol takes her time.
+    let time = time + LocalDuration::from_millis(50);
+    alice.step(
+        Input::Received(bob, msg.raw(NetworkMessage::Pong(nonces[&bob]))),
+        time,
+    );
+    let time = time + LocalDuration::from_secs(4);
+    alice.step(
+        Input::Received(carol, msg.raw(NetworkMessage::Pong(nonces[&carol]))),
+        time,
+    );
+
+    let fast = alice.syncmgr.request_timeout(&bob);
+    let slow = alice.syncmgr.request_timeout(&carol);
+
+    assert!(fast >= syncmgr::MIN_REQUEST_TIMEOUT);
+    assert!(fast < slow);
+    assert!(slow <= syncmgr::MAX_REQUEST_TIMEOUT);
+
+    // Both are asked for the headers of a block they announce, and never respond.
+    let hash = BITCOIN_HEADERS.tail[0].block_hash();
+    for peer in [bob, carol].iter().copied() {
+        alice.step(
+            Input::Received(
+                peer,
+                msg.raw(NetworkMessage::Inv(vec![Inventory::Block(hash)])),
+            ),
+            time,
+        );
+    }
+    rx.try_iter().for_each(drop);
+
+    let timed_out = |rx: &chan::Receiver<Out>| {
+        rx.try_iter()
+            .filter_map(|o| match o {
+                Out::Event(Event::SyncManager(syncmgr::Event::TimedOut(addr))) => Some(addr),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+    };
+
+    // Bob's stall is detected first.
+    alice.step(Input::Timeout, time + fast);
+    assert_eq!(timed_out(&rx), vec![bob]);
+
+    // Carol eventually times out too.
+    alice.step(Input::Timeout, time + slow);
+    assert_eq!(timed_out(&rx), vec![carol]);
+}
+
+#[test]
+fn test_clock_out_of_sync() {
+    let network = Network::Mainnet;
+    let genesis = network.genesis();
+    let time = LocalTime::from_secs(genesis.time as u64);
+    let local = ([0, 0, 0, 0], 0).into();
+    let msg = message::Builder::new(network);
+    let (tx, rx) = chan::unbounded();
+
+    let mut alice = Builder {
+        cache: model::Cache::new(genesis),
+        clock: AdjustedTime::new(time),
+        filters: model::FilterCache::new(FilterHeader::genesis(network)),
+        peers: HashMap::new(),
+        rng: fastrand::Rng::new(),
+        cfg: Config {
+            halt_on_clock_skew: true,
+            ..setup::CONFIG.clone()
+        },
+    }
+    .build(tx);
+
+    // Our peers agree that our clock is two hours behind.
+    let skewed = time + LocalDuration::from_mins(120);
+    let peers = (1..=4)
+        .map(|i| PeerId::from(([131, 31, 11, i], 8333)))
+        .collect::<Vec<_>>();
+
+    for (i, peer) in peers.iter().copied().enumerate() {
+        alice.step(Input::Connecting { addr: peer }, time);
+        alice.step(
+            Input::Connected {
+                addr: peer,
+                local_addr: local,
+                link: Link::Outbound,
+            },
+            time,
+        );
+        alice.step(
+            Input::Received(
+                peer,
+                msg.raw(NetworkMessage::Version(
+                    alice.peermgr.version(local, peer, i as u64, 0, skewed),
+                )),
+            ),
+            time,
+        );
+        alice.step(Input::Received(peer, msg.raw(NetworkMessage::Verack)), time);
+    }
+    let events = rx
+        .try_iter()
+        .filter_map(|o| match o {
+            Out::Event(Event::ClockOutOfSync { offset }) => Some(offset),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(events, vec![7200]);
+    assert_eq!(alice.clock.offset(), 0, "the clock isn't adjusted");
+
+    // Headers aren't imported until the skew is acknowledged.
+    let headers = BITCOIN_HEADERS
+        .tail
+        .iter()
+        .take(8)
+        .cloned()
+        .collect::<Vec<_>>();
+
+    alice.step(
+        Input::Received(peers[0], msg.raw(NetworkMessage::Headers(headers.clone()))),
+        time,
+    );
+    assert_eq!(alice.tree.height(), 0);
+
+    alice.step(Input::Command(Command::AcknowledgeClockSkew), time);
+    alice.step(
+        Input::Received(peers[0], msg.raw(NetworkMessage::Headers(headers))),
+        time,
+    );
+    assert_eq!(alice.tree.height(), 8);
+}
+
+#[test]
+fn test_message_counts() {
+    let network = Network::Mainnet;
+    let (mut alice, _rx, time) = setup::singleton(network);
+    let local = ([0, 0, 0, 0], 0).into();
+    let bob: PeerId = ([131, 31, 11, 33], 8333).into();
+    let msg = message::Builder::new(network);
+    let counts = |alice: &mut Protocol<_, _, _>, addr| {
+        let (tx, rx) = chan::bounded(1);
+        alice.step(Input::Command(Command::GetMessageCounts(addr, tx)), time);
+        rx.try_recv().unwrap()
+    };
+
+    assert_eq!(counts(&mut alice, bob), None);
+
+    alice.step(Input::Connecting { addr: bob }, time);
+    alice.step(
+        Input::Connected {
+            addr: bob,
+            local_addr: local,
+            link: Link::Outbound,
+        },
+        time,
+    );
+    alice.step(
+        Input::Received(
+            bob,
+            msg.raw(NetworkMessage::Version(
+                alice.peermgr.version(local, bob, 42, 0, time),
+            )),
+        ),
+        time,
+    );
+    alice.step(Input::Received(bob, msg.raw(NetworkMessage::Verack)), time);
+
+    for nonce in 0..3 {
+        alice.step(
+            Input::Received(bob, msg.raw(NetworkMessage::Ping(nonce))),
+            time,
+        );
+    }
+    alice.step(
+        Input::Received(
+            bob,
+            msg.raw(NetworkMessage::Headers(vec![BITCOIN_HEADERS.tail[0]])),
+        ),
+        time,
+    );
+    alice.step(Input::Unrecognized(bob, String::from("protoconf")), time);
+
+    let expected = [
+        ("headers", 1),
+        ("ping", 3),
+        ("protoconf", 1),
+        ("verack", 1),
+        ("version", 1),
+    ]
+    .iter()
+    .map(|(cmd, n)| (cmd.to_string(), *n))
+    .collect::<BTreeMap<_, _>>();
+
+    assert_eq!(counts(&mut alice, bob), Some(expected));
+}
+
+#[test]
+fn test_refetch_headers() {
+    let network = Network::Mainnet;
+    let (mut alice, rx, _) = setup::singleton(network);
+    let local = ([0, 0, 0, 0], 0).into();
+    let bob: PeerId = ([131, 31, 11, 33], 8333).into();
+    let msg = message::Builder::new(network);
+    let chain = NonEmpty::from((BITCOIN_HEADERS.head, BITCOIN_HEADERS.tail[..20].to_vec()));
+    let time = LocalTime::from_block_time(chain.last().time);
+
+    // A chain of the given length forking off ours at height 5.
+    let fork = |len: usize| {
+        let mut headers: Vec<BlockHeader> = Vec::new();
+        for header in &BITCOIN_HEADERS.tail[5..5 + len] {
+            let mut header = *header;
+            header.prev_blockhash = headers
+                .last()
+                .map_or(BITCOIN_HEADERS.tail[4].block_hash(), |h| h.block_hash());
+            header.nonce += 1;
+            headers.push(header);
+        }
+        headers
+    };
+    let refetched = |rx: &chan::Receiver<Out>| {
+        rx.try_iter().find_map(|o| match o {
+            Out::Event(Event::SyncManager(syncmgr::Event::HeadersRefetched {
+                addr,
+                from,
+                to,
+                result,
+            })) if addr == bob => Some((from, to, result)),
+            _ => None,
+        })
+    };
+    alice.tree = model::Cache::from(chain.clone());
+
+    alice.step(Input::Connecting { addr: bob }, time);
+    alice.step(
+        Input::Connected {
+            addr: bob,
+            local_addr: local,
+            link: Link::Outbound,
+        },
+        time,
+    );
+    alice.step(
+        Input::Received(
+            bob,
+            msg.raw(NetworkMessage::Version(
+                alice.peermgr.version(local, bob, 42, 20, time),
+            )),
+        ),
+        time,
+    );
+    alice.step(Input::Received(bob, msg.raw(NetworkMessage::Verack)), time);
+    // Bob has nothing new for us.
+    alice.step(
+        Input::Received(bob, msg.raw(NetworkMessage::Headers(vec![]))),
+        time,
+    );
+    rx.try_iter().for_each(drop);
+
+    // Refetching a range that comes back identical leaves our chain as it is.
+    alice.step(
+        Input::Command(Command::RefetchHeaders {
+            peer: bob,
+            from: 5,
+            to: 10,
+        }),
+        time,
+    );
+    let (locators, stop_hash) = rx
+        .try_iter()
+        .find_map(|o| match payload(&o) {
+            Some((
+                addr,
+                NetworkMessage::GetHeaders(GetHeadersMessage {
+                    locator_hashes,
+                    stop_hash,
+                    ..
+                }),
+            )) if addr == bob => Some((locator_hashes.clone(), *stop_hash)),
+            _ => None,
+        })
+        .expect("headers are requested from bob");
+
+    assert_eq!(locators, vec![BITCOIN_HEADERS.tail[3].block_hash()]);
+    assert_eq!(stop_hash, BITCOIN_HEADERS.tail[9].block_hash());
+
+    let tip = alice.tree.tip();
+    alice.step(
+        Input::Received(
+            bob,
+            msg.raw(NetworkMessage::Headers(
+                BITCOIN_HEADERS.tail[4..10].to_vec(),
+            )),
+        ),
+        time,
+    );
+    assert_eq!(refetched(&rx), Some((5, 10, ImportResult::TipUnchanged)));
+    assert_eq!(alice.tree.tip(), tip);
+    assert_eq!(alice.tree.height(), 20);
+
+    // Refetching a range that comes back different, with more work, replaces it.
+    alice.step(
+        Input::Command(Command::RefetchHeaders {
+            peer: bob,
+            from: 6,
+            to: 20,
+        }),
+        time,
+    );
+    let fork = fork(16);
+    alice.step(
+        Input::Received(bob, msg.raw(NetworkMessage::Headers(fork.clone()))),
+        time,
+    );
+
+    match refetched(&rx) {
+        Some((6, 20, ImportResult::TipChanged(hash, 21, _))) => {
+            assert_eq!(hash, fork.last().unwrap().block_hash());
+        }
+        other => panic!("unexpected refetch result: {:?}", other),
+    }
+    assert_eq!(alice.tree.height(), 21);
+    assert_eq!(alice.tree.get_block_hash(6), Some(fork[0].block_hash()));
+}
+
+#[test]
+fn test_sync_from_checkpoint() {
+    let network = Network::Mainnet;
+    let (mut alice, rx, _) = setup::singleton(network);
+    let local = ([0, 0, 0, 0], 0).into();
+    let bob: PeerId = ([131, 31, 11, 33], 8333).into();
+    let msg = message::Builder::new(network);
+    let headers = &BITCOIN_HEADERS.tail;
+    let time = LocalTime::from_block_time(headers[19].time);
+    // The checkpoint is the block at height 10.
+    let checkpoint = headers[9];
+    let chainwork = std::iter::once(&BITCOIN_HEADERS.head)
+        .chain(&headers[..10])
+        .fold(Work::default(), |work, h| work + h.work());
+
+    alice.tree =
+        model::Cache::from_checkpoint(10, checkpoint, chainwork, &network.params()).unwrap();
+    assert_eq!(alice.tree.height(), 10);
+    assert_eq!(alice.tree.tip().0, checkpoint.block_hash());
+
+    alice.step(Input::Connecting { addr: bob }, time);
+    alice.step(
+        Input::Connected {
+            addr: bob,
+            local_addr: local,
+            link: Link::Outbound,
+        },
+        time,
+    );
+    alice.step(
+        Input::Received(
+            bob,
+            msg.raw(NetworkMessage::Version(
+                alice.peermgr.version(local, bob, 42, 20, time),
+            )),
+        ),
+        time,
+    );
+    alice.step(Input::Received(bob, msg.raw(NetworkMessage::Verack)), time);
+
+    // Alice asks for the headers following the checkpoint.
+    let locators =
+        rx.try_iter()
+            .find_map(|o| match payload(&o) {
+                Some((
+                    addr,
+                    NetworkMessage::GetHeaders(GetHeadersMessage { locator_hashes, .. }),
+                )) if addr == bob => Some(locator_hashes.clone()),
+                _ => None,
+            })
+            .expect("headers are requested from bob");
+    assert_eq!(locators, vec![checkpoint.block_hash()]);
+
+    alice.step(
+        Input::Received(
+            bob,
+            msg.raw(NetworkMessage::Headers(headers[10..20].to_vec())),
+        ),
+        time,
+    );
+
+    // Heights are those of the full chain, not relative to the checkpoint.
+    assert_eq!(alice.tree.height(), 20);
+    assert_eq!(alice.tree.tip().0, headers[19].block_hash());
+    assert_eq!(
+        alice
+            .tree
+            .get_block(&headers[14].block_hash())
+            .map(|(h, _)| h),
+        Some(15)
+    );
+    assert_eq!(alice.tree.get_block_by_height(15), Some(&headers[14]));
+    assert_eq!(alice.tree.get_block_by_height(9), None);
+    assert_eq!(
+        alice.tree.chain_work(),
+        headers[10..20]
+            .iter()
+            .fold(chainwork, |work, h| work + h.work())
+    );
+}
+
+#[test]
+fn test_inbound_reachability() {
+    let network = Network::Mainnet;
+    let local = ([131, 31, 11, 1], 8333).into();
+    let bob: PeerId = ([131, 31, 11, 33], 8333).into();
+    let msg = message::Builder::new(network);
+    let reachable = |rx: &chan::Receiver<Out>| {
+        rx.try_iter()
+            .any(|o| matches!(o, Out::Event(Event::InboundReachable)))
+    };
+    let unreachable = |rx: &chan::Receiver<Out>| {
+        rx.try_iter()
+            .any(|o| matches!(o, Out::Event(Event::NoInboundConnections(_))))
+    };
+
+    // A successful inbound handshake shows we're reachable.
+    let (mut alice, rx, time) = setup::singleton(network);
+    alice.initialize(time).unwrap();
+    alice.step(
+        Input::Connected {
+            addr: bob,
+            local_addr: local,
+            link: Link::Inbound,
+        },
+        time,
+    );
+    alice.step(
+        Input::Received(
+            bob,
+            msg.raw(NetworkMessage::Version(
+                alice.peermgr.version(bob, local, 42, 0, time),
+            )),
+        ),
+        time,
+    );
+    alice.step(Input::Received(bob, msg.raw(NetworkMessage::Verack)), time);
+    assert!(reachable(&rx));
+
+    alice.step(Input::Timeout, time + reachability::INBOUND_WINDOW);
+    assert!(!unreachable(&rx), "Alice is known to be reachable");
+
+    // Without inbound connections, Alice warns once the window has elapsed.
+    let (mut alice, rx, time) = setup::singleton(network);
+    alice.initialize(time).unwrap();
+
+    alice.step(
+        Input::Timeout,
+        time + reachability::INBOUND_WINDOW - LocalDuration::from_secs(1),
+    );
+    assert!(!unreachable(&rx));
+
+    alice.step(Input::Timeout, time + reachability::INBOUND_WINDOW);
+    assert!(unreachable(&rx));
+
+    alice.step(
+        Input::Timeout,
+        time + reachability::INBOUND_WINDOW + LocalDuration::from_secs(60),
+    );
+    assert!(!unreachable(&rx), "The warning is emitted once");
+}
+
+#[test]
+fn test_coalesce_reorgs() {
+    let network = Network::Regtest;
+    let genesis = network.genesis();
+    let time = LocalTime::from_secs(genesis.time as u64 + 60 * 60 * 24);
+
+    // Build a chain of the given length on top of a header. Different seeds yield
+    // different chains.
+    let chain = |prev: &BlockHeader, len: usize, seed: u32| {
+        let mut headers: Vec<BlockHeader> = Vec::new();
+        for _ in 0..len {
+            let prev = headers.last().unwrap_or(prev);
+            let mut header = BlockHeader {
+                version: 1,
+                prev_blockhash: prev.block_hash(),
+                merkle_root: Default::default(),
+                time: prev.time + 600 + seed,
+                bits: prev.bits,
+                nonce: 0,
+            };
+            nakamoto_test::block::solve(&mut header);
+            headers.push(header);
+        }
+        headers
+    };
+    let hashes = |headers: &[BlockHeader]| headers.iter().map(|h| h.block_hash()).collect();
+
+    // g <- a1 <- a2 <- a3
+    // g <- b1 <- b2 <- b3 <- b4
+    //             b2 <- c3 <- c4 <- c5
+    let a = chain(&genesis, 3, 0);
+    let b = chain(&genesis, 4, 1);
+    let c = chain(&b[1], 3, 2);
+
+    for coalesce in [false, true].iter().copied() {
+        let tree = BlockCache::from(
+            store::Memory::new(NonEmpty::new(genesis)),
+            network.params(),
+            &[],
+        )
+        .unwrap();
+        let (tx, rx) = chan::unbounded();
+        let mut alice = Builder {
+            cache: tree,
+            clock: AdjustedTime::new(time),
+            filters: model::FilterCache::new(FilterHeader::genesis(network)),
+            peers: HashMap::new(),
+            rng: fastrand::Rng::new(),
+            cfg: Config {
+                network,
+                params: network.params(),
+                coalesce_reorgs: coalesce,
+                ..setup::CONFIG.clone()
+            },
+        }
+        .build(tx);
+
+        let (reply, _) = chan::bounded(1);
+        alice.step(
+            Input::Command(Command::ImportHeaders(a.clone(), reply)),
+            time,
+        );
+        rx.try_iter().for_each(drop);
+
+        // Both heavier chains are imported in a single step.
+        let (reply, result) = chan::bounded(1);
+        let headers = b.iter().chain(c.iter()).cloned().collect();
+        alice.step(Input::Command(Command::ImportHeaders(headers, reply)), time);
+
+        let reorgs = rx
+            .try_iter()
+            .filter_map(|o| match o {
+                Out::Event(Event::SyncManager(syncmgr::Event::HeadersImported(
+                    ImportResult::TipChanged(tip, height, reverted),
+                ))) => Some((tip, height, reverted)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(alice.tree.tip().0, c[2].block_hash());
+
+        if coalesce {
+            // The only blocks disconnected from the initial chain are those of `a`.
+            let expected = (c[2].block_hash(), 5, hashes(&a));
+
+            assert_eq!(reorgs, vec![expected.clone()]);
+            assert_eq!(
+                result.try_recv().unwrap().unwrap(),
+                ImportResult::TipChanged(expected.0, expected.1, expected.2)
+            );
+        } else {
+            assert_eq!(
+                reorgs,
+                vec![
+                    (b[3].block_hash(), 4, hashes(&a)),
+                    (c[2].block_hash(), 5, hashes(&b[2..])),
+                ]
+            );
+        }
+    }
+}
+
+#[test]
+fn test_peers_with_block() {
+    let network = Network::Mainnet;
+    let (mut alice, rx, time) = setup::singleton(network);
+    let local = ([0, 0, 0, 0], 0).into();
+    let bob: PeerId = ([131, 31, 11, 33], 8333).into();
+    let carol: PeerId = ([132, 32, 12, 34], 8333).into();
+    let dave: PeerId = ([133, 33, 13, 35], 8333).into();
+    let msg = message::Builder::new(network);
+    let chain = NonEmpty::from((BITCOIN_HEADERS.head, BITCOIN_HEADERS.tail[..8].to_vec()));
+    let hash = chain.last().block_hash();
+
+    alice.tree = model::Cache::from(chain);
+
+    for (nonce, peer) in [bob, carol, dave].iter().copied().enumerate() {
+        alice.step(Input::Connecting { addr: peer }, time);
+        alice.step(
+            Input::Connected {
+                addr: peer,
+                local_addr: local,
+                link: Link::Outbound,
+            },
+            time,
+        );
+        alice.step(
+            Input::Received(
+                peer,
+                msg.raw(NetworkMessage::Version(alice.peermgr.version(
+                    local,
+                    peer,
+                    nonce as u64,
+                    8,
+                    time,
+                ))),
+            ),
+            time,
+        );
+        alice.step(Input::Received(peer, msg.raw(NetworkMessage::Verack)), time);
+    }
+    assert!(alice.peers_with_block(&hash).is_empty());
+
+    for peer in [bob, carol].iter().copied() {
+        alice.step(
+            Input::Received(
+                peer,
+                msg.raw(NetworkMessage::Inv(vec![Inventory::Block(hash)])),
+            ),
+            time,
+        );
+    }
+    assert_eq!(alice.peers_with_block(&hash), vec![bob, carol]);
+    rx.try_iter().for_each(drop);
+
+    // The block is requested from a peer that announced it.
+    alice.step(Input::Command(Command::GetBlock(hash)), time);
+    let requested = rx
+        .try_iter()
+        .filter_map(|o| match payload(&o) {
+            Some((addr, NetworkMessage::GetData(inv))) if inv == &[Inventory::Block(hash)] => {
+                Some(addr)
+            }
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(requested.len(), 1);
+    assert!(requested[0] == bob || requested[0] == carol);
+
+    // Peers that disconnect are forgotten.
+    alice.step(
+        Input::Disconnected(bob, DisconnectReason::PeerTimeout),
+        time,
+    );
+    assert_eq!(alice.peers_with_block(&hash), vec![carol]);
+}
+
+#[test]
+fn test_protocol_state() {
+    let network = Network::Mainnet;
+    let (mut alice, rx, _) = setup::singleton(network);
+    let local = ([0, 0, 0, 0], 0).into();
+    let bob: PeerId = ([131, 31, 11, 33], 8333).into();
+    let msg = message::Builder::new(network);
+    let headers = BITCOIN_HEADERS.tail[..8].to_vec();
+    let tip = headers[7];
+    let time = LocalTime::from_block_time(tip.time);
+    let states = |rx: &chan::Receiver<Out>| {
+        rx.try_iter()
+            .filter_map(|o| match o {
+                Out::Event(Event::StateChanged(state)) => Some(state),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(alice.state(), ProtocolState::Initializing);
+
+    // Our address book is empty.
+    alice.initialize(time).unwrap();
+    assert_eq!(states(&rx), vec![ProtocolState::Bootstrapping]);
+
+    alice.step(Input::Command(Command::Connect(bob)), time);
+    assert_eq!(states(&rx), vec![ProtocolState::Connecting]);
+
+    alice.step(Input::Connecting { addr: bob }, time);
+    alice.step(
+        Input::Connected {
+            addr: bob,
+            local_addr: local,
+            link: Link::Outbound,
+        },
+        time,
+    );
+    alice.step(
+        Input::Received(
+            bob,
+            msg.raw(NetworkMessage::Version(
+                alice.peermgr.version(local, bob, 42, 8, time),
+            )),
+        ),
+        time,
+    );
+    assert!(states(&rx).is_empty());
+
+    // Bob has a longer chain.
+    alice.step(Input::Received(bob, msg.raw(NetworkMessage::Verack)), time);
+    assert_eq!(states(&rx), vec![ProtocolState::SyncingHeaders]);
+
+    alice.step(
+        Input::Received(bob, msg.raw(NetworkMessage::Headers(headers))),
+        time,
+    );
+    assert_eq!(alice.tree.height(), 8);
+    assert_eq!(states(&rx), vec![ProtocolState::Synced]);
+
+    alice.step(Input::Command(Command::GetBlock(tip.block_hash())), time);
+    assert_eq!(states(&rx), vec![ProtocolState::SyncingBlocks]);
+
+    alice.step(
+        Input::Received(
+            bob,
+            msg.raw(NetworkMessage::Block(bitcoin::Block {
+                header: tip,
+                txdata: vec![],
+            })),
+        ),
+        time,
+    );
+    assert_eq!(states(&rx), vec![ProtocolState::Synced]);
+    assert_eq!(alice.state(), ProtocolState::Synced);
+
+    alice.step(Input::Command(Command::Shutdown), time);
+    assert_eq!(states(&rx), vec![ProtocolState::ShuttingDown]);
+    assert_eq!(alice.state(), ProtocolState::ShuttingDown);
+}
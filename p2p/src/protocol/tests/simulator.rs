@@ -158,7 +158,7 @@ pub struct Peer {
 
 impl Peer {
     pub fn initialize(&mut self, time: LocalTime) {
-        self.protocol.initialize(time)
+        self.protocol.initialize(time).unwrap()
     }
 
     pub fn schedule(&mut self, inbox: &mut VecDeque<(PeerId, Input)>, output: Out) {
@@ -426,7 +426,7 @@ pub fn run<T: BlockTree, F: Filters, P: peer::Store>(
 
     // Add peers to simulator.
     for ((addr, proto, rx), evs) in peers.into_iter().zip(inputs.into_iter()) {
-        proto.initialize(local_time);
+        proto.initialize(local_time).unwrap();
 
         for o in rx.try_iter() {
             Sim::schedule(&mut tmp, &mut events, &addr, o);
@@ -0,0 +1,104 @@
+//! Rolling measurement of sync throughput.
+//!
+//! Throughput is measured over a sliding window, so that it reflects recent performance
+//! rather than a lifetime average. Comparing header and block throughput helps tell
+//! whether syncing is held back by the network or by validation.
+//!
+use std::cmp;
+use std::collections::VecDeque;
+
+use nakamoto_common::block::time::{LocalDuration, LocalTime};
+
+/// Span of time over which throughput is measured.
+pub const WINDOW: LocalDuration = LocalDuration::from_secs(60);
+
+/// Recent sync throughput.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SyncSpeed {
+    /// Block headers imported per second.
+    pub headers_per_sec: f64,
+    /// Blocks received per second.
+    pub blocks_per_sec: f64,
+    /// Bytes of blocks received per second.
+    pub block_bytes_per_sec: f64,
+}
+
+/// Measures the rate of a quantity over the last [`WINDOW`].
+#[derive(Debug, Default)]
+struct Meter {
+    /// Recorded amounts, oldest first.
+    samples: VecDeque<(LocalTime, u64)>,
+    /// Time of the first recorded amount. Used to measure rates before a full window has
+    /// elapsed.
+    since: Option<LocalTime>,
+}
+
+impl Meter {
+    /// Record an amount.
+    fn record(&mut self, now: LocalTime, amount: u64) {
+        self.since.get_or_insert(now);
+        self.samples.push_back((now, amount));
+        self.expire(now);
+    }
+
+    /// Get the rate per second over the last window.
+    fn rate(&mut self, now: LocalTime) -> f64 {
+        self.expire(now);
+
+        let since = if let Some(since) = self.since {
+            since
+        } else {
+            return 0.;
+        };
+        let total = self.samples.iter().map(|(_, n)| n).sum::<u64>();
+        // Don't let a burst right after we started inflate the rate.
+        let span = cmp::max(cmp::min(WINDOW, now - since), LocalDuration::from_secs(1));
+
+        total as f64 * 1000. / span.as_millis() as f64
+    }
+
+    /// Drop the samples that fell out of the window.
+    fn expire(&mut self, now: LocalTime) {
+        while let Some((time, _)) = self.samples.front() {
+            if now - *time < WINDOW {
+                break;
+            }
+            self.samples.pop_front();
+        }
+    }
+}
+
+/// Measures sync throughput.
+#[derive(Debug, Default)]
+pub struct Throughput {
+    headers: Meter,
+    blocks: Meter,
+    block_bytes: Meter,
+}
+
+impl Throughput {
+    /// Create a new throughput measurement.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Called when block headers were imported.
+    pub fn headers_imported(&mut self, count: usize, now: LocalTime) {
+        self.headers.record(now, count as u64);
+    }
+
+    /// Called when a block of the given size was received.
+    pub fn block_received(&mut self, size: usize, now: LocalTime) {
+        self.blocks.record(now, 1);
+        self.block_bytes.record(now, size as u64);
+    }
+
+    /// Get the throughput over the last [`WINDOW`].
+    pub fn speed(&mut self, now: LocalTime) -> SyncSpeed {
+        SyncSpeed {
+            headers_per_sec: self.headers.rate(now),
+            blocks_per_sec: self.blocks.rate(now),
+            block_bytes_per_sec: self.block_bytes.rate(now),
+        }
+    }
+}
@@ -0,0 +1,59 @@
+//! Background validation of the assumed-valid region of the chain.
+//!
+//! Headers leading up to an assume-valid block are imported with only minimal checks, so
+//! that the client can catch up with the network quickly. Once that block is part of the
+//! active chain, the skipped checks are run over the headers below it, a batch at a time,
+//! so that syncing past it isn't held up.
+//!
+use nakamoto_common::block::time::Clock;
+use nakamoto_common::block::tree::BlockTree;
+use nakamoto_common::block::Height;
+
+use crate::event::Event;
+
+/// Number of headers validated per protocol step.
+pub const BATCH_SIZE: Height = 2000;
+
+/// Re-validates the assumed-valid region of the chain in the background.
+#[derive(Debug, Default)]
+pub struct BackgroundValidation {
+    /// Height up to which headers were fully validated.
+    validated: Height,
+    /// Whether validation is over, either because it completed, or because it failed.
+    done: bool,
+}
+
+impl BackgroundValidation {
+    /// Create a new background validation.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Validate the next batch of headers, if any. Returns an event once validation is over.
+    pub fn step<T: BlockTree>(&mut self, tree: &T, clock: &impl Clock) -> Option<Event> {
+        let assumed = tree.assumed_valid_height();
+
+        if self.done || assumed <= self.validated {
+            return None;
+        }
+        let end = Height::min(self.validated + BATCH_SIZE, assumed);
+
+        if let Err((height, err)) = tree.revalidate(self.validated + 1..end + 1, clock) {
+            self.done = true;
+
+            let hash = tree
+                .get_block_hash(height)
+                .expect("invalid headers are part of the active chain");
+
+            return Some(Event::ValidationFailure(height, hash, err.to_string()));
+        }
+        self.validated = end;
+
+        if end == assumed {
+            self.done = true;
+
+            return Some(Event::BackgroundValidationComplete(end));
+        }
+        None
+    }
+}
@@ -1,15 +1,53 @@
 //! Reactor trait.
+use std::fmt;
+use std::hash::Hash;
 use std::{io, net};
 
 use crossbeam_channel as chan;
 
 use nakamoto_common::block::filter::Filters;
+use nakamoto_common::block::time::LocalTime;
 use nakamoto_common::block::tree::BlockTree;
 use nakamoto_common::p2p::peer;
 
 use crate::error::Error;
 use crate::event::Event;
-use crate::protocol::{self, Command};
+use crate::protocol::{self, Command, Input};
+
+/// Identifies a peer connection of a reactor. Besides the address of the remote peer,
+/// which is what the reactor connects to, a key may carry a namespace, so that several
+/// connections to the same address can be told apart. See [`protocol::multiplex::Peer`].
+pub trait PeerKey: Copy + Eq + Hash + fmt::Debug + fmt::Display {
+    /// Address of the remote peer.
+    fn addr(&self) -> net::SocketAddr;
+    /// Key of an inbound connection from the given address.
+    fn inbound(addr: net::SocketAddr) -> Self;
+}
+
+impl PeerKey for net::SocketAddr {
+    fn addr(&self) -> net::SocketAddr {
+        *self
+    }
+
+    fn inbound(addr: net::SocketAddr) -> Self {
+        addr
+    }
+}
+
+/// A state machine that can be driven by a reactor, eg. a protocol instance, or a
+/// [`protocol::multiplex::Multiplexer`] of instances. Outputs are sent on a channel
+/// given to the machine when it is built.
+pub trait Machine {
+    /// Identifies the peers of the machine.
+    type Peer: PeerKey;
+    /// Commands accepted by the machine.
+    type Command;
+
+    /// Initialize the machine. Called once, before any input is processed.
+    fn initialize(&mut self, time: LocalTime) -> Result<(), Error>;
+    /// Process the next input and advance the state machine by one step.
+    fn step(&mut self, input: Input<Self::Peer, Self::Command>, time: LocalTime);
+}
 
 /// Any network reactor that can drive the light-client protocol.
 pub trait Reactor {
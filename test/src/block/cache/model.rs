@@ -6,13 +6,14 @@ use std::ops::Range;
 use nakamoto_common::block::filter::{self, BlockFilter, FilterHash, FilterHeader, Filters};
 use nakamoto_common::block::iter::Iter;
 use nakamoto_common::block::tree::{BlockTree, Branch, Error, ImportResult};
-use nakamoto_common::block::Height;
+use nakamoto_common::block::{Height, Work};
 
 use std::collections::{BTreeMap, HashMap, VecDeque};
 
 use nonempty::NonEmpty;
 
 use bitcoin::blockdata::block::BlockHeader;
+use bitcoin::consensus::params::Params;
 use bitcoin::hash_types::BlockHash;
 
 #[derive(Debug, Clone)]
@@ -20,7 +21,13 @@ pub struct Cache {
     pub headers: HashMap<BlockHash, BlockHeader>,
     pub chain: NonEmpty<BlockHeader>,
     pub tip: BlockHash,
+    /// Hash of the root of the chain. This is the genesis, unless the cache was created
+    /// from a checkpoint.
     pub genesis: BlockHash,
+    /// Height of the root of the chain.
+    pub root: Height,
+    /// Cumulative work of the chain up to and including the root, if rooted at a checkpoint.
+    pub root_work: Option<Work>,
 }
 
 impl Cache {
@@ -36,9 +43,34 @@ impl Cache {
             chain,
             tip: hash,
             genesis: hash,
+            root: 0,
+            root_work: None,
         }
     }
 
+    /// Create a cache rooted at a trusted checkpoint instead of the genesis, given the
+    /// checkpoint's height, header, and the cumulative work of the chain up to and
+    /// including it. The checkpoint takes the place of the genesis: it is never
+    /// re-orged, and headers below it are never requested. Heights are those of the full
+    /// chain, so the checkpoint keeps its own height.
+    pub fn from_checkpoint(
+        height: Height,
+        header: BlockHeader,
+        chainwork: Work,
+        params: &Params,
+    ) -> Result<Self, Error> {
+        let target = header.target();
+
+        if target > params.pow_limit {
+            return Err(Error::InvalidBlockTarget(target, params.pow_limit));
+        }
+        Ok(Self {
+            root: height,
+            root_work: Some(chainwork),
+            ..Self::new(header)
+        })
+    }
+
     pub fn from(chain: NonEmpty<BlockHeader>) -> Self {
         let genesis = chain.head.block_hash();
         let tip = chain.last().block_hash();
@@ -53,11 +85,13 @@ impl Cache {
             chain,
             tip,
             genesis,
+            root: 0,
+            root_work: None,
         }
     }
 
     pub fn rollback(&mut self, height: Height) -> Result<(), Error> {
-        for block in self.chain.tail.drain(height as usize..) {
+        for block in self.chain.tail.drain((height - self.root) as usize..) {
             self.headers.remove(&block.block_hash());
         }
         Ok(())
@@ -73,7 +107,7 @@ impl Cache {
         }
 
         match headers.pop_front() {
-            Some(root) if root.block_hash() == self.genesis => {
+            Some(root) if self.is_genesis(&root.block_hash()) => {
                 Some(NonEmpty::from((root, headers.into())))
             }
             _ => None,
@@ -99,7 +133,14 @@ impl Cache {
                     let a_hash = a.last().block_hash();
                     let b_hash = b.last().block_hash();
 
-                    b_hash.cmp(&a_hash)
+                    // The current tip is kept on equal work. Otherwise, the lowest hash wins.
+                    if a_hash == self.tip {
+                        std::cmp::Ordering::Greater
+                    } else if b_hash == self.tip {
+                        std::cmp::Ordering::Less
+                    } else {
+                        b_hash.cmp(&a_hash)
+                    }
                 } else {
                     a_work.cmp(&b_work)
                 }
@@ -129,6 +170,10 @@ impl BlockTree for Cache {
         }
     }
 
+    fn is_genesis(&self, hash: &BlockHash) -> bool {
+        &self.genesis == hash
+    }
+
     fn extend_tip<C>(&mut self, header: BlockHeader, _context: &C) -> Result<ImportResult, Error> {
         if header.prev_blockhash == self.tip {
             let hash = header.block_hash();
@@ -146,7 +191,7 @@ impl BlockTree for Cache {
     fn get_block(&self, hash: &BlockHash) -> Option<(Height, &BlockHeader)> {
         for (height, header) in self.chain.iter().enumerate() {
             if hash == &header.block_hash() {
-                return Some((height as Height, header));
+                return Some((self.root + height as Height, header));
             }
         }
         None
@@ -154,11 +199,26 @@ impl BlockTree for Cache {
 
     fn locate_headers(
         &self,
-        _locators: &[BlockHash],
-        _stop_hash: BlockHash,
-        _max: usize,
+        locators: &[BlockHash],
+        stop_hash: BlockHash,
+        max: usize,
     ) -> Vec<BlockHeader> {
-        unimplemented!()
+        let start = locators
+            .iter()
+            .find_map(|h| self.get_block(h))
+            .map_or(self.root, |(height, _)| height)
+            + 1;
+        let stop = self
+            .get_block(&stop_hash)
+            .map_or(self.height(), |(height, _)| height);
+
+        self.chain
+            .iter()
+            .skip((start - self.root) as usize)
+            .take(max)
+            .take((stop + 1).saturating_sub(start) as usize)
+            .cloned()
+            .collect()
     }
 
     fn locator_hashes(&self, _from: Height) -> Vec<BlockHash> {
@@ -166,7 +226,25 @@ impl BlockTree for Cache {
     }
 
     fn get_block_by_height(&self, height: Height) -> Option<&BlockHeader> {
-        self.chain.get(height as usize)
+        self.chain.get(height.checked_sub(self.root)? as usize)
+    }
+
+    fn genesis(&self) -> &BlockHeader {
+        &self.chain.head
+    }
+
+    fn chain_work(&self) -> Work {
+        let root = self.root_work.unwrap_or_else(|| self.chain.head.work());
+
+        root + Branch(&self.chain.tail).work()
+    }
+
+    fn range<'a>(&'a self, range: Range<Height>) -> Box<dyn Iterator<Item = BlockHeader> + 'a> {
+        Box::new(
+            self.iter()
+                .filter(move |(height, _)| range.contains(height))
+                .map(|(_, header)| header),
+        )
     }
 
     fn tip(&self) -> (BlockHash, BlockHeader) {
@@ -175,11 +253,11 @@ impl BlockTree for Cache {
     }
 
     fn height(&self) -> Height {
-        self.chain.len() as Height - 1
+        self.root + self.chain.len() as Height - 1
     }
 
     fn iter<'a>(&'a self) -> Box<dyn DoubleEndedIterator<Item = (Height, BlockHeader)> + 'a> {
-        Box::new(Iter::new(&self.chain).map(|(i, h)| (i, *h)))
+        Box::new(Iter::new(&self.chain).map(move |(i, h)| (self.root + i, *h)))
     }
 
     fn contains(&self, hash: &BlockHash) -> bool {
@@ -194,6 +272,7 @@ impl BlockTree for Cache {
 #[derive(Clone)]
 pub struct FilterCache {
     headers: NonEmpty<(FilterHash, FilterHeader)>,
+    blocks: Vec<BlockHash>,
     filters: BTreeMap<Height, BlockFilter>,
 }
 
@@ -201,6 +280,7 @@ impl FilterCache {
     pub fn new(genesis: FilterHeader) -> Self {
         Self {
             headers: NonEmpty::new((FilterHash::default(), genesis)),
+            blocks: Vec::new(),
             filters: BTreeMap::new(),
         }
     }
@@ -222,15 +302,36 @@ impl Filters for FilterCache {
             .collect()
     }
 
+    fn get_block_hash(&self, height: Height) -> Option<BlockHash> {
+        // The genesis block hash isn't known.
+        height
+            .checked_sub(1)
+            .and_then(|h| self.blocks.get(h as usize))
+            .copied()
+    }
+
     fn import_headers(
         &mut self,
-        headers: Vec<(FilterHash, FilterHeader)>,
+        headers: Vec<(BlockHash, FilterHash, FilterHeader)>,
     ) -> Result<Height, filter::Error> {
-        self.headers.tail.extend(headers);
+        for (block, hash, header) in headers {
+            self.blocks.push(block);
+            self.headers.tail.push((hash, header));
+        }
 
         Ok(self.height())
     }
 
+    fn get_filter(&self, height: Height) -> Option<BlockFilter> {
+        self.filters.get(&height).cloned()
+    }
+
+    fn import_filter(&mut self, height: Height, filter: BlockFilter) -> Result<(), filter::Error> {
+        self.filters.insert(height, filter);
+
+        Ok(())
+    }
+
     fn tip(&self) -> (&FilterHash, &FilterHeader) {
         let (hash, header) = self.headers.last();
         (&hash, &header)
@@ -245,6 +346,7 @@ impl Filters for FilterCache {
         let height = self.height() - n as Height;
 
         self.headers.tail.truncate(height as usize);
+        self.blocks.truncate(height as usize);
 
         let heights = self
             .filters